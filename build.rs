@@ -0,0 +1,50 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+// The `tls` feature links libssl and libcrypto from OpenSSL 3. They are
+// looked up with pkg-config, or in OPENSSL_LIB_DIR, so that a missing
+// library fails the build with a hint rather than the link with a wall of
+// undefined symbols. Builds without the feature do not need OpenSSL at all.
+fn main() {
+    println!("cargo:rerun-if-env-changed=OPENSSL_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_LIBDIR");
+    if env::var_os("CARGO_FEATURE_TLS").is_none() {
+        return;
+    }
+
+    if let Some(dir) = env::var_os("OPENSSL_LIB_DIR") {
+        let dir = Path::new(&dir);
+        if !dir.join("libssl.so").exists() && !dir.join("libssl.a").exists() {
+            panic!("OPENSSL_LIB_DIR={} holds no libssl", dir.display());
+        }
+        println!("cargo:rustc-link-search=native={}", dir.display());
+        println!("cargo:rustc-link-lib=ssl");
+        println!("cargo:rustc-link-lib=crypto");
+        return;
+    }
+
+    let found = Command::new("pkg-config")
+        .args(["--atleast-version=3.0.0", "openssl"])
+        .status()
+        .is_ok_and(|status| status.success());
+    let libs = Command::new("pkg-config").args(["--libs", "openssl"]).output();
+    match libs {
+        Ok(libs) if found && libs.status.success() => {
+            for flag in String::from_utf8_lossy(&libs.stdout).split_whitespace() {
+                if let Some(dir) = flag.strip_prefix("-L") {
+                    println!("cargo:rustc-link-search=native={}", dir);
+                } else if let Some(lib) = flag.strip_prefix("-l") {
+                    println!("cargo:rustc-link-lib={}", lib);
+                }
+            }
+        },
+        _ => panic!(
+            "the `tls` feature needs OpenSSL 3 with its development files \
+             (libssl-dev on Debian and Ubuntu, openssl-devel on Fedora), found \
+             through pkg-config or OPENSSL_LIB_DIR; build without `--features tls` \
+             to serve plain HTTP only"
+        ),
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use hyper::{Body, Request, Response, StatusCode};
+use crate::event;
+use crate::acl::{self, AclRule};
+use crate::auth::{self, AuthZone};
+use crate::config::Config;
+use crate::files::is_denied;
+
+/// Name of the per-directory access file read with `--access-files`.
+pub const FILE_NAME: &str = ".rustyaccess";
+
+// Every access file from the served directory down to the requested file
+// applies: each one's allow/deny rules must let the client in, deny-files
+// patterns add up, and the deepest file with an auth directive picks the
+// credentials. A subdirectory can narrow what its parents allow, never widen it.
+//
+//     # one directive per line
+//     auth-basic .htpasswd      # relative to this directory
+//     auth-ldap                 # or check against --ldap-url
+//     auth-realm Members
+//     allow 192.168.0.0/16
+//     deny all
+//     deny-files *.bak /drafts/**
+struct AccessFile {
+    auth: Option<AuthZone>,
+    realm: Option<String>,
+    acl: Vec<AclRule>,
+    deny_files: Vec<String>,
+}
+
+// Parsed files by path, with the modification time they were read at.
+type Cache = HashMap<PathBuf, (SystemTime, Arc<AccessFile>)>;
+
+static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+
+/// Applies the access files between `base` and `full_path`; a 401, 403 or,
+/// for a file that does not parse, 500 response when the request is refused.
+pub async fn check(config: &Config, base: &Path, full_path: &Path, client_ip: IpAddr, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let relative = full_path.strip_prefix(base).ok()?;
+    let mut dir = base.to_path_buf();
+    let mut rest: Vec<String> = relative.iter().map(|s| s.to_string_lossy().into_owned()).collect();
+    let mut auth = None;
+    loop {
+        match load(&dir) {
+            Ok(Some(file)) => {
+                if !acl::allows(&file.acl, client_ip) || is_denied(&file.deny_files, &format!("/{}", rest.join("/"))) {
+                    return Some(error(StatusCode::FORBIDDEN, "403 Forbidden"));
+                }
+                if file.auth.is_some() {
+                    auth = Some(file);
+                }
+            },
+            Ok(None) => {},
+            Err(e) => {
+                event::error(&e);
+                return Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"));
+            },
+        }
+        // The last segment is the file itself.
+        if rest.len() <= 1 {
+            break;
+        }
+        dir.push(rest.remove(0));
+    }
+
+    let file = auth?;
+    let zone = file.auth.as_ref()?;
+    if zone.uses_ldap() && (config.ldap.server.is_none() || config.ldap.bind_dn.is_none()) {
+        event::error(&format!("'auth-ldap' in {} files requires '--ldap-url' and '--ldap-bind-dn'", FILE_NAME));
+        return Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"));
+    }
+    let realm = file.realm.as_deref().unwrap_or(&config.auth_realm);
+    auth::authenticate(zone, realm, &config.ldap, req).await.err()
+}
+
+// Parsed files are kept until their modification time changes.
+fn load(dir: &Path) -> Result<Option<Arc<AccessFile>>, String> {
+    let path = dir.join(FILE_NAME);
+    let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return Ok(None),
+    };
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((cached, file)) = cache.lock().unwrap().get(&path) {
+        if *cached == modified {
+            return Ok(Some(file.clone()));
+        }
+    }
+    let file = Arc::new(parse(&path, dir)?);
+    cache.lock().unwrap().insert(path, (modified, file.clone()));
+    Ok(Some(file))
+}
+
+fn parse(path: &Path, dir: &Path) -> Result<AccessFile, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut file = AccessFile { auth: None, realm: None, acl: Vec::new(), deny_files: Vec::new() };
+    for (number, line) in contents.lines().enumerate() {
+        let invalid = |reason: String| format!("{}:{}: {}", path.display(), number + 1, reason);
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (directive, value) = match line.split_once(char::is_whitespace) {
+            Some((directive, value)) => (directive, value.trim()),
+            None => (line, ""),
+        };
+        match (directive, value) {
+            ("", _) => {},
+            ("auth-basic", htpasswd) if !htpasswd.is_empty() => {
+                file.auth = Some(AuthZone::directory(Some(&dir.join(htpasswd))).map_err(invalid)?);
+            },
+            ("auth-ldap", "") => file.auth = Some(AuthZone::directory(None).map_err(invalid)?),
+            ("auth-realm", realm) if !realm.is_empty() => {
+                if realm.contains(['"', '\\']) || realm.chars().any(|c| c.is_control()) {
+                    return Err(invalid("the realm cannot contain quotes, backslashes or control characters".to_string()));
+                }
+                file.realm = Some(realm.to_string());
+            },
+            ("allow" | "deny", value) => file.acl.push(AclRule::parse(directive == "allow", value).map_err(invalid)?),
+            ("deny-files", patterns) => file.deny_files.extend(patterns.split_whitespace().map(|p| p.to_string())),
+            (directive, _) => return Err(invalid(format!("unknown or incomplete directive '{}'", directive))),
+        }
+    }
+    Ok(file)
+}
+
+fn error(status_code: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(format!("<html>{}</html>", message)))
+        .unwrap()
+}
@@ -0,0 +1,242 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::StatusCode;
+use crate::auth;
+use crate::event::{self, Level};
+use crate::files::is_denied;
+use crate::log_format::{self, LogFormat};
+use crate::syslog;
+
+/// Where access log lines go and when that file is rotated.
+pub struct AccessLogConfig {
+    /// stdout when unset.
+    pub path: Option<PathBuf>,
+    pub max_size: Option<u64>,
+    pub rotate: Option<Rotate>,
+    /// Rotated files kept as PATH.1 (newest) to PATH.N.
+    pub keep: usize,
+    pub format: LogFormat,
+    /// `--log-exclude` patterns of paths whose successful requests are not logged.
+    pub exclude: Vec<String>,
+    pub samples: Vec<LogSample>,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            path: None,
+            max_size: None,
+            rotate: None,
+            keep: 7,
+            format: LogFormat::parse("--log-format", log_format::DEFAULT).unwrap(),
+            exclude: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl AccessLogConfig {
+    /// Whether a request gets a log line. Errors (4xx and 5xx) always do;
+    /// other requests are dropped by `--log-exclude` or thinned out by the
+    /// `--log-sample` rule with the longest prefix covering `path`.
+    pub fn wants(&self, path: &str, status: StatusCode) -> bool {
+        if status.is_client_error() || status.is_server_error() {
+            return true;
+        }
+        if is_denied(&self.exclude, path) {
+            return false;
+        }
+        let segments = auth::segments(path);
+        self.samples.iter()
+            .filter(|sample| segments.starts_with(&auth::segments(&sample.prefix)))
+            .max_by_key(|sample| sample.prefix.len())
+            .is_none_or(|sample| sample.take())
+    }
+}
+
+/// A `--log-sample PREFIX=RATE` rule: RATE (0 to 1) of the requests under
+/// PREFIX are logged, spread evenly, e.g. every tenth for 0.1.
+pub struct LogSample {
+    prefix: String,
+    rate: f64,
+    seen: AtomicU64,
+}
+
+impl LogSample {
+    pub fn parse(option: &str, value: &str) -> Result<LogSample, String> {
+        let invalid = || format!("invalid value '{}' for '{}': expected PREFIX=RATE with RATE from 0 to 1", value, option);
+        let (prefix, rate) = value.split_once('=').ok_or_else(invalid)?;
+        let rate = rate.parse::<f64>().ok().filter(|rate| (0.0..=1.0).contains(rate)).ok_or_else(invalid)?;
+        if !prefix.starts_with('/') {
+            return Err(invalid());
+        }
+        Ok(LogSample { prefix: prefix.trim_end_matches('/').to_string(), rate, seen: AtomicU64::new(0) })
+    }
+
+    // True whenever the running count times the rate reaches the next whole number.
+    fn take(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Rotate {
+    Hourly,
+    Daily,
+}
+
+impl Rotate {
+    pub fn parse(option: &str, value: &str) -> Result<Rotate, String> {
+        match value {
+            "hourly" => Ok(Rotate::Hourly),
+            "daily" => Ok(Rotate::Daily),
+            _ => Err(format!("invalid value '{}' for '{}': expected hourly or daily", value, option)),
+        }
+    }
+
+    // Index of the current hour or UTC day since the epoch.
+    fn period(self, now: SystemTime) -> u64 {
+        let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match self {
+            Rotate::Hourly => secs / 3600,
+            Rotate::Daily => secs / 86400,
+        }
+    }
+}
+
+enum Message {
+    Line(String),
+    Flush(Sender<()>),
+}
+
+static SENDER: OnceLock<Sender<Message>> = OnceLock::new();
+
+/// Sends access log lines to the configured file. Requests only queue their
+/// line; a writer thread appends them in batches and rotates the file.
+pub fn open(config: &AccessLogConfig) -> Result<(), String> {
+    let path = match &config.path {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+    let failed = |e: io::Error| format!("Failed to open log file {}: {}", path.display(), e);
+    let file = open_append(&path).map_err(failed)?;
+    let size = file.metadata().map_err(failed)?.len();
+    let writer = LogWriter {
+        file: BufWriter::new(file),
+        size,
+        period: config.rotate.map(|rotate| rotate.period(SystemTime::now())),
+        path,
+        max_size: config.max_size,
+        rotate: config.rotate,
+        keep: config.keep,
+    };
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || writer.run(receiver))
+        .map_err(|e| format!("Failed to start the access log writer: {}", e))?;
+    let _ = SENDER.set(sender);
+    Ok(())
+}
+
+pub fn write(line: &str) {
+    if syslog::send(Level::Info, line) {
+        return;
+    }
+    match SENDER.get() {
+        Some(sender) => {
+            let _ = sender.send(Message::Line(line.to_string()));
+        },
+        None => println!("{}", line),
+    }
+}
+
+/// Waits for queued lines to reach the file, e.g. before exiting.
+pub fn flush() {
+    if let Some(sender) = SENDER.get() {
+        let (done, flushed) = mpsc::channel();
+        if sender.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.recv_timeout(Duration::from_secs(5));
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+struct LogWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    period: Option<u64>,
+    max_size: Option<u64>,
+    rotate: Option<Rotate>,
+    keep: usize,
+}
+
+impl LogWriter {
+    fn run(mut self, receiver: Receiver<Message>) {
+        while let Ok(message) = receiver.recv() {
+            self.handle(message);
+            // Write everything already queued, then flush once for the batch.
+            while let Ok(message) = receiver.try_recv() {
+                self.handle(message);
+            }
+            if let Err(e) = self.file.flush() {
+                event::error(&format!("Failed to write access log {}: {}", self.path.display(), e));
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Line(line) => {
+                if let Err(e) = self.rotate_if_due(line.len() as u64 + 1) {
+                    event::error(&format!("Failed to rotate access log {}: {}", self.path.display(), e));
+                }
+                if writeln!(self.file, "{}", line).is_ok() {
+                    self.size += line.len() as u64 + 1;
+                }
+            },
+            Message::Flush(done) => {
+                let _ = self.file.flush();
+                let _ = done.send(());
+            },
+        }
+    }
+
+    fn rotate_if_due(&mut self, incoming: u64) -> io::Result<()> {
+        let too_big = self.max_size.is_some_and(|max| self.size + incoming > max);
+        let period = self.rotate.map(|rotate| rotate.period(SystemTime::now()));
+        if !too_big && period == self.period {
+            return Ok(());
+        }
+        self.period = period;
+        if self.size == 0 {
+            return Ok(());
+        }
+        self.file.flush()?;
+
+        // PATH.N-1 -> PATH.N, ..., PATH -> PATH.1; the oldest falls off.
+        let numbered = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(numbered(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(numbered(n), numbered(n + 1));
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
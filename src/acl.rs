@@ -0,0 +1,106 @@
+use std::net::IpAddr;
+use crate::auth;
+
+/// An address block such as `10.0.0.0/8`, `2001:db8::/32` or a single address.
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| format!("'{}' is not an IP address", address))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("'{}' is not a prefix length between 0 and {}", len, max))?,
+            None => max,
+        };
+        Ok(Cidr { network, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 clients as ::ffff:a.b.c.d.
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// An `allow` or `deny` rule; `all` matches every client.
+pub struct AclRule {
+    allow: bool,
+    cidr: Option<Cidr>,
+}
+
+impl AclRule {
+    pub fn parse(allow: bool, value: &str) -> Result<AclRule, String> {
+        let cidr = match value {
+            "all" => None,
+            value => Some(Cidr::parse(value)?),
+        };
+        Ok(AclRule { allow, cidr })
+    }
+}
+
+/// The first rule matching `ip` decides, as in nginx; clients no rule
+/// matches are allowed, so a list usually ends with `deny all`.
+pub fn allows(rules: &[AclRule], ip: IpAddr) -> bool {
+    rules.iter()
+        .find(|rule| rule.cidr.as_ref().is_none_or(|cidr| cidr.contains(ip)))
+        .is_none_or(|rule| rule.allow)
+}
+
+/// `--allow-ip`/`--deny-ip` rules: the global list applies to every request,
+/// then the list of the longest matching path prefix, if any.
+#[derive(Default)]
+pub struct IpAcl {
+    global: Vec<AclRule>,
+    prefixes: Vec<(String, Vec<AclRule>)>,
+}
+
+impl IpAcl {
+    /// Adds a `[PREFIX=]CIDR|all` rule after those already given.
+    pub fn add(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let invalid = |reason: String| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let allow = option == "--allow-ip";
+        let (prefix, cidr) = match value.split_once('=') {
+            Some((prefix, _)) if !prefix.starts_with('/') => return Err(invalid("the prefix must start with '/'".to_string())),
+            Some((prefix, cidr)) => (Some(prefix.trim_end_matches('/')), cidr),
+            None => (None, value),
+        };
+        let rule = AclRule::parse(allow, cidr).map_err(invalid)?;
+        match prefix {
+            None => self.global.push(rule),
+            Some(prefix) => match self.prefixes.iter_mut().find(|(p, _)| p == prefix) {
+                Some((_, rules)) => rules.push(rule),
+                None => self.prefixes.push((prefix.to_string(), vec![rule])),
+            },
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, path: &str, ip: IpAddr) -> bool {
+        if !allows(&self.global, ip) {
+            return false;
+        }
+        let segments = auth::segments(path);
+        self.prefixes.iter()
+            .filter(|(prefix, _)| segments.starts_with(&auth::segments(prefix)))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .is_none_or(|(_, rules)| allows(rules, ip))
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+use hyper::{Body, Request, Response, StatusCode};
+use url::form_urlencoded;
+use crate::auth::{self, RemoteUser, WatchedFile};
+use crate::password::constant_time_eq;
+
+pub const HEADER: &str = "x-api-key";
+pub const QUERY_PARAM: &str = "api_key";
+
+/// API keys for the `--api-key-auth` prefixes, read from `--api-keys`. Each
+/// key may only be used under the path prefixes listed next to it.
+#[derive(Default)]
+pub struct ApiKeys {
+    pub prefixes: Vec<String>,
+    pub keys: Option<WatchedFile<Vec<ApiKey>>>,
+}
+
+pub struct ApiKey {
+    name: String,
+    key: String,
+    prefixes: Vec<String>,
+}
+
+impl ApiKeys {
+    pub fn enabled(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    pub fn covers(&self, path: &str) -> bool {
+        let segments = auth::segments(path);
+        self.prefixes.iter().any(|prefix| segments.starts_with(&auth::segments(prefix)))
+    }
+}
+
+// `NAME KEY PREFIX...` lines; blank lines and `#` comments are skipped.
+pub fn load(path: &Path) -> Result<Vec<ApiKey>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut keys = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [name, key, prefixes @ ..] if !prefixes.is_empty() && prefixes.iter().all(|p| p.starts_with('/')) => {
+                keys.push(ApiKey {
+                    name: name.to_string(),
+                    key: key.to_string(),
+                    prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+                });
+            },
+            _ => return Err(format!("{}:{}: expected 'NAME KEY /PREFIX...'", path.display(), number + 1)),
+        }
+    }
+    Ok(keys)
+}
+
+/// Looks for the key in the `X-Api-Key` header, then the `api_key` query
+/// parameter. A missing or unknown key gets a 401, a key used outside its
+/// prefixes a 403; `None` lets the request through as the key's name.
+pub fn authenticate(config: &ApiKeys, path: &str, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let presented = req.headers().get(HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .or_else(|| form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .find(|(name, _)| name == QUERY_PARAM)
+            .map(|(_, value)| value.into_owned()));
+    let keys = config.keys.as_ref()?.get();
+    // A key listed on several lines is valid under all of their prefixes.
+    let matching: Vec<&ApiKey> = match presented {
+        Some(presented) => keys.iter().filter(|key| constant_time_eq(key.key.as_bytes(), presented.as_bytes())).collect(),
+        None => Vec::new(),
+    };
+    if matching.is_empty() {
+        return Some(error(StatusCode::UNAUTHORIZED, "401 Unauthorized"));
+    }
+
+    let segments = auth::segments(path);
+    let key = match matching.iter().find(|key| key.prefixes.iter().any(|prefix| segments.starts_with(&auth::segments(prefix)))) {
+        Some(key) => key,
+        None => return Some(error(StatusCode::FORBIDDEN, "403 Forbidden")),
+    };
+    req.extensions_mut().insert(RemoteUser(key.name.clone()));
+    None
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(format!("<html>{}</html>", message)))
+        .unwrap()
+}
@@ -0,0 +1,332 @@
+// Argon2 password hashes (RFC 9106) in the PHC string format that htpasswd,
+// libsodium and the argon2 CLI produce:
+// `$argon2id$v=19$m=65536,t=3,p=4$<salt>$<hash>`. Lanes are filled one after
+// the other rather than in parallel threads; the result is the same.
+
+use crate::base64;
+use crate::blake2b::blake2b;
+
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+// Entries come from the administrator's htpasswd file, but a typo should not
+// be able to make every login allocate gigabytes.
+const MAX_MEMORY_KIB: u32 = 1 << 20;
+const MAX_PASSES: u32 = 64;
+const MAX_LANES: u32 = 64;
+
+type Block = [u64; BLOCK_WORDS];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Variant {
+    D = 0,
+    I = 1,
+    Id = 2,
+}
+
+/// Checks `password` against an `$argon2id$`, `$argon2i$` or `$argon2d$` string.
+pub fn verify(password: &[u8], hash: &str) -> bool {
+    let fields: Vec<&str> = hash.split('$').collect();
+    let (variant, rest) = match fields.as_slice() {
+        ["", variant, rest @ ..] => (*variant, rest),
+        _ => return false,
+    };
+    let variant = match variant {
+        "argon2d" => Variant::D,
+        "argon2i" => Variant::I,
+        "argon2id" => Variant::Id,
+        _ => return false,
+    };
+    // The version field is absent in hashes made before version 1.3.
+    let (version, rest) = match rest {
+        [version, rest @ ..] if version.starts_with("v=") => match version[2..].parse::<u32>() {
+            Ok(version @ (0x10 | 0x13)) => (version, rest),
+            _ => return false,
+        },
+        rest => (0x10, rest),
+    };
+    let (params, salt, expected) = match rest {
+        [params, salt, expected] => (params, salt, expected),
+        _ => return false,
+    };
+    let (mut memory, mut passes, mut lanes) = (0, 0, 0);
+    for param in params.split(',') {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name, value.parse::<u32>().unwrap_or(0)),
+            None => return false,
+        };
+        match name {
+            "m" => memory = value,
+            "t" => passes = value,
+            "p" => lanes = value,
+            _ => return false,
+        }
+    }
+    if !(1..=MAX_PASSES).contains(&passes) || !(1..=MAX_LANES).contains(&lanes) {
+        return false;
+    }
+    if memory < 8 * lanes || memory > MAX_MEMORY_KIB {
+        return false;
+    }
+    let (salt, expected) = match (base64::decode(salt), base64::decode(expected)) {
+        (Some(salt), Some(expected)) if salt.len() >= 8 && expected.len() >= 4 => (salt, expected),
+        _ => return false,
+    };
+
+    let params = Params { variant, version, memory, passes, lanes };
+    // PHC strings carry neither a secret key nor associated data.
+    let tag = hash_password(&params, password, &salt, &[], &[], expected.len());
+    crate::password::constant_time_eq(&tag, &expected)
+}
+
+struct Params {
+    variant: Variant,
+    version: u32,
+    memory: u32,
+    passes: u32,
+    lanes: u32,
+}
+
+fn hash_password(params: &Params, password: &[u8], salt: &[u8], secret: &[u8], associated: &[u8], tag_len: usize) -> Vec<u8> {
+    let lanes = params.lanes as usize;
+    let mut h0_input = Vec::new();
+    for value in [params.lanes, tag_len as u32, params.memory, params.passes, params.version, params.variant as u32] {
+        h0_input.extend_from_slice(&value.to_le_bytes());
+    }
+    for data in [password, salt, secret, associated] {
+        h0_input.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        h0_input.extend_from_slice(data);
+    }
+    let h0 = blake2b(64, &h0_input);
+
+    let segment_length = params.memory as usize / (SYNC_POINTS * lanes);
+    let lane_length = segment_length * SYNC_POINTS;
+    let mut memory = vec![[0u64; BLOCK_WORDS]; lane_length * lanes];
+
+    for lane in 0..lanes {
+        for column in 0..2 {
+            let seed = [h0.as_slice(), &(column as u32).to_le_bytes(), &(lane as u32).to_le_bytes()].concat();
+            memory[lane * lane_length + column] = block_from_bytes(&long_hash(1024, &seed));
+        }
+    }
+
+    let geometry = Geometry { lanes, lane_length, segment_length };
+    for pass in 0..params.passes as usize {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                fill_segment(params, &geometry, &mut memory, pass, slice, lane);
+            }
+        }
+    }
+
+    let mut last = memory[lane_length - 1];
+    for lane in 1..lanes {
+        xor_into(&mut last, &memory[lane * lane_length + lane_length - 1]);
+    }
+    let bytes: Vec<u8> = last.iter().flat_map(|word| word.to_le_bytes()).collect();
+    long_hash(tag_len, &bytes)
+}
+
+struct Geometry {
+    lanes: usize,
+    lane_length: usize,
+    segment_length: usize,
+}
+
+fn fill_segment(params: &Params, geometry: &Geometry, memory: &mut [Block], pass: usize, slice: usize, lane: usize) {
+    let Geometry { lanes, lane_length, segment_length } = *geometry;
+    let data_independent = params.variant == Variant::I
+        || (params.variant == Variant::Id && pass == 0 && slice < SYNC_POINTS / 2);
+
+    let zero = [0u64; BLOCK_WORDS];
+    let mut input = [0u64; BLOCK_WORDS];
+    let mut addresses = [0u64; BLOCK_WORDS];
+    let values = [pass, lane, slice, memory.len(), params.passes as usize, params.variant as usize];
+    for (word, value) in input.iter_mut().zip(values) {
+        *word = value as u64;
+    }
+    let next_addresses = |input: &mut Block, addresses: &mut Block| {
+        input[6] += 1;
+        *addresses = compress(&zero, input);
+        *addresses = compress(&zero, addresses);
+    };
+
+    // The first two blocks of every lane were seeded from H0.
+    let first = if pass == 0 && slice == 0 { 2 } else { 0 };
+    if data_independent && first != 0 {
+        next_addresses(&mut input, &mut addresses);
+    }
+
+    for index in first..segment_length {
+        let column = slice * segment_length + index;
+        let current = lane * lane_length + column;
+        let previous = if column == 0 { current + lane_length - 1 } else { current - 1 };
+
+        let pseudo_random = if data_independent {
+            if index % BLOCK_WORDS == 0 {
+                next_addresses(&mut input, &mut addresses);
+            }
+            addresses[index % BLOCK_WORDS]
+        } else {
+            memory[previous][0]
+        };
+
+        let ref_lane = if pass == 0 && slice == 0 { lane } else { (pseudo_random >> 32) as usize % lanes };
+        let same_lane = ref_lane == lane;
+        let area = if pass == 0 {
+            if slice == 0 || same_lane {
+                slice * segment_length + index - 1
+            } else {
+                slice * segment_length - usize::from(index == 0)
+            }
+        } else if same_lane {
+            lane_length - segment_length + index - 1
+        } else {
+            lane_length - segment_length - usize::from(index == 0)
+        };
+        let j1 = pseudo_random & 0xFFFF_FFFF;
+        let relative = (j1 * j1) >> 32;
+        let relative = area - 1 - ((area as u64 * relative) >> 32) as usize;
+        let start = if pass == 0 || slice == SYNC_POINTS - 1 { 0 } else { (slice + 1) * segment_length };
+        let reference = ref_lane * lane_length + (start + relative) % lane_length;
+
+        let mut block = compress(&memory[previous], &memory[reference]);
+        // Version 1.3 mixes the old contents into later passes.
+        if pass > 0 && params.version == 0x13 {
+            xor_into(&mut block, &memory[current]);
+        }
+        memory[current] = block;
+    }
+}
+
+// The compression function G: BLAKE2b's round permutation over the rows and
+// then the columns of X xor Y, viewed as an 8x8 matrix of 16-byte registers.
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = *x;
+    xor_into(&mut r, y);
+    let mut q = r;
+    for row in 0..8 {
+        let base = 16 * row;
+        let mut indices = [0usize; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = base + i;
+        }
+        permute(&mut q, &indices);
+    }
+    for column in 0..8 {
+        let mut indices = [0usize; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = 2 * column + (i / 2) * 16 + i % 2;
+        }
+        permute(&mut q, &indices);
+    }
+    xor_into(&mut q, &r);
+    q
+}
+
+fn permute(v: &mut Block, i: &[usize; 16]) {
+    mix(v, i[0], i[4], i[8], i[12]);
+    mix(v, i[1], i[5], i[9], i[13]);
+    mix(v, i[2], i[6], i[10], i[14]);
+    mix(v, i[3], i[7], i[11], i[15]);
+    mix(v, i[0], i[5], i[10], i[15]);
+    mix(v, i[1], i[6], i[11], i[12]);
+    mix(v, i[2], i[7], i[8], i[13]);
+    mix(v, i[3], i[4], i[9], i[14]);
+}
+
+// BLAKE2b's G with the additions replaced by a + b + 2 * lo(a) * lo(b).
+fn mix(v: &mut Block, a: usize, b: usize, c: usize, d: usize) {
+    let add = |x: u64, y: u64| x.wrapping_add(y).wrapping_add(2u64.wrapping_mul(x & 0xFFFF_FFFF).wrapping_mul(y & 0xFFFF_FFFF));
+    v[a] = add(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = add(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = add(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = add(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+// H', the variable-length hash built from chained 64-byte BLAKE2b outputs.
+fn long_hash(out_len: usize, data: &[u8]) -> Vec<u8> {
+    let input = [&(out_len as u32).to_le_bytes()[..], data].concat();
+    if out_len <= 64 {
+        return blake2b(out_len, &input);
+    }
+    // The first 32 bytes of each chained hash, then the whole last one,
+    // sized so that the lengths add up to out_len.
+    let rounds = out_len.div_ceil(32) - 2;
+    let mut out = Vec::with_capacity(out_len);
+    let mut v = blake2b(64, &input);
+    for _ in 1..rounds {
+        out.extend_from_slice(&v[..32]);
+        v = blake2b(64, &v);
+    }
+    out.extend_from_slice(&v[..32]);
+    out.extend_from_slice(&blake2b(out_len - 32 * rounds, &v));
+    out
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks(8)) {
+        *word = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    block
+}
+
+fn xor_into(target: &mut Block, other: &Block) {
+    for (a, b) in target.iter_mut().zip(other) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // RFC 9106 section 5: 32-byte password, salt, secret and associated
+    // data, 32 KiB, 3 passes, 4 lanes.
+    fn rfc9106(variant: Variant) -> String {
+        let params = Params { variant, version: 0x13, memory: 32, passes: 3, lanes: 4 };
+        hex(&hash_password(&params, &[1; 32], &[2; 16], &[3; 8], &[4; 12], 32))
+    }
+
+    #[test]
+    fn rfc9106_argon2id() {
+        assert_eq!(rfc9106(Variant::Id), "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659");
+    }
+
+    #[test]
+    fn rfc9106_argon2d() {
+        assert_eq!(rfc9106(Variant::D), "512b391b6f1162975371d30919734294f868e3be3984f3c1a13a4db9fabe4acb");
+    }
+
+    #[test]
+    fn rfc9106_argon2i() {
+        assert_eq!(rfc9106(Variant::I), "c814d9d1dc7f37aa13f0d77f2494bda1c8de6b016dd388d29952a4c4672b6ce8");
+    }
+
+    #[test]
+    fn verifies_phc_strings() {
+        // Made with libargon2.
+        let hash = "$argon2id$v=19$m=256,t=2,p=1$c29tZXNhbHQ$nf65EOgLrQMR/uIPnA4rEsF5h7TKyQwu9U1bMCHGi/4";
+        assert!(verify(b"password", hash));
+        assert!(!verify(b"Password", hash));
+        assert!(!verify(b"password", &hash.replace("t=2", "t=3")));
+        assert!(verify(b"correct horse", "$argon2i$v=19$m=4096,t=3,p=2$c2FsdHNhbHRzYWx0$t3POhlLihl4GpSwlEP4b0HTQ52fA1lRDRi6bjb6h6pY"));
+        assert!(verify(b"x", "$argon2d$v=19$m=64,t=1,p=4$MTIzNDU2Nzg$tQWmB/O/MjCixTONkvnbtA"));
+    }
+
+    #[test]
+    fn rejects_malformed_and_oversized_parameters() {
+        assert!(!verify(b"password", "$argon2x$v=19$m=256,t=2,p=1$c29tZXNhbHQ$nf65EOgLrQMR/uIPnA4rEsF5h7TKyQwu9U1bMCHGi/4"));
+        assert!(!verify(b"password", "$argon2id$v=19$m=256,t=2,p=1$c29tZXNhbHQ"));
+        assert!(!verify(b"password", "$argon2id$v=19$m=4194304,t=2,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA"));
+        assert!(!verify(b"password", "$argon2id$v=19$m=256,t=0,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use crate::ldap::LdapConfig;
+use crate::{base64, event, password};
+
+/// A path prefix protected by Basic auth, from `--basic-auth PREFIX=HTPASSWD`
+/// or `--ldap-auth PREFIX`.
+pub struct AuthZone {
+    prefix: String,
+    backend: Backend,
+}
+
+enum Backend {
+    Htpasswd(WatchedFile<HashMap<String, String>>),
+    Ldap,
+}
+
+/// A file that is parsed again whenever its modification time changes, so
+/// users and keys can be added without a restart.
+pub struct WatchedFile<T> {
+    path: PathBuf,
+    parse: fn(&Path) -> Result<T, String>,
+    state: Mutex<(Option<SystemTime>, Arc<T>)>,
+}
+
+impl<T> WatchedFile<T> {
+    pub fn load(path: &Path, parse: fn(&Path) -> Result<T, String>) -> Result<WatchedFile<T>, String> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let value = parse(path)?;
+        Ok(WatchedFile { path: path.to_path_buf(), parse, state: Mutex::new((modified, Arc::new(value))) })
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        let mut state = self.state.lock().unwrap();
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified != state.0 {
+            // A file caught halfway through an edit keeps the previous contents.
+            match (self.parse)(&self.path) {
+                Ok(value) => *state = (modified, Arc::new(value)),
+                Err(e) => event::error(&e),
+            }
+        }
+        state.1.clone()
+    }
+}
+
+/// The user a request was authenticated as, kept in the request extensions.
+#[derive(Clone)]
+pub struct RemoteUser(pub String);
+
+impl AuthZone {
+    pub fn parse(option: &str, value: &str) -> Result<AuthZone, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (prefix, file) = value.split_once('=').ok_or_else(|| invalid("expected PREFIX=HTPASSWD"))?;
+        if !prefix.starts_with('/') {
+            return Err(invalid("the prefix must start with '/'"));
+        }
+        Ok(AuthZone {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            backend: Backend::Htpasswd(WatchedFile::load(Path::new(file), load)?),
+        })
+    }
+
+    pub fn ldap(option: &str, prefix: &str) -> Result<AuthZone, String> {
+        if !prefix.starts_with('/') {
+            return Err(format!("invalid value '{}' for '{}': the prefix must start with '/'", prefix, option));
+        }
+        Ok(AuthZone { prefix: prefix.trim_end_matches('/').to_string(), backend: Backend::Ldap })
+    }
+
+    /// The zone declared in a per-directory access file, which covers the
+    /// directory it sits in rather than a URL prefix. No file means LDAP.
+    pub fn directory(htpasswd: Option<&Path>) -> Result<AuthZone, String> {
+        let backend = match htpasswd {
+            Some(file) => Backend::Htpasswd(WatchedFile::load(file, load)?),
+            None => Backend::Ldap,
+        };
+        Ok(AuthZone { prefix: String::new(), backend })
+    }
+
+    pub fn uses_ldap(&self) -> bool {
+        matches!(self.backend, Backend::Ldap)
+    }
+
+    fn matches(&self, segments: &[&str]) -> bool {
+        segments.starts_with(&self::segments(&self.prefix))
+    }
+
+    async fn verify(&self, ldap: &LdapConfig, user: &str, password: String) -> bool {
+        let users = match &self.backend {
+            Backend::Htpasswd(users) => users.get(),
+            Backend::Ldap => return match ldap.verify(user, &password).await {
+                Ok(verified) => verified,
+                Err(e) => {
+                    event::error(&format!("LDAP authentication failed: {}", e));
+                    false
+                },
+            },
+        };
+        let hash = match users.get(user).cloned() {
+            Some(hash) => hash,
+            None => return false,
+        };
+        // bcrypt and Argon2 are slow on purpose; keep them off the reactor.
+        tokio::task::spawn_blocking(move || password::verify(&password, &hash))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+// `user:hash` lines; blank lines and `#` comments are skipped.
+fn load(path: &Path) -> Result<HashMap<String, String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, hash)) if !user.is_empty() => {
+                users.insert(user.to_string(), hash.to_string());
+            },
+            _ => return Err(format!("{}:{}: expected 'user:hash'", path.display(), number + 1)),
+        }
+    }
+    Ok(users)
+}
+
+/// Path segments after resolving `.`, `..` and repeated slashes. Protected
+/// prefixes are compared this way so `//private` or `/public/../private`
+/// cannot slip past a `/private` zone.
+pub fn segments(path: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+    segments
+}
+
+/// The zone with the longest prefix covering `path`.
+pub fn find_zone<'a>(zones: &'a [AuthZone], path: &str) -> Option<&'a AuthZone> {
+    let segments = segments(path);
+    zones.iter()
+        .filter(|zone| zone.matches(&segments))
+        .max_by_key(|zone| zone.prefix.len())
+}
+
+/// Checks the request's Basic credentials against the zone's htpasswd file or
+/// the LDAP server and records the user in the request extensions; a 401
+/// challenge otherwise.
+pub async fn authenticate(zone: &AuthZone, realm: &str, ldap: &LdapConfig, req: &mut Request<Body>) -> Result<(), Response<Body>> {
+    let credentials = req.headers().get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_basic);
+    if let Some((user, password)) = credentials {
+        if zone.verify(ldap, &user, password).await {
+            req.extensions_mut().insert(RemoteUser(user));
+            return Ok(());
+        }
+    }
+
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+    Err(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge).unwrap())
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>401 Unauthorized</html>"))
+        .unwrap())
+}
+
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::event;
+
+// Past this many tracked clients, those with nothing left to remember are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Bans clients whose 4xx responses (429 included) reach `threshold` within
+/// the sliding `window`, fail2ban style; older errors no longer count.
+pub struct BanConfig {
+    pub threshold: Option<u32>,
+    pub window: Duration,
+    pub duration: Duration,
+    /// `--ban-admin PATH`: GET lists bans, DELETE lifts them.
+    pub admin_path: Option<String>,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+struct Client {
+    // Times of the latest errors, at most `threshold` of them.
+    errors: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Client {
+    fn is_remembered(&self, window: Duration, now: Instant) -> bool {
+        self.errors.back().is_some_and(|t| now - *t <= window)
+            || self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            threshold: None,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(600),
+            admin_path: None,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl BanConfig {
+    /// Unix socket peers (0.0.0.0) are all the same client and never banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        if self.threshold.is_none() || ip.is_unspecified() {
+            return false;
+        }
+        let clients = self.clients.lock().unwrap();
+        clients.get(&ip).and_then(|client| client.banned_until).is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn record(&self, ip: IpAddr, status: StatusCode) {
+        let threshold = match self.threshold {
+            Some(threshold) if status.is_client_error() && !ip.is_unspecified() => threshold.max(1) as usize,
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&ip) {
+            clients.retain(|_, client| client.is_remembered(self.window, now));
+        }
+        let client = clients.entry(ip).or_insert(Client { errors: VecDeque::new(), banned_until: None });
+        client.errors.push_back(now);
+        while client.errors.len() > threshold || client.errors.front().is_some_and(|t| now - *t > self.window) {
+            client.errors.pop_front();
+        }
+        if client.errors.len() == threshold {
+            client.errors.clear();
+            client.banned_until = Some(now + self.duration);
+            event::warning(&format!("Banned {} for {}s", ip, self.duration.as_secs()));
+        }
+    }
+
+    pub fn is_admin_path(&self, path: &str) -> bool {
+        self.admin_path.as_deref() == Some(path)
+    }
+}
+
+/// The admin endpoint: GET answers one `IP SECONDS_LEFT` line per ban,
+/// DELETE lifts the ban of `?ip=ADDRESS`, or all of them without a query.
+pub fn admin(config: &BanConfig, req: &Request<Body>) -> Response<Body> {
+    let now = Instant::now();
+    let mut clients = config.clients.lock().unwrap();
+    let body = match *req.method() {
+        Method::GET | Method::HEAD => {
+            let mut bans: Vec<(IpAddr, u64)> = clients.iter()
+                .filter_map(|(ip, client)| client.banned_until.filter(|until| *until > now).map(|until| (*ip, until)))
+                .map(|(ip, until)| (ip, (until - now).as_secs()))
+                .collect();
+            bans.sort();
+            bans.iter().map(|(ip, left)| format!("{} {}\n", ip, left)).collect::<String>()
+        },
+        Method::DELETE => {
+            let ip = url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .find(|(name, _)| name == "ip")
+                .map(|(_, ip)| ip.parse::<IpAddr>());
+            match ip {
+                Some(Ok(ip)) => {
+                    clients.remove(&ip.to_canonical());
+                },
+                Some(Err(_)) => return error(StatusCode::BAD_REQUEST, "<html>400 Bad Request</html>"),
+                None => clients.retain(|_, client| client.banned_until.is_none()),
+            }
+            return Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap();
+        },
+        _ => {
+            let mut response = error(StatusCode::METHOD_NOT_ALLOWED, "<html>405 Method Not Allowed</html>");
+            response.headers_mut().insert(ALLOW, HeaderValue::from_static("GET, HEAD, DELETE"));
+            return response;
+        },
+    };
+    Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn error(status_code: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(message))
+        .unwrap()
+}
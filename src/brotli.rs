@@ -0,0 +1,672 @@
+// A small streaming Brotli (RFC 7932) encoder.
+// Every chunk passed to `compress` becomes one or more meta-blocks, each with
+// one block type per category and prefix codes built for its own contents;
+// matches may reach back into the previous 64 KiB of input. Literal context
+// modelling and the static dictionary are not used.
+
+use crate::deflate::StreamEncoder;
+use crate::huffman::code_lengths;
+use crate::lz77::Matcher;
+
+// WBITS = 16, the window that takes a single header bit.
+const WINDOW_SIZE: usize = (1 << 16) - 16;
+// Meta-blocks up to this length have a four-nibble MLEN.
+const META_BLOCK_MAX: usize = 1 << 16;
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 5;
+
+const LITERALS: usize = 256;
+const COMMANDS: usize = 704;
+// 16 short codes and 48 long ones, with NPOSTFIX = 0 and NDIRECT = 0.
+const DISTANCES: usize = 64;
+
+const INSERT_BASE: [u32; 24] = [
+    0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210, 22594,
+];
+const INSERT_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24];
+const COPY_BASE: [u32; 24] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118,
+];
+const COPY_EXTRA: [u32; 24] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24];
+// The first insert-and-copy code of each cell, by insert code / 8 and copy
+// code / 8. Codes below 128 reuse the last distance and are not emitted.
+const COMMAND_CELLS: [[usize; 3]; 3] = [[128, 192, 384], [256, 320, 512], [448, 576, 640]];
+
+// Code length code lengths are stored in this order, each with a fixed
+// code given as (bits, length).
+const CODE_LENGTH_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const CODE_LENGTH_CODES: [(u32, u32); 6] = [(0, 2), (7, 4), (3, 3), (2, 2), (1, 2), (15, 4)];
+const REPEAT_PREVIOUS: u8 = 16;
+const REPEAT_ZERO: u8 = 17;
+
+struct Command {
+    insert: usize,
+    copy: usize,
+    // None for the literals that end a meta-block, whose copy is ignored.
+    distance: Option<usize>,
+}
+
+pub struct BrotliEncoder {
+    // None at quality 0, which stores every chunk uncompressed.
+    matcher: Option<Matcher>,
+    bits: u64,
+    nbits: u32,
+    out: Vec<u8>,
+    header_written: bool,
+}
+
+impl BrotliEncoder {
+    /// `quality` follows the brotli tool: 0 stores, 1 is fastest, 11 searches hardest.
+    pub fn new(quality: u32) -> Self {
+        let matcher = match quality {
+            0 => None,
+            _ => Some(Matcher::new(WINDOW_SIZE, 1 << quality.min(11))),
+        };
+        BrotliEncoder { matcher, bits: 0, nbits: 0, out: Vec::new(), header_written: false }
+    }
+
+    fn header(&mut self) {
+        if !self.header_written {
+            self.header_written = true;
+            // WBITS = 16
+            self.put_bits(0, 1);
+        }
+    }
+
+    fn meta_block(&mut self, data: &[u8]) {
+        let Some(matcher) = &mut self.matcher else {
+            return self.store(data);
+        };
+        let (sequences, trailing) = matcher.parse(data);
+        let mut commands: Vec<Command> = sequences.iter()
+            .map(|s| Command { insert: s.literals, copy: s.length, distance: Some(s.distance) })
+            .collect();
+        if trailing > 0 {
+            commands.push(Command { insert: trailing, copy: 2, distance: None });
+        }
+
+        let mut literal_counts = vec![0u32; LITERALS];
+        let mut command_counts = vec![0u32; COMMANDS];
+        let mut distance_counts = vec![0u32; DISTANCES];
+        let mut pos = 0;
+        for command in &commands {
+            for &byte in &data[pos..pos + command.insert] {
+                literal_counts[byte as usize] += 1;
+            }
+            command_counts[command_code(command.insert, command.copy).0] += 1;
+            if let Some(distance) = command.distance {
+                distance_counts[distance_code(distance).0] += 1;
+            }
+            pos += command.insert + command.copy;
+        }
+        let literals_code = PrefixCode::new(&literal_counts);
+        let commands_code = PrefixCode::new(&command_counts);
+        let distances_code = PrefixCode::new(&distance_counts);
+
+        // Coded data that ends up larger than the input is stored instead.
+        let saved = (self.out.len(), self.bits, self.nbits);
+
+        // ISLAST = 0, MNIBBLES = 4, MLEN - 1, ISUNCOMPRESSED = 0
+        self.put_bits(0, 1);
+        self.put_bits(0, 2);
+        self.put_bits(data.len() as u32 - 1, 16);
+        self.put_bits(0, 1);
+        // One block type for each category, NPOSTFIX = 0 and NDIRECT = 0, the
+        // LSB6 context mode, and one literal and one distance prefix code.
+        self.put_bits(0, 3);
+        self.put_bits(0, 6);
+        self.put_bits(0, 2);
+        self.put_bits(0, 2);
+        literals_code.store(self, 8);
+        commands_code.store(self, 10);
+        distances_code.store(self, 6);
+
+        let mut pos = 0;
+        for command in &commands {
+            let (code, insert_code, copy_code) = command_code(command.insert, command.copy);
+            commands_code.put(self, code);
+            self.put_bits((command.insert as u32) - INSERT_BASE[insert_code], INSERT_EXTRA[insert_code]);
+            self.put_bits((command.copy as u32) - COPY_BASE[copy_code], COPY_EXTRA[copy_code]);
+            for &byte in &data[pos..pos + command.insert] {
+                literals_code.put(self, byte as usize);
+            }
+            if let Some(distance) = command.distance {
+                let (code, extra, extra_bits) = distance_code(distance);
+                distances_code.put(self, code);
+                self.put_bits(extra, extra_bits);
+            }
+            pos += command.insert + command.copy;
+        }
+
+        if self.out.len() - saved.0 > data.len() {
+            self.out.truncate(saved.0);
+            (self.bits, self.nbits) = (saved.1, saved.2);
+            self.store(data);
+        }
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        // ISLAST = 0, MNIBBLES = 4, MLEN - 1, ISUNCOMPRESSED = 1
+        self.put_bits(0, 1);
+        self.put_bits(0, 2);
+        self.put_bits(data.len() as u32 - 1, 16);
+        self.put_bits(1, 1);
+        self.align();
+        self.out.extend_from_slice(data);
+    }
+
+    // Huffman codes are packed starting from their most significant bit.
+    fn put_code(&mut self, code: u16, len: u8) {
+        if len > 0 {
+            let reversed = (code as u32).reverse_bits() >> (32 - len as u32);
+            self.put_bits(reversed, len as u32);
+        }
+    }
+
+    fn put_bits(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += len;
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.put_bits(0, 8 - self.nbits);
+        }
+    }
+}
+
+impl StreamEncoder for BrotliEncoder {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        self.header();
+        for block in data.chunks(META_BLOCK_MAX) {
+            self.meta_block(block);
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        self.header();
+        // ISLAST = 1, ISLASTEMPTY = 1
+        self.put_bits(1, 1);
+        self.put_bits(1, 1);
+        self.align();
+        std::mem::take(&mut self.out)
+    }
+}
+
+// The insert-and-copy code of a command, with its insert and copy length codes.
+fn command_code(insert: usize, copy: usize) -> (usize, usize, usize) {
+    let insert_code = INSERT_BASE.iter().rposition(|&b| b as usize <= insert).unwrap();
+    let copy_code = COPY_BASE.iter().rposition(|&b| b as usize <= copy).unwrap();
+    let code = COMMAND_CELLS[insert_code >> 3][copy_code >> 3] | (insert_code & 7) << 3 | (copy_code & 7);
+    (code, insert_code, copy_code)
+}
+
+// The distance code of a distance, with its extra bits and their count.
+fn distance_code(distance: usize) -> (usize, u32, u32) {
+    let d = distance + 3;
+    let bucket = d.ilog2() - 1;
+    let prefix = (d >> bucket) & 1;
+    let offset = (2 + prefix) << bucket;
+    (16 + 2 * (bucket as usize - 1) + prefix, (d - offset) as u32, bucket)
+}
+
+struct PrefixCode {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+    symbols: usize,
+}
+
+impl PrefixCode {
+    fn new(counts: &[u32]) -> PrefixCode {
+        let lengths = code_lengths(counts, MAX_CODE_LENGTH);
+        let codes = canonical_codes(&lengths);
+        let symbols = counts.iter().filter(|&&c| c > 0).count();
+        PrefixCode { lengths, codes, symbols }
+    }
+
+    fn put(&self, encoder: &mut BrotliEncoder, symbol: usize) {
+        // A code with a single symbol takes no bits at all.
+        if self.symbols > 1 {
+            encoder.put_code(self.codes[symbol], self.lengths[symbol]);
+        }
+    }
+
+    fn store(&self, encoder: &mut BrotliEncoder, alphabet_bits: u32) {
+        if self.symbols <= 1 {
+            // HSKIP = 1 (a simple prefix code), NSYM - 1 = 0, the symbol
+            let symbol = self.lengths.iter().position(|&len| len > 0).unwrap_or(0);
+            encoder.put_bits(1, 2);
+            encoder.put_bits(0, 2);
+            encoder.put_bits(symbol as u32, alphabet_bits);
+            return;
+        }
+
+        let last = self.lengths.iter().rposition(|&len| len > 0).unwrap();
+        let runs = run_lengths(&self.lengths[..=last]);
+        let mut counts = [0u32; 18];
+        for &(symbol, _) in &runs {
+            counts[symbol as usize] += 1;
+        }
+        let mut lengths = code_lengths(&counts, MAX_CODE_LENGTH_CODE_LENGTH);
+        let codes = canonical_codes(&lengths);
+        let single = counts.iter().filter(|&&c| c > 0).count() == 1;
+
+        // The decoder stops at the length that completes the code, unless
+        // only one code length symbol is used.
+        let mut stored = CODE_LENGTH_ORDER.len();
+        if !single {
+            while lengths[CODE_LENGTH_ORDER[stored - 1]] == 0 {
+                stored -= 1;
+            }
+        }
+        let skip = match CODE_LENGTH_ORDER[..3].iter().take_while(|&&s| lengths[s] == 0).count() {
+            0 | 1 => 0,
+            skip => skip,
+        };
+        encoder.put_bits(skip as u32, 2);
+        for &symbol in &CODE_LENGTH_ORDER[skip..stored] {
+            let (bits, len) = CODE_LENGTH_CODES[lengths[symbol] as usize];
+            encoder.put_bits(bits, len);
+        }
+        if single {
+            lengths.fill(0);
+        }
+        for (symbol, extra) in runs {
+            encoder.put_code(codes[symbol as usize], lengths[symbol as usize]);
+            match symbol {
+                REPEAT_PREVIOUS => encoder.put_bits(extra, 2),
+                REPEAT_ZERO => encoder.put_bits(extra, 3),
+                _ => {},
+            }
+        }
+    }
+}
+
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &len in lengths {
+        counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for len in 1..16 {
+        code = (code + counts[len - 1]) << 1;
+        next[len] = code;
+    }
+    lengths.iter().map(|&len| {
+        let code = next[len as usize];
+        next[len as usize] += 1;
+        code
+    }).collect()
+}
+
+// Code lengths as code length symbols with their extra bits. Runs of zeros
+// become REPEAT_ZERO and runs of a length REPEAT_PREVIOUS; consecutive
+// repeat codes multiply, so a long run is written most significant part first.
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u32)> {
+    let mut runs = Vec::new();
+    // The length REPEAT_PREVIOUS repeats before any is written.
+    let mut previous = 8;
+    let mut i = 0;
+    while i < lengths.len() {
+        let value = lengths[i];
+        let mut count = lengths[i..].iter().take_while(|&&l| l == value).count();
+        i += count;
+        if value == 0 {
+            repeat(&mut runs, 0, REPEAT_ZERO, 3, count);
+        } else {
+            if value != previous {
+                runs.push((value, 0));
+                count -= 1;
+            }
+            repeat(&mut runs, value, REPEAT_PREVIOUS, 2, count);
+            previous = value;
+        }
+    }
+    runs
+}
+
+fn repeat(runs: &mut Vec<(u8, u32)>, value: u8, code: u8, extra_bits: u32, count: usize) {
+    if count < 3 {
+        runs.extend(std::iter::repeat_n((value, 0), count));
+        return;
+    }
+    let start = runs.len();
+    let mut rest = count - 3;
+    loop {
+        runs.push((code, (rest & ((1 << extra_bits) - 1)) as u32));
+        rest >>= extra_bits;
+        if rest == 0 {
+            break;
+        }
+        rest -= 1;
+    }
+    runs[start..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An RFC 7932 decoder of its own, for streams with one block type and
+    // one prefix code per category, as made here and by the reference
+    // encoder at its lowest qualities.
+    struct Bits<'a> {
+        data: &'a [u8],
+        pos: usize,
+        bit: u32,
+    }
+
+    impl Bits<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.pos] >> self.bit) & 1;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+            bit as u32
+        }
+
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        fn align(&mut self) {
+            if self.bit != 0 {
+                assert_eq!(self.bits(8 - self.bit), 0, "padding bits");
+            }
+        }
+    }
+
+    // A canonical prefix code as the number of codes of each length and
+    // the symbols in code order; a lone symbol takes no bits.
+    struct Huffman {
+        counts: [u16; 16],
+        symbols: Vec<u16>,
+    }
+
+    impl Huffman {
+        fn new(lengths: &[u8]) -> Huffman {
+            let mut counts = [0u16; 16];
+            for &length in lengths {
+                counts[length as usize] += 1;
+            }
+            counts[0] = 0;
+            let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] > 0).collect();
+            symbols.sort_by_key(|&s| lengths[s as usize]);
+            Huffman { counts, symbols }
+        }
+
+        fn decode(&self, bits: &mut Bits) -> usize {
+            if self.symbols.len() == 1 {
+                return self.symbols[0] as usize;
+            }
+            let (mut code, mut first, mut index) = (0, 0, 0);
+            for length in 1..16 {
+                code |= bits.bit() as usize;
+                let count = self.counts[length] as usize;
+                if code < first + count {
+                    return self.symbols[index + code - first] as usize;
+                }
+                index += count;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+            panic!("invalid prefix code");
+        }
+    }
+
+    fn prefix_code(bits: &mut Bits, alphabet_size: usize) -> Huffman {
+        let mut lengths = vec![0u8; alphabet_size];
+        let hskip = bits.bits(2) as usize;
+        if hskip == 1 {
+            let alphabet_bits = (alphabet_size - 1).ilog2() + 1;
+            let count = bits.bits(2) as usize + 1;
+            let symbols: Vec<usize> = (0..count).map(|_| bits.bits(alphabet_bits) as usize).collect();
+            let simple: &[u8] = match count {
+                1 => &[1],
+                2 => &[1, 1],
+                3 => &[1, 2, 2],
+                _ if bits.bit() == 0 => &[2, 2, 2, 2],
+                _ => &[1, 2, 3, 3],
+            };
+            for (&symbol, &length) in symbols.iter().zip(simple) {
+                lengths[symbol] = length;
+            }
+            return Huffman::new(&lengths);
+        }
+
+        let mut code_length_lengths = [0u8; 18];
+        let mut space = 32;
+        let mut codes = 0;
+        for &symbol in &CODE_LENGTH_ORDER[hskip..] {
+            let mut value = bits.bits(2);
+            let mut read = 2;
+            let length = loop {
+                if let Some(length) = CODE_LENGTH_CODES.iter().position(|&code| code == (value, read)) {
+                    break length as u8;
+                }
+                value |= bits.bit() << read;
+                read += 1;
+            };
+            code_length_lengths[symbol] = length;
+            if length > 0 {
+                space -= 32 >> length;
+                codes += 1;
+                if space <= 0 {
+                    break;
+                }
+            }
+        }
+        assert!(codes == 1 || space == 0, "incomplete code length code");
+        let code_length_code = Huffman::new(&code_length_lengths);
+
+        let (mut symbol, mut space) = (0, 32768);
+        let (mut previous, mut repeat, mut repeat_length) = (8, 0, 0);
+        while symbol < alphabet_size && space > 0 {
+            let code = code_length_code.decode(bits) as u8;
+            if code < REPEAT_PREVIOUS {
+                repeat = 0;
+                lengths[symbol] = code;
+                symbol += 1;
+                if code > 0 {
+                    previous = code;
+                    space -= 32768 >> code;
+                }
+                continue;
+            }
+            let (length, extra_bits) = if code == REPEAT_PREVIOUS { (previous, 2) } else { (0, 3) };
+            if repeat_length != length {
+                repeat = 0;
+                repeat_length = length;
+            }
+            // Consecutive repeat codes of one length multiply.
+            let old = repeat;
+            if repeat > 0 {
+                repeat = (repeat - 2) << extra_bits;
+            }
+            repeat += bits.bits(extra_bits) as usize + 3;
+            for _ in old..repeat {
+                lengths[symbol] = length;
+                symbol += 1;
+                if length > 0 {
+                    space -= 32768 >> length;
+                }
+            }
+        }
+        assert_eq!(space, 0, "incomplete prefix code");
+        Huffman::new(&lengths)
+    }
+
+    // Insert and copy length codes of an insert-and-copy code, and whether
+    // it reuses the last distance.
+    fn command(code: usize) -> (usize, usize, bool) {
+        const CELLS: [(usize, usize); 9] = [(0, 0), (0, 8), (8, 0), (8, 8), (0, 16), (16, 0), (8, 16), (16, 8), (16, 16)];
+        if code < 128 {
+            return ((code >> 3) & 7, (code & 7) | (code & 64) >> 3, true);
+        }
+        let (insert, copy) = CELLS[(code >> 6) - 2];
+        (insert | (code >> 3) & 7, copy | code & 7, false)
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut bits = Bits { data, pos: 0, bit: 0 };
+        let window_bits = if bits.bit() == 0 {
+            16
+        } else {
+            match bits.bits(3) {
+                0 => match bits.bits(3) {
+                    0 => 17,
+                    m => 8 + m,
+                },
+                n => 17 + n,
+            }
+        };
+
+        let mut out = Vec::new();
+        let mut distances = [4, 11, 15, 16];
+        loop {
+            let last = bits.bit() == 1;
+            if last && bits.bit() == 1 {
+                break;
+            }
+            let nibbles = bits.bits(2);
+            assert_ne!(nibbles, 3, "metadata block");
+            let length = bits.bits(4 * (nibbles + 4)) as usize + 1;
+            if !last && bits.bit() == 1 {
+                bits.align();
+                out.extend_from_slice(&data[bits.pos..bits.pos + length]);
+                bits.pos += length;
+                continue;
+            }
+
+            assert_eq!(bits.bits(3), 0, "one block type per category");
+            assert_eq!(bits.bits(6), 0, "NPOSTFIX and NDIRECT");
+            bits.bits(2);
+            assert_eq!(bits.bits(2), 0, "one literal and one distance prefix code");
+            let literals = prefix_code(&mut bits, LITERALS);
+            let commands = prefix_code(&mut bits, COMMANDS);
+            let distance_code = prefix_code(&mut bits, DISTANCES);
+
+            let end = out.len() + length;
+            loop {
+                let (insert_code, copy_code, last_distance) = command(commands.decode(&mut bits));
+                let insert = (INSERT_BASE[insert_code] + bits.bits(INSERT_EXTRA[insert_code])) as usize;
+                let copy = (COPY_BASE[copy_code] + bits.bits(COPY_EXTRA[copy_code])) as usize;
+                for _ in 0..insert {
+                    out.push(literals.decode(&mut bits) as u8);
+                }
+                if out.len() >= end {
+                    break;
+                }
+                let code = if last_distance { 0 } else { distance_code.decode(&mut bits) };
+                let distance = match code {
+                    0..=3 => distances[code],
+                    4..=15 => {
+                        let delta = [-1, 1, -2, 2, -3, 3][(code - 4) % 6];
+                        (distances[(code - 4) / 6] as isize + delta) as usize
+                    },
+                    _ => {
+                        let extra_bits = 1 + ((code as u32 - 16) >> 1);
+                        let offset = ((2 + (code - 16) % 2) << extra_bits) - 4;
+                        offset + bits.bits(extra_bits) as usize + 1
+                    },
+                };
+                if code != 0 {
+                    distances = [distance, distances[0], distances[1], distances[2]];
+                }
+                assert!(distance <= out.len() && distance <= (1 << window_bits) - 16, "distance out of range");
+                for _ in 0..copy {
+                    out.push(out[out.len() - distance]);
+                }
+                if out.len() >= end {
+                    break;
+                }
+            }
+            assert_eq!(out.len(), end, "meta-block length");
+            if last {
+                break;
+            }
+        }
+        bits.align();
+        assert_eq!(bits.pos, data.len(), "trailing data");
+        out
+    }
+
+    fn encode(mut encoder: impl StreamEncoder, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(encoder.compress(chunk));
+        }
+        out.extend(encoder.finish());
+        out
+    }
+
+    // Text that compresses well, noise from a xorshift generator that does
+    // not, and letters from it with no matches to speak of.
+    fn samples() -> Vec<Vec<u8>> {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(2000);
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let noise = (0..70_000).map(|_| next() as u8).collect();
+        let letters = (0..70_000).map(|_| b'a' + (next() % 26) as u8).collect();
+        vec![Vec::new(), b"a".to_vec(), b"abcabcabcabcabcabcabcabc".to_vec(), text, noise, letters, vec![0; 100_000]]
+    }
+
+    #[test]
+    fn round_trip() {
+        for quality in [0, 1, 4, 11] {
+            for sample in samples() {
+                let whole = encode(BrotliEncoder::new(quality), &[&sample]);
+                assert_eq!(decompress(&whole), sample, "quality {}", quality);
+                let chunks: Vec<&[u8]> = sample.chunks(8192).collect();
+                assert_eq!(decompress(&encode(BrotliEncoder::new(quality), &chunks)), sample, "quality {} in chunks", quality);
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_input() {
+        let samples = samples();
+        let text = encode(BrotliEncoder::new(4), &[&samples[3]]);
+        assert!(text.len() < samples[3].len() / 20, "{} bytes from {}", text.len(), samples[3].len());
+        // Letters alone take under 5 bits each with a code of their own.
+        let letters = encode(BrotliEncoder::new(4), &[&samples[5]]);
+        assert!(letters.len() < samples[5].len() * 5 / 8, "{} bytes from {}", letters.len(), samples[5].len());
+    }
+
+    #[test]
+    fn run_lengths_multiply() {
+        let mut lengths = vec![3, 3, 3, 3, 3, 3, 3, 3];
+        lengths.extend([0; 40]);
+        lengths.extend([3; 30]);
+        let runs = run_lengths(&lengths);
+        // 3, then 3 + 4 more; 6 + 34 zeros; 30 threes, repeated across the zeros.
+        assert_eq!(runs, [(3, 0), (16, 0), (16, 0), (17, 3), (17, 5), (16, 0), (16, 1), (16, 3)]);
+    }
+
+    // The decoder itself, on a stream from the reference encoder at
+    // quality 1.
+    #[test]
+    fn decoder_reads_reference_output() {
+        let data = [
+            0x83, 0x2f, 0x00, 0x00, 0x80, 0xaa, 0xaa, 0xaa, 0xea, 0xff, 0x74, 0xa5, 0xf3, 0x95, 0x98, 0xf9,
+            0x70, 0x62, 0xd8, 0x80, 0x0b, 0x1c, 0xc3, 0x08, 0x27, 0xc0, 0xe1, 0x00, 0x4e, 0x34, 0xd6, 0xdc,
+            0x3a, 0x45, 0x84, 0xab, 0x1e,
+        ];
+        assert_eq!(decompress(&data), b"abracadabra ".repeat(8));
+    }
+}
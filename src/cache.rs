@@ -0,0 +1,420 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, EXPIRES, PRAGMA, SET_COOKIE, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use httpdate::parse_http_date;
+use crate::proxy::bad_gateway;
+
+pub struct CacheConfig {
+    /// Bytes of response bodies kept in memory; 0 disables the cache.
+    pub memory_size: u64,
+    /// Entries pushed out of memory are written here instead of being dropped.
+    pub disk_dir: Option<PathBuf>,
+    pub disk_size: u64,
+    /// Upper bound on the freshness lifetime an upstream may ask for.
+    pub max_ttl: Duration,
+    pub max_entry_size: u64,
+    store: Mutex<Store>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            memory_size: 0,
+            disk_dir: None,
+            disk_size: 1024 * 1024 * 1024,
+            max_ttl: Duration::from_secs(3600),
+            max_entry_size: 1024 * 1024,
+            store: Mutex::default(),
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn enabled(&self) -> bool {
+        self.memory_size > 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let store = self.lock();
+        CacheStats {
+            entries: store.entries.len(),
+            memory_used: store.memory_used,
+            disk_used: store.disk_used,
+            hits: store.hits,
+            misses: store.misses,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    memory_used: u64,
+    disk_used: u64,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Stored,
+    size: u64,
+    stored_at: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+enum Stored {
+    Memory(Bytes),
+    /// On its way to disk, still answered from memory.
+    Spilling(Bytes, PathBuf),
+    Disk(PathBuf),
+}
+
+// An entry pushed out of memory, written to `path` once the lock is released.
+struct Spill {
+    key: String,
+    path: PathBuf,
+    bytes: Bytes,
+}
+
+pub enum Lookup {
+    Hit(Response<Body>),
+    Miss(String),
+    Bypass,
+}
+
+/// Looks the request up; `Miss` carries the key to store the response under.
+pub async fn lookup(config: &CacheConfig, req: &Request<Body>) -> Lookup {
+    if !config.enabled() || !is_cacheable_request(req) {
+        return Lookup::Bypass;
+    }
+    let key = cache_key(req);
+    let found = {
+        let mut store = config.lock();
+        store.clock += 1;
+        let clock = store.clock;
+        match store.entries.get_mut(&key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                entry.last_used = clock;
+                let body = match &entry.body {
+                    Stored::Memory(bytes) | Stored::Spilling(bytes, _) => Ok(bytes.clone()),
+                    Stored::Disk(path) => Err(path.clone()),
+                };
+                Some((entry.status, entry.headers.clone(), body, entry.stored_at.elapsed()))
+            },
+            Some(_) => {
+                store.remove(&key);
+                None
+            },
+            None => None,
+        }
+    };
+
+    // HEAD is answered from stored GET responses but never fills the cache.
+    let (status, headers, body, age) = match found {
+        Some(found) => found,
+        None if req.method() == Method::GET => {
+            config.lock().misses += 1;
+            return Lookup::Miss(key);
+        },
+        None => return Lookup::Bypass,
+    };
+    let body = match body {
+        Ok(bytes) => bytes,
+        Err(path) => match tokio::fs::read(&path).await {
+            Ok(bytes) => Bytes::from(bytes),
+            Err(_) => {
+                let mut store = config.lock();
+                store.remove(&key);
+                if req.method() != Method::GET {
+                    return Lookup::Bypass;
+                }
+                store.misses += 1;
+                return Lookup::Miss(key);
+            }
+        },
+    };
+    config.lock().hits += 1;
+
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response.headers_mut().insert(AGE, HeaderValue::from(age.as_secs()));
+    response.headers_mut().insert("X-Cache", HeaderValue::from_static("HIT"));
+    Lookup::Hit(response)
+}
+
+/// Stores a cacheable upstream response under `key`. The body is buffered only
+/// when its Content-Length fits the entry limit; otherwise it streams through.
+pub async fn store(config: &CacheConfig, key: String, response: Response<Body>) -> Response<Body> {
+    let ttl = freshness(&response).map(|ttl| ttl.min(config.max_ttl));
+    let len = response.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let (ttl, len) = match (ttl, len) {
+        (Some(ttl), Some(len)) if !ttl.is_zero() && len <= config.max_entry_size => (ttl, len),
+        _ => return mark(response, "MISS"),
+    };
+
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) if body.len() as u64 == len => body,
+        _ => return bad_gateway(),
+    };
+
+    let now = Instant::now();
+    let entry = Entry {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: Stored::Memory(body.clone()),
+        size: len,
+        stored_at: now,
+        expires: now + ttl,
+        last_used: 0,
+    };
+    let spills = config.lock().insert(config, key, entry);
+    // Written outside the lock, off the runtime's worker threads.
+    for spill in spills {
+        let written = tokio::fs::write(&spill.path, &spill.bytes).await.is_ok();
+        if !config.lock().spilled(&spill.key, &spill.path, written) {
+            let _ = tokio::fs::remove_file(&spill.path).await;
+        }
+    }
+
+    mark(Response::from_parts(parts, Body::from(body)), "MISS")
+}
+
+fn mark(mut response: Response<Body>, status: &'static str) -> Response<Body> {
+    response.headers_mut().insert("X-Cache", HeaderValue::from_static(status));
+    response
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub memory_used: u64,
+    pub disk_used: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Store {
+    fn insert(&mut self, config: &CacheConfig, key: String, mut entry: Entry) -> Vec<Spill> {
+        self.remove(&key);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.memory_used += entry.size;
+        self.entries.insert(key, entry);
+
+        // Least recently used entries leave memory first, spilling to disk when
+        // a cache directory is configured.
+        let mut spills = Vec::new();
+        while self.memory_used > config.memory_size {
+            let victim = self.entries.iter()
+                .filter(|(_, entry)| matches!(entry.body, Stored::Memory(_)))
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let victim = match victim {
+                Some(victim) => victim,
+                None => break,
+            };
+            match self.spill(config, &victim) {
+                Some(spill) => spills.push(spill),
+                None => self.remove(&victim),
+            }
+        }
+        while self.disk_used > config.disk_size {
+            let victim = self.entries.iter()
+                .filter(|(_, entry)| matches!(entry.body, Stored::Spilling(..) | Stored::Disk(_)))
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match victim {
+                Some(victim) => self.remove(&victim),
+                None => break,
+            }
+        }
+        spills.retain(|spill| self.entries.contains_key(&spill.key));
+        spills
+    }
+
+    // Counts the entry as on disk already, so the disk limit covers writes in
+    // flight. The path is unique, as a newer entry under the same key may be
+    // spilled before this one is written.
+    fn spill(&mut self, config: &CacheConfig, key: &str) -> Option<Spill> {
+        let dir = config.disk_dir.as_ref()?;
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(key)?;
+        let bytes = match &entry.body {
+            Stored::Memory(bytes) => bytes.clone(),
+            _ => return None,
+        };
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let path = dir.join(format!("{:016x}-{}", hasher.finish(), clock));
+        entry.body = Stored::Spilling(bytes.clone(), path.clone());
+        self.memory_used -= entry.size;
+        self.disk_used += entry.size;
+        Some(Spill { key: key.to_string(), path, bytes })
+    }
+
+    // Settles a spill once its write is done; false when the file is not kept,
+    // as the write failed or the entry was dropped or replaced meanwhile.
+    fn spilled(&mut self, key: &str, path: &Path, written: bool) -> bool {
+        let current = self.entries.get_mut(key)
+            .filter(|entry| matches!(&entry.body, Stored::Spilling(_, spilling) if spilling == path));
+        let entry = match current {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if !written {
+            self.remove(key);
+            return false;
+        }
+        entry.body = Stored::Disk(path.to_path_buf());
+        true
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            match entry.body {
+                Stored::Memory(_) => self.memory_used -= entry.size,
+                Stored::Spilling(..) => self.disk_used -= entry.size,
+                Stored::Disk(path) => {
+                    self.disk_used -= entry.size;
+                    let _ = std::fs::remove_file(path);
+                },
+            }
+        }
+    }
+}
+
+fn is_cacheable_request(req: &Request<Body>) -> bool {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return false;
+    }
+    if req.headers().contains_key(AUTHORIZATION) {
+        return false;
+    }
+    let no_cache = |name| req.headers().get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("no-cache") || v.to_ascii_lowercase().contains("no-store"));
+    !no_cache(CACHE_CONTROL) && !no_cache(PRAGMA)
+}
+
+// Only GET responses are stored, so HEAD shares their key. Responses vary on
+// Accept-Encoding at most, so that is the only header that goes into the key.
+fn cache_key(req: &Request<Body>) -> String {
+    let accept_encoding = req.headers().get("Accept-Encoding")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let target = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    format!("{} {} {}", Method::GET, target, accept_encoding)
+}
+
+// Freshness lifetime from s-maxage/max-age, falling back to Expires - Date.
+// Anything marked private, no-store or no-cache is not stored at all.
+fn freshness(response: &Response<Body>) -> Option<Duration> {
+    if !matches!(response.status(), StatusCode::OK | StatusCode::MOVED_PERMANENTLY | StatusCode::NOT_FOUND) {
+        return None;
+    }
+    let headers = response.headers();
+    if headers.contains_key(SET_COOKIE) {
+        return None;
+    }
+    let vary_ok = headers.get_all(VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .all(|name| name.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !vary_ok {
+        return None;
+    }
+
+    let cache_control: Vec<String> = headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect();
+    if cache_control.iter().any(|d| d == "private" || d == "no-store" || d == "no-cache") {
+        return None;
+    }
+    let max_age = |name: &str| cache_control.iter()
+        .filter_map(|d| d.strip_prefix(name))
+        .filter_map(|d| d.strip_prefix('='))
+        .find_map(|secs| secs.trim_matches('"').parse().ok())
+        .map(Duration::from_secs);
+    if let Some(ttl) = max_age("s-maxage").or_else(|| max_age("max-age")) {
+        return Some(ttl);
+    }
+
+    let date = |name| headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_http_date(v).ok());
+    let expires = date(EXPIRES)?;
+    let now = date(DATE).unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)], body: &'static str) -> Response<Body> {
+        let mut builder = Response::builder().header(CONTENT_LENGTH, body.len());
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    fn get(target: &str) -> Request<Body> {
+        Request::builder().uri(target).body(Body::empty()).unwrap()
+    }
+
+    async fn hit(config: &CacheConfig, target: &str) -> Option<Bytes> {
+        match lookup(config, &get(target)).await {
+            Lookup::Hit(response) => Some(hyper::body::to_bytes(response.into_body()).await.unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn private_and_varying_responses_are_not_stored() {
+        assert!(freshness(&response(&[("Cache-Control", "private, max-age=60")], "")).is_none());
+        assert!(freshness(&response(&[("Cache-Control", "max-age=60"), ("Vary", "Cookie")], "")).is_none());
+        assert_eq!(freshness(&response(&[("Cache-Control", "max-age=60"), ("Vary", "Accept-Encoding")], "")), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn spilled_entries_are_served_from_disk() {
+        let dir = std::env::temp_dir().join(format!("rustywebserver-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = CacheConfig { memory_size: 8, disk_dir: Some(dir.clone()), ..CacheConfig::default() };
+
+        for (target, body) in [("/a", "first"), ("/b", "second")] {
+            let key = match lookup(&config, &get(target)).await {
+                Lookup::Miss(key) => key,
+                _ => panic!("expected a miss"),
+            };
+            store(&config, key, response(&[("Cache-Control", "max-age=60")], body)).await;
+        }
+        let stats = config.stats();
+        assert_eq!((stats.memory_used, stats.disk_used), (6, 5));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(hit(&config, "/a").await.as_deref(), Some(&b"first"[..]));
+        assert_eq!(hit(&config, "/b").await.as_deref(), Some(&b"second"[..]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use hyper::{Body, Response, StatusCode};
+use crate::brotli::BrotliEncoder;
+use crate::deflate::{GzipEncoder, StreamEncoder, ZlibEncoder};
+use crate::zstd::ZstdEncoder;
+
+pub struct CompressionConfig {
+    pub brotli_level: u32,
+    pub zstd_level: u32,
+    pub gzip_level: u32,
+    pub deflate_level: u32,
+    pub min_size: u64,
+    /// Media types eligible for compression; `type/*` matches a whole family.
+    pub types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            brotli_level: 4,
+            zstd_level: 3,
+            gzip_level: 6,
+            deflate_level: 6,
+            min_size: 1024,
+            types: [
+                "text/*",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ].iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // Listed in the order preferred when the client weighs them equally.
+    const ALL: [Encoding; 4] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encoder(self, config: &CompressionConfig) -> Box<dyn StreamEncoder + Send> {
+        match self {
+            Encoding::Brotli => Box::new(BrotliEncoder::new(config.brotli_level)),
+            Encoding::Zstd => Box::new(ZstdEncoder::new(config.zstd_level)),
+            Encoding::Gzip => Box::new(GzipEncoder::new(config.gzip_level)),
+            Encoding::Deflate => Box::new(ZlibEncoder::new(config.deflate_level)),
+        }
+    }
+}
+
+pub fn compress_response(config: &CompressionConfig, accept_encoding: Option<&str>, response: Response<Body>) -> Response<Body> {
+    if response.status() != StatusCode::OK || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+    // Event streams are sent event by event, which an encoder would hold back.
+    let content_type = response.headers().get(CONTENT_TYPE);
+    if !is_compressible(&config.types, content_type) || content_type.is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream")) {
+        return response;
+    }
+    let len = response.headers().get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if matches!(len, Some(len) if len < config.min_size) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let varies = parts.headers.get_all(VARY).iter()
+        .any(|v| v.to_str().map(|v| v.to_ascii_lowercase().contains("accept-encoding")).unwrap_or(false));
+    if !varies {
+        parts.headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    let encoding = match negotiate(accept_encoding) {
+        Some(encoding) => encoding,
+        None => return Response::from_parts(parts, body),
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(ACCEPT_RANGES);
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    // The encoded bytes differ from the file, so only a weak validator is honest.
+    if let Some(etag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            let weak = format!("W/{}", etag);
+            parts.headers.insert(ETAG, HeaderValue::from_str(&weak).unwrap());
+        }
+    }
+
+    Response::from_parts(parts, encode_body(body, encoding.encoder(config)))
+}
+
+fn encode_body(body: Body, encoder: Box<dyn StreamEncoder + Send>) -> Body {
+    let stream = stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    let out = encoder.compress(&chunk);
+                    if !out.is_empty() {
+                        return Some((Ok(Bytes::from(out)), Some((body, encoder))));
+                    }
+                },
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => return Some((Ok(Bytes::from(encoder.finish())), None)),
+            }
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+fn is_compressible(types: &[String], content_type: Option<&HeaderValue>) -> bool {
+    let content_type = match content_type.and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    types.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(family) => essence.split('/').next() == Some(family),
+        None => pattern.eq_ignore_ascii_case(&essence),
+    })
+}
+
+fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in Encoding::ALL {
+        let q = quality(accept_encoding, encoding.name());
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+pub fn quality(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
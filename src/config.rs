@@ -0,0 +1,1107 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::access_file;
+use crate::access_log::{AccessLogConfig, LogSample, Rotate};
+use crate::acl::IpAcl;
+use crate::api_key::{self, ApiKeys};
+use crate::auth::{AuthZone, WatchedFile};
+use crate::ban::BanConfig;
+use crate::cache::CacheConfig;
+use crate::compress::CompressionConfig;
+use crate::conn_limit::ConnectionLimits;
+use crate::cors::CorsRule;
+use crate::credentials::{self, Credentials};
+use crate::cron::CronJob;
+use crate::files::{Alias, TrailingSlash};
+use crate::gateway::{GatewayRoute, Protocol};
+use crate::geoip::GeoRules;
+use crate::health::HealthConfig;
+use crate::hooks::Hooks;
+use crate::hotlink::HotlinkConfig;
+use crate::jobs::JobsConfig;
+use crate::jwt::JwtConfig;
+use crate::log_format::{self, LogFormat};
+use crate::ldap::LdapConfig;
+use crate::metrics::MetricsConfig;
+use crate::mime_map::MimeMap;
+use crate::oidc::OidcConfig;
+use crate::prefork::PreforkConfig;
+use crate::proxy::ProxyRoute;
+use crate::rate_limit::{RateLimiter, RouteLimit};
+use crate::redirect::RedirectRule;
+use crate::rewrite::RewriteRule;
+use crate::route::Route;
+use crate::rlimit::ResourceLimits;
+use crate::sandbox::Sandbox;
+use crate::script_limit::ScriptLimits;
+use crate::script_log::ScriptLog;
+use crate::security::SecurityHeaders;
+use crate::syslog::{self, LogSink, Target};
+use crate::throttle::{BandwidthLimit, RouteBandwidth};
+use crate::tls::Acceptor;
+use crate::jwt::parse_url;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{StatusCode, Uri};
+
+// hyper refuses read buffers smaller than this.
+const MIN_HEADER_SIZE: usize = 8192;
+
+pub const USAGE: &str = "\
+Usage: rustywebserver [OPTIONS] --port <PORT> --root <ROOT_FOLDER>
+       rustywebserver [OPTIONS] <PORT> <ROOT_FOLDER>";
+
+pub const HELP: &str = "\
+A small static file and script web server.
+
+Usage: rustywebserver [OPTIONS] --port <PORT> --root <ROOT_FOLDER>
+       rustywebserver [OPTIONS] <PORT> <ROOT_FOLDER>
+
+Server:
+  -p, --port <PORT>                 Port to listen on (ignored under systemd socket activation)
+  -r, --root <ROOT_FOLDER>          Folder to serve files and scripts from
+      --bind <ADDRESS>              IPv4/IPv6 address or host name to listen on, repeatable [default: 0.0.0.0]
+      --unix-socket <PATH>          Also (or, without a port, only) listen on a Unix domain socket
+      --unix-socket-mode <MODE>     Octal permissions for the socket file [default: 0660]
+      --ipv6-only                   Do not accept IPv4 clients on IPv6 sockets such as [::]
+      --acceptors <N|auto>          SO_REUSEPORT sockets per address, `auto` for one per core [default: 1]
+      --https-redirect-port <PORT>  Also listen for plain HTTP on PORT and 301-redirect it to HTTPS
+      --https-port <PORT>           Port of the HTTPS origin used in redirects [default: 443]
+      --tls-cert <PATH>             Serve HTTPS on the TCP listeners with this PEM certificate chain;
+                                    repeat it, each with its --tls-key, for more sites: clients get the
+                                    first certificate covering the host name they ask for (SNI)
+      --tls-key <PATH>              PEM private key of the --tls-cert given in the same place
+      --tls-client-ca <PATH>        Require client certificates issued by a CA in this PEM bundle (mTLS);
+                                    scripts and $ssl_client_s_dn see the certificate's subject
+      --proxy-protocol              Expect a PROXY protocol v1/v2 header on every connection
+                                    and log the client address it carries
+      --access-log <PATH>           Append access log lines to a file instead of stdout (alias: --log-file)
+      --access-log-max-size <BYTES> Rotate the access log before it grows past this size
+      --access-log-rotate <WHEN>    Also rotate it hourly or daily (UTC)
+      --access-log-keep <N>         Rotated logs kept as PATH.1 to PATH.N [default: 7]
+      --log-format <TEMPLATE>       Access log line, nginx style: $remote_addr, $country, $request_method,
+                                    $uri, $args, $request, $server_protocol, $status, $status_text,
+                                    $body_bytes_sent, $request_time, $request_id, $time_local,
+                                    $time_iso8601, $ssl_client_s_dn and $http_<header>, e.g.
+                                    $http_user_agent; or `json` for one JSON object per request and
+                                    per server event
+                                    [default: '$request_method $remote_addr $uri -> $status ($status_text)']
+      --log-exclude <PATTERN>       Leave successful requests for paths matching a --deny style pattern,
+                                    e.g. /healthz or favicon.ico, out of the access log (repeatable)
+      --log-sample <PREFIX=RATE>    Log only RATE (0-1) of the successful requests under PREFIX, e.g.
+                                    /api/poll=0.01; errors are always logged (repeatable)
+      --syslog <PATH|HOST[:PORT]>   Send access lines and server events to syslog instead, through a
+                                    local socket such as /dev/log or over UDP (port 514 by default)
+      --syslog-facility <NAME>      Facility of syslog messages, e.g. local0 [default: daemon]
+      --syslog-tag <TAG>            Program name in syslog and journald entries [default: rustywebserver]
+      --journald                    Send access lines and server events to the systemd journal instead
+
+Static files:
+      --alias <PREFIX=DIR>          Serve URL paths under PREFIX from DIR instead of the root (repeatable)
+      --script-dir <DIR|PREFIX=DIR> Run the files in DIR, a folder of the root, as scripts; PREFIX=DIR mounts
+                                    an outside DIR at PREFIX, e.g. /cgi-bin=/srv/cgi-bin (repeatable);
+                                    a SCRIPT.meta file next to a script sets its content-type, timeout,
+                                    methods, cache-ttl and auth-basic, auth-ldap or auth-realm
+                                    [default: scripts]
+      --route <'METHOD PATH -> SCRIPT'>  Run SCRIPT, relative to the root, for METHOD (or '*') requests to
+                                    PATH, whose '{name}' segments reach it as ROUTE_<name>, e.g.
+                                    'GET /api/users/{id} -> scripts/user_get.sh'; the first match wins (repeatable)
+      --routes-file <PATH>          Read routes from a file, one per line in the same form
+      --redirect <'PATH TARGET [STATUS]'>  Redirect a path, or a prefix ending in '*', to TARGET with
+                                    301 (default), 302, 307 or 308; '*' in TARGET takes the rest (repeatable)
+      --redirects-file <PATH>       Read redirects from a file, one per line in the same form
+      --rewrite <'REGEX REPLACEMENT [FLAG]'>  Rewrite matching URL paths before routing, nginx style;
+                                    FLAG is last, break, redirect or permanent (repeatable)
+      --index <NAMES>               Comma-separated index file names [default: index.html,index.htm]
+      --trailing-slash <POLICY>     redirect: 301 /dir to /dir/ and /file/ to /file; serve: accept both
+                                    [default: redirect]
+      --deny <PATTERN>              Refuse paths matching a glob pattern (repeatable)
+      --access-files                Read .rustyaccess files in served directories for auth-basic,
+                                    auth-ldap, auth-realm, allow/deny IP and deny-files rules
+      --hotlink-protect <PATTERN>   Refuse files matching a --deny style pattern, e.g. '*.jpg', to pages
+                                    on other sites, judged by the Referer (repeatable)
+      --hotlink-allow <HOST>        Also accept Referers from HOST, e.g. '*.example.com' (repeatable)
+      --hotlink-block-empty         Refuse protected files to requests without a Referer too
+      --hotlink-placeholder <PATH>  Serve this file instead of a 403 to refused requests
+      --mime-types <PATH>           Load extra MIME types from an nginx or Apache mime.types file
+      --charset <CHARSET|off>       Charset added to text responses [default: utf-8]
+      --charset-types <TYPES>       Media types that get a charset [default: text/html,text/plain]
+      --no-precompressed            Do not serve precompressed .br/.gz siblings
+
+Access control:
+      --allow-ip <[PREFIX=]CIDR|all>  Allow clients in CIDR, everywhere or under PREFIX (repeatable)
+      --deny-ip <[PREFIX=]CIDR|all>   Refuse clients in CIDR with 403; the first matching rule decides,
+                                    global rules first, then those of the longest PREFIX (repeatable)
+      --geoip-db <PATH>             MaxMind DB (.mmdb) country or city database; adds the client's country
+                                    to the access log and scripts (Geoip_country)
+      --geoip-allow <[PREFIX=]CC,...>  Only serve clients from these countries, everywhere or under PREFIX;
+                                    `--` stands for addresses without a country (repeatable)
+      --geoip-deny <[PREFIX=]CC,...>   Refuse clients from these countries with 403 (repeatable)
+      --rate-limit <REQS/SEC>       Answer 429 to clients sending more requests per second on average
+      --rate-limit-burst <N>        Requests a client may send in a row before it is limited
+                                    [default: the rate, at least 1]
+      --rate-limit-route <PREFIX=REQS/SEC[:BURST]>  Also limit each client under PREFIX, e.g.
+                                    /scripts/login=0.2:5; the longest PREFIX applies (repeatable)
+      --ban-threshold <N>           Ban clients at the socket once they get N 4xx/429 responses within
+                                    the ban window
+      --ban-window <SECS>           Sliding window the errors are counted in [default: 60]
+      --ban-time <SECS>             How long a ban lasts [default: 600]
+      --ban-admin <PATH>            Endpoint listing bans (GET) and lifting them (DELETE [?ip=ADDR]);
+                                    restrict it with --allow-ip or --basic-auth
+      --basic-auth <PREFIX=HTPASSWD>  Require HTTP Basic auth under PREFIX, checked against an htpasswd
+                                    file with bcrypt, Argon2, apr1 or {SHA} hashes (repeatable)
+      --ldap-auth <PREFIX>          Require HTTP Basic auth under PREFIX, checked with an LDAP bind (repeatable)
+      --ldap-url <URL>              ldap://HOST[:PORT] of the directory; passwords cross it unencrypted
+      --ldap-bind-dn <TEMPLATE>     DN to bind as, `{user}` is replaced, e.g. uid={user},ou=people,dc=example,dc=com
+      --ldap-group <DN>             Also require membership of this group (member, uniqueMember or memberUid)
+      --auth-realm <REALM>          Realm named in the 401 challenge [default: Restricted]
+      --api-key-auth <PREFIX>       Require an X-Api-Key header or api_key query parameter under PREFIX
+                                    (repeatable)
+      --api-keys <PATH>             Keys file, one `NAME KEY /PREFIX...` line per key listing where it is valid
+      --jwt-auth <PREFIX>           Require a valid `Authorization: Bearer` JWT under PREFIX (repeatable)
+      --jwt-secret-file <PATH>      Shared secret for HS256 tokens
+      --jwt-jwks <PATH|URL>         JWK Set file or http(s):// URL with the RSA keys for RS256 tokens
+      --jwt-issuer <ISS>            Only accept tokens whose iss claim is ISS
+      --jwt-audience <AUD>          Only accept tokens whose aud claim contains AUD
+      --oidc-auth <PREFIX>          Require an OpenID Connect login under PREFIX (repeatable)
+      --oidc-issuer <URL>           Issuer URL of the provider, used for discovery
+      --oidc-client-id <ID>         Client ID registered with the provider
+      --oidc-client-secret-file <PATH>  File holding the client secret
+      --oidc-redirect-uri <URL>     Public URL of the login callback, e.g. https://example.com/oauth2/callback
+      --oidc-session-ttl <SECS>     Lifetime of the session cookie [default: 28800]
+      --oidc-cookie-secret-file <PATH>  Key signing the cookies, so sessions survive restarts [default: random]
+
+Reverse proxy:
+      --proxy <PREFIX=URL>          Forward requests under PREFIX to an http:// upstream (repeatable);
+                                    a path in URL replaces PREFIX, e.g. /api=http://127.0.0.1:9000
+                                    Several upstreams: PREFIX=[round-robin|least-conn|weighted:]URL[*WEIGHT],...
+      --fastcgi <PATTERN=ADDR>      Hand requests to a FastCGI server such as php-fpm at HOST:PORT or
+                                    unix:PATH; PATTERN is a file name pattern for existing files, e.g.
+                                    *.php, or a /PREFIX whose rest becomes PATH_INFO (repeatable)
+      --scgi <PATTERN=ADDR>         Hand requests to an SCGI server, matched and addressed as with
+                                    --fastcgi (repeatable)
+      --uwsgi <PATTERN=ADDR>        Hand requests to a uWSGI server over its uwsgi protocol, matched and
+                                    addressed as with --fastcgi (repeatable)
+      --proxy-cache-size <BYTES>    Cache proxied GET responses in memory up to this size, 0 disables [default: 0]
+      --proxy-cache-dir <PATH>      Spill entries evicted from memory to this directory
+      --proxy-cache-disk-size <BYTES>  Largest size of the spill directory [default: 1073741824]
+      --proxy-cache-max-ttl <SECS>  Longest time a response is considered fresh [default: 3600]
+      --proxy-cache-max-entry <BYTES>  Largest response body that is cached [default: 1048576]
+
+Security headers:
+      --hsts <SECS>                 Send Strict-Transport-Security with this max-age
+      --csp <POLICY>                Send this Content-Security-Policy
+      --header <NAME: VALUE>        Add or override a response header, `NAME:` removes a default (repeatable)
+      --no-security-headers         Do not send the default X-Content-Type-Options, X-Frame-Options
+                                    and Referrer-Policy headers
+      --cors <'PREFIX origins=ORIGIN,...|* [SETTING...]'>  Answer CORS preflights and add CORS headers
+                                    under PREFIX; settings: methods=GET,... headers=NAME,... (default:
+                                    whatever is asked) expose=NAME,... max-age=SECS credentials (repeatable)
+
+Compression:
+      --brotli-level <0-11>         brotli compression level [default: 4]
+      --zstd-level <1-19>           zstd compression level [default: 3]
+      --gzip-level <0-9>            gzip compression level [default: 6]
+      --deflate-level <0-9>         deflate compression level [default: 6]
+      --compress-min-size <BYTES>   Smallest response worth compressing [default: 1024]
+      --compress-types <TYPES>      Comma-separated media types to compress, `type/*` allowed
+
+Connections:
+      --limit-rate <BYTES/SEC>      Send each response body at most this fast
+      --limit-rate-after <BYTES>    Bytes of each response sent at full speed first [default: 0]
+      --limit-rate-route <PREFIX=BYTES/SEC[:BYTES]>  Rate, and optionally full-speed bytes, for
+                                    responses under PREFIX instead; the longest PREFIX applies (repeatable)
+      --keep-alive-timeout <SECS>   Idle time before closing a keep-alive connection, 0 disables [default: 5]
+      --header-timeout <SECS>       Time allowed to receive request headers [default: 10]
+      --header-min-rate <BYTES/SEC> Close connections whose request headers arrive slower than this
+                                    after the first second, 0 disables [default: 0]
+      --body-timeout <SECS>         Time allowed to receive a request body [default: 30]
+      --request-timeout <SECS>      Time allowed to produce a response [default: 300]
+      --script-exit-status <CODE=STATUS,...>  Answer scripts exiting with CODE with STATUS instead of
+                                    500, e.g. 2=400,3=404,4=403; their output is sent as usual (repeatable)
+      --script-form-vars            Also pass the fields of form POSTs up to 64 KiB to scripts as
+                                    FORM_<name> variables; the body still arrives on stdin
+      --script-hide-errors          Answer failed scripts with a plain 500 page instead of their stderr,
+                                    which may give away internals; stderr is always logged
+      --script-json-vars            Also pass the top-level members of JSON object POSTs up to 64 KiB to
+                                    scripts as JSON_<key> variables, nested values as JSON text
+      --prefork <PATH=WORKERS>      Keep WORKERS processes of the script at PATH running and reuse them:
+                                    each request arrives on stdin as NAME=VALUE lines, a blank line and
+                                    CONTENT_LENGTH bytes, the answer is headers with Content-Length,
+                                    a blank line and the body (repeatable)
+      --script-cache-size <BYTES>   Cache GET script responses in memory up to this size, keyed by path and
+                                    query, for the max-age the script sends or its cache-ttl, 0 disables
+                                    [default: 0]
+      --script-cache-max-ttl <SECS> Longest time a script response is considered fresh [default: 3600]
+      --cron <'SCHEDULE SCRIPT'>    Run SCRIPT, relative to the root, on a five-field cron SCHEDULE in UTC or
+                                    @hourly, @daily, @weekly, @monthly, @yearly, as a GET to its URL would,
+                                    e.g. '*/15 * * * * scripts/warm-cache.sh' (repeatable)
+      --cron-file <PATH>            Read schedules from a file, one per line in the same form
+      --jobs <PATH>                 Serve background jobs under PATH, e.g. /jobs: a POST to a script whose
+                                    SCRIPT.meta says 'async = true' gets a 202 with a PATH/<ID> to poll and
+                                    the script's response at PATH/<ID>/output once it is done
+      --jobs-max <N>                Jobs kept at once; the oldest finished one makes room [default: 100]
+      --jobs-ttl <SECS>             Time a finished job's output is kept [default: 3600]
+      --script-log-dir <DIR>        Append what each script writes to stderr to DIR/<script path>.log, a
+                                    line at a time with the time and request ID
+      --script-log-stdout           Log the scripts' output, the responses they send, there too
+      --script-cwd <DIR>            Working directory scripts start in [default: the script's folder]
+      --script-path <DIRS>          PATH scripts start with; nothing else of the server's environment
+                                    reaches them [default: /usr/local/bin:/usr/bin:/bin]
+      --script-pass-env <NAME>      Also pass the server's NAME variable on to scripts, e.g. TZ or LANG
+                                    (repeatable)
+      --script-user <USER>          Run scripts as USER, a name or UID, when the server runs as root
+      --script-group <GROUP>        Run scripts with GROUP, a name or GID [default: the user's group]
+      --script-sandbox              Start scripts in their own mount, PID, network and IPC namespaces,
+                                    with the root folder read-only, only loopback networking and a
+                                    seccomp filter against mount, ptrace, module loading and the like
+      --script-timeout <SECS>       Kill scripts, and what they started, after SECS and answer 504;
+                                    event streams are exempt, 0 disables [default: 0]
+      --shutdown-timeout <SECS>     Time to drain connections on SIGTERM/SIGINT [default: 30]
+      --on-start <COMMAND>          Run COMMAND once the sockets are bound, with SERVER_EVENT=start and the
+                                    addresses in SERVER_ADDRESSES (repeatable, run in order)
+      --on-stop <COMMAND>           Run COMMAND on SIGTERM/SIGINT before connections are drained, with
+                                    SERVER_EVENT=stop (repeatable, run in order)
+      --hook-failure <POLICY>       warn: log a failed hook and go on; abort: a failed start hook stops the
+                                    server, a failed stop hook makes it exit with status 1 [default: warn]
+      --hook-timeout <SECS>         Kill hooks running longer than SECS, a failure [default: 30]
+      --max-header-size <BYTES>     Largest accepted request head, at least 8192 [default: 65536]
+      --max-uri-length <BYTES>      Longest accepted request target [default: 8192]
+      --max-body-size <BYTES>       Largest accepted request body [default: 10485760]
+      --max-connections <N>         Open connections served at once; more are answered 503 and closed,
+                                    TLS ones without the 503
+      --max-connections-per-ip <N>  Open connections one client IP may hold
+      --max-pending-per-ip <N>      Connections one client IP may hold open before sending a complete request
+      --max-script-output <BYTES>   Largest output a script may write; past it the script is killed and
+                                    the client gets a 502, or a cut-off response once it has started
+      --max-scripts <N>             Script processes running at once; more requests wait, then get 503
+      --max-scripts-per-file <N>    Processes of any one script running at once
+      --script-queue-timeout <SECS> Time a request waits for a script slot, 0 answers 503 at once [default: 0]
+      --script-max-memory <BYTES>   Address space each script process may use; allocations past it fail
+      --script-max-cpu <SECS>       CPU time each script process may use before it is killed
+      --script-max-files <N>        Files each script process may hold open
+      --script-max-processes <N>    Processes the scripts' user may run, counted across the user
+
+Monitoring:
+      --metrics <PATH>              Serve Prometheus metrics at PATH, e.g. /metrics; restrict it with
+                                    --allow-ip or serve it on --metrics-port
+      --metrics-port <PORT>         Serve the metrics on this port only [default path: /metrics]
+      --metrics-route <PREFIX>      Count requests under PREFIX as their own route, in the metrics and
+                                    the status page's latency and error figures (repeatable)
+      --status-page <PATH>          Serve an HTML status page at PATH, e.g. /_status (JSON with
+                                    ?format=json); restrict it with --allow-ip
+      --healthz <PATH>              Answer liveness probes at PATH, e.g. /healthz
+      --readyz <PATH>               Answer readiness probes at PATH, e.g. /readyz: 503 unless the root is
+                                    readable, --max-connections is not reached and each --proxy route
+                                    has a reachable upstream
+      --slow-request <SECS>         Warn with a timing breakdown about requests taking at least SECS,
+                                    e.g. 0.5
+
+  -h, --help                        Print help
+  -V, --version                     Print version
+";
+
+pub enum Cli {
+    Run(Box<Config>),
+    Help,
+    Version,
+}
+
+pub struct Config {
+    pub port: Option<u16>,
+    pub root: PathBuf,
+    pub bind: Vec<String>,
+    pub ipv6_only: bool,
+    pub acceptors: usize,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_mode: u32,
+    pub https_redirect_port: Option<u16>,
+    pub https_port: u16,
+    pub tls: Option<Acceptor>,
+    pub proxy_protocol: bool,
+    pub access_log: AccessLogConfig,
+    pub log_sink: Option<LogSink>,
+    pub metrics: MetricsConfig,
+    pub status_page: Option<String>,
+    pub health: HealthConfig,
+    pub slow_request: Option<Duration>,
+    pub compression: CompressionConfig,
+    pub security_headers: SecurityHeaders,
+    pub cors: Vec<CorsRule>,
+    pub precompressed: bool,
+    pub aliases: Vec<Alias>,
+    pub script_dirs: Vec<PathBuf>,
+    pub redirects: Vec<RedirectRule>,
+    pub rewrites: Vec<RewriteRule>,
+    pub routes: Vec<Route>,
+    pub index_files: Vec<String>,
+    pub trailing_slash: TrailingSlash,
+    pub deny: Vec<String>,
+    pub access_files: bool,
+    pub hotlink: HotlinkConfig,
+    pub ip_acl: IpAcl,
+    pub geoip_db: Option<PathBuf>,
+    pub geoip_rules: GeoRules,
+    pub rate_limit: Option<RateLimiter>,
+    pub rate_limit_routes: Vec<RouteLimit>,
+    pub bans: BanConfig,
+    pub auth_zones: Vec<AuthZone>,
+    pub auth_realm: String,
+    pub ldap: LdapConfig,
+    pub api_keys: ApiKeys,
+    pub jwt: JwtConfig,
+    pub oidc: OidcConfig,
+    pub proxy_routes: Vec<ProxyRoute>,
+    pub gateways: Vec<GatewayRoute>,
+    pub proxy_cache: CacheConfig,
+    pub script_cache: CacheConfig,
+    pub jobs: JobsConfig,
+    pub script_log: Option<ScriptLog>,
+    pub cron: Vec<CronJob>,
+    pub hooks: Hooks,
+    pub mime_map: MimeMap,
+    pub charset: Option<String>,
+    pub charset_types: Vec<String>,
+    pub limit_rate: Option<BandwidthLimit>,
+    pub limit_rate_routes: Vec<RouteBandwidth>,
+    pub keep_alive_timeout: Duration,
+    pub header_timeout: Duration,
+    pub header_min_rate: u64,
+    pub body_timeout: Duration,
+    pub script_timeout: Option<Duration>,
+    pub script_hide_errors: bool,
+    pub script_form_vars: bool,
+    pub script_json_vars: bool,
+    pub script_credentials: Option<Credentials>,
+    pub script_cwd: Option<PathBuf>,
+    pub script_env_path: String,
+    pub script_pass_env: Vec<String>,
+    pub sandbox: Option<Arc<Sandbox>>,
+    pub script_exit_status: HashMap<i32, StatusCode>,
+    pub prefork: PreforkConfig,
+    pub request_timeout: Duration,
+    pub max_header_size: usize,
+    pub max_uri_length: usize,
+    pub max_body_size: u64,
+    pub max_script_output: Option<u64>,
+    pub shutdown_timeout: Duration,
+    pub connection_limits: ConnectionLimits,
+    pub script_limits: ScriptLimits,
+    pub script_rlimits: ResourceLimits,
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Cli, String> {
+        let mut config = Config {
+            port: None,
+            root: PathBuf::new(),
+            bind: Vec::new(),
+            ipv6_only: false,
+            acceptors: 1,
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            https_redirect_port: None,
+            https_port: 443,
+            tls: None,
+            proxy_protocol: false,
+            access_log: AccessLogConfig::default(),
+            log_sink: None,
+            metrics: MetricsConfig::default(),
+            status_page: None,
+            health: HealthConfig::default(),
+            slow_request: None,
+            compression: CompressionConfig::default(),
+            security_headers: SecurityHeaders::default(),
+            cors: Vec::new(),
+            precompressed: true,
+            aliases: Vec::new(),
+            script_dirs: Vec::new(),
+            redirects: Vec::new(),
+            rewrites: Vec::new(),
+            routes: Vec::new(),
+            index_files: vec!["index.html".to_string(), "index.htm".to_string()],
+            trailing_slash: TrailingSlash::Redirect,
+            deny: vec!["/forbidden.html".to_string()],
+            access_files: false,
+            hotlink: HotlinkConfig::default(),
+            ip_acl: IpAcl::default(),
+            geoip_db: None,
+            geoip_rules: GeoRules::default(),
+            rate_limit: None,
+            rate_limit_routes: Vec::new(),
+            bans: BanConfig::default(),
+            auth_zones: Vec::new(),
+            auth_realm: "Restricted".to_string(),
+            ldap: LdapConfig::default(),
+            api_keys: ApiKeys::default(),
+            jwt: JwtConfig::default(),
+            oidc: OidcConfig::default(),
+            proxy_routes: Vec::new(),
+            gateways: Vec::new(),
+            proxy_cache: CacheConfig::default(),
+            script_cache: CacheConfig::default(),
+            jobs: JobsConfig::default(),
+            script_log: None,
+            cron: Vec::new(),
+            hooks: Hooks::default(),
+            mime_map: MimeMap::default(),
+            charset: Some("utf-8".to_string()),
+            charset_types: vec!["text/html".to_string(), "text/plain".to_string()],
+            limit_rate: None,
+            limit_rate_routes: Vec::new(),
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            header_min_rate: 0,
+            body_timeout: Duration::from_secs(30),
+            script_timeout: None,
+            script_hide_errors: false,
+            script_form_vars: false,
+            script_json_vars: false,
+            script_credentials: None,
+            script_cwd: None,
+            script_env_path: "/usr/local/bin:/usr/bin:/bin".to_string(),
+            script_pass_env: Vec::new(),
+            sandbox: None,
+            script_exit_status: HashMap::new(),
+            prefork: PreforkConfig::default(),
+            request_timeout: Duration::from_secs(300),
+            max_header_size: 64 * 1024,
+            max_uri_length: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            max_script_output: None,
+            shutdown_timeout: Duration::from_secs(30),
+            connection_limits: ConnectionLimits::default(),
+            script_limits: ScriptLimits::default(),
+            script_rlimits: ResourceLimits::default(),
+        };
+
+        let mut port = None;
+        let mut root = None;
+        let mut positional = Vec::new();
+        let mut tls_certs = Vec::new();
+        let mut tls_keys = Vec::new();
+        let mut tls_client_ca = None;
+        let mut script_dirs = Vec::new();
+        let mut script_sandbox = false;
+        let mut script_user = None;
+        let mut script_group = None;
+        let mut script_log_stdout = false;
+        let mut rate_limit = None;
+        let mut rate_limit_burst = None;
+        let mut limit_rate = None;
+        let mut limit_rate_after = 0;
+        let mut log_format = None;
+        let mut log_target = None;
+        let mut syslog_facility = 3;
+        let mut syslog_tag = "rustywebserver".to_string();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                positional.push(arg.as_str());
+                continue;
+            }
+
+            let (option, inline) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => (option, Some(value)),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || match inline {
+                Some(value) => Ok(value),
+                None => args.next()
+                    .map(|v| v.as_str())
+                    .ok_or_else(|| format!("a value is required for '{}' but none was supplied", option)),
+            };
+            let flag = || match inline {
+                Some(_) => Err(format!("unexpected value for flag '{}'", option)),
+                None => Ok(()),
+            };
+
+            match option {
+                "-h" | "--help" => return Ok(Cli::Help),
+                "-V" | "--version" => return Ok(Cli::Version),
+                "-p" | "--port" => port = Some(value()?),
+                "-r" | "--root" => root = Some(value()?),
+                "--bind" => config.bind.push(parse_host(option, value()?)?),
+                "--ipv6-only" => {
+                    flag()?;
+                    config.ipv6_only = true;
+                },
+                "--acceptors" => config.acceptors = match value()? {
+                    "auto" => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+                    n => match parse_number(option, n)? {
+                        0 => return Err(format!("invalid value '{}' for '{}': must be at least 1", n, option)),
+                        n => n,
+                    },
+                },
+                "--unix-socket" => config.unix_socket = Some(PathBuf::from(value()?)),
+                "--unix-socket-mode" => {
+                    let mode = value()?;
+                    config.unix_socket_mode = u32::from_str_radix(mode, 8)
+                        .ok()
+                        .filter(|mode| *mode <= 0o777)
+                        .ok_or_else(|| format!("invalid value '{}' for '{}': expected an octal mode", mode, option))?;
+                },
+                "--https-redirect-port" => config.https_redirect_port = Some(parse_number(option, value()?)?),
+                "--https-port" => config.https_port = parse_number(option, value()?)?,
+                "--tls-cert" => tls_certs.push(PathBuf::from(value()?)),
+                "--tls-key" => tls_keys.push(PathBuf::from(value()?)),
+                "--tls-client-ca" => tls_client_ca = Some(PathBuf::from(value()?)),
+                "--proxy-protocol" => {
+                    flag()?;
+                    config.proxy_protocol = true;
+                },
+                "--access-log" | "--log-file" => config.access_log.path = Some(PathBuf::from(value()?)),
+                "--access-log-max-size" => config.access_log.max_size = Some(parse_number(option, value()?)?),
+                "--access-log-rotate" => config.access_log.rotate = Some(Rotate::parse(option, value()?)?),
+                "--access-log-keep" => config.access_log.keep = parse_number(option, value()?)?,
+                "--log-format" => log_format = Some(LogFormat::parse(option, value()?)?),
+                "--log-exclude" => config.access_log.exclude.push(value()?.to_string()),
+                "--log-sample" => config.access_log.samples.push(LogSample::parse(option, value()?)?),
+                "--metrics" => {
+                    let path = value()?;
+                    if !path.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the path must start with '/'", path, option));
+                    }
+                    config.metrics.path = Some(path.to_string());
+                },
+                "--metrics-port" => config.metrics.port = Some(parse_number(option, value()?)?),
+                "--metrics-route" => {
+                    let prefix = value()?;
+                    if !prefix.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the prefix must start with '/'", prefix, option));
+                    }
+                    config.metrics.routes.push(prefix.trim_end_matches('/').to_string());
+                },
+                "--status-page" => {
+                    let path = value()?;
+                    if !path.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the path must start with '/'", path, option));
+                    }
+                    config.status_page = Some(path.to_string());
+                },
+                "--healthz" | "--readyz" => {
+                    let path = value()?;
+                    if !path.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the path must start with '/'", path, option));
+                    }
+                    let slot = if option == "--healthz" { &mut config.health.healthz } else { &mut config.health.readyz };
+                    *slot = Some(path.to_string());
+                },
+                "--slow-request" => {
+                    let value = value()?;
+                    let secs = value.parse::<f64>().ok().filter(|secs| secs.is_finite() && *secs > 0.0)
+                        .ok_or_else(|| format!("invalid value '{}' for '{}': expected a positive number of seconds", value, option))?;
+                    config.slow_request = Some(Duration::from_secs_f64(secs));
+                },
+                "--syslog" | "--journald" => {
+                    if log_target.is_some() {
+                        return Err("'--syslog' and '--journald' cannot be used together".to_string());
+                    }
+                    log_target = Some(match option {
+                        "--syslog" => Target::parse(option, value()?)?,
+                        _ => {
+                            flag()?;
+                            Target::Journald
+                        },
+                    });
+                },
+                "--syslog-facility" => syslog_facility = syslog::parse_facility(option, value()?)?,
+                "--syslog-tag" => syslog_tag = value()?.to_string(),
+                "--api-key-auth" => {
+                    let prefix = value()?;
+                    if !prefix.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the prefix must start with '/'", prefix, option));
+                    }
+                    config.api_keys.prefixes.push(prefix.to_string());
+                },
+                "--api-keys" => config.api_keys.keys = Some(WatchedFile::load(Path::new(value()?), api_key::load)?),
+                "--jwt-auth" => {
+                    let prefix = value()?;
+                    if !prefix.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the prefix must start with '/'", prefix, option));
+                    }
+                    config.jwt.prefixes.push(prefix.to_string());
+                },
+                "--jwt-secret-file" => {
+                    let path = value()?;
+                    let secret = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                    config.jwt.secret = Some(secret.trim_ascii_end().to_vec());
+                },
+                "--jwt-jwks" => config.jwt.keys.set(option, value()?)?,
+                "--jwt-issuer" => config.jwt.issuer = Some(value()?.to_string()),
+                "--jwt-audience" => config.jwt.audience = Some(value()?.to_string()),
+                "--oidc-auth" => {
+                    let prefix = value()?;
+                    if !prefix.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the prefix must start with '/'", prefix, option));
+                    }
+                    config.oidc.prefixes.push(prefix.to_string());
+                },
+                "--oidc-issuer" => {
+                    let issuer = value()?;
+                    parse_url(option, issuer)?;
+                    config.oidc.issuer = Some(issuer.to_string());
+                },
+                "--oidc-client-id" => config.oidc.client_id = Some(value()?.to_string()),
+                "--oidc-client-secret-file" => {
+                    let path = value()?;
+                    let secret = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                    config.oidc.client_secret = Some(secret.trim_end().to_string());
+                },
+                "--oidc-redirect-uri" => {
+                    let uri = value()?;
+                    config.oidc.redirect_uri = match uri.parse::<Uri>() {
+                        Ok(parsed) if parsed.scheme().is_some() && parsed.host().is_some() => Some(parsed),
+                        _ => return Err(format!("invalid value '{}' for '{}': expected an absolute URL", uri, option)),
+                    };
+                },
+                "--oidc-session-ttl" => config.oidc.session_ttl = parse_seconds(option, value()?)?,
+                "--oidc-cookie-secret-file" => {
+                    let path = value()?;
+                    let key = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                    config.oidc.cookie_key = key.trim_ascii_end().to_vec();
+                },
+                "--proxy" => config.proxy_routes.push(ProxyRoute::parse(option, value()?)?),
+                "--fastcgi" => config.gateways.push(GatewayRoute::parse(option, value()?, Protocol::FastCgi)?),
+                "--scgi" => config.gateways.push(GatewayRoute::parse(option, value()?, Protocol::Scgi)?),
+                "--uwsgi" => config.gateways.push(GatewayRoute::parse(option, value()?, Protocol::Uwsgi)?),
+                "--proxy-cache-size" => config.proxy_cache.memory_size = parse_number(option, value()?)?,
+                "--proxy-cache-dir" => {
+                    let dir = PathBuf::from(value()?);
+                    if !dir.is_dir() {
+                        return Err(format!("invalid value '{}' for '{}': not a directory", dir.display(), option));
+                    }
+                    config.proxy_cache.disk_dir = Some(dir);
+                },
+                "--proxy-cache-disk-size" => config.proxy_cache.disk_size = parse_number(option, value()?)?,
+                "--proxy-cache-max-ttl" => config.proxy_cache.max_ttl = parse_seconds(option, value()?)?,
+                "--proxy-cache-max-entry" => config.proxy_cache.max_entry_size = parse_number(option, value()?)?,
+                "--hsts" => {
+                    let max_age: u64 = parse_number(option, value()?)?;
+                    let value = HeaderValue::from_str(&format!("max-age={}; includeSubDomains", max_age)).unwrap();
+                    config.security_headers.set(HeaderName::from_static("strict-transport-security"), Some(value));
+                },
+                "--cors" => config.cors.push(CorsRule::parse(option, value()?)?),
+                "--csp" => {
+                    let policy = value()?;
+                    let value = HeaderValue::from_str(policy)
+                        .map_err(|_| format!("invalid value '{}' for '{}'", policy, option))?;
+                    config.security_headers.set(HeaderName::from_static("content-security-policy"), Some(value));
+                },
+                "--header" => config.security_headers.parse(option, value()?)?,
+                "--no-security-headers" => {
+                    flag()?;
+                    config.security_headers.clear();
+                },
+                "--brotli-level" => config.compression.brotli_level = parse_level(option, value()?, 0..=11)?,
+                "--zstd-level" => config.compression.zstd_level = parse_level(option, value()?, 1..=19)?,
+                "--gzip-level" => config.compression.gzip_level = parse_level(option, value()?, 0..=9)?,
+                "--deflate-level" => config.compression.deflate_level = parse_level(option, value()?, 0..=9)?,
+                "--compress-min-size" => config.compression.min_size = parse_number(option, value()?)?,
+                "--compress-types" => config.compression.types = parse_list(value()?),
+                "--alias" => config.aliases.push(Alias::parse(option, value()?)?),
+                // A mounted directory is known now; one inside the root once
+                // the root is.
+                "--script-dir" => match value()? {
+                    value if value.contains('=') => {
+                        let alias = Alias::parse(option, value)?;
+                        config.script_dirs.push(alias.dir().to_path_buf());
+                        config.aliases.push(alias);
+                    },
+                    value => script_dirs.push(value),
+                },
+                "--redirect" => config.redirects.push(RedirectRule::parse(option, value()?)?),
+                "--redirects-file" => config.redirects.extend(RedirectRule::load(Path::new(value()?))?),
+                "--rewrite" => config.rewrites.push(RewriteRule::parse(option, value()?)?),
+                "--route" => config.routes.push(Route::parse(option, value()?)?),
+                "--routes-file" => config.routes.extend(Route::load(Path::new(value()?))?),
+                "--trailing-slash" => config.trailing_slash = match value()? {
+                    "redirect" => TrailingSlash::Redirect,
+                    "serve" => TrailingSlash::Serve,
+                    policy => return Err(format!("invalid value '{}' for '{}': expected redirect or serve", policy, option)),
+                },
+                "--index" => config.index_files = parse_list(value()?),
+                "--deny" => config.deny.push(value()?.to_string()),
+                "--hotlink-protect" => config.hotlink.patterns.push(value()?.to_string()),
+                "--hotlink-allow" => config.hotlink.allowed_hosts.push(value()?.to_string()),
+                "--hotlink-block-empty" => {
+                    flag()?;
+                    config.hotlink.block_empty = true;
+                },
+                "--hotlink-placeholder" => {
+                    let path = PathBuf::from(value()?);
+                    if !path.is_file() {
+                        return Err(format!("invalid value '{}' for '{}': not a file", path.display(), option));
+                    }
+                    config.hotlink.placeholder = Some(path);
+                },
+                "--access-files" => {
+                    flag()?;
+                    config.access_files = true;
+                },
+                "--allow-ip" | "--deny-ip" => config.ip_acl.add(option, value()?)?,
+                "--geoip-db" => config.geoip_db = Some(PathBuf::from(value()?)),
+                "--geoip-allow" | "--geoip-deny" => config.geoip_rules.add(option, value()?)?,
+                "--rate-limit" => rate_limit = Some(parse_rate(option, value()?)?),
+                "--rate-limit-burst" => rate_limit_burst = Some(parse_rate(option, value()?)?),
+                "--rate-limit-route" => config.rate_limit_routes.push(RouteLimit::parse(option, value()?)?),
+                "--ban-threshold" => config.bans.threshold = Some(parse_number(option, value()?)?),
+                "--ban-window" => config.bans.window = parse_seconds(option, value()?)?,
+                "--ban-time" => config.bans.duration = parse_seconds(option, value()?)?,
+                "--ban-admin" => {
+                    let path = value()?;
+                    if !path.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the path must start with '/'", path, option));
+                    }
+                    config.bans.admin_path = Some(path.to_string());
+                },
+                "--basic-auth" => config.auth_zones.push(AuthZone::parse(option, value()?)?),
+                "--ldap-auth" => config.auth_zones.push(AuthZone::ldap(option, value()?)?),
+                "--ldap-url" => config.ldap.set_url(option, value()?)?,
+                "--ldap-bind-dn" => {
+                    let template = value()?;
+                    if !template.contains("{user}") {
+                        return Err(format!("invalid value '{}' for '{}': the template must contain {{user}}", template, option));
+                    }
+                    config.ldap.bind_dn = Some(template.to_string());
+                },
+                "--ldap-group" => config.ldap.group = Some(value()?.to_string()),
+                "--auth-realm" => {
+                    let realm = value()?;
+                    if realm.contains('"') || HeaderValue::from_str(realm).is_err() {
+                        return Err(format!("invalid value '{}' for '{}'", realm, option));
+                    }
+                    config.auth_realm = realm.to_string();
+                },
+                "--mime-types" => config.mime_map = MimeMap::load(Path::new(value()?))?,
+                "--charset" => config.charset = match value()? {
+                    "off" => None,
+                    charset => Some(charset.to_string()),
+                },
+                "--charset-types" => config.charset_types = parse_list(value()?),
+                "--limit-rate" => limit_rate = Some(parse_number::<u64>(option, value()?)?).filter(|rate| *rate > 0),
+                "--limit-rate-after" => limit_rate_after = parse_number(option, value()?)?,
+                "--limit-rate-route" => config.limit_rate_routes.push(RouteBandwidth::parse(option, value()?)?),
+                "--keep-alive-timeout" => config.keep_alive_timeout = parse_seconds(option, value()?)?,
+                "--header-timeout" => config.header_timeout = parse_seconds(option, value()?)?,
+                "--header-min-rate" => config.header_min_rate = parse_number(option, value()?)?,
+                "--body-timeout" => config.body_timeout = parse_seconds(option, value()?)?,
+                "--script-form-vars" => {
+                    flag()?;
+                    config.script_form_vars = true;
+                },
+                "--script-hide-errors" => {
+                    flag()?;
+                    config.script_hide_errors = true;
+                },
+                "--script-json-vars" => {
+                    flag()?;
+                    config.script_json_vars = true;
+                },
+                "--script-sandbox" => {
+                    flag()?;
+                    script_sandbox = true;
+                },
+                "--script-log-dir" => config.script_log = Some(ScriptLog { dir: PathBuf::from(value()?), stdout: false }),
+                "--script-log-stdout" => {
+                    flag()?;
+                    script_log_stdout = true;
+                },
+                "--script-cwd" => {
+                    let dir = value()?;
+                    let path = std::fs::canonicalize(dir).ok().filter(|path| path.is_dir())
+                        .ok_or_else(|| format!("invalid value '{}' for '{}': not a directory", dir, option))?;
+                    config.script_cwd = Some(path);
+                },
+                "--script-path" => config.script_env_path = value()?.to_string(),
+                "--script-pass-env" => {
+                    let name = value()?;
+                    if name.is_empty() || name.contains(['=', '\0']) {
+                        return Err(format!("invalid value '{}' for '{}': not a variable name", name, option));
+                    }
+                    config.script_pass_env.push(name.to_string());
+                },
+                "--script-user" => script_user = Some(value()?),
+                "--script-group" => script_group = Some(value()?),
+                "--script-exit-status" => parse_exit_statuses(option, value()?, &mut config.script_exit_status)?,
+                "--prefork" => config.prefork.parse(option, value()?)?,
+                "--script-timeout" => config.script_timeout = Some(parse_seconds(option, value()?)?).filter(|t| !t.is_zero()),
+                "--request-timeout" => config.request_timeout = parse_seconds(option, value()?)?,
+                "--max-header-size" => {
+                    config.max_header_size = parse_number(option, value()?)?;
+                    if config.max_header_size < MIN_HEADER_SIZE {
+                        return Err(format!("invalid value for '{}': must be at least {}", option, MIN_HEADER_SIZE));
+                    }
+                },
+                "--max-uri-length" => config.max_uri_length = parse_number(option, value()?)?,
+                "--max-body-size" => config.max_body_size = parse_number(option, value()?)?,
+                "--max-connections" => config.connection_limits.max_total = Some(parse_number(option, value()?)?),
+                "--max-pending-per-ip" => config.connection_limits.max_pending_per_ip = Some(parse_number(option, value()?)?),
+                "--max-connections-per-ip" => config.connection_limits.max_per_ip = Some(parse_number(option, value()?)?),
+                "--script-cache-size" => config.script_cache.memory_size = parse_number(option, value()?)?,
+                "--script-cache-max-ttl" => config.script_cache.max_ttl = parse_seconds(option, value()?)?,
+                "--cron" => config.cron.push(CronJob::parse(option, value()?)?),
+                "--cron-file" => config.cron.extend(CronJob::load(Path::new(value()?))?),
+                "--jobs" => {
+                    let path = value()?;
+                    if !path.starts_with('/') {
+                        return Err(format!("invalid value '{}' for '{}': the path must start with '/'", path, option));
+                    }
+                    config.jobs.path = Some(path.trim_end_matches('/').to_string());
+                },
+                "--jobs-max" => config.jobs.max_jobs = parse_number(option, value()?)?,
+                "--jobs-ttl" => config.jobs.ttl = parse_seconds(option, value()?)?,
+                "--max-script-output" => config.max_script_output = Some(parse_number(option, value()?)?),
+                "--max-scripts" => config.script_limits.max_total = Some(parse_number(option, value()?)?),
+                "--max-scripts-per-file" => config.script_limits.max_per_script = Some(parse_number(option, value()?)?),
+                "--script-queue-timeout" => config.script_limits.queue_timeout = parse_seconds(option, value()?)?,
+                "--script-max-memory" => config.script_rlimits.memory = Some(parse_number(option, value()?)?),
+                "--script-max-cpu" => config.script_rlimits.cpu = Some(parse_number(option, value()?)?),
+                "--script-max-files" => config.script_rlimits.open_files = Some(parse_number(option, value()?)?),
+                "--script-max-processes" => config.script_rlimits.processes = Some(parse_number(option, value()?)?),
+                "--shutdown-timeout" => config.shutdown_timeout = parse_seconds(option, value()?)?,
+                "--on-start" => config.hooks.start.push(PathBuf::from(value()?)),
+                "--on-stop" => config.hooks.stop.push(PathBuf::from(value()?)),
+                "--hook-failure" => config.hooks.abort_on_failure = match value()? {
+                    "warn" => false,
+                    "abort" => true,
+                    policy => return Err(format!("invalid value '{}' for '{}': expected warn or abort", policy, option)),
+                },
+                "--hook-timeout" => config.hooks.timeout = parse_seconds(option, value()?)?,
+                "--no-precompressed" => {
+                    flag()?;
+                    config.precompressed = false;
+                },
+                _ => return Err(format!("unexpected argument '{}' found", arg)),
+            }
+        }
+
+        // The original `<PORT> <ROOT_FOLDER>` form is still accepted. The port
+        // may be left out when listening on a Unix socket or when systemd
+        // passes the listening sockets in.
+        let port_optional = config.unix_socket.is_some() || env::var_os("LISTEN_FDS").is_some();
+        let mut positional = positional.into_iter();
+        let port = match port {
+            Some(port) => Some(port),
+            None if positional.len() >= 2 || !port_optional => positional.next(),
+            None => None,
+        };
+        let root = root.or_else(|| positional.next());
+        if let Some(extra) = positional.next() {
+            return Err(format!("unexpected argument '{}' found", extra));
+        }
+
+        config.access_log.format = match log_format {
+            Some(log_format) => log_format,
+            None if config.geoip_db.is_some() => LogFormat::parse("--log-format", log_format::DEFAULT_GEOIP).unwrap(),
+            None => LogFormat::parse("--log-format", log_format::DEFAULT).unwrap(),
+        };
+
+        if config.metrics.port.is_some() && config.metrics.path.is_none() {
+            config.metrics.path = Some("/metrics".to_string());
+        }
+
+        if let Some(target) = log_target {
+            if config.access_log.path.is_some() {
+                return Err("'--access-log' cannot be used with '--syslog' or '--journald'".to_string());
+            }
+            config.log_sink = Some(LogSink { target, facility: syslog_facility, tag: syslog_tag });
+        }
+
+        config.limit_rate = limit_rate.map(|rate| BandwidthLimit { rate, burst: limit_rate_after });
+
+        if let Some(rate) = rate_limit {
+            config.rate_limit = Some(RateLimiter::new(rate, rate_limit_burst.unwrap_or(rate)));
+        }
+
+        // Access files and the htpasswd files they point at are never served.
+        if config.access_files {
+            config.deny.push(access_file::FILE_NAME.to_string());
+            config.deny.push(".htpasswd".to_string());
+        }
+
+        if !config.geoip_rules.is_empty() && config.geoip_db.is_none() {
+            return Err("'--geoip-allow' and '--geoip-deny' require '--geoip-db'".to_string());
+        }
+
+        if config.api_keys.enabled() && config.api_keys.keys.is_none() {
+            return Err("'--api-key-auth' requires '--api-keys'".to_string());
+        }
+
+        if config.jwt.enabled() && config.jwt.secret.is_none() && !config.jwt.keys.is_configured() {
+            return Err("'--jwt-auth' requires '--jwt-secret-file' or '--jwt-jwks'".to_string());
+        }
+
+        if config.auth_zones.iter().any(|zone| zone.uses_ldap()) {
+            if config.ldap.server.is_none() {
+                return Err("'--ldap-auth' requires '--ldap-url'".to_string());
+            }
+            if config.ldap.bind_dn.is_none() {
+                return Err("'--ldap-auth' requires '--ldap-bind-dn'".to_string());
+            }
+        }
+
+        if config.oidc.enabled() {
+            if let Some(missing) = config.oidc.missing_option() {
+                return Err(format!("'--oidc-auth' requires '{}'", missing));
+            }
+        }
+
+        if config.bind.is_empty() {
+            config.bind.push(Ipv4Addr::UNSPECIFIED.to_string());
+        }
+
+        config.port = match port {
+            Some(port) => Some(parse_number("--port", port)?),
+            None if port_optional => None,
+            None => return Err("the following required arguments were not provided: --port <PORT>".to_string()),
+        };
+        let root = root.ok_or("the following required arguments were not provided: --root <ROOT_FOLDER>")?;
+        // Absolute, so DOCUMENT_ROOT and SCRIPT_FILENAME still hold from a
+        // script's own working directory.
+        config.root = std::path::absolute(root).unwrap_or_else(|_| PathBuf::from(root));
+        if !config.root.is_dir() {
+            return Err(format!("invalid value '{}' for '--root': not a directory", root));
+        }
+
+        if tls_certs.len() != tls_keys.len() {
+            return Err("each '--tls-cert' requires a '--tls-key'".to_string());
+        }
+        if !tls_certs.is_empty() {
+            let pairs: Vec<_> = tls_certs.into_iter().zip(tls_keys).collect();
+            config.tls = Some(Acceptor::new(&pairs, tls_client_ca.as_deref())?);
+        } else if tls_client_ca.is_some() {
+            return Err("'--tls-client-ca' requires '--tls-cert'".to_string());
+        }
+
+        if script_dirs.is_empty() && config.script_dirs.is_empty() {
+            config.script_dirs.push(config.root.join("scripts"));
+        }
+        for dir in script_dirs {
+            let relative = Path::new(dir.trim_start_matches('/'));
+            if relative.components().any(|component| !matches!(component, Component::Normal(_))) {
+                return Err(format!("invalid value '{}' for '--script-dir': expected a folder inside the root", dir));
+            }
+            let full_path = config.root.join(relative);
+            if !full_path.is_dir() {
+                return Err(format!("invalid value '{}' for '--script-dir': not a directory", dir));
+            }
+            config.script_dirs.push(full_path);
+        }
+        PreforkConfig::resolve(&mut config)?;
+        match (script_user, script_group) {
+            (Some(user), group) => config.script_credentials = Some(credentials::resolve(user, group)?),
+            (None, Some(group)) => return Err(format!("invalid value '{}' for '--script-group': needs --script-user", group)),
+            (None, None) => {},
+        }
+        match &mut config.script_log {
+            Some(log) => log.stdout = script_log_stdout,
+            None if script_log_stdout => return Err("'--script-log-stdout' requires '--script-log-dir'".to_string()),
+            None => {},
+        }
+        if script_sandbox {
+            let credentials = config.script_credentials.unwrap_or_else(credentials::current);
+            config.sandbox = Some(Sandbox::new(&config.root, credentials)?);
+        }
+
+        Ok(Cli::Run(Box::new(config)))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value '{}' for '{}'", value, option))
+}
+
+// Accepts `0.0.0.0`, `::`, `[::1]` or a host name to be resolved at startup.
+fn parse_host(option: &str, value: &str) -> Result<String, String> {
+    let host = value.trim_start_matches('[').trim_end_matches(']');
+    let valid = host.parse::<IpAddr>().is_ok()
+        || (!host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'));
+    if !valid {
+        return Err(format!("invalid value '{}' for '{}'", value, option));
+    }
+    Ok(host.to_string())
+}
+
+// `2=400,3=404`: non-zero exit codes and the 4xx or 5xx status they stand for.
+fn parse_exit_statuses(option: &str, value: &str, statuses: &mut HashMap<i32, StatusCode>) -> Result<(), String> {
+    let invalid = || format!("invalid value '{}' for '{}': expected CODE=STATUS,... with a 4xx or 5xx STATUS", value, option);
+    for item in parse_list(value) {
+        let (code, status) = item.split_once('=').ok_or_else(invalid)?;
+        let code = code.trim().parse::<i32>().ok().filter(|&code| code != 0).ok_or_else(invalid)?;
+        let status = status.trim().parse::<u16>().ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(invalid)?;
+        statuses.insert(code, status);
+    }
+    Ok(())
+}
+
+fn parse_rate(option: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid value '{}' for '{}': expected a positive number", value, option)),
+    }
+}
+
+fn parse_seconds(option: &str, value: &str) -> Result<Duration, String> {
+    parse_number(option, value).map(Duration::from_secs)
+}
+
+fn parse_level(option: &str, value: &str, levels: RangeInclusive<u32>) -> Result<u32, String> {
+    match parse_number(option, value)? {
+        level if levels.contains(&level) => Ok(level),
+        _ => Err(format!("invalid value '{}' for '{}': expected {}-{}", value, option, levels.start(), levels.end())),
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &[&str]) -> Box<Config> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        match Config::from_args(&args) {
+            Ok(Cli::Run(config)) => config,
+            Ok(_) => panic!("expected a configuration"),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn positional_port_and_root() {
+        let root = std::env::temp_dir();
+        let config = run(&["8080", root.to_str().unwrap()]);
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(config.root, std::path::absolute(&root).unwrap());
+    }
+
+    #[test]
+    fn flags_match_positional_form() {
+        let root = std::env::temp_dir();
+        let root = root.to_str().unwrap();
+        let positional = run(&["8080", root]);
+        let flags = run(&["--port", "8080", "--root", root]);
+        assert_eq!(flags.port, positional.port);
+        assert_eq!(flags.root, positional.root);
+    }
+
+    #[test]
+    fn positional_root_is_required() {
+        let err = Config::from_args(&["8080".to_string()]).err().unwrap();
+        assert!(err.contains("--root"), "{}", err);
+    }
+}
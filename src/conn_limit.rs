@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// Written to connections over a cap before they are closed.
+pub const REJECT_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nRetry-After: 1\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 36\r\n\r\n<html>503 Service Unavailable</html>";
+
+/// Caps on open connections, overall and per client IP.
+#[derive(Default)]
+pub struct ConnectionLimits {
+    pub max_total: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    /// Connections per IP that have not sent a complete request yet, the
+    /// ones a slowloris attack keeps open.
+    pub max_pending_per_ip: Option<usize>,
+    open: Mutex<Open>,
+    pending: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Holds a connection's slot until it is dropped.
+pub struct ConnectionGuard<'a> {
+    limits: &'a ConnectionLimits,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    /// Claims a slot for a connection from `ip`, or `None` when a cap is
+    /// reached. Unix socket peers (0.0.0.0) only count towards the total.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionGuard<'_>> {
+        let ip = Some(ip).filter(|ip| !ip.is_unspecified());
+        let mut open = self.open.lock().unwrap();
+        if self.max_total.is_some_and(|max| open.total >= max) {
+            return None;
+        }
+        if let Some(ip) = ip {
+            let count = open.per_ip.get(&ip).copied().unwrap_or(0);
+            if self.max_per_ip.is_some_and(|max| count >= max) {
+                return None;
+            }
+            open.per_ip.insert(ip, count + 1);
+        }
+        open.total += 1;
+        Some(ConnectionGuard { limits: self, ip })
+    }
+
+    /// Whether `--max-connections` is reached, so new connections are turned away.
+    pub fn is_full(&self) -> bool {
+        self.max_total.is_some_and(|max| self.open.lock().unwrap().total >= max)
+    }
+}
+
+/// Counts a connection as pending until it is dropped, which happens once its
+/// first request headers are in.
+pub struct PendingGuard {
+    pending: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimits {
+    /// `Ok(None)` when pending connections are not limited or the peer is a
+    /// Unix socket; `Err` when `ip` already has too many.
+    pub fn acquire_pending(&self, ip: IpAddr) -> Result<Option<PendingGuard>, ()> {
+        let max = match self.max_pending_per_ip {
+            Some(max) if !ip.is_unspecified() => max,
+            _ => return Ok(None),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let count = pending.entry(ip).or_insert(0);
+        if *count >= max {
+            return Err(());
+        }
+        *count += 1;
+        Ok(Some(PendingGuard { pending: self.pending.clone(), ip }))
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(count) = pending.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                pending.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = open.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::auth;
+
+/// A `--cors 'PREFIX origins=... [methods=...] [headers=...] [expose=...]
+/// [max-age=SECS] [credentials]'` rule for the URL paths under PREFIX.
+pub struct CorsRule {
+    prefix: String,
+    // Empty means any origin.
+    origins: Vec<String>,
+    methods: String,
+    // Request headers a preflight may ask for; `None` allows whatever it asks.
+    headers: Option<String>,
+    expose: Option<String>,
+    max_age: u64,
+    credentials: bool,
+}
+
+impl CorsRule {
+    pub fn parse(option: &str, value: &str) -> Result<CorsRule, String> {
+        let invalid = |reason: String| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let mut words = value.split_whitespace();
+        let prefix = words.next().filter(|p| p.starts_with('/'))
+            .ok_or_else(|| invalid("expected a PREFIX starting with '/'".to_string()))?;
+        let mut rule = CorsRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            origins: Vec::new(),
+            methods: "GET, HEAD, POST".to_string(),
+            headers: None,
+            expose: None,
+            max_age: 600,
+            credentials: false,
+        };
+        let list = |value: &str| value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).collect::<Vec<_>>().join(", ");
+        let mut origins = None;
+        for word in words {
+            match word.split_once('=') {
+                Some(("origins", "*")) => origins = Some(Vec::new()),
+                Some(("origins", value)) => origins = Some(value.split(',')
+                    .map(|origin| origin.trim_end_matches('/').to_string())
+                    .collect()),
+                Some(("methods", value)) => rule.methods = list(value).to_ascii_uppercase(),
+                Some(("headers", value)) => rule.headers = Some(list(value)),
+                Some(("expose", value)) => rule.expose = Some(list(value)),
+                Some(("max-age", value)) => rule.max_age = value.parse()
+                    .map_err(|_| invalid(format!("'{}' is not a number of seconds", value)))?,
+                None if word == "credentials" => rule.credentials = true,
+                _ => return Err(invalid(format!("unknown setting '{}'", word))),
+            }
+        }
+        rule.origins = origins.ok_or_else(|| invalid("missing origins=ORIGIN,... or origins=*".to_string()))?;
+        let header_value = |v: &Option<String>| v.as_deref().is_none_or(|v| HeaderValue::from_str(v).is_ok());
+        if HeaderValue::from_str(&rule.methods).is_err() || !header_value(&rule.headers) || !header_value(&rule.expose) {
+            return Err(invalid("invalid characters in a list".to_string()));
+        }
+        Ok(rule)
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.split(", ").any(|allowed| allowed == method)
+    }
+
+    // A wildcard without credentials can be answered with `*`; otherwise the
+    // origin is echoed and caches must keep responses per origin.
+    fn insert_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.origins.is_empty() && !self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+}
+
+/// The rule with the longest prefix covering `path`.
+pub fn find_rule<'a>(rules: &'a [CorsRule], path: &str) -> Option<&'a CorsRule> {
+    let segments = auth::segments(path);
+    rules.iter()
+        .filter(|rule| segments.starts_with(&auth::segments(&rule.prefix)))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ORIGIN) && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers a preflight request: 204 with the allowed methods and headers, or
+/// 403 when the origin or method is not allowed.
+pub fn preflight(rule: &CorsRule, req: &Request<Body>) -> Response<Body> {
+    let origin = req.headers().get(ORIGIN).unwrap();
+    let method = req.headers().get(ACCESS_CONTROL_REQUEST_METHOD).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let allowed = origin.to_str().is_ok_and(|origin| rule.allows_origin(origin)) && rule.allows_method(method);
+    if !allowed {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>403 Forbidden</html>"))
+            .unwrap();
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    let headers = response.headers_mut();
+    rule.insert_origin(headers, origin);
+    headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&rule.methods).unwrap());
+    let requested = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS);
+    match (&rule.headers, requested) {
+        (Some(allowed), _) => {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_str(allowed).unwrap());
+        },
+        (None, Some(requested)) => {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            headers.append(VARY, HeaderValue::from_static("Access-Control-Request-Headers"));
+        },
+        (None, None) => {},
+    }
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(rule.max_age));
+    response
+}
+
+/// Adds the CORS headers to the response of an allowed cross-origin request,
+/// unless the handler (e.g. a script) already answered with its own.
+pub fn apply(rule: &CorsRule, origin: &HeaderValue, headers: &mut HeaderMap) {
+    if headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) || !origin.to_str().is_ok_and(|o| rule.allows_origin(o)) {
+        return;
+    }
+    rule.insert_origin(headers, origin);
+    if let Some(expose) = &rule.expose {
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_str(expose).unwrap());
+    }
+}
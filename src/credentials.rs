@@ -0,0 +1,63 @@
+use std::ffi::CString;
+use std::ptr;
+
+/// The user and group scripts run as, from `--script-user` and `--script-group`.
+#[derive(Clone, Copy)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// The server's own effective IDs.
+pub fn current() -> Credentials {
+    unsafe { Credentials { uid: libc::geteuid(), gid: libc::getegid() } }
+}
+
+/// A user name or UID, with the group a name or GID; without a group the
+/// user's primary group is used. Only root can switch, so anyone else gets
+/// an error rather than scripts that fail to start.
+pub fn resolve(user: &str, group: Option<&str>) -> Result<Credentials, String> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(format!("invalid value '{}' for '--script-user': the server is not running as root", user));
+    }
+    let invalid = |value: &str, option: &str, reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+    let (uid, primary_gid) = match user.parse::<libc::uid_t>() {
+        Ok(uid) => (uid, lookup_user_by_id(uid)),
+        Err(_) => lookup_user(user).map(|(uid, gid)| (uid, Some(gid)))
+            .ok_or_else(|| invalid(user, "--script-user", "no such user"))?,
+    };
+    let gid = match group {
+        Some(group) => match group.parse::<libc::gid_t>() {
+            Ok(gid) => gid,
+            Err(_) => lookup_group(group).ok_or_else(|| invalid(group, "--script-group", "no such group"))?,
+        },
+        None => primary_gid.ok_or_else(|| invalid(user, "--script-user", "unknown UID, give --script-group too"))?,
+    };
+    Ok(Credentials { uid, gid })
+}
+
+fn lookup_user(name: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = ptr::null_mut();
+    unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    (!found.is_null()).then_some((entry.pw_uid, entry.pw_gid))
+}
+
+fn lookup_user_by_id(uid: libc::uid_t) -> Option<libc::gid_t> {
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = ptr::null_mut();
+    unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    (!found.is_null()).then_some(entry.pw_gid)
+}
+
+fn lookup_group(name: &str) -> Option<libc::gid_t> {
+    let name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found = ptr::null_mut();
+    unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    (!found.is_null()).then_some(entry.gr_gid)
+}
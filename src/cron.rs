@@ -0,0 +1,218 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use crate::config::Config;
+use crate::log_format::{self, RequestId};
+use crate::{event, script_meta};
+
+/// A `--cron '*/5 * * * * scripts/warm.sh'` entry: the script, relative to
+/// the root folder, runs whenever the five fields (minute, hour, day of
+/// month, month, day of week) match the time in UTC.
+pub struct CronJob {
+    expression: String,
+    schedule: Schedule,
+    script: PathBuf,
+    // A run still going when the next is due makes that one skipped.
+    running: Arc<AtomicBool>,
+}
+
+// One bit per allowed value of each field.
+struct Schedule {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    // With both restricted, cron runs on either day, not only on both.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Marks a scheduled run; scripts see its schedule as CRON_SCHEDULE.
+#[derive(Clone)]
+pub struct Scheduled(pub String);
+
+impl CronJob {
+    pub fn parse(option: &str, value: &str) -> Result<CronJob, String> {
+        let invalid = |reason: String| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let (fields, script) = match words.as_slice() {
+            [name, script] if name.starts_with('@') => {
+                let fields = match *name {
+                    "@hourly" => "0 * * * *",
+                    "@daily" | "@midnight" => "0 0 * * *",
+                    "@weekly" => "0 0 * * 0",
+                    "@monthly" => "0 0 1 * *",
+                    "@yearly" | "@annually" => "0 0 1 1 *",
+                    name => return Err(invalid(format!("unknown schedule '{}'", name))),
+                };
+                (fields.split(' ').collect::<Vec<_>>(), script)
+            },
+            [minute, hour, day, month, weekday, script] => (vec![*minute, *hour, *day, *month, *weekday], script),
+            _ => return Err(invalid("expected '<MIN> <HOUR> <DAY> <MONTH> <WEEKDAY> <SCRIPT>' or '@daily <SCRIPT>'".to_string())),
+        };
+        let field = |index: usize, name: &str, min: u32, max: u32| {
+            parse_field(fields[index], min, max).ok_or_else(|| invalid(format!("the {} must be '*' or values, ranges and steps within {}-{}", name, min, max)))
+        };
+        let minutes = field(0, "minute", 0, 59)?;
+        let hours = field(1, "hour", 0, 23)?;
+        let days = field(2, "day of month", 1, 31)?;
+        let months = field(3, "month", 1, 12)?;
+        // Sunday is both 0 and 7.
+        let weekdays = field(4, "day of week", 0, 7)?;
+        let weekdays = (weekdays | weekdays >> 7) & 0x7f;
+        let schedule = Schedule {
+            minutes,
+            hours: hours as u32,
+            days: days as u32,
+            months: months as u16,
+            weekdays: weekdays as u8,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        };
+        let script = PathBuf::from(script.trim_start_matches('/'));
+        if script.components().any(|c| c == std::path::Component::ParentDir) {
+            return Err(invalid("the script must be a path inside the root folder".to_string()));
+        }
+        let expression = fields.join(" ");
+        Ok(CronJob { expression, schedule, script, running: Arc::default() })
+    }
+
+    /// Reads one entry per line; blank lines and `#` comments are skipped.
+    pub fn load(path: &Path) -> Result<Vec<CronJob>, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        contents.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| CronJob::parse(&path.display().to_string(), line))
+            .collect()
+    }
+}
+
+impl Schedule {
+    fn matches(&self, time: SystemTime) -> bool {
+        let (_, month, day, hour, minute, _) = log_format::civil(time);
+        // 1970-01-01 was a Thursday.
+        let days = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86400);
+        let weekday = (days + 4) % 7;
+        let day_matches = self.days & 1 << day != 0;
+        let weekday_matches = self.weekdays & 1 << weekday != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        };
+        self.minutes & 1 << minute != 0 && self.hours & 1 << hour != 0 && self.months & 1 << month != 0 && day_matches
+    }
+}
+
+// `*`, `N`, `N-M`, each optionally `/STEP`, separated by commas.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                // `N/STEP` runs from N to the end of the range.
+                None if part.contains('/') => (range.parse().ok()?, max),
+                None => (range.parse().ok()?, range.parse().ok()?),
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+/// Checks the schedules at the start of every minute and runs the scripts
+/// due, each as a GET to its URL would, under the same limits and timeouts.
+pub fn start(config: Arc<Config>) {
+    if config.cron.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut last_minute = None;
+        loop {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            tokio::time::sleep(Duration::from_secs(60) - Duration::from_nanos((now.as_nanos() % 60_000_000_000) as u64)).await;
+            // Sleeping can end a little early; a minute is checked only once.
+            let now = SystemTime::now() + Duration::from_millis(500);
+            let minute = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 60);
+            if last_minute == Some(minute) {
+                continue;
+            }
+            last_minute = Some(minute);
+            for (index, job) in config.cron.iter().enumerate() {
+                if !job.schedule.matches(now) {
+                    continue;
+                }
+                if job.running.swap(true, Ordering::SeqCst) {
+                    event::warning(&format!("Skipping the scheduled run of {}: the last one is still going", job.script.display()));
+                    continue;
+                }
+                tokio::spawn(run(config.clone(), index));
+            }
+        }
+    });
+}
+
+async fn run(config: Arc<Config>, index: usize) {
+    let job = &config.cron[index];
+    let script_path = config.root.join(&job.script);
+    let url = format!("/{}", job.script.to_string_lossy());
+    let mut req = match Request::get(url.as_str()).body(Body::empty()) {
+        Ok(req) => req,
+        Err(_) => {
+            event::error(&format!("Cannot schedule {}: not a valid URL path", job.script.display()));
+            job.running.store(false, Ordering::SeqCst);
+            return;
+        },
+    };
+    RequestId::assign(&mut req);
+    req.extensions_mut().insert(Scheduled(job.expression.clone()));
+    match script_meta::load(&script_path) {
+        Ok(Some(meta)) => {
+            req.extensions_mut().insert(meta);
+        },
+        Ok(None) => {},
+        Err(e) => event::error(&e),
+    }
+
+    let started = Instant::now();
+    let client_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let status = if !script_path.is_file() {
+        event::error(&format!("The scheduled script {} does not exist", script_path.display()));
+        None
+    } else {
+        match crate::handle_script(req, script_path.clone(), client_addr, &config).await {
+            Ok(response) => {
+                let status = response.status();
+                // Nobody reads the output, but the script gets to finish writing it.
+                let mut body = response.into_body();
+                while let Some(Ok(_)) = body.data().await {}
+                Some(status)
+            },
+            Err(_) => None,
+        }
+    };
+    let elapsed = started.elapsed().as_secs_f64();
+    match status {
+        Some(status) if status.is_success() => event::info(&format!("Scheduled run of {} -> {} in {:.3}s", url, status, elapsed)),
+        Some(status) => event::warning(&format!("Scheduled run of {} -> {} in {:.3}s", url, status, elapsed)),
+        None => event::warning(&format!("Scheduled run of {} failed", url)),
+    }
+    job.running.store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,633 @@
+// A small streaming DEFLATE (RFC 1951) encoder with gzip (RFC 1952) and
+// zlib (RFC 1950) framing.
+// Every chunk passed to `compress` becomes one block coded with the fixed
+// Huffman tables; matches may reach back into the previous 32 KiB of input.
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const STORED_MAX: usize = 65535;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC_TABLE[((c ^ b as u32) & 0xFF) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+pub struct Deflater {
+    max_chain: usize,
+    history: Vec<u8>,
+    base: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    bits: u64,
+    nbits: u32,
+    out: Vec<u8>,
+}
+
+impl Deflater {
+    /// `level` follows the zlib convention: 0 stores, 1 is fastest, 9 searches hardest.
+    pub fn new(level: u32) -> Self {
+        let max_chain = match level {
+            0 => 0,
+            1 => 4,
+            2 => 8,
+            3 => 16,
+            4 => 32,
+            5 => 64,
+            6 => 128,
+            7 => 256,
+            8 => 1024,
+            _ => 4096,
+        };
+        Deflater {
+            max_chain,
+            history: Vec::new(),
+            base: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; WINDOW_SIZE],
+            bits: 0,
+            nbits: 0,
+            out: Vec::new(),
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return std::mem::take(&mut self.out);
+        }
+        if self.max_chain == 0 {
+            self.store(data);
+            return std::mem::take(&mut self.out);
+        }
+
+        let start = self.history.len();
+        self.history.extend_from_slice(data);
+        let end = self.history.len();
+
+        // BFINAL = 0, BTYPE = 01 (fixed Huffman codes)
+        self.put_bits(0, 1);
+        self.put_bits(1, 2);
+
+        let mut i = start;
+        while i < end {
+            let (len, dist) = self.find_match(i, end);
+            if len >= MIN_MATCH {
+                self.put_match(len, dist);
+                for p in i..i + len {
+                    self.insert(p, end);
+                }
+                i += len;
+            } else {
+                self.put_symbol(self.history[i] as u16);
+                self.insert(i, end);
+                i += 1;
+            }
+        }
+        self.put_symbol(256);
+
+        if self.history.len() > WINDOW_SIZE {
+            let drop = self.history.len() - WINDOW_SIZE;
+            self.history.drain(..drop);
+            self.base += drop;
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        // An empty final fixed block terminates the stream.
+        self.put_bits(1, 1);
+        self.put_bits(1, 2);
+        self.put_symbol(256);
+        if self.nbits > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.nbits = 0;
+        }
+        std::mem::take(&mut self.out)
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        for block in data.chunks(STORED_MAX) {
+            self.put_bits(0, 1);
+            self.put_bits(0, 2);
+            self.align();
+            let len = block.len() as u16;
+            self.out.extend_from_slice(&len.to_le_bytes());
+            self.out.extend_from_slice(&(!len).to_le_bytes());
+            self.out.extend_from_slice(block);
+        }
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let h = ((self.history[i] as usize) << 10)
+            ^ ((self.history[i + 1] as usize) << 5)
+            ^ (self.history[i + 2] as usize);
+        h & (HASH_SIZE - 1)
+    }
+
+    fn insert(&mut self, i: usize, end: usize) {
+        if i + MIN_MATCH > end {
+            return;
+        }
+        let h = self.hash(i);
+        let abs = self.base + i;
+        self.prev[abs % WINDOW_SIZE] = self.head[h];
+        self.head[h] = abs + 1;
+    }
+
+    fn find_match(&self, i: usize, end: usize) -> (usize, usize) {
+        if i + MIN_MATCH > end {
+            return (0, 0);
+        }
+        let abs = self.base + i;
+        let max_len = MAX_MATCH.min(end - i);
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        let mut candidate = self.head[self.hash(i)];
+        let mut chain = self.max_chain;
+
+        while candidate != 0 && chain > 0 {
+            let c = candidate - 1;
+            if c >= abs || c < self.base || abs - c > WINDOW_SIZE {
+                break;
+            }
+            let ci = c - self.base;
+            let mut len = 0;
+            while len < max_len && self.history[ci + len] == self.history[i + len] {
+                len += 1;
+            }
+            if len > best_len {
+                best_len = len;
+                best_dist = abs - c;
+                if len == max_len {
+                    break;
+                }
+            }
+            let next = self.prev[c % WINDOW_SIZE];
+            // Slots are reused every WINDOW_SIZE bytes; a link that does not
+            // point backwards belongs to a newer position and ends the chain.
+            if next == 0 || next > c {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        (best_len, best_dist)
+    }
+
+    fn put_match(&mut self, len: usize, dist: usize) {
+        let li = LENGTH_BASE.iter().rposition(|&b| b as usize <= len).unwrap();
+        self.put_symbol(257 + li as u16);
+        self.put_bits((len - LENGTH_BASE[li] as usize) as u32, LENGTH_EXTRA[li]);
+
+        let di = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+        self.put_code(di as u32, 5);
+        self.put_bits((dist - DIST_BASE[di] as usize) as u32, DIST_EXTRA[di]);
+    }
+
+    fn put_symbol(&mut self, symbol: u16) {
+        let (code, len) = match symbol {
+            0..=143 => (0x30 + symbol as u32, 8),
+            144..=255 => (0x190 + (symbol - 144) as u32, 9),
+            256..=279 => ((symbol - 256) as u32, 7),
+            _ => (0xC0 + (symbol - 280) as u32, 8),
+        };
+        self.put_code(code, len);
+    }
+
+    // Huffman codes are packed starting from their most significant bit.
+    fn put_code(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.put_bits(reversed, len);
+    }
+
+    fn put_bits(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += len;
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.put_bits(0, 8 - self.nbits);
+        }
+    }
+}
+
+pub trait StreamEncoder {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8>;
+    fn finish(&mut self) -> Vec<u8>;
+}
+
+pub struct GzipEncoder {
+    deflater: Deflater,
+    crc: u32,
+    size: u32,
+    header_written: bool,
+}
+
+impl GzipEncoder {
+    pub fn new(level: u32) -> Self {
+        GzipEncoder {
+            deflater: Deflater::new(level),
+            crc: 0,
+            size: 0,
+            header_written: false,
+        }
+    }
+
+    fn header(&mut self) -> Vec<u8> {
+        if self.header_written {
+            return Vec::new();
+        }
+        self.header_written = true;
+        vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]
+    }
+}
+
+impl StreamEncoder for GzipEncoder {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = self.header();
+        self.crc = crc32_update(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        out.extend(self.deflater.compress(data));
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let mut out = self.header();
+        out.extend(self.deflater.finish());
+        out.extend_from_slice(&self.crc.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out
+    }
+}
+
+pub fn adler32_update(adler: u32, data: &[u8]) -> u32 {
+    let mut a = adler & 0xFFFF;
+    let mut b = adler >> 16;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+pub struct ZlibEncoder {
+    deflater: Deflater,
+    level: u32,
+    adler: u32,
+    header_written: bool,
+}
+
+impl ZlibEncoder {
+    pub fn new(level: u32) -> Self {
+        ZlibEncoder {
+            deflater: Deflater::new(level),
+            level,
+            adler: 1,
+            header_written: false,
+        }
+    }
+
+    fn header(&mut self) -> Vec<u8> {
+        if self.header_written {
+            return Vec::new();
+        }
+        self.header_written = true;
+        // 32 KiB window, FLEVEL hint chosen so that the header checksum holds.
+        let flags = match self.level {
+            0 => 0x01,
+            1..=5 => 0x5e,
+            6 => 0x9c,
+            _ => 0xda,
+        };
+        vec![0x78, flags]
+    }
+}
+
+impl StreamEncoder for ZlibEncoder {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = self.header();
+        self.adler = adler32_update(self.adler, data);
+        out.extend(self.deflater.compress(data));
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let mut out = self.header();
+        out.extend(self.deflater.finish());
+        out.extend_from_slice(&self.adler.to_be_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An RFC 1951 decoder of its own, with its own tables, to read back
+    // stored, fixed and dynamic Huffman blocks.
+    struct Bits<'a> {
+        data: &'a [u8],
+        pos: usize,
+        bit: u32,
+    }
+
+    impl Bits<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.pos] >> self.bit) & 1;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+            bit as u32
+        }
+
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        fn align(&mut self) {
+            if self.bit != 0 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+    }
+
+    // A canonical Huffman code as the number of codes of each length and
+    // the symbols in code order.
+    struct Huffman {
+        counts: [u16; 16],
+        symbols: Vec<u16>,
+    }
+
+    impl Huffman {
+        fn new(lengths: &[u8]) -> Huffman {
+            let mut counts = [0u16; 16];
+            for &length in lengths {
+                counts[length as usize] += 1;
+            }
+            counts[0] = 0;
+            let mut offsets = [0u16; 16];
+            for length in 1..16 {
+                offsets[length] = offsets[length - 1] + counts[length - 1];
+            }
+            let mut symbols = vec![0; lengths.len()];
+            for (symbol, &length) in lengths.iter().enumerate() {
+                if length != 0 {
+                    symbols[offsets[length as usize] as usize] = symbol as u16;
+                    offsets[length as usize] += 1;
+                }
+            }
+            Huffman { counts, symbols }
+        }
+
+        fn decode(&self, bits: &mut Bits) -> u16 {
+            let (mut code, mut first, mut index) = (0, 0, 0);
+            for length in 1..16 {
+                code |= bits.bit() as usize;
+                let count = self.counts[length] as usize;
+                if code < first + count {
+                    return self.symbols[index + code - first];
+                }
+                index += count;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+            panic!("invalid Huffman code");
+        }
+    }
+
+    const LENGTHS: [(usize, u32); 29] = [
+        (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0), (11, 1), (13, 1), (15, 1), (17, 1),
+        (19, 2), (23, 2), (27, 2), (31, 2), (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4),
+        (115, 4), (131, 5), (163, 5), (195, 5), (227, 5), (258, 0),
+    ];
+    const DISTANCES: [(usize, u32); 30] = [
+        (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2), (17, 3), (25, 3), (33, 4), (49, 4),
+        (65, 5), (97, 5), (129, 6), (193, 6), (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9),
+        (2049, 10), (3073, 10), (4097, 11), (6145, 11), (8193, 12), (12289, 12), (16385, 13), (24577, 13),
+    ];
+
+    // The inflated data and the number of bytes the stream took up.
+    fn inflate(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut bits = Bits { data, pos: 0, bit: 0 };
+        let mut out = Vec::new();
+        loop {
+            let last = bits.bit() == 1;
+            match bits.bits(2) {
+                0 => {
+                    bits.align();
+                    let len = u16::from_le_bytes([data[bits.pos], data[bits.pos + 1]]);
+                    let nlen = u16::from_le_bytes([data[bits.pos + 2], data[bits.pos + 3]]);
+                    assert_eq!(len, !nlen, "stored block length check");
+                    bits.pos += 4;
+                    out.extend_from_slice(&data[bits.pos..bits.pos + len as usize]);
+                    bits.pos += len as usize;
+                },
+                1 => {
+                    let mut lengths = [8u8; 288];
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    inflate_block(&mut bits, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]));
+                },
+                2 => {
+                    let literals = bits.bits(5) as usize + 257;
+                    let distances = bits.bits(5) as usize + 1;
+                    let code_lengths = bits.bits(4) as usize + 4;
+                    const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+                    let mut lengths = [0u8; 19];
+                    for &i in &ORDER[..code_lengths] {
+                        lengths[i] = bits.bits(3) as u8;
+                    }
+                    let code = Huffman::new(&lengths);
+                    let mut lengths = Vec::new();
+                    while lengths.len() < literals + distances {
+                        match code.decode(&mut bits) {
+                            length @ 0..=15 => lengths.push(length as u8),
+                            16 => {
+                                let previous = *lengths.last().unwrap();
+                                let repeat = 3 + bits.bits(2) as usize;
+                                lengths.extend(std::iter::repeat_n(previous, repeat));
+                            },
+                            17 => {
+                                let repeat = 3 + bits.bits(3) as usize;
+                                lengths.extend(std::iter::repeat_n(0, repeat));
+                            },
+                            _ => {
+                                let repeat = 11 + bits.bits(7) as usize;
+                                lengths.extend(std::iter::repeat_n(0, repeat));
+                            },
+                        }
+                    }
+                    let (literal_lengths, distance_lengths) = lengths.split_at(literals);
+                    inflate_block(&mut bits, &mut out, &Huffman::new(literal_lengths), &Huffman::new(distance_lengths));
+                },
+                _ => panic!("reserved block type"),
+            }
+            if last {
+                bits.align();
+                return (out, bits.pos);
+            }
+        }
+    }
+
+    fn inflate_block(bits: &mut Bits, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) {
+        loop {
+            match literals.decode(bits) as usize {
+                literal @ 0..=255 => out.push(literal as u8),
+                256 => return,
+                symbol => {
+                    let (base, extra) = LENGTHS[symbol - 257];
+                    let length = base + bits.bits(extra) as usize;
+                    let (base, extra) = DISTANCES[distances.decode(bits) as usize];
+                    let distance = base + bits.bits(extra) as usize;
+                    assert!(distance <= out.len(), "distance past the start of the output");
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+            }
+        }
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        // ID1 ID2, CM = deflate, no flags; MTIME, XFL and OS are not checked.
+        assert_eq!(&data[..4], [0x1f, 0x8b, 8, 0]);
+        let (out, used) = inflate(&data[10..]);
+        let trailer = &data[10 + used..];
+        assert_eq!(trailer.len(), 8, "trailing data after the gzip member");
+        assert_eq!(u32::from_le_bytes(trailer[..4].try_into().unwrap()), crc32_update(0, &out));
+        assert_eq!(u32::from_le_bytes(trailer[4..].try_into().unwrap()), out.len() as u32);
+        out
+    }
+
+    fn encode(mut encoder: impl StreamEncoder, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(encoder.compress(chunk));
+        }
+        out.extend(encoder.finish());
+        out
+    }
+
+    // Text that compresses well, and noise from a xorshift generator that
+    // does not.
+    fn samples() -> Vec<Vec<u8>> {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(2000);
+        let mut state = 0x2545_f491_u32;
+        let noise = (0..70_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        vec![Vec::new(), b"a".to_vec(), b"abcabcabcabcabcabcabcabc".to_vec(), text, noise, vec![0; 100_000]]
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32_update(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32_update(crc32_update(0, b"1234"), b"56789"), 0xcbf4_3926);
+        assert_eq!(adler32_update(1, b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        for level in [0, 1, 6, 9] {
+            for sample in samples() {
+                let whole = encode(GzipEncoder::new(level), &[&sample]);
+                assert_eq!(gunzip(&whole), sample, "level {}", level);
+                // Chunks of the size a body arrives in, so matches reach
+                // back into earlier ones.
+                let chunks: Vec<&[u8]> = sample.chunks(8192).collect();
+                assert_eq!(gunzip(&encode(GzipEncoder::new(level), &chunks)), sample, "level {} in chunks", level);
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_repetitive_input() {
+        let text = &samples()[3];
+        let compressed = encode(GzipEncoder::new(6), &[text]);
+        assert!(compressed.len() < text.len() / 20, "{} bytes from {}", compressed.len(), text.len());
+    }
+
+    #[test]
+    fn zlib_round_trip() {
+        for level in [0, 1, 6, 9] {
+            for sample in samples() {
+                let data = encode(ZlibEncoder::new(level), &[&sample]);
+                assert_eq!((data[0] as u16 * 256 + data[1] as u16) % 31, 0, "header check bits");
+                assert_eq!(data[0] & 0x0f, 8, "CM = deflate");
+                let (out, used) = inflate(&data[2..]);
+                assert_eq!(out, sample);
+                assert_eq!(&data[2 + used..], adler32_update(1, &sample).to_be_bytes());
+            }
+        }
+    }
+
+    // The decoder itself, on streams made by zlib: fixed Huffman codes with
+    // matches, and dynamic ones (`Z_HUFFMAN_ONLY`).
+    #[test]
+    fn decoder_reads_zlib_output() {
+        let fixed = [
+            0x78, 0xda, 0x4b, 0x4c, 0x2a, 0x4a, 0x4c, 0x4e, 0x4c, 0x49, 0x04, 0x52, 0x0a, 0x89, 0x34, 0x60,
+            0x03, 0x00, 0xcb, 0xd2, 0x23, 0xa1,
+        ];
+        assert_eq!(inflate(&fixed[2..]).0, b"abracadabra ".repeat(8));
+        let dynamic = [
+            0x78, 0x01, 0x05, 0xc1, 0xb1, 0x09, 0x00, 0x00, 0x08, 0x04, 0xb1, 0x55, 0x5c, 0xce, 0xe2, 0x0a,
+            0xe1, 0xf1, 0xc0, 0xf9, 0x4d, 0x06, 0x45, 0x49, 0xa8, 0xe5, 0x7a, 0x6b, 0x50, 0x94, 0x84, 0x07,
+            0xb2, 0x75, 0x0b, 0xe3,
+        ];
+        assert_eq!(inflate(&dynamic[2..]).0, b"mississippi river mississippi");
+    }
+}
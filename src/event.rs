@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use crate::json::Json;
+use crate::{log_format, metrics, syslog};
+
+// Server events such as startup, shutdown and runtime errors. Info goes to
+// stdout, warnings and errors to stderr, as text or, with `--log-format
+// json`, as JSON objects a log collector can parse like the access lines.
+// With `--syslog` or `--journald` they go there with the matching priority.
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn use_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn info(message: &str) {
+    log(Level::Info, message);
+}
+
+pub fn warning(message: &str) {
+    log(Level::Warning, message);
+}
+
+pub fn error(message: &str) {
+    log(Level::Error, message);
+}
+
+fn log(level: Level, message: &str) {
+    let line = if JSON.load(Ordering::Relaxed) {
+        Json::Object(vec![
+            ("time".to_string(), Json::String(log_format::time_iso8601(SystemTime::now()))),
+            ("type".to_string(), Json::String("event".to_string())),
+            ("level".to_string(), Json::String(level.name().to_string())),
+            ("message".to_string(), Json::String(message.to_string())),
+        ]).to_string()
+    } else {
+        message.to_string()
+    };
+    if let Level::Error = level {
+        metrics::record_error(message);
+    }
+    if syslog::send(level, &line) {
+        return;
+    }
+    match level {
+        Level::Info => println!("{}", line),
+        Level::Warning | Level::Error => eprintln!("{}", line),
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io;
+use futures_util::stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::event;
+use crate::gateway::{self, Connection, Output};
+
+// Record types and the responder role from the FastCGI 1.0 specification.
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+const REQUEST_COMPLETE: u8 = 0;
+
+// Each request gets a connection of its own, which the application closes
+// once it is done, so it is always request 1.
+const REQUEST_ID: u16 = 1;
+const MAX_CONTENT: usize = 65535;
+
+/// Runs one request over a fresh connection: the parameters, then the body
+/// as it arrives on stdin, while the application's stdout is streamed back.
+/// What it writes to stderr is logged.
+pub fn exchange(connection: Box<dyn Connection>, params: HashMap<String, String>, body: Body, limit: u64, upstream: String) -> Output {
+    let (reader, mut writer) = tokio::io::split(connection);
+    let sender = upstream.clone();
+    tokio::spawn(async move {
+        // The application may answer without reading all of its input, so
+        // a failed write only matters when the body was too large.
+        if let Err(e) = send_request(&mut writer, &params, body, limit).await {
+            if e.kind() == io::ErrorKind::InvalidData {
+                event::warning(&format!("FastCGI request to {} cut off: {}", sender, e));
+                let _ = writer.shutdown().await;
+            }
+        }
+    });
+
+    Box::pin(stream::unfold(Some(reader), move |reader| {
+        let upstream = upstream.clone();
+        async move {
+            let mut reader = reader?;
+            loop {
+                let mut header = [0u8; 8];
+                if let Err(e) = reader.read_exact(&mut header).await {
+                    return Some((Err(ended_early(e)), None));
+                }
+                let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+                let mut content = vec![0; length + header[6] as usize];
+                if let Err(e) = reader.read_exact(&mut content).await {
+                    return Some((Err(ended_early(e)), None));
+                }
+                content.truncate(length);
+                match header[1] {
+                    STDOUT if !content.is_empty() => return Some((Ok(Bytes::from(content)), Some(reader))),
+                    STDERR => {
+                        for line in String::from_utf8_lossy(&content).lines().filter(|line| !line.trim().is_empty()) {
+                            event::error(&format!("FastCGI {} stderr: {}", upstream, line));
+                        }
+                    },
+                    END_REQUEST => {
+                        return match content.get(4) {
+                            Some(&REQUEST_COMPLETE) => None,
+                            status => {
+                                let e = io::Error::other(format!("request refused with protocol status {:?}", status));
+                                Some((Err(e), None))
+                            },
+                        };
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }))
+}
+
+async fn send_request(writer: &mut (impl AsyncWrite + Unpin), params: &HashMap<String, String>, mut body: Body, limit: u64) -> io::Result<()> {
+    let mut begin = Vec::with_capacity(8);
+    begin.extend_from_slice(&RESPONDER.to_be_bytes());
+    begin.extend_from_slice(&[0; 6]);
+    let mut request = record(BEGIN_REQUEST, &begin);
+
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        push_length(&mut encoded, name.len());
+        push_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    for chunk in encoded.chunks(MAX_CONTENT) {
+        request.extend(record(PARAMS, chunk));
+    }
+    request.extend(record(PARAMS, &[]));
+    writer.write_all(&request).await?;
+
+    let mut received = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(gateway::too_large());
+        }
+        for piece in chunk.chunks(MAX_CONTENT) {
+            writer.write_all(&record(STDIN, piece)).await?;
+        }
+    }
+    writer.write_all(&record(STDIN, &[])).await?;
+    writer.flush().await
+}
+
+// Content is padded to a multiple of 8 bytes, as the specification recommends.
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(8 + content.len() + padding);
+    record.extend_from_slice(&[VERSION, kind]);
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[padding as u8, 0]);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding, 0);
+    record
+}
+
+// Lengths under 128 take one byte, longer ones four with the top bit set.
+fn push_length(out: &mut Vec<u8>, length: usize) {
+    if length < 128 {
+        out.push(length as u8);
+    } else {
+        out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+fn ended_early(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::other("connection closed before the request ended"),
+        _ => e,
+    }
+}
@@ -0,0 +1,378 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use hyper::{Body, Request, Response, StatusCode};
+use httpdate::{fmt_http_date, parse_http_date};
+use crate::compress::quality;
+use crate::config::Config;
+use crate::glob::glob_match;
+use crate::mime_map::with_charset;
+use crate::range::{parse_range, ByteRange};
+use crate::timing::{self, Phase};
+
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+pub async fn serve_file(config: &Config, req: &Request<Body>, full_path: &Path) -> Response<Body> {
+    let (variant, encoding, has_variants) = if config.precompressed {
+        find_precompressed(req, full_path).await
+    } else {
+        (None, None, false)
+    };
+    let file_path = variant.as_deref().unwrap_or(full_path);
+
+    let opening = Instant::now();
+    let file = match File::open(file_path).await {
+        Ok(file) => file,
+        Err(_) => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>404 Not Found</html>"))
+                .unwrap();
+        }
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(_) => return internal_error(),
+    };
+    timing::record(req.extensions(), Phase::File, opening.elapsed());
+
+    let content_type = with_charset(
+        config.mime_map.lookup(full_path),
+        config.charset.as_deref(),
+        &config.charset_types,
+    );
+
+    let len = metadata.len();
+    let etag = make_etag(&metadata);
+
+    let modified = metadata.modified().ok();
+    let last_modified = modified.map(fmt_http_date);
+
+    if is_not_modified(req, &etag, modified) {
+        let mut builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("ETag", etag);
+        if let Some(last_modified) = last_modified {
+            builder = builder.header("Last-Modified", last_modified);
+        }
+        return builder
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // A range for another version of the file than the one If-Range names
+    // is answered with the whole new one.
+    let range_header = req.headers().get("Range")
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_matches(req, &etag, modified));
+    let mut builder = Response::builder();
+    if let Some(last_modified) = last_modified {
+        builder = builder.header("Last-Modified", last_modified);
+    }
+    if let Some(encoding) = encoding {
+        builder = builder.header("Content-Encoding", encoding);
+    }
+    if has_variants {
+        builder = builder.header("Vary", "Accept-Encoding");
+    }
+    match parse_range(range_header, len) {
+        ByteRange::Full => builder
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Content-Length", len.to_string())
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", etag)
+            .body(Body::wrap_stream(timing::timed(ReaderStream::new(file), req.extensions(), Phase::File)))
+            .unwrap(),
+        ByteRange::Partial(start, end) => {
+            let mut file = file;
+            if file.seek(SeekFrom::Start(start)).await.is_err() {
+                return internal_error();
+            }
+            let part_len = end - start + 1;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Type", content_type)
+                .header("Content-Length", part_len.to_string())
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                .header("Accept-Ranges", "bytes")
+                .header("ETag", etag)
+                .body(Body::wrap_stream(timing::timed(ReaderStream::new(file.take(part_len)), req.extensions(), Phase::File)))
+                .unwrap()
+        },
+        ByteRange::Unsatisfiable => Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Content-Range", format!("bytes */{}", len))
+            .body(Body::from("<html>416 Range Not Satisfiable</html>"))
+            .unwrap(),
+    }
+}
+
+/// How `/dir` and `/file/` are treated.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TrailingSlash {
+    /// 301 to `/dir/` and `/file`, so relative links in index pages resolve.
+    Redirect,
+    /// Serve the directory index or the file under either spelling.
+    Serve,
+}
+
+/// A `--alias /media=/mnt/storage/media` mount serving a directory outside the root.
+pub struct Alias {
+    prefix: String,
+    dir: PathBuf,
+}
+
+impl Alias {
+    pub fn parse(option: &str, value: &str) -> Result<Alias, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (prefix, dir) = value.split_once('=').ok_or_else(|| invalid("expected PREFIX=DIR"))?;
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(invalid("the prefix must start with '/'"));
+        }
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(invalid("not a directory"));
+        }
+        Ok(Alias { prefix: prefix.to_string(), dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// Maps a URL path onto the file system through the longest matching alias,
+/// or the root. Returns the directory the file has to stay inside, the URL
+/// prefix that directory is mounted at and the file path; `None` when the
+/// path tries to climb out with `..`.
+pub fn map_path<'a>(config: &'a Config, path: &str) -> Option<(&'a Path, &'a str, PathBuf)> {
+    let alias = config.aliases.iter()
+        .filter(|alias| match path.strip_prefix(alias.prefix.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        })
+        .max_by_key(|alias| alias.prefix.len());
+    let (base, prefix) = match alias {
+        Some(alias) => (alias.dir.as_path(), alias.prefix.as_str()),
+        None => (config.root.as_path(), ""),
+    };
+
+    let mut full_path = base.to_path_buf();
+    for segment in path[prefix.len()..].split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => return None,
+            segment => full_path.push(segment),
+        }
+    }
+    Some((base, prefix, full_path))
+}
+
+/// Patterns starting with `/` are matched against the whole URL path, others
+/// only against its last segment (so `*.bak` denies backups anywhere).
+pub fn is_denied(deny: &[String], path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    deny.iter().any(|pattern| {
+        if pattern.starts_with('/') {
+            glob_match(pattern, path)
+        } else {
+            glob_match(pattern, name)
+        }
+    })
+}
+
+pub fn find_index(dir: &Path, index_files: &[String]) -> Option<PathBuf> {
+    index_files.iter()
+        .map(|name| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+// Looks for `file.br`/`file.gz` next to the requested file, picking the
+// encoding the client prefers among the ones present on disk. A sibling
+// older than the file is left over from an earlier version and ignored.
+async fn find_precompressed(req: &Request<Body>, full_path: &Path) -> (Option<PathBuf>, Option<&'static str>, bool) {
+    let accept_encoding = req.headers().get("Accept-Encoding").and_then(|v| v.to_str().ok());
+    let mut has_variants = false;
+    let mut best: Option<(PathBuf, &'static str, f32)> = None;
+    let modified = match tokio::fs::metadata(full_path).await {
+        Ok(metadata) if metadata.is_file() => metadata.modified().ok(),
+        _ => return (None, None, false),
+    };
+    for (encoding, extension) in PRECOMPRESSED {
+        let mut sibling = full_path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        let sibling = PathBuf::from(sibling);
+        let fresh = match tokio::fs::metadata(&sibling).await {
+            Ok(metadata) => metadata.is_file() && metadata.modified().ok() >= modified,
+            Err(_) => false,
+        };
+        if !fresh {
+            continue;
+        }
+        has_variants = true;
+        let q = accept_encoding.map(|a| quality(a, encoding)).unwrap_or(0.0);
+        if q > 0.0 && best.as_ref().is_none_or(|(_, _, best_q)| q > *best_q) {
+            best = Some((sibling, encoding, q));
+        }
+    }
+    match best {
+        Some((sibling, encoding, _)) => (Some(sibling), Some(encoding), has_variants),
+        None => (None, None, has_variants),
+    }
+}
+
+fn make_etag(metadata: &std::fs::Metadata) -> String {
+    let mtime = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", mtime, metadata.len())
+}
+
+fn is_not_modified(req: &Request<Body>, etag: &str, modified: Option<SystemTime>) -> bool {
+    // If-Modified-Since is only consulted when the client did not send an ETag.
+    if let Some(if_none_match) = req.headers().get("If-None-Match") {
+        return if_none_match.to_str().map(|v| etag_matches(v, etag)).unwrap_or(false);
+    }
+
+    let since = req.headers().get("If-Modified-Since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_http_date(v).ok());
+    match (since, modified) {
+        (Some(since), Some(modified)) => {
+            let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let since_secs = since.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            modified_secs <= since_secs
+        },
+        _ => false,
+    }
+}
+
+fn if_range_matches(req: &Request<Body>, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_range = match req.headers().get("If-Range") {
+        Some(value) => match value.to_str() {
+            Ok(value) => value.trim(),
+            Err(_) => return false,
+        },
+        None => return true,
+    };
+    // An entity tag is compared strongly, so a weak one never matches; a date
+    // has to be the exact Last-Modified.
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return if_range == etag;
+    }
+    match (parse_http_date(if_range), modified) {
+        (Ok(date), Some(modified)) => {
+            let modified_secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let date_secs = date.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            modified_secs == date_secs
+        },
+        _ => false,
+    }
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+    // If-None-Match uses the weak comparison, so a W/ prefix is ignored.
+    if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag.trim_start_matches("W/") == etag)
+}
+
+fn internal_error() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from("Internal Server Error"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use hyper::body::to_bytes;
+    use crate::config::Cli;
+
+    // A root holding `app.js` and a newer `app.js.gz`, removed on drop.
+    struct Site(PathBuf);
+
+    impl Site {
+        fn new(name: &str) -> Site {
+            let root = std::env::temp_dir().join(format!("rustywebserver-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&root).unwrap();
+            fs::write(root.join("app.js"), "console.log(1);").unwrap();
+            fs::write(root.join("app.js.gz"), "gzipped").unwrap();
+            let site = Site(root);
+            site.touch("app.js.gz", SystemTime::now() + Duration::from_secs(60));
+            site
+        }
+
+        fn touch(&self, name: &str, time: SystemTime) {
+            fs::File::options().write(true).open(self.0.join(name)).unwrap().set_modified(time).unwrap();
+        }
+
+        async fn get(&self, accept_encoding: Option<&str>) -> Response<Body> {
+            let root = self.0.to_str().unwrap().to_string();
+            let config = match Config::from_args(&["8080".to_string(), root]) {
+                Ok(Cli::Run(config)) => config,
+                _ => panic!("config"),
+            };
+            let mut req = Request::builder().uri("/app.js");
+            if let Some(accept_encoding) = accept_encoding {
+                req = req.header("Accept-Encoding", accept_encoding);
+            }
+            serve_file(&config, &req.body(Body::empty()).unwrap(), &self.0.join("app.js")).await
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn body(response: Response<Body>) -> Vec<u8> {
+        to_bytes(response.into_body()).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn serves_gzip_sibling() {
+        let site = Site::new("gzip-sibling");
+        let response = site.get(Some("gzip, deflate")).await;
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        assert!(response.headers()["Content-Type"].to_str().unwrap().contains("javascript"));
+        assert_eq!(body(response).await, b"gzipped");
+    }
+
+    // The plain file still varies, since clients that accept gzip get another.
+    #[tokio::test]
+    async fn varies_when_gzip_is_not_accepted() {
+        let site = Site::new("gzip-refused");
+        let response = site.get(Some("br;q=1, gzip;q=0")).await;
+        assert!(!response.headers().contains_key("Content-Encoding"));
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        assert_eq!(body(response).await, b"console.log(1);");
+    }
+
+    #[tokio::test]
+    async fn ignores_stale_sibling() {
+        let site = Site::new("stale-sibling");
+        site.touch("app.js.gz", SystemTime::now() - Duration::from_secs(60));
+        let response = site.get(Some("gzip")).await;
+        assert!(!response.headers().contains_key("Content-Encoding"));
+        assert!(!response.headers().contains_key("Vary"));
+        assert_eq!(body(response).await, b"console.log(1);");
+    }
+}
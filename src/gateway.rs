@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use crate::config::Config;
+use crate::glob::glob_match;
+use crate::proxy::bad_gateway;
+use crate::timing::{self, Phase};
+use crate::{event, fastcgi, scgi, script_env, script_preamble, script_response, uwsgi, PathInfo, Preamble};
+
+// How long to wait for an application server to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A route handing requests to an application server over a CGI-like
+/// protocol, e.g. `--fastcgi '*.php=unix:/run/php/php-fpm.sock'`.
+pub struct GatewayRoute {
+    pattern: Pattern,
+    address: Address,
+    protocol: Protocol,
+}
+
+enum Pattern {
+    /// `/app`: everything under the prefix, with the rest as PATH_INFO.
+    Prefix(String),
+    /// `*.php`: existing files whose name matches.
+    Name(String),
+}
+
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    FastCgi,
+    Scgi,
+    Uwsgi,
+}
+
+/// What an application server sends back: the CGI response, headers first.
+pub type Output = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Connection for T {}
+
+impl GatewayRoute {
+    /// `PATTERN=ADDR`, where PATTERN is a URL prefix or a file name pattern
+    /// and ADDR is `HOST:PORT` or `unix:PATH`.
+    pub fn parse(option: &str, value: &str, protocol: Protocol) -> Result<GatewayRoute, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (pattern, address) = value.split_once('=').ok_or_else(|| invalid("expected PATTERN=ADDR"))?;
+        let pattern = if pattern.starts_with('/') {
+            Pattern::Prefix(pattern.trim_end_matches('/').to_string())
+        } else if !pattern.is_empty() && !pattern.contains('/') {
+            Pattern::Name(pattern.to_string())
+        } else {
+            return Err(invalid("expected a /PREFIX or a file name pattern such as *.php"));
+        };
+        let address = match address.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Address::Unix(PathBuf::from(path)),
+            Some(_) => return Err(invalid("expected unix:PATH")),
+            None => match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Address::Tcp(address.to_string()),
+                _ => return Err(invalid("expected HOST:PORT or unix:PATH")),
+            },
+        };
+        Ok(GatewayRoute { pattern, address, protocol })
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        let connect = async {
+            Ok::<Box<dyn Connection>, io::Error>(match &self.address {
+                Address::Tcp(address) => Box::new(TcpStream::connect(address.as_str()).await?),
+                Address::Unix(path) => Box::new(UnixStream::connect(path).await?),
+            })
+        };
+        tokio::time::timeout(CONNECT_TIMEOUT, connect).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")))
+    }
+
+    fn describe(&self) -> String {
+        match &self.address {
+            Address::Tcp(address) => address.clone(),
+            Address::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// The route with the longest prefix covering `path`.
+pub fn find_prefix_route<'a>(routes: &'a [GatewayRoute], path: &str) -> Option<&'a GatewayRoute> {
+    routes.iter()
+        .filter_map(|route| match &route.pattern {
+            Pattern::Prefix(prefix) => Some((route, prefix)),
+            Pattern::Name(_) => None,
+        })
+        .filter(|(_, prefix)| path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+        .max_by_key(|(_, prefix)| prefix.len())
+        .map(|(route, _)| route)
+}
+
+/// The first route whose file name pattern matches `full_path`, if it exists.
+pub fn find_file_route<'a>(routes: &'a [GatewayRoute], full_path: &Path) -> Option<&'a GatewayRoute> {
+    let name = full_path.file_name()?.to_string_lossy();
+    routes.iter()
+        .find(|route| matches!(&route.pattern, Pattern::Name(pattern) if glob_match(pattern, &name)))
+        .filter(|_| full_path.is_file())
+}
+
+/// Sends the request to the route's application server and streams its
+/// response back, parsed like a script's output. `full_path` is the file a
+/// file name route matched; prefix routes pass `None`.
+pub async fn forward(route: &GatewayRoute, mut req: Request<Body>, full_path: Option<&Path>, client_addr: SocketAddr, config: &Config) -> Response<Body> {
+    let script_path = match (&route.pattern, full_path) {
+        (_, Some(full_path)) => full_path.to_path_buf(),
+        (Pattern::Prefix(prefix), None) => {
+            let path_info = req.uri().path()[prefix.len()..].to_string();
+            req.extensions_mut().insert(PathInfo(path_info));
+            config.root.join(prefix.trim_start_matches('/'))
+        },
+        (Pattern::Name(_), None) => config.root.clone(),
+    };
+    let (parts, body) = req.into_parts();
+    // Only the CGI variables; the older script names would only clutter
+    // what the application sees.
+    let mut params: HashMap<String, String> = script_env(&parts, &script_path, client_addr, config).into_iter()
+        .filter(|(name, _)| name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_'))
+        .collect();
+    // A directory's index file: `/blog/` runs `/blog/index.php`.
+    if let (Some(name), true) = (full_path.and_then(Path::file_name), parts.uri.path().ends_with('/')) {
+        params.insert("SCRIPT_NAME".to_string(), format!("{}{}", parts.uri.path(), name.to_string_lossy()));
+    }
+
+    let waiting = Instant::now();
+    let connection = match route.connect().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            event::error(&format!("Gateway error for {}: {}", route.describe(), e));
+            return bad_gateway();
+        },
+    };
+    let upstream = route.describe();
+    let mut output = match route.protocol {
+        Protocol::FastCgi => fastcgi::exchange(connection, params, body, config.max_body_size, upstream.clone()),
+        Protocol::Scgi => scgi::exchange(connection, params, body, config.max_body_size, upstream.clone()),
+        Protocol::Uwsgi => uwsgi::exchange(connection, params, body, config.max_body_size, upstream.clone()),
+    };
+
+    let mut head = Vec::new();
+    let preamble = loop {
+        match script_preamble(&head) {
+            Preamble::Incomplete => {},
+            preamble => break preamble,
+        }
+        match output.next().await {
+            Some(Ok(chunk)) => head.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                event::error(&format!("Gateway error for {}: {}", upstream, e));
+                return bad_gateway();
+            },
+            None => break Preamble::Plain,
+        }
+    };
+    timing::record(&parts.extensions, Phase::Upstream, waiting.elapsed());
+    let (headers, offset) = match preamble {
+        Preamble::Headers(headers, offset) => (headers, offset),
+        _ => (Vec::new(), 0),
+    };
+    let builder = match script_response(&headers) {
+        Ok(builder) => builder,
+        Err(e) => {
+            event::error(&format!("Gateway {} sent {}", upstream, e));
+            return bad_gateway();
+        },
+    };
+    let first = Bytes::from(head.split_off(offset));
+    let rest = output.map(move |chunk| {
+        if let Err(e) = &chunk {
+            event::error(&format!("Gateway error for {} after the response started: {}", upstream, e));
+        }
+        chunk
+    });
+    builder.body(Body::wrap_stream(stream::once(async move { Ok(first) }).chain(rest))).unwrap()
+}
+
+/// The body's length for protocols that need it before the body itself:
+/// the declared CONTENT_LENGTH, or a chunked body read whole. The body comes
+/// back whole in the second case.
+pub async fn sized_body(params: &HashMap<String, String>, body: &mut Body, limit: u64) -> io::Result<(u64, Option<Vec<u8>>)> {
+    if let Some(length) = params.get("CONTENT_LENGTH").and_then(|length| length.parse::<u64>().ok()) {
+        return Ok((length, None));
+    }
+    let mut received = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if (received.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        received.extend_from_slice(&chunk);
+    }
+    Ok((received.len() as u64, Some(received)))
+}
+
+/// Writes the body, or what `sized_body` read of it, to the application.
+pub async fn send_body(writer: &mut (impl AsyncWrite + Unpin), mut body: Body, buffered: Option<Vec<u8>>, limit: u64) -> io::Result<()> {
+    if let Some(buffered) = buffered {
+        return writer.write_all(&buffered).await;
+    }
+    let mut received = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(too_large());
+        }
+        writer.write_all(&chunk).await?;
+    }
+    Ok(())
+}
+
+/// Marks a body over --max-body-size; the request is then cut off.
+pub fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "request body over --max-body-size")
+}
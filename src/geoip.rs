@@ -0,0 +1,269 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+use crate::auth;
+use crate::json::Json;
+
+// Reads MaxMind DB (.mmdb) files such as GeoLite2-Country or GeoIP2-City:
+// a binary search tree over the address bits whose leaves point into a data
+// section of typed values. https://maxmind.github.io/MaxMind-DB/
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+// Nested maps and pointers deeper than this mean a corrupt file.
+const MAX_DEPTH: usize = 32;
+
+/// Country code used for clients the database has no country for, such as
+/// private addresses.
+pub const UNKNOWN: &str = "--";
+
+static DATABASE: OnceLock<Database> = OnceLock::new();
+
+struct Database {
+    data: Vec<u8>,
+    node_count: u32,
+    record_size: u16,
+    ip_version: u16,
+}
+
+/// Loads the database that `country` looks addresses up in.
+pub fn open(path: &Path) -> Result<(), String> {
+    let data = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let database = Database::parse(data).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let _ = DATABASE.set(database);
+    Ok(())
+}
+
+/// ISO 3166 code of the country `ip` is in, or `UNKNOWN`. Addresses of
+/// anonymous networks fall back to the country they are registered in.
+pub fn country(ip: IpAddr) -> Option<String> {
+    let database = DATABASE.get()?;
+    let record = database.lookup(ip.to_canonical());
+    let code = record.as_ref().and_then(|record| {
+        ["country", "registered_country"].iter()
+            .find_map(|key| record.get(key)?.get("iso_code")?.as_str())
+    });
+    Some(code.unwrap_or(UNKNOWN).to_string())
+}
+
+impl Database {
+    fn parse(data: Vec<u8>) -> Result<Database, &'static str> {
+        let tail = data.len().saturating_sub(128 * 1024);
+        let marker = data[tail..].windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file")?;
+        let metadata_start = tail + marker + METADATA_MARKER.len();
+        let metadata = Decoder { data: &data[metadata_start..] }.decode(0, 0)?.0;
+        let number = |key| metadata.get(key).and_then(|v| v.as_f64()).ok_or("incomplete metadata");
+        let node_count = number("node_count")? as u32;
+        let record_size = number("record_size")? as u16;
+        let ip_version = number("ip_version")? as u16;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err("unsupported record size");
+        }
+        let database = Database { data, node_count, record_size, ip_version };
+        if database.data_start() > metadata_start {
+            return Err("search tree larger than the file");
+        }
+        Ok(database)
+    }
+
+    fn tree_size(&self) -> usize {
+        self.node_count as usize * self.record_size as usize / 4
+    }
+
+    // A 16 byte separator follows the search tree.
+    fn data_start(&self) -> usize {
+        self.tree_size() + 16
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Json> {
+        let (bits, len): (u128, u32) = match ip {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) if self.ip_version == 6 => (u128::from(ip), 128),
+            IpAddr::V6(_) => return None,
+        };
+        let mut node = 0;
+        // IPv4 addresses live under ::/96 in IPv6 databases.
+        if matches!(ip, IpAddr::V4(_)) && self.ip_version == 6 {
+            for _ in 0..96 {
+                if node >= self.node_count {
+                    break;
+                }
+                node = self.record(node, 0)?;
+            }
+        }
+        for i in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> i) as u8 & 1)?;
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = (node - self.node_count) as usize - 16;
+        let decoder = Decoder { data: self.data.get(self.data_start()..)? };
+        decoder.decode(offset, 0).ok().map(|(value, _)| value)
+    }
+
+    fn record(&self, node: u32, bit: u8) -> Option<u32> {
+        let width = self.record_size as usize / 4;
+        let start = node as usize * width;
+        let b = self.data.get(start..start + width)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0u32, |n, b| n << 8 | *b as u32);
+        Some(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => (b[3] as u32 & 0xF0) << 20 | be(&b[0..3]),
+            (28, _) => (b[3] as u32 & 0x0F) << 24 | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], &'static str> {
+        self.data.get(offset..offset + len).ok_or("truncated data section")
+    }
+
+    // Returns the value at `offset` and the offset just past it.
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Json, usize), &'static str> {
+        if depth > MAX_DEPTH {
+            return Err("data nested too deeply");
+        }
+        let control = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let size = (control >> 3) & 0x3;
+            let extra = self.bytes(offset, size as usize + 1)?;
+            let be = |bytes: &[u8]| bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+            let low = (control & 0x7) as usize;
+            let target = match size {
+                0 => low << 8 | be(extra),
+                1 => (low << 16 | be(extra)) + 2048,
+                2 => (low << 24 | be(extra)) + 526_336,
+                _ => be(extra),
+            };
+            let (value, _) = self.decode(target, depth + 1)?;
+            return Ok((value, offset + size as usize + 1));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+        let mut size = (control & 0x1F) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let bytes = self.bytes(offset, extra)?;
+            let n = bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+            size = match extra {
+                1 => 29 + n,
+                2 => 285 + n,
+                _ => 65_821 + n,
+            };
+            offset += extra;
+        }
+
+        let unsigned = |offset, size| -> Result<f64, &'static str> {
+            Ok(self.bytes(offset, size)?.iter().fold(0u128, |n, b| n << 8 | *b as u128) as f64)
+        };
+        Ok(match kind {
+            2 => (Json::String(String::from_utf8_lossy(self.bytes(offset, size)?).into_owned()), offset + size),
+            3 => {
+                let bytes: [u8; 8] = self.bytes(offset, 8)?.try_into().unwrap();
+                (Json::Number(f64::from_be_bytes(bytes)), offset + 8)
+            },
+            4 => (Json::Null, offset + size),
+            5 | 6 | 9 | 10 => (Json::Number(unsigned(offset, size)?), offset + size),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let key = match key {
+                        Json::String(key) => key,
+                        _ => return Err("map key is not a string"),
+                    };
+                    entries.push((key, value));
+                    offset = next;
+                }
+                (Json::Object(entries), offset)
+            },
+            8 => {
+                let n = self.bytes(offset, size)?.iter().fold(0u32, |n, b| n << 8 | *b as u32);
+                (Json::Number(n as i32 as f64), offset + size)
+            },
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (item, next) = self.decode(offset, depth + 1)?;
+                    items.push(item);
+                    offset = next;
+                }
+                (Json::Array(items), offset)
+            },
+            14 => (Json::Bool(size != 0), offset),
+            15 => {
+                let bytes: [u8; 4] = self.bytes(offset, 4)?.try_into().unwrap();
+                (Json::Number(f32::from_be_bytes(bytes) as f64), offset + 4)
+            },
+            _ => return Err("unknown data type"),
+        })
+    }
+}
+
+/// `--geoip-allow`/`--geoip-deny` country lists, global or for a path prefix;
+/// the longest matching prefix's lists apply.
+#[derive(Default)]
+pub struct GeoRules {
+    rules: Vec<GeoRule>,
+}
+
+struct GeoRule {
+    prefix: String,
+    allow: bool,
+    countries: Vec<String>,
+}
+
+impl GeoRules {
+    pub fn add(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let (prefix, countries) = match value.split_once('=') {
+            Some((prefix, countries)) => (prefix, countries),
+            None => ("/", value),
+        };
+        let countries: Vec<String> = countries.split(',').map(|c| c.trim().to_ascii_uppercase()).collect();
+        let valid = prefix.starts_with('/')
+            && countries.iter().all(|c| c == UNKNOWN || (c.len() == 2 && c.bytes().all(|b| b.is_ascii_uppercase())));
+        if !valid {
+            return Err(format!("invalid value '{}' for '{}': expected [PREFIX=]CC,CC,...", value, option));
+        }
+        self.rules.push(GeoRule {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            allow: option == "--geoip-allow",
+            countries,
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// With an allow list the country must be on it; with a deny list it must not.
+    pub fn allows(&self, path: &str, country: &str) -> bool {
+        let segments = auth::segments(path);
+        let longest = self.rules.iter()
+            .filter(|rule| segments.starts_with(&auth::segments(&rule.prefix)))
+            .map(|rule| rule.prefix.len())
+            .max();
+        self.rules.iter()
+            .filter(|rule| Some(rule.prefix.len()) == longest && segments.starts_with(&auth::segments(&rule.prefix)))
+            .all(|rule| rule.allow == rule.countries.iter().any(|c| c == country))
+    }
+}
@@ -0,0 +1,71 @@
+// Shell-style pattern matching for URL paths: `*` matches within one path
+// segment, `**` crosses segments, `?` matches one character and `[...]`
+// matches a character class (`[!...]` negates it).
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            if pattern.get(1) == Some(&'*') {
+                let rest = &pattern[2..];
+                (0..=text.len()).any(|i| match_from(rest, &text[i..]))
+            } else {
+                let rest = &pattern[1..];
+                let segment = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+                (0..=segment).any(|i| match_from(rest, &text[i..]))
+            }
+        },
+        Some('?') => match text.first() {
+            Some(&c) if c != '/' => match_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some('[') => match (text.first(), parse_class(pattern)) {
+            (Some(&c), Some((matches, len))) => matches(c) && match_from(&pattern[len..], &text[1..]),
+            (Some(&c), None) => c == '[' && match_from(&pattern[1..], &text[1..]),
+            (None, _) => false,
+        },
+        Some(&p) => match text.first() {
+            Some(&c) if c == p => match_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+// Returns a predicate for the class starting at `pattern[0] == '['` and the
+// number of pattern characters it spans, or None if the class is unterminated.
+fn parse_class(pattern: &[char]) -> Option<(impl Fn(char) -> bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let start = i;
+    while i < pattern.len() && (pattern[i] != ']' || i == start) {
+        i += 1;
+    }
+    if i >= pattern.len() {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    let body = &pattern[start..i];
+    let mut j = 0;
+    while j < body.len() {
+        if j + 2 < body.len() && body[j + 1] == '-' {
+            ranges.push((body[j], body[j + 2]));
+            j += 3;
+        } else {
+            ranges.push((body[j], body[j]));
+            j += 1;
+        }
+    }
+
+    let matches = move |c: char| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated;
+    Some((matches, i + 1))
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::config::Config;
+use crate::proxy;
+
+// How long a readiness probe waits for each upstream to accept a connection.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `--healthz` and `--readyz` paths for orchestrator probes. They are
+/// answered before redirects, access rules and rate limits apply.
+#[derive(Default)]
+pub struct HealthConfig {
+    pub healthz: Option<String>,
+    pub readyz: Option<String>,
+}
+
+/// The probe response when `req` is for one of the configured paths.
+pub async fn respond(config: &Config, req: &Request<Body>) -> Option<Response<Body>> {
+    let path = Some(req.uri().path());
+    let ready = if config.health.healthz.as_deref() == path {
+        false
+    } else if config.health.readyz.as_deref() == path {
+        true
+    } else {
+        return None;
+    };
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Some(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, HeaderValue::from_static("GET, HEAD"))
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>405 Method Not Allowed</html>"))
+            .unwrap());
+    }
+    // Liveness only needs the process to answer.
+    let problems = if ready { readiness_problems(config).await } else { Vec::new() };
+    let (status, body) = if problems.is_empty() {
+        (StatusCode::OK, "ok\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n") + "\n")
+    };
+    Some(Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body))
+        .unwrap())
+}
+
+// Ready means the root folder can be listed, new connections are still
+// accepted and every proxy route has an upstream that accepts connections.
+async fn readiness_problems(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = tokio::fs::read_dir(&config.root).await {
+        problems.push(format!("root folder {} is not accessible: {}", config.root.display(), e));
+    }
+    if config.connection_limits.is_full() {
+        problems.push("at the connection limit".to_string());
+    }
+    for prefix in proxy::unreachable(&config.proxy_routes, UPSTREAM_TIMEOUT).await {
+        problems.push(format!("no upstream reachable for {}", prefix));
+    }
+    problems
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use crate::config::Config;
+use crate::event;
+
+// Output of a failed hook kept for the log.
+const OUTPUT_LOGGED: usize = 4096;
+
+/// `--on-start` and `--on-stop` commands, run in order as the server's own
+/// user: the start hooks once the sockets are bound, the stop hooks when a
+/// shutdown signal arrives, while connections are still being served.
+pub struct Hooks {
+    pub start: Vec<PathBuf>,
+    pub stop: Vec<PathBuf>,
+    /// `--hook-failure abort`: a failed start hook stops the server before it
+    /// serves, and a failed stop hook makes it exit with status 1.
+    pub abort_on_failure: bool,
+    pub timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks { start: Vec::new(), stop: Vec::new(), abort_on_failure: false, timeout: Duration::from_secs(30) }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Event {
+    Start,
+    Stop,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Start => "start",
+            Event::Stop => "stop",
+        }
+    }
+}
+
+/// Runs the hooks for `hook_event`, with the addresses the server listens on
+/// in SERVER_ADDRESSES. False when one failed; with --hook-failure abort the
+/// rest are then skipped.
+pub async fn run(config: &Config, hook_event: Event, addresses: &[String]) -> bool {
+    let hooks = match hook_event {
+        Event::Start => &config.hooks.start,
+        Event::Stop => &config.hooks.stop,
+    };
+    let mut succeeded = true;
+    for hook in hooks {
+        if run_one(config, hook, hook_event, addresses).await {
+            continue;
+        }
+        succeeded = false;
+        if config.hooks.abort_on_failure {
+            break;
+        }
+    }
+    succeeded
+}
+
+async fn run_one(config: &Config, hook: &Path, hook_event: Event, addresses: &[String]) -> bool {
+    let mut cmd = std::process::Command::new(hook);
+    cmd.env("SERVER_EVENT", hook_event.name())
+        .env("SERVER_ADDRESSES", addresses.join(" "))
+        .env("SERVER_SOFTWARE", format!("rustywebserver/{}", env!("CARGO_PKG_VERSION")))
+        .env("DOCUMENT_ROOT", &config.root)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout also kills what it started.
+        .process_group(0);
+    let mut cmd = Command::from(cmd);
+    cmd.kill_on_drop(true);
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            event::error(&format!("Failed to run {} hook {}: {}", hook_event.name(), hook.display(), e));
+            return false;
+        },
+    };
+    let pid = child.id();
+    let output = match tokio::time::timeout(config.hooks.timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            event::error(&format!("Failed to run {} hook {}: {}", hook_event.name(), hook.display(), e));
+            return false;
+        },
+        Err(_) => {
+            if let Some(pid) = pid {
+                unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+            }
+            event::error(&format!("The {} hook {} timed out after {}s and was killed", hook_event.name(), hook.display(), config.hooks.timeout.as_secs()));
+            return false;
+        },
+    };
+    if output.status.success() {
+        event::info(&format!("Ran {} hook {}", hook_event.name(), hook.display()));
+        return true;
+    }
+    event::error(&format!("The {} hook {} failed: {}", hook_event.name(), hook.display(), output.status));
+    for stream in [&output.stdout, &output.stderr] {
+        let text = String::from_utf8_lossy(&stream[..stream.len().min(OUTPUT_LOGGED)]);
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            event::error(&format!("The {} hook {}: {}", hook_event.name(), hook.display(), line));
+        }
+    }
+    false
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use hyper::header::{HOST, REFERER};
+use hyper::{Body, Request};
+use crate::files::is_denied;
+use crate::glob::glob_match;
+
+/// Refuses protected files to pages on other sites, judged by the Referer.
+#[derive(Default)]
+pub struct HotlinkConfig {
+    /// Patterns as in `--deny`, e.g. `*.jpg` or `/media/**`.
+    pub patterns: Vec<String>,
+    /// Referer hosts allowed besides the request's own Host, `*` wildcards allowed.
+    pub allowed_hosts: Vec<String>,
+    /// Also refuse requests without a Referer; by default they pass, since
+    /// browsers and privacy tools often leave it out.
+    pub block_empty: bool,
+    /// Served in place of a refused file instead of a 403.
+    pub placeholder: Option<PathBuf>,
+}
+
+impl HotlinkConfig {
+    pub fn blocks(&self, req: &Request<Body>, path: &str, relative_path: &str) -> bool {
+        if !is_denied(&self.patterns, path) && !is_denied(&self.patterns, relative_path) {
+            return false;
+        }
+        let referer = match req.headers().get(REFERER).and_then(|v| v.to_str().ok()) {
+            Some(referer) => referer,
+            None => return self.block_empty,
+        };
+        let referer_host = match url::Url::parse(referer).ok().and_then(|url| url.host_str().map(|h| h.to_ascii_lowercase())) {
+            Some(host) => host,
+            None => return true,
+        };
+        let own_host = req.headers().get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(|host| strip_port(host).to_ascii_lowercase());
+        if own_host.as_deref() == Some(referer_host.as_str()) {
+            return false;
+        }
+        !self.allowed_hosts.iter().any(|allowed| glob_match(&allowed.to_ascii_lowercase(), &referer_host))
+    }
+}
+
+// `example.com:8080` -> `example.com`, `[::1]:8080` -> `[::1]`.
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
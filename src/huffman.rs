@@ -0,0 +1,45 @@
+// Huffman code lengths limited to a maximum length, shared by the brotli
+// and zstd encoders.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Code lengths of at most `limit` bits. Rare symbols are counted as more
+/// frequent until the Huffman code fits, as the brotli reference encoder
+/// does; a lone symbol still gets one bit.
+pub fn code_lengths(counts: &[u32], limit: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; counts.len()];
+    let symbols: Vec<usize> = (0..counts.len()).filter(|&s| counts[s] > 0).collect();
+    if symbols.len() == 1 {
+        lengths[symbols[0]] = 1;
+    }
+    if symbols.len() <= 1 {
+        return lengths;
+    }
+    let mut floor = 1;
+    loop {
+        let mut heap: BinaryHeap<_> = symbols.iter().enumerate()
+            .map(|(node, &s)| Reverse((counts[s].max(floor) as u64, node)))
+            .collect();
+        let mut parents = vec![0; 2 * symbols.len() - 1];
+        let mut next = symbols.len();
+        while let (Some(Reverse((a_weight, a))), Some(Reverse((b_weight, b)))) = (heap.pop(), heap.pop()) {
+            parents[a] = next;
+            parents[b] = next;
+            heap.push(Reverse((a_weight + b_weight, next)));
+            next += 1;
+        }
+        // Parents come after their children, so depths fill in from the root.
+        let mut depths = vec![0u8; next];
+        for node in (0..next - 1).rev() {
+            depths[node] = depths[parents[node]] + 1;
+        }
+        if depths[..symbols.len()].iter().all(|&d| d <= limit) {
+            for (node, &s) in symbols.iter().enumerate() {
+                lengths[s] = depths[node];
+            }
+            return lengths;
+        }
+        floor *= 2;
+    }
+}
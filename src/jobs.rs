@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue, ALLOW, LOCATION, RETRY_AFTER, TRANSFER_ENCODING};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::task::AbortHandle;
+use crate::config::Config;
+use crate::json::Json;
+use crate::{event, oidc};
+
+// Output kept of a finished job; a script writing more fails with 502.
+const OUTPUT_MAX: usize = 16 * 1024 * 1024;
+
+/// `--jobs`: a POST to a script whose sidecar file says `async = true` is
+/// answered 202 at once with a job URL under `path`, while the script runs
+/// in the background. `GET <path>/<id>` tells how the job is doing,
+/// `GET <path>/<id>/output` gives the script's response once it is done and
+/// `DELETE <path>/<id>` stops and forgets it. Job URLs are held to the
+/// access rules of the script's own URL and sidecar file.
+pub struct JobsConfig {
+    pub path: Option<String>,
+    /// Jobs kept at once, running or finished; past it the oldest finished
+    /// one is dropped, or the POST answered 503 when all are running.
+    pub max_jobs: usize,
+    /// How long a finished job is kept.
+    pub ttl: Duration,
+    store: Mutex<HashMap<String, Job>>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            path: None,
+            max_jobs: 100,
+            ttl: Duration::from_secs(3600),
+            store: Mutex::default(),
+        }
+    }
+}
+
+struct Job {
+    script: String,
+    script_path: PathBuf,
+    started: SystemTime,
+    state: State,
+}
+
+enum State {
+    Running(AbortHandle),
+    Finished(Instant, Output),
+}
+
+struct Output {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl JobsConfig {
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Whether `path` is a job URL.
+    pub fn covers(&self, path: &str) -> bool {
+        self.path.as_deref()
+            .and_then(|prefix| path.strip_prefix(prefix))
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// The URL path and file of the script that started the job `path`
+    /// belongs to, whose access rules its job URLs are held to.
+    pub fn origin(&self, path: &str) -> Option<(String, PathBuf)> {
+        if !self.covers(path) {
+            return None;
+        }
+        let (id, _) = job_id(self, path);
+        self.lock().get(id).map(|job| (job.script.clone(), job.script_path.clone()))
+    }
+
+    // Expired jobs are dropped whenever the store is used.
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Job>> {
+        let mut jobs = self.store.lock().unwrap();
+        jobs.retain(|_, job| !matches!(job.state, State::Finished(at, _) if at.elapsed() > self.ttl));
+        jobs
+    }
+}
+
+/// Reads the request body, starts the script on it in the background and
+/// answers 202 with the job's URL in `Location`.
+pub async fn start(config: Arc<Config>, req: Request<Body>, script_path: PathBuf, client_addr: SocketAddr) -> Response<Body> {
+    let (parts, mut body) = req.into_parts();
+    // The client is gone before the script reads its input, so it is read
+    // here, under the same limits.
+    let limit = config.max_body_size;
+    let read = async {
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.ok()?;
+            if (received.len() + chunk.len()) as u64 > limit {
+                return Some(Err(()));
+            }
+            received.extend_from_slice(&chunk);
+        }
+        Some(Ok(received))
+    };
+    let received = match tokio::time::timeout(config.body_timeout, read).await {
+        Ok(Some(Ok(received))) => received,
+        Ok(Some(Err(()))) => return crate::payload_too_large(),
+        Ok(None) => return error(StatusCode::BAD_REQUEST, "400 Bad Request"),
+        Err(_) => return crate::request_timeout(),
+    };
+    let req = Request::from_parts(parts, Body::from(received));
+
+    let jobs = &config.jobs;
+    let prefix = jobs.path.as_deref().unwrap_or_default();
+    let id = oidc::random_bytes(16).iter().fold(String::new(), |mut id, b| {
+        let _ = write!(id, "{:02x}", b);
+        id
+    });
+    let mut store = jobs.lock();
+    if store.len() >= jobs.max_jobs {
+        let oldest = store.iter()
+            .filter_map(|(id, job)| match job.state {
+                State::Finished(at, _) => Some((id.clone(), at)),
+                State::Running(_) => None,
+            })
+            .min_by_key(|(_, at)| *at);
+        match oldest {
+            Some((oldest, _)) => {
+                store.remove(&oldest);
+            },
+            None => {
+                drop(store);
+                event::warning(&format!("No room for another job of {}: {} are running", script_path.display(), jobs.max_jobs));
+                return crate::service_unavailable();
+            },
+        }
+    }
+    let script = req.uri().path().to_string();
+    let job_path = script_path.clone();
+    let task = tokio::spawn({
+        let (config, id) = (config.clone(), id.clone());
+        async move {
+            let output = match crate::handle_script(req, script_path.clone(), client_addr, &config).await {
+                Ok(response) => collect(response, &script_path).await,
+                Err(_) => failed(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"),
+            };
+            if let Some(job) = config.jobs.lock().get_mut(&id) {
+                job.state = State::Finished(Instant::now(), output);
+            }
+        }
+    });
+    let job = Job { script, script_path: job_path, started: SystemTime::now(), state: State::Running(task.abort_handle()) };
+    store.insert(id.clone(), job);
+    drop(store);
+
+    let location = format!("{}/{}", prefix, id);
+    let body = Json::Object(vec![
+        ("id".to_string(), Json::String(id)),
+        ("state".to_string(), Json::String("running".to_string())),
+        ("url".to_string(), Json::String(location.clone())),
+    ]);
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(LOCATION, location)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Answers a request for a job URL.
+pub fn respond(jobs: &JobsConfig, req: &Request<Body>) -> Response<Body> {
+    let prefix = jobs.path.as_deref().unwrap_or_default();
+    let (id, output) = job_id(jobs, req.uri().path());
+    let mut store = jobs.lock();
+    if !store.contains_key(id) {
+        return error(StatusCode::NOT_FOUND, "404 Not Found");
+    }
+    match req.method() {
+        &Method::GET | &Method::HEAD => {},
+        &Method::DELETE if !output => {
+            if let Some(Job { state: State::Running(task), .. }) = store.remove(id) {
+                // Dropping the running script stops it.
+                task.abort();
+            }
+            return Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+        },
+        _ => {
+            let allowed = if output { "GET, HEAD" } else { "GET, HEAD, DELETE" };
+            let mut response = error(StatusCode::METHOD_NOT_ALLOWED, "405 Method Not Allowed");
+            response.headers_mut().insert(ALLOW, HeaderValue::from_static(allowed));
+            return response;
+        },
+    }
+    let job = &store[id];
+    match (&job.state, output) {
+        (State::Finished(_, result), true) => {
+            let mut response = Response::builder().status(result.status).body(Body::from(result.body.clone())).unwrap();
+            *response.headers_mut() = result.headers.clone();
+            response
+        },
+        (State::Running(_), true) => {
+            let mut response = status(id, job, prefix);
+            *response.status_mut() = StatusCode::ACCEPTED;
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+            response
+        },
+        (_, false) => status(id, job, prefix),
+    }
+}
+
+// The job ID in a job URL, and whether the URL is for its output.
+fn job_id<'a>(jobs: &JobsConfig, path: &'a str) -> (&'a str, bool) {
+    let prefix = jobs.path.as_deref().unwrap_or_default();
+    let rest = path[prefix.len()..].trim_start_matches('/');
+    match rest.strip_suffix("/output") {
+        Some(id) => (id, true),
+        None => (rest, false),
+    }
+}
+
+fn status(id: &str, job: &Job, prefix: &str) -> Response<Body> {
+    let mut fields = vec![
+        ("id".to_string(), Json::String(id.to_string())),
+        ("script".to_string(), Json::String(job.script.clone())),
+        ("started".to_string(), Json::String(httpdate::fmt_http_date(job.started))),
+    ];
+    match &job.state {
+        State::Running(_) => fields.push(("state".to_string(), Json::String("running".to_string()))),
+        State::Finished(_, output) => {
+            fields.push(("state".to_string(), Json::String("finished".to_string())));
+            fields.push(("status".to_string(), Json::Number(output.status.as_u16() as f64)));
+            fields.push(("output".to_string(), Json::String(format!("{}/{}/output", prefix, id))));
+        },
+    }
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(Json::Object(fields).to_string()))
+        .unwrap()
+}
+
+// The script's whole response, kept until the job expires.
+async fn collect(response: Response<Body>, script_path: &std::path::Path) -> Output {
+    let (parts, mut body) = response.into_parts();
+    let mut received = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return failed(StatusCode::BAD_GATEWAY, "502 Bad Gateway");
+        };
+        if received.len() + chunk.len() > OUTPUT_MAX {
+            event::error(&format!("Job of {} wrote more than {} bytes", script_path.display(), OUTPUT_MAX));
+            return failed(StatusCode::BAD_GATEWAY, "502 Bad Gateway");
+        }
+        received.extend_from_slice(&chunk);
+    }
+    let mut headers = parts.headers;
+    headers.remove(TRANSFER_ENCODING);
+    Output { status: parts.status, headers, body: Bytes::from(received) }
+}
+
+fn failed(status_code: StatusCode, message: &str) -> Output {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", HeaderValue::from_static("text/html; charset=utf-8"));
+    Output { status: status_code, headers, body: Bytes::from(format!("<html>{}</html>", message)) }
+}
+
+fn error(status_code: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(format!("<html>{}</html>", message)))
+        .unwrap()
+}
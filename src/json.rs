@@ -0,0 +1,353 @@
+// A minimal JSON reader (RFC 8259) for token claims and key sets, and the
+// writer for JSON log lines.
+
+use std::fmt;
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+// Deeply nested input would otherwise overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(format!("unexpected data at offset {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+// Compact output on one line; whole numbers are written without a fraction
+// and non-finite ones, which JSON cannot express, as null.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if !n.is_finite() => f.write_str("null"),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            },
+            Json::Object(members) => {
+                f.write_str("{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.bytes.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str, value: Json) -> Result<Json, String> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(format!("unexpected character at offset {}", self.pos))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth >= MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            None => Err("unexpected end of input".to_string()),
+            Some(b'n') => self.expect("null", Json::Null),
+            Some(b't') => self.expect("true", Json::Bool(true)),
+            Some(b'f') => self.expect("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        },
+                        _ => return Err(format!("expected ',' or ']' at offset {}", self.pos)),
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.bytes.get(self.pos) != Some(&b'"') {
+                        return Err(format!("expected a member name at offset {}", self.pos));
+                    }
+                    let name = self.string()?;
+                    self.skip_whitespace();
+                    if self.bytes.get(self.pos) != Some(&b':') {
+                        return Err(format!("expected ':' at offset {}", self.pos));
+                    }
+                    self.pos += 1;
+                    members.push((name, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        },
+                        _ => return Err(format!("expected ',' or '}}' at offset {}", self.pos)),
+                    }
+                }
+            },
+            Some(_) => self.number(),
+        }
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?, stricter than what
+    // Rust's float parser takes.
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        let invalid = || format!("invalid number at offset {}", start);
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        match self.bytes.get(self.pos) {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => {
+                self.digits();
+            },
+            _ => return Err(invalid()),
+        }
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !self.digits() {
+                return Err(invalid());
+            }
+        }
+        if matches!(self.bytes.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.bytes.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !self.digits() {
+                return Err(invalid());
+            }
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|n| n.parse::<f64>().ok())
+            .map(Json::Number)
+            .ok_or_else(invalid)
+    }
+
+    // Skips a run of digits; false when there was none.
+    fn digits(&mut self) -> bool {
+        let start = self.pos;
+        while matches!(self.bytes.get(self.pos), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.bytes.get(self.pos), None | Some(b'"' | b'\\')) {
+                self.pos += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| "invalid UTF-8")?);
+            match self.bytes.get(self.pos) {
+                None => return Err("unterminated string".to_string()),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                },
+                _ => {},
+            }
+            let escape = *self.bytes.get(self.pos + 1).ok_or("unterminated string")?;
+            self.pos += 2;
+            match escape {
+                b'"' => out.push('"'),
+                b'\\' => out.push('\\'),
+                b'/' => out.push('/'),
+                b'b' => out.push('\u{8}'),
+                b'f' => out.push('\u{c}'),
+                b'n' => out.push('\n'),
+                b'r' => out.push('\r'),
+                b't' => out.push('\t'),
+                b'u' => {
+                    let mut code = self.hex4()?;
+                    // Characters outside the BMP come as a surrogate pair.
+                    if (0xD800..0xDC00).contains(&code) && self.bytes[self.pos..].starts_with(b"\\u") {
+                        self.pos += 2;
+                        let low = self.hex4()?;
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                    }
+                    out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                },
+                _ => return Err(format!("invalid escape at offset {}", self.pos - 1)),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.pos..self.pos + 4).ok_or("unterminated string")?;
+        let code = std::str::from_utf8(digits).ok()
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| format!("invalid \\u escape at offset {}", self.pos))?;
+        self.pos += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values() {
+        let value = Json::parse(r#" {"a": [1, -2.5e3, true, false, null], "b": {"c": "d"}, "": 0} "#).unwrap();
+        let Some(Json::Array(items)) = value.get("a") else { panic!("expected an array") };
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[1].as_f64(), Some(-2500.0));
+        assert!(matches!(items[4], Json::Null));
+        assert_eq!(value.get("b").and_then(|b| b.get("c")).and_then(Json::as_str), Some("d"));
+        assert_eq!(value.get("").and_then(Json::as_f64), Some(0.0));
+        assert!(value.get("missing").is_none());
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let value = Json::parse(r#""\"\\\/\b\f\n\r\t\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(value.as_str(), Some("\"\\/\u{8}\u{c}\n\r\té😀"));
+        // A lone surrogate cannot be a char.
+        assert_eq!(Json::parse(r#""\ud800""#).unwrap().as_str(), Some("\u{FFFD}"));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for text in [
+            "", "[", "[1,]", "{\"a\":1,}", "{\"a\" 1}", "{a:1}", "\"open", "\"\\x\"", "\"\\u12\"",
+            "tru", "nul", "1 2", "[1]]", "01", "+1", "1.", ".5", "1e", "-", "--1", "0x10",
+        ] {
+            assert!(Json::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn limits_nesting() {
+        assert!(Json::parse(&format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH))).is_ok());
+        assert!(Json::parse(&format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1))).is_err());
+    }
+
+    #[test]
+    fn writes_compact_json() {
+        let value = Json::Object(vec![
+            ("n".to_string(), Json::Number(3.0)),
+            ("f".to_string(), Json::Number(0.5)),
+            ("nan".to_string(), Json::Number(f64::NAN)),
+            ("s".to_string(), Json::String("a\"b\\c\n\u{1}".to_string())),
+            ("a".to_string(), Json::Array(vec![Json::Bool(true), Json::Null])),
+        ]);
+        let text = value.to_string();
+        assert_eq!(text, r#"{"n":3,"f":0.5,"nan":null,"s":"a\"b\\c\n\u0001","a":[true,null]}"#);
+        assert_eq!(Json::parse(&text).unwrap().get("s").and_then(Json::as_str), Some("a\"b\\c\n\u{1}"));
+    }
+
+    // --script-json-vars passes members that are not strings as this text.
+    #[test]
+    fn writes_parsed_members_back() {
+        let Json::Object(members) = Json::parse(r#"{"tags": ["a", "b"], "n": 1e2, "o": {"x": -0.5, "y": [ ]}, "t": true}"#).unwrap() else {
+            panic!("expected an object")
+        };
+        let written: Vec<String> = members.iter().map(|(_, value)| value.to_string()).collect();
+        assert_eq!(written, [r#"["a","b"]"#, "100", r#"{"x":-0.5,"y":[]}"#, "true"]);
+    }
+}
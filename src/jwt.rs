@@ -0,0 +1,295 @@
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderValue, AUTHORIZATION, HOST, WWW_AUTHENTICATE};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use crate::event;
+use crate::auth::{self, RemoteUser};
+use crate::json::Json;
+use crate::password::constant_time_eq;
+use crate::rsa::RsaPublicKey;
+use crate::sha256::hmac_sha256;
+use crate::{base64, tls};
+
+// Tolerated clock difference with the token issuer for exp and nbf.
+const LEEWAY: u64 = 60;
+// A token naming an unknown key refetches the JWKS URL at most this often.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bearer token checks for the `--jwt-auth` prefixes. HS256 tokens are checked
+/// against `--jwt-secret-file`, RS256 tokens against the keys of `--jwt-jwks`.
+#[derive(Default)]
+pub struct JwtConfig {
+    pub prefixes: Vec<String>,
+    pub secret: Option<Vec<u8>>,
+    pub keys: KeySet,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+/// RSA keys from a JWK Set file, or from an http:// or https:// URL that is
+/// fetched at startup and again when tokens name a key it does not have.
+#[derive(Default)]
+pub struct KeySet {
+    url: Option<Uri>,
+    keys: RwLock<Vec<Jwk>>,
+    last_fetch: RwLock<Option<Instant>>,
+}
+
+struct Jwk {
+    kid: Option<String>,
+    key: RsaPublicKey,
+}
+
+/// The verified claims, kept in the request extensions for scripts.
+#[derive(Clone)]
+pub struct JwtClaims(pub Vec<(String, String)>);
+
+impl JwtConfig {
+    pub fn enabled(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    pub fn covers(&self, path: &str) -> bool {
+        let segments = auth::segments(path);
+        self.prefixes.iter().any(|prefix| segments.starts_with(&auth::segments(prefix)))
+    }
+}
+
+impl KeySet {
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        if value.starts_with("http://") || value.starts_with("https://") {
+            self.url = Some(parse_url(option, value)?);
+        } else {
+            let path = Path::new(value);
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            *self.keys.write().unwrap() = parse_jwks(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn from_url(url: Uri) -> KeySet {
+        KeySet { url: Some(url), ..KeySet::default() }
+    }
+
+    /// Whether keys are configured, possibly still to be fetched.
+    pub fn is_configured(&self) -> bool {
+        self.url.is_some() || !self.keys.read().unwrap().is_empty()
+    }
+
+    pub async fn fetch(&self) -> Result<(), String> {
+        let uri = match &self.url {
+            Some(uri) => uri.clone(),
+            None => return Ok(()),
+        };
+        *self.last_fetch.write().unwrap() = Some(Instant::now());
+        let body = fetch(Request::get(uri.clone()).body(Body::empty()).unwrap()).await?;
+        *self.keys.write().unwrap() = parse_jwks(&body).map_err(|e| format!("{}: {}", uri, e))?;
+        Ok(())
+    }
+
+    fn has_key(&self, kid: Option<&str>) -> bool {
+        let keys = self.keys.read().unwrap();
+        match kid {
+            Some(kid) => keys.iter().any(|jwk| jwk.kid.as_deref() == Some(kid)),
+            None => !keys.is_empty(),
+        }
+    }
+
+    async fn verify_rs256(&self, kid: Option<&str>, message: &[u8], signature: &[u8]) -> bool {
+        let stale = self.last_fetch.read().unwrap().is_none_or(|at| at.elapsed() >= REFRESH_INTERVAL);
+        if self.url.is_some() && !self.has_key(kid) && stale {
+            if let Err(e) = self.fetch().await {
+                event::error(&e);
+            }
+        }
+        self.keys.read().unwrap().iter()
+            .filter(|jwk| kid.is_none() || jwk.kid.as_deref() == kid)
+            .any(|jwk| jwk.key.verify_sha256(message, signature))
+    }
+}
+
+/// An http:// or https:// URL for `fetch`.
+pub fn parse_url(option: &str, value: &str) -> Result<Uri, String> {
+    let uri: Uri = value.parse().map_err(|_| format!("invalid value '{}' for '{}'", value, option))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => Ok(uri),
+        _ => Err(format!("invalid value '{}' for '{}': expected an http:// or https:// URL", value, option)),
+    }
+}
+
+/// Sends a request and returns the body of a successful response.
+pub async fn fetch(req: Request<Body>) -> Result<String, String> {
+    let uri = req.uri().clone();
+    let response = match uri.scheme_str() {
+        Some("https") => send_https(req).await,
+        _ => Client::new().request(req).await.map_err(|e| e.to_string()),
+    };
+    let response = response.map_err(|e| format!("Failed to fetch {}: {}", uri, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch {}: {}", uri, response.status()));
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| format!("Failed to fetch {}: {}", uri, e))?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// hyper's client has no TLS of its own, so an https:// request gets a
+// connection of its own, closed once the response is read.
+async fn send_https(mut req: Request<Body>) -> Result<Response<Body>, String> {
+    let uri = req.uri().clone();
+    let (host, port) = match uri.authority() {
+        Some(authority) => (authority.host(), authority.port_u16().unwrap_or(443)),
+        None => return Err("no host in the URL".to_string()),
+    };
+    let stream = tls::connect(host, port).await.map_err(|e| e.to_string())?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+    let authority = HeaderValue::from_str(uri.authority().map_or("", |authority| authority.as_str())).map_err(|e| e.to_string())?;
+    req.headers_mut().insert(HOST, authority);
+    *req.uri_mut() = uri.path_and_query().map_or("/", |target| target.as_str()).parse().map_err(|_| "an invalid path".to_string())?;
+    sender.send_request(req).await.map_err(|e| e.to_string())
+}
+
+// RSA signing keys from a JWK Set (RFC 7517 section 5); other keys are skipped.
+fn parse_jwks(text: &str) -> Result<Vec<Jwk>, String> {
+    let jwks = Json::parse(text)?;
+    let keys = match jwks.get("keys") {
+        Some(Json::Array(keys)) => keys,
+        _ => return Err("expected a JWK Set with a \"keys\" array".to_string()),
+    };
+    let mut out = Vec::new();
+    for key in keys {
+        let field = |name| key.get(name).and_then(Json::as_str);
+        if field("kty") != Some("RSA") || field("use").is_some_and(|u| u != "sig") {
+            continue;
+        }
+        let number = |name| field(name).and_then(base64::decode_url).ok_or(format!("RSA key without a valid '{}'", name));
+        out.push(Jwk {
+            kid: field("kid").map(|kid| kid.to_string()),
+            key: RsaPublicKey::new(&number("n")?, &number("e")?)?,
+        });
+    }
+    Ok(out)
+}
+
+/// Checks the Bearer token and records its claims (and `sub` as the remote
+/// user) in the request extensions; a 401 challenge otherwise (RFC 6750).
+pub async fn authenticate(config: &JwtConfig, realm: &str, req: &mut Request<Body>) -> Result<(), Response<Body>> {
+    let token = req.headers().get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim().to_string());
+    let token = match token {
+        Some(token) => token,
+        None => return Err(unauthorized(realm, None)),
+    };
+    let claims = match verify(config, &token).await {
+        Ok(claims) => claims,
+        Err(reason) => return Err(unauthorized(realm, Some(reason))),
+    };
+
+    if let Some(sub) = claims.get("sub").and_then(Json::as_str) {
+        req.extensions_mut().insert(RemoteUser(sub.to_string()));
+    }
+    let claims = match claims {
+        Json::Object(members) => members.into_iter()
+            .filter(|(name, _)| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+            .filter_map(|(name, value)| Some((name, claim_value(&value)?)))
+            .filter(|(_, value)| !value.contains('\0'))
+            .collect(),
+        _ => Vec::new(),
+    };
+    req.extensions_mut().insert(JwtClaims(claims));
+    Ok(())
+}
+
+async fn verify(config: &JwtConfig, token: &str) -> Result<Json, &'static str> {
+    let claims = verify_token(config.secret.as_deref(), &config.keys, token).await?;
+    if let Some(issuer) = &config.issuer {
+        if claims.get("iss").and_then(Json::as_str) != Some(issuer) {
+            return Err("wrong issuer");
+        }
+    }
+    if let Some(audience) = &config.audience {
+        if !has_audience(&claims, audience) {
+            return Err("wrong audience");
+        }
+    }
+    Ok(claims)
+}
+
+/// Checks the signature and the exp and nbf claims of a compact JWT and
+/// returns its claims.
+pub async fn verify_token(secret: Option<&[u8]>, keys: &KeySet, token: &str) -> Result<Json, &'static str> {
+    let parts: Vec<&str> = token.split('.').collect();
+    let (header, payload, signature) = match parts.as_slice() {
+        [header, payload, signature] => (*header, *payload, *signature),
+        _ => return Err("malformed token"),
+    };
+    let decode_json = |part| base64::decode_url(part)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|text| Json::parse(&text).ok())
+        .ok_or("malformed token");
+    let header = decode_json(header)?;
+    let claims = decode_json(payload)?;
+    let signature = base64::decode_url(signature).ok_or("malformed token")?;
+    let message = &token[..token.rfind('.').unwrap()];
+
+    // The algorithm is only trusted when a key of that kind is configured,
+    // so "none" or an HS256 token signed with a public key is refused.
+    let kid = header.get("kid").and_then(Json::as_str);
+    let valid = match (header.get("alg").and_then(Json::as_str), secret) {
+        (Some("HS256"), Some(secret)) => constant_time_eq(&hmac_sha256(secret, message.as_bytes()), &signature),
+        (Some("RS256"), _) => keys.verify_rs256(kid, message.as_bytes(), &signature).await,
+        _ => false,
+    };
+    if !valid {
+        return Err("invalid signature");
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as f64;
+    let time = |name| claims.get(name).and_then(Json::as_f64);
+    if time("exp").is_some_and(|exp| now > exp + LEEWAY as f64) {
+        return Err("token expired");
+    }
+    if time("nbf").is_some_and(|nbf| now + (LEEWAY as f64) < nbf) {
+        return Err("token not yet valid");
+    }
+    Ok(claims)
+}
+
+pub fn has_audience(claims: &Json, audience: &str) -> bool {
+    match claims.get("aud") {
+        Some(Json::String(aud)) => aud == audience,
+        Some(Json::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+        _ => false,
+    }
+}
+
+// Strings, numbers and booleans as written; arrays of strings comma-separated.
+// Claims that cannot become environment variables are left out.
+fn claim_value(value: &Json) -> Option<String> {
+    match value {
+        Json::String(s) => Some(s.clone()),
+        Json::Bool(b) => Some(b.to_string()),
+        Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => Some((*n as i64).to_string()),
+        Json::Number(n) => Some(n.to_string()),
+        Json::Array(items) => items.iter().map(|item| item.as_str()).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+        _ => None,
+    }
+}
+
+fn unauthorized(realm: &str, error: Option<&str>) -> Response<Body> {
+    let challenge = match error {
+        Some(error) => format!("Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"", realm, error),
+        None => format!("Bearer realm=\"{}\"", realm),
+    };
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge).unwrap())
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>401 Unauthorized</html>"))
+        .unwrap()
+}
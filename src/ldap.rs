@@ -0,0 +1,236 @@
+// Just enough LDAPv3 (RFC 4511) to check a password with a simple bind and
+// look up group membership. Messages are BER encoded by hand.
+
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_UNBIND_REQUEST: u8 = 0x42;
+const TAG_SEARCH_REQUEST: u8 = 0x63;
+const TAG_SEARCH_ENTRY: u8 = 0x64;
+const TAG_SEARCH_DONE: u8 = 0x65;
+const TAG_SIMPLE_AUTH: u8 = 0x80;
+const TAG_FILTER_OR: u8 = 0xa1;
+const TAG_FILTER_EQUALITY: u8 = 0xa3;
+
+/// Settings for `--ldap-auth` zones: users bind as the DN made from
+/// `--ldap-bind-dn`, and must be in `--ldap-group` when that is set.
+#[derive(Default)]
+pub struct LdapConfig {
+    /// `host:port` of an ldap:// server.
+    pub server: Option<String>,
+    /// DN template where `{user}` stands for the user name.
+    pub bind_dn: Option<String>,
+    pub group: Option<String>,
+}
+
+impl LdapConfig {
+    pub fn set_url(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let authority = value.strip_prefix("ldap://").ok_or_else(|| invalid("only ldap:// URLs are supported"))?;
+        let authority = authority.trim_end_matches('/');
+        if authority.is_empty() || authority.contains('/') {
+            return Err(invalid("expected ldap://HOST[:PORT]"));
+        }
+        let has_port = match authority.rsplit_once(':') {
+            Some((host, port)) => !host.ends_with(':') && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+        self.server = Some(if has_port { authority.to_string() } else { format!("{}:389", authority) });
+        Ok(())
+    }
+
+    /// Binds as the user and checks group membership. Connection problems are
+    /// reported separately from wrong credentials so they can be logged.
+    pub async fn verify(&self, user: &str, password: &str) -> io::Result<bool> {
+        let (server, template) = match (&self.server, &self.bind_dn) {
+            (Some(server), Some(template)) => (server, template),
+            _ => return Ok(false),
+        };
+        // An empty password would be an unauthenticated bind, which succeeds
+        // without checking anything (RFC 4513 section 5.1.2).
+        if password.is_empty() || user.is_empty() {
+            return Ok(false);
+        }
+        let dn = template.replace("{user}", &escape_dn_value(user));
+        tokio::time::timeout(TIMEOUT, self.bind_and_search(server, &dn, user, password))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "LDAP server timed out")))
+    }
+
+    async fn bind_and_search(&self, server: &str, dn: &str, user: &str, password: &str) -> io::Result<bool> {
+        let mut stream = TcpStream::connect(server).await?;
+
+        let bind = [
+            tlv(TAG_INTEGER, &[3]),
+            tlv(TAG_OCTET_STRING, dn.as_bytes()),
+            tlv(TAG_SIMPLE_AUTH, password.as_bytes()),
+        ].concat();
+        stream.write_all(&message(1, TAG_BIND_REQUEST, &bind)).await?;
+        let (tag, op) = read_message(&mut stream, 1).await?;
+        if tag != TAG_BIND_RESPONSE || result_code(&op)? != 0 {
+            return Ok(false);
+        }
+
+        let allowed = match &self.group {
+            None => true,
+            Some(group) => {
+                // groupOfNames, groupOfUniqueNames and posixGroup members.
+                let filter = tlv(TAG_FILTER_OR, &[
+                    equality("member", dn),
+                    equality("uniqueMember", dn),
+                    equality("memberUid", user),
+                ].concat());
+                let search = [
+                    tlv(TAG_OCTET_STRING, group.as_bytes()),
+                    tlv(TAG_ENUMERATED, &[0]), // baseObject
+                    tlv(TAG_ENUMERATED, &[0]), // neverDerefAliases
+                    tlv(TAG_INTEGER, &[1]),
+                    tlv(TAG_INTEGER, &[TIMEOUT.as_secs() as u8]),
+                    tlv(0x01, &[0]), // typesOnly FALSE
+                    filter,
+                    tlv(TAG_SEQUENCE, &tlv(TAG_OCTET_STRING, b"1.1")), // no attributes
+                ].concat();
+                stream.write_all(&message(2, TAG_SEARCH_REQUEST, &search)).await?;
+                let mut found = false;
+                loop {
+                    let (tag, op) = read_message(&mut stream, 2).await?;
+                    match tag {
+                        TAG_SEARCH_ENTRY => found = true,
+                        TAG_SEARCH_DONE => break found && result_code(&op)? == 0,
+                        _ => {}, // continuation references
+                    }
+                }
+            },
+        };
+
+        let _ = stream.write_all(&message(3, TAG_UNBIND_REQUEST, &[])).await;
+        Ok(allowed)
+    }
+}
+
+fn equality(attribute: &str, value: &str) -> Vec<u8> {
+    tlv(TAG_FILTER_EQUALITY, &[
+        tlv(TAG_OCTET_STRING, attribute.as_bytes()),
+        tlv(TAG_OCTET_STRING, value.as_bytes()),
+    ].concat())
+}
+
+// LDAPMessage ::= SEQUENCE { messageID, protocolOp }
+fn message(id: u8, op_tag: u8, op: &[u8]) -> Vec<u8> {
+    tlv(TAG_SEQUENCE, &[tlv(TAG_INTEGER, &[id]), tlv(op_tag, op)].concat())
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|&b| b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(&bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+async fn read_tlv(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let tag = stream.read_u8().await?;
+    let first = stream.read_u8().await?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            return Err(invalid("unsupported BER length"));
+        }
+        let mut len = 0usize;
+        for _ in 0..count {
+            len = len << 8 | stream.read_u8().await? as usize;
+        }
+        len
+    };
+    if len > 1024 * 1024 {
+        return Err(invalid("LDAP message too large"));
+    }
+    let mut content = vec![0u8; len];
+    stream.read_exact(&mut content).await?;
+    Ok((tag, content))
+}
+
+// Splits one element off the front of a BER buffer.
+fn split_tlv(data: &[u8]) -> io::Result<(u8, &[u8], &[u8])> {
+    let malformed = || invalid("malformed LDAP message");
+    let (&tag, rest) = data.split_first().ok_or_else(malformed)?;
+    let (&first, rest) = rest.split_first().ok_or_else(malformed)?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err(malformed());
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return Err(malformed());
+    }
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+// Reads the next message, checks its ID and returns the protocolOp.
+async fn read_message(stream: &mut TcpStream, id: u8) -> io::Result<(u8, Vec<u8>)> {
+    let (tag, content) = read_tlv(stream).await?;
+    if tag != TAG_SEQUENCE {
+        return Err(invalid("malformed LDAP message"));
+    }
+    let (tag, message_id, rest) = split_tlv(&content)?;
+    if tag != TAG_INTEGER || message_id != [id] {
+        return Err(invalid("unexpected LDAP message ID"));
+    }
+    let (op_tag, op, _controls) = split_tlv(rest)?;
+    Ok((op_tag, op.to_vec()))
+}
+
+// LDAPResult starts with the resultCode ENUMERATED.
+fn result_code(op: &[u8]) -> io::Result<u32> {
+    let (tag, code, _) = split_tlv(op)?;
+    if tag != TAG_ENUMERATED {
+        return Err(invalid("malformed LDAP result"));
+    }
+    Ok(code.iter().fold(0u32, |code, &b| code << 8 | b as u32))
+}
+
+// Escapes a user name for use as an attribute value in a DN (RFC 4514 section 2.4).
+fn escape_dn_value(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut out = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                out.push('\\');
+                out.push(c);
+            },
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if i == 0 || i == last => out.push_str("\\ "),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,153 @@
+use std::env;
+use std::io;
+use std::process;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use tokio::net::{TcpListener, UnixListener};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::config::Config;
+use crate::server::Listener;
+
+const BACKLOG: i32 = 1024;
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes over sockets passed by systemd socket activation (sd_listen_fds).
+/// Returns an empty list when the server was not socket-activated.
+pub fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    let pid_matches = env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map(|pid| pid == process::id())
+        .unwrap_or(false);
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+    // The variables are meant for this process only, not for the scripts it runs.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let count = match count {
+        Some(count) if pid_matches => count,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these descriptors to us and nothing else owns them.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+        if !socket.is_listener()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("fd {}: passed socket is not listening", fd)));
+        }
+        socket.set_nonblocking(true)?;
+        let is_inet = socket.local_addr()?.as_socket().is_some();
+        let fd = socket.into_raw_fd();
+        if is_inet {
+            // SAFETY: `fd` was just released from `socket` and is a listening TCP socket.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listeners.push(Listener::Tcp(TcpListener::from_std(listener)?));
+        } else {
+            // SAFETY: `fd` was just released from `socket` and is a listening Unix socket.
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listeners.push(Listener::Unix(UnixListener::from_std(listener)?));
+        }
+    }
+    Ok(listeners)
+}
+
+/// Binds every `--bind` entry. Host names may resolve to several addresses
+/// (e.g. `localhost` to both 127.0.0.1 and ::1), each getting its own socket.
+pub async fn bind_tcp(config: &Config) -> io::Result<Vec<TcpListener>> {
+    match config.port {
+        Some(port) => bind_port(config, port).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Binds the plain-HTTP port that redirects to HTTPS on the same `--bind` addresses.
+pub async fn bind_redirect(config: &Config) -> io::Result<Vec<TcpListener>> {
+    match config.https_redirect_port {
+        Some(port) => bind_port(config, port).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Binds the `--metrics-port` on the same `--bind` addresses.
+pub async fn bind_metrics(config: &Config) -> io::Result<Vec<TcpListener>> {
+    match config.metrics.port {
+        Some(port) => bind_port(config, port).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn bind_port(config: &Config, port: u16) -> io::Result<Vec<TcpListener>> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for host in &config.bind {
+        for addr in resolve(host, port).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    // With several acceptors every address gets that many SO_REUSEPORT sockets
+    // and the kernel spreads incoming connections across them.
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        for _ in 0..config.acceptors {
+            let listener = bind_addr(addr, config.ipv6_only, config.acceptors > 1)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)))?;
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no addresses found", host)));
+    }
+    Ok(addrs)
+}
+
+fn bind_addr(addr: SocketAddr, ipv6_only: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        // Without IPV6_V6ONLY a `[::]` socket also accepts IPv4 clients.
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a Unix domain socket, replacing a stale socket file left behind by a
+/// previous run but refusing to steal one that a live server still answers on.
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: exists and is not a socket", path.display())));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{}: another server is listening", path.display())));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
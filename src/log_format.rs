@@ -0,0 +1,413 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::header::{HeaderMap, HeaderName, REFERER, USER_AGENT};
+use hyper::{Body, Method, Request, StatusCode, Uri, Version};
+use crate::config::Config;
+use crate::json::Json;
+use crate::timing::Timings;
+use crate::tls::Session;
+use crate::{access_log, event, geoip, metrics, oidc};
+
+/// The access log line used without `--log-format`.
+pub const DEFAULT: &str = "$request_method $remote_addr $uri -> $status ($status_text)";
+/// The default once `--geoip-db` is loaded.
+pub const DEFAULT_GEOIP: &str = "$request_method $remote_addr $country $uri -> $status ($status_text)";
+
+// Incoming request IDs longer than this, or with other characters, are
+// replaced by a generated one rather than copied into the log.
+const MAX_REQUEST_ID: usize = 128;
+
+// Request headers in every JSON line.
+static JSON_HEADERS: [HeaderName; 2] = [USER_AGENT, REFERER];
+
+/// An nginx style `--log-format` template such as
+/// `'$remote_addr [$time_local] "$request" $status $body_bytes_sent $request_time'`,
+/// or `json` for one JSON object per line with all of those fields.
+/// `${name}` separates a variable from text that follows it.
+pub struct LogFormat {
+    kind: Kind,
+}
+
+enum Kind {
+    Template(Vec<Part>),
+    Json,
+}
+
+enum Part {
+    Text(String),
+    Var(Var),
+}
+
+enum Var {
+    RemoteAddr,
+    Country,
+    Method,
+    Uri,
+    Args,
+    Request,
+    Protocol,
+    Status,
+    StatusText,
+    BodyBytesSent,
+    RequestTime,
+    RequestId,
+    TimeLocal,
+    TimeIso8601,
+    SslClientSDn,
+    Header(HeaderName),
+}
+
+impl LogFormat {
+    pub fn parse(option: &str, value: &str) -> Result<LogFormat, String> {
+        if value == "json" {
+            return Ok(LogFormat { kind: Kind::Json });
+        }
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = value;
+        while let Some(dollar) = rest.find('$') {
+            text.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => match braced.split_once('}') {
+                    Some((name, after)) => (name, after),
+                    None => return Err(format!("invalid value '{}' for '{}': unclosed '${{'", value, option)),
+                },
+                None => {
+                    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                },
+            };
+            if name.is_empty() {
+                text.push('$');
+                continue;
+            }
+            let var = Var::parse(name)
+                .ok_or_else(|| format!("invalid value '{}' for '{}': unknown variable '${}'", value, option, name))?;
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(Part::Var(var));
+            rest = after;
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(LogFormat { kind: Kind::Template(parts) })
+    }
+
+    pub fn is_json(&self) -> bool {
+        matches!(self.kind, Kind::Json)
+    }
+
+    fn headers(&self) -> Vec<&HeaderName> {
+        match &self.kind {
+            Kind::Template(parts) => parts.iter()
+                .filter_map(|part| match part {
+                    Part::Var(Var::Header(name)) => Some(name),
+                    _ => None,
+                })
+                .collect(),
+            Kind::Json => JSON_HEADERS.iter().collect(),
+        }
+    }
+
+    fn uses(&self, wanted: fn(&Var) -> bool) -> bool {
+        match &self.kind {
+            Kind::Template(parts) => parts.iter().any(|part| matches!(part, Part::Var(var) if wanted(var))),
+            Kind::Json => true,
+        }
+    }
+
+    fn render(&self, entry: &Entry, status: StatusCode, bytes_sent: u64) -> String {
+        let parts = match &self.kind {
+            Kind::Template(parts) => parts,
+            Kind::Json => return render_json(entry, status, bytes_sent),
+        };
+        let mut line = String::new();
+        for part in parts {
+            let var = match part {
+                Part::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                },
+                Part::Var(var) => var,
+            };
+            match var {
+                Var::RemoteAddr => line.push_str(&entry.client_addr.ip().to_string()),
+                Var::Country => line.push_str(entry.country.as_deref().unwrap_or(geoip::UNKNOWN)),
+                Var::Method => line.push_str(entry.method.as_str()),
+                Var::Uri => line.push_str(entry.uri.path()),
+                Var::Args => line.push_str(entry.uri.query().unwrap_or("")),
+                Var::Request => {
+                    let target = entry.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                    line.push_str(&format!("{} {} {:?}", entry.method, target, entry.version));
+                },
+                Var::Protocol => line.push_str(&format!("{:?}", entry.version)),
+                Var::Status => line.push_str(status.as_str()),
+                Var::StatusText => line.push_str(status.canonical_reason().unwrap_or("Unknown")),
+                Var::BodyBytesSent => line.push_str(&bytes_sent.to_string()),
+                Var::RequestTime => line.push_str(&format!("{:.3}", entry.start.elapsed().as_secs_f64())),
+                Var::RequestId => line.push_str(&entry.request_id),
+                Var::TimeLocal => line.push_str(&time_local(entry.time)),
+                Var::TimeIso8601 => line.push_str(&time_iso8601(entry.time)),
+                Var::SslClientSDn => line.push_str(entry.client_subject.as_deref().unwrap_or("-")),
+                Var::Header(name) => line.push_str(entry.header(name).unwrap_or("-")),
+            }
+        }
+        line
+    }
+}
+
+fn render_json(entry: &Entry, status: StatusCode, bytes_sent: u64) -> String {
+    let string = |s: &str| Json::String(s.to_string());
+    let mut fields = vec![
+        ("time".to_string(), string(&time_iso8601(entry.time))),
+        ("type".to_string(), string("access")),
+        ("remote_addr".to_string(), string(&entry.client_addr.ip().to_string())),
+    ];
+    if let Some(country) = &entry.country {
+        fields.push(("country".to_string(), string(country)));
+    }
+    if let Some(subject) = &entry.client_subject {
+        fields.push(("ssl_client_s_dn".to_string(), string(subject)));
+    }
+    fields.extend([
+        ("request_id".to_string(), string(&entry.request_id)),
+        ("method".to_string(), string(entry.method.as_str())),
+        ("uri".to_string(), string(entry.uri.path())),
+        ("args".to_string(), entry.uri.query().map_or(Json::Null, string)),
+        ("protocol".to_string(), string(&format!("{:?}", entry.version))),
+        ("status".to_string(), Json::Number(status.as_u16() as f64)),
+        ("body_bytes_sent".to_string(), Json::Number(bytes_sent as f64)),
+        ("request_time".to_string(), Json::Number((entry.start.elapsed().as_secs_f64() * 1000.0).round() / 1000.0)),
+    ]);
+    for name in &JSON_HEADERS {
+        let key = format!("http_{}", name.as_str().replace('-', "_"));
+        fields.push((key, entry.header(name).map_or(Json::Null, string)));
+    }
+    Json::Object(fields).to_string()
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Var> {
+        Some(match name {
+            "remote_addr" => Var::RemoteAddr,
+            "country" => Var::Country,
+            "request_method" => Var::Method,
+            "uri" => Var::Uri,
+            "args" => Var::Args,
+            "request" => Var::Request,
+            "server_protocol" => Var::Protocol,
+            "status" => Var::Status,
+            "status_text" => Var::StatusText,
+            "body_bytes_sent" => Var::BodyBytesSent,
+            "request_time" => Var::RequestTime,
+            "request_id" => Var::RequestId,
+            "time_local" => Var::TimeLocal,
+            "time_iso8601" => Var::TimeIso8601,
+            "ssl_client_s_dn" => Var::SslClientSDn,
+            _ => {
+                // $http_user_agent is the User-Agent header.
+                let header = name.strip_prefix("http_").filter(|h| !h.is_empty())?;
+                Var::Header(HeaderName::from_bytes(header.replace('_', "-").as_bytes()).ok()?)
+            },
+        })
+    }
+}
+
+/// What the log line needs from a request, taken before it is handled since
+/// handlers consume it and rewrites change its URI.
+pub struct Entry {
+    config: Arc<Config>,
+    start: Instant,
+    time: SystemTime,
+    client_addr: SocketAddr,
+    country: Option<String>,
+    client_subject: Option<String>,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    request_id: String,
+    headers: Vec<(HeaderName, String)>,
+    timings: Option<Arc<Timings>>,
+    _in_flight: metrics::InFlight,
+}
+
+impl Entry {
+    pub fn new(config: Arc<Config>, req: &Request<Body>, client_addr: SocketAddr) -> Entry {
+        let format = &config.access_log.format;
+        let header = |name: &HeaderName| req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+        let headers = format.headers().into_iter()
+            .filter_map(|name| Some((name.clone(), header(name)?)))
+            .collect();
+        let request_id = req.extensions().get::<RequestId>().map(|RequestId(id)| id.clone()).unwrap_or_default();
+        let country = if format.uses(|var| matches!(var, Var::Country)) {
+            geoip::country(client_addr.ip())
+        } else {
+            None
+        };
+        Entry {
+            start: Instant::now(),
+            time: SystemTime::now(),
+            client_addr,
+            country,
+            client_subject: req.extensions().get::<Session>().and_then(|session| session.client_subject.clone()),
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            request_id,
+            headers,
+            timings: Timings::of(req),
+            config,
+            _in_flight: metrics::request_started(),
+        }
+    }
+
+    /// Leaves the request out of `--slow-request` warnings, e.g. an event
+    /// stream that stays open by design.
+    pub fn untimed(&mut self) {
+        self.timings = None;
+    }
+
+    fn header(&self, name: &HeaderName) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The ID of a request in `$request_id` and in errors logged for it.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn assign<B>(req: &mut Request<B>) {
+        let id = request_id(req.headers());
+        req.extensions_mut().insert(RequestId(id));
+    }
+}
+
+// Keeps a sane X-Request-Id set by a proxy in front, else makes one up.
+fn request_id(headers: &HeaderMap) -> String {
+    let incoming = headers.get("X-Request-Id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)));
+    if let Some(id) = incoming {
+        return id.to_string();
+    }
+    // A random per-process prefix and a counter: unique without reading
+    // /dev/urandom for every request.
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let prefix = PREFIX.get_or_init(|| oidc::random_bytes(8).iter().fold(0, |n, b| n << 8 | *b as u64));
+    format!("{:016x}{:016x}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A response body that writes the access log line once it has been sent,
+/// or once the client went away, so the line has the bytes sent and the
+/// full duration.
+pub struct LoggedBody {
+    inner: Body,
+    entry: Option<Entry>,
+    status: StatusCode,
+    bytes_sent: u64,
+    responded: Instant,
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, entry: Entry, status: StatusCode) -> LoggedBody {
+        LoggedBody { inner, entry: Some(entry), status, bytes_sent: 0, responded: Instant::now() }
+    }
+
+    fn finish(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            let route = entry.config.metrics.route(entry.uri.path());
+            metrics::request_finished(&entry.method, route, self.status, self.bytes_sent, entry.start.elapsed());
+            if self.status.is_server_error() {
+                metrics::record_error(&format!("{} {} {} -> {}", entry.client_addr.ip(), entry.method, entry.uri.path(), self.status));
+            }
+            if let (Some(threshold), Some(timings)) = (entry.config.slow_request, &entry.timings) {
+                let finished = Instant::now();
+                if finished.duration_since(entry.start) >= threshold {
+                    event::warning(&format!(
+                        "Slow request: {} {} {} -> {} took {:.3}s: {}",
+                        entry.client_addr.ip(), entry.method, entry.uri.path(), self.status.as_u16(),
+                        finished.duration_since(entry.start).as_secs_f64(), timings.describe(self.responded, finished),
+                    ));
+                }
+            }
+            if !entry.config.access_log.wants(entry.uri.path(), self.status) {
+                return;
+            }
+            access_log::write(&entry.config.access_log.format.render(&entry, self.status, self.bytes_sent));
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let result = Pin::new(&mut self.inner).poll_data(cx);
+        match &result {
+            Poll::Ready(Some(Ok(chunk))) => self.bytes_sent += chunk.len() as u64,
+            Poll::Ready(_) => self.finish(),
+            Poll::Pending => {},
+        }
+        result
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// `15/Oct/2026:09:30:00 +0000`; times are logged in UTC.
+fn time_local(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = civil(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hour, minute, second)
+}
+
+// `2026-10-15T09:30:00+00:00`
+pub fn time_iso8601(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil(time);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00", year, month, day, hour, minute, second)
+}
+
+// Calendar date and time of day in UTC, after Howard Hinnant's days_from_civil inverse.
+pub fn civil(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
@@ -0,0 +1,131 @@
+// Greedy LZ77 parsing over a sliding window, shared by the brotli and zstd
+// encoders. Matches are found through hash chains over 4-byte prefixes and
+// may reach back into input parsed by earlier calls.
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 4096;
+// As in zlib, a shortest match this far back costs more than its literals.
+const TOO_FAR: usize = 4096;
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+
+/// `literals` bytes copied as they are, then `length` bytes repeated from
+/// `distance` bytes back.
+pub struct Sequence {
+    pub literals: usize,
+    pub length: usize,
+    pub distance: usize,
+}
+
+pub struct Matcher {
+    window: usize,
+    max_chain: usize,
+    history: Vec<u8>,
+    base: usize,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl Matcher {
+    /// Matches reach at most `window` bytes back; `max_chain` bounds the
+    /// candidates tried at each position.
+    pub fn new(window: usize, max_chain: usize) -> Self {
+        Matcher {
+            window,
+            max_chain,
+            history: Vec::new(),
+            base: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; window],
+        }
+    }
+
+    /// Splits `data` into sequences, returning them with the number of
+    /// literals left after the last match.
+    pub fn parse(&mut self, data: &[u8]) -> (Vec<Sequence>, usize) {
+        let start = self.history.len();
+        self.history.extend_from_slice(data);
+        let end = self.history.len();
+
+        let mut sequences = Vec::new();
+        let mut literals = 0;
+        let mut i = start;
+        while i < end {
+            let (length, distance) = self.find_match(i, end);
+            if length >= MIN_MATCH {
+                sequences.push(Sequence { literals, length, distance });
+                literals = 0;
+                for p in i..i + length {
+                    self.insert(p, end);
+                }
+                i += length;
+            } else {
+                literals += 1;
+                self.insert(i, end);
+                i += 1;
+            }
+        }
+
+        if self.history.len() > self.window {
+            let drop = self.history.len() - self.window;
+            self.history.drain(..drop);
+            self.base += drop;
+        }
+        (sequences, literals)
+    }
+
+    fn hash(&self, i: usize) -> usize {
+        let bytes = [self.history[i], self.history[i + 1], self.history[i + 2], self.history[i + 3]];
+        (u32::from_le_bytes(bytes).wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, i: usize, end: usize) {
+        if i + MIN_MATCH > end {
+            return;
+        }
+        let h = self.hash(i);
+        let abs = self.base + i;
+        self.prev[abs % self.window] = self.head[h];
+        self.head[h] = abs + 1;
+    }
+
+    fn find_match(&self, i: usize, end: usize) -> (usize, usize) {
+        if i + MIN_MATCH > end {
+            return (0, 0);
+        }
+        let abs = self.base + i;
+        let max_len = MAX_MATCH.min(end - i);
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        let mut candidate = self.head[self.hash(i)];
+        let mut chain = self.max_chain;
+
+        while candidate != 0 && chain > 0 {
+            let c = candidate - 1;
+            if c >= abs || c < self.base || abs - c > self.window {
+                break;
+            }
+            let ci = c - self.base;
+            let mut len = 0;
+            while len < max_len && self.history[ci + len] == self.history[i + len] {
+                len += 1;
+            }
+            if len > best_len && (len > MIN_MATCH || abs - c <= TOO_FAR) {
+                best_len = len;
+                best_dist = abs - c;
+                if len == max_len {
+                    break;
+                }
+            }
+            let next = self.prev[c % self.window];
+            // As in the deflate matcher, a link that does not point
+            // backwards belongs to a newer position and ends the chain.
+            if next == 0 || next > c {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        (best_len, best_dist)
+    }
+}
@@ -1,287 +1,1524 @@
+mod access_file;
+mod access_log;
+mod acl;
+mod api_key;
+mod argon2;
+mod auth;
+mod ban;
+mod base64;
+mod bcrypt;
+mod blake2b;
+mod brotli;
+mod cache;
+mod compress;
+mod config;
+mod conn_limit;
+mod cors;
+mod credentials;
+mod cron;
+mod deflate;
+mod event;
+mod fastcgi;
+mod files;
+mod gateway;
+mod geoip;
+mod glob;
+mod health;
+mod huffman;
+mod hooks;
+mod hotlink;
+mod jobs;
+mod json;
+mod jwt;
+mod ldap;
+mod listen;
+mod log_format;
+mod lz77;
+mod md5;
+mod metrics;
+mod mime_map;
+mod oidc;
+mod password;
+mod prefork;
+mod proxy;
+mod proxy_protocol;
+mod range;
+mod rate_limit;
+mod regex;
+mod redirect;
+mod rewrite;
+mod rlimit;
+mod route;
+mod rsa;
+mod sandbox;
+mod scgi;
+mod script_limit;
+mod script_log;
+mod script_meta;
+mod security;
+mod server;
+mod sha1;
+mod sha256;
+mod status;
+mod syslog;
+mod throttle;
+mod timing;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(not(feature = "tls"))]
+#[path = "no_tls.rs"]
+mod tls;
+mod uwsgi;
+mod websocket;
+mod zstd;
+
+use std::borrow::Cow;
 use std::env;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::process::Stdio;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::Command as TokioCommand;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode, Method};
-use mime_guess::{from_path, mime};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use tokio::process::{ChildStdin, Command as TokioCommand};
+use tokio::signal::unix::{signal, SignalKind};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION, TRANSFER_ENCODING};
+use hyper::{Body, Request, Response, StatusCode, Method, Uri};
 use url::form_urlencoded;
-use std::collections::HashMap;
-
-async fn handle_request(req: Request<Body>, root: PathBuf, client_addr: SocketAddr) -> Result<Response<Body>, hyper::Error> {
-    let path = req.uri().path().to_string(); 
-    let full_path = root.join(path.trim_start_matches('/'));
-    let method = req.method().clone();
+use std::collections::{BTreeMap, HashMap};
+use cache::Lookup;
+use compress::compress_response;
+use config::{Cli, Config, HELP, USAGE};
+use files::{find_index, is_denied, map_path, serve_file, TrailingSlash};
+use log_format::RequestId;
+use rewrite::Rewrite;
+use script_meta::{DefaultContentType, ScriptMeta};
+use timing::Phase;
+use server::Listener;
 
-    if full_path.is_dir() || !full_path.starts_with(&root) {
-        let status_code = StatusCode::FORBIDDEN;
-        let status_text = "Forbidden";
-        let message = "<html>403 Forbidden</html>"; 
-        log_request(&method, &path, &client_addr, status_code, status_text);
+async fn handle_request(mut req: Request<Body>, config: Arc<Config>, client_addr: SocketAddr) -> Result<Response<Body>, hyper::Error> {
+    // CORS rules match the URL as requested; routing below uses the rewritten one.
+    let request_path = req.uri().path().to_string();
+    let original_uri = req.uri().clone();
+    timing::queued(req.extensions());
+    // Size limits are checked before any routing, redirects included.
+    let uri_length = req.uri().path_and_query().map(|p| p.as_str().len()).unwrap_or(0);
+    if uri_length > config.max_uri_length {
+        let status_code = StatusCode::URI_TOO_LONG;
+        let message = "<html>414 URI Too Long</html>";
         return Ok(Response::builder()
             .status(status_code)
-            .header("Connection", "close")
             .header("Content-Type", "text/html; charset=utf-8")
             .body(Body::from(message))
             .unwrap());
     }
 
-    if path == "/forbidden.html" {
+    let content_length = req.headers().get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if matches!(content_length, Some(len) if len > config.max_body_size) {
+        return Ok(payload_too_large());
+    }
+
+    if let Some(response) = health::respond(&config, &req).await {
+        return Ok(without_body_for_head(req.method(), response));
+    }
+    if let Some((status_code, location)) = redirect::find_redirect(&config.redirects, req.uri().path(), req.uri().query()) {
+        return Ok(Response::builder()
+            .status(status_code)
+            .header("Location", location)
+            .body(Body::empty())
+            .unwrap());
+    }
+    match rewrite::apply(&config.rewrites, req.uri().path(), req.uri().query()) {
+        Rewrite::Unchanged => {},
+        Rewrite::Internal(target) => match target.parse::<Uri>() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => {
+                let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+                return Ok(Response::builder()
+                    .status(status_code)
+                    .body(Body::from("Internal Server Error"))
+                    .unwrap());
+            }
+        },
+        Rewrite::Redirect(status_code, location) => {
+            return Ok(Response::builder()
+                .status(status_code)
+                .header("Location", location)
+                .body(Body::empty())
+                .unwrap());
+        },
+    }
+    let path = req.uri().path().to_string();
+    let mapped = map_path(&config, &path);
+    let method = req.method().clone();
+    // A job's URLs are guarded like the script that started it.
+    let job_origin = config.jobs.origin(&path);
+    let auth_path = job_origin.as_ref().map_or(path.as_str(), |(script, _)| script.as_str());
+
+    if !config.ip_acl.allows(auth_path, client_addr.ip()) {
         let status_code = StatusCode::FORBIDDEN;
-        let status_text = "Forbidden";
-        let message = "<html>403 Forbidden</html>";
-        log_request(&method, &path, &client_addr, status_code, status_text);
         return Ok(Response::builder()
             .status(status_code)
-            .header("Connection", "close")
             .header("Content-Type", "text/html; charset=utf-8")
-            .body(Body::from(message))
+            .body(Body::from("<html>403 Forbidden</html>"))
             .unwrap());
     }
 
-    if req.method() == Method::GET {
-        if full_path.starts_with(root.join("scripts")) && path.ends_with("simple.sh") {
-            let fixed_response = "Packet received\n";
-            let status_code = StatusCode::OK;
-            let status_text = "OK";
-            log_request(&method, &path, &client_addr, status_code, status_text);
+    if !config.geoip_rules.is_empty() {
+        let country = geoip::country(client_addr.ip()).unwrap_or_else(|| geoip::UNKNOWN.to_string());
+        if !config.geoip_rules.allows(auth_path, &country) {
+            let status_code = StatusCode::FORBIDDEN;
             return Ok(Response::builder()
                 .status(status_code)
-                .header("Content-Type", "text/plain; charset=utf-8")
-                .header("Content-Length", fixed_response.len().to_string())
-                .header("Connection", "close")
-                .body(Body::from(fixed_response))
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>403 Forbidden</html>"))
                 .unwrap());
-        } else if full_path.starts_with(root.join("scripts")) {
-            let response = handle_script(req, full_path).await;
-            if let Ok(ref res) = response {
-                let status_code = res.status();
-                let status_text = res.status().canonical_reason().unwrap_or("Unknown");
-                log_request(&method, &path, &client_addr, status_code, status_text);
-                return response;
-            } else {
-                let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-                let status_text = "Internal Server Error";
-                let message = "Internal Server Error";
-                log_request(&method, &path, &client_addr, status_code, status_text);
+        }
+    }
+
+    if let Err(retry_after) = rate_limit::acquire(config.rate_limit.as_ref(), &config.rate_limit_routes, &path, client_addr.ip()) {
+        return Ok(too_many_requests(retry_after));
+    }
+
+    if cors::is_preflight(&req) {
+        if let Some(rule) = cors::find_rule(&config.cors, &request_path) {
+            return Ok(cors::preflight(rule, &req));
+        }
+    }
+
+    if config.metrics.is_endpoint(&path) {
+        return Ok(without_body_for_head(&method, metrics::endpoint(&req)));
+    }
+
+    if config.status_page.as_deref() == Some(path.as_str()) {
+        return Ok(without_body_for_head(&method, status::page(&config, &req)));
+    }
+
+    if config.bans.is_admin_path(&path) {
+        let response = ban::admin(&config.bans, &req);
+        return Ok(without_body_for_head(&method, response));
+    }
+
+    if let Some(zone) = auth::find_zone(&config.auth_zones, auth_path) {
+        if let Err(response) = auth::authenticate(zone, &config.auth_realm, &config.ldap, &mut req).await {
+            return Ok(response);
+        }
+    }
+
+    if config.oidc.is_callback(&path) {
+        return Ok(oidc::callback(&config.oidc, &req).await);
+    }
+    if config.oidc.covers(auth_path) {
+        if let Some(response) = oidc::authenticate(&config.oidc, &original_uri, &mut req) {
+            return Ok(response);
+        }
+    }
+
+    if config.api_keys.covers(auth_path) {
+        if let Some(response) = api_key::authenticate(&config.api_keys, auth_path, &mut req) {
+            return Ok(response);
+        }
+    }
+
+    if config.jwt.covers(auth_path) {
+        if let Err(response) = jwt::authenticate(&config.jwt, &config.auth_realm, &mut req).await {
+            return Ok(response);
+        }
+    }
+
+    if config.jobs.covers(&path) {
+        if let Some((_, script_path)) = &job_origin {
+            if let Some(response) = script_meta::authenticate(&config, script_path, &mut req).await {
+                return Ok(response);
+            }
+        }
+        return Ok(without_body_for_head(&method, jobs::respond(&config.jobs, &req)));
+    }
+
+    // Rejected like map_path does for files, before a prefix is matched.
+    if proxy::has_dot_dot(&path) && (!config.proxy_routes.is_empty() || !config.gateways.is_empty()) {
+        let status_code = StatusCode::FORBIDDEN;
+        return Ok(Response::builder()
+            .status(status_code)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>403 Forbidden</html>"))
+            .unwrap());
+    }
+
+    if let Some(route) = proxy::find_route(&config.proxy_routes, &path) {
+        return Ok(proxy::forward(route, &config.proxy_cache, req, client_addr).await);
+    }
+
+    if let Some(route) = gateway::find_prefix_route(&config.gateways, &path) {
+        let response = gateway::forward(route, req, None, client_addr, &config).await;
+        return Ok(without_body_for_head(&method, response));
+    }
+
+    match route::find(&config.routes, &method, &path) {
+        route::Match::Found(route, params) => {
+            let script_path = config.root.join(&route.script);
+            if !script_path.is_file() {
+                event::error(&format!("The script {} routed to by {} does not exist", script_path.display(), path));
                 return Ok(Response::builder()
-                    .status(status_code)
-                    .header("Connection", "close")
-                    .body(Body::from(message))
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Internal Server Error"))
                     .unwrap());
             }
+            req.extensions_mut().insert(params);
+            if let Some(response) = script_meta::check(&config, &script_path, &mut req).await {
+                return Ok(response);
+            }
+            if method == Method::POST && script_meta::runs_async(req.extensions()) {
+                return Ok(jobs::start(config.clone(), req, script_path, client_addr).await);
+            }
+            let accept_encoding = req.headers().get("Accept-Encoding").and_then(|v| v.to_str().ok()).map(str::to_string);
+            return match handle_script(req, script_path, client_addr, &config).await {
+                Ok(response) => Ok(without_body_for_head(&method, compress_response(&config.compression, accept_encoding.as_deref(), response))),
+                Err(_) => Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Internal Server Error"))
+                    .unwrap()),
+            };
+        },
+        route::Match::WrongMethod(allowed) => {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header("Allow", allowed.join(", "))
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>405 Method Not Allowed</html>"))
+                .unwrap());
+        },
+        route::Match::None => {},
+    }
+
+    let (base, mount, mut full_path) = match mapped {
+        Some(mapped) => mapped,
+        None => {
+            let status_code = StatusCode::FORBIDDEN;
+            return Ok(Response::builder()
+                .status(status_code)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>403 Forbidden</html>"))
+                .unwrap());
         }
+    };
 
-        
-        match File::open(&full_path).await {
-            Ok(mut file) => {
-                let mut contents = Vec::new();
-                if file.read_to_end(&mut contents).await.is_ok() {
-                    let mime_type = from_path(&full_path).first_or_octet_stream();
-                    let content_type = if mime_type.type_() == mime::TEXT && mime_type.subtype() == mime::HTML {
-                        "text/html; charset=utf-8".to_string()
-                    } else if mime_type.type_() == mime::TEXT && mime_type.subtype() == mime::PLAIN {
-                        "text/plain; charset=utf-8".to_string()
-                    } else {
-                        mime_type.as_ref().to_string()
-                    };
-
-                    let status_code = StatusCode::OK;
-                    let status_text = "OK";
-                    log_request(&method, &path, &client_addr, status_code, status_text);
-                    return Ok(Response::builder()
-                        .status(status_code)
-                        .header("Content-Type", content_type)
-                        .header("Content-Length", contents.len().to_string())
-                        .header("Connection", "close")
-                        .body(Body::from(contents))
-                        .unwrap());
-                } else {
-                    let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-                    let status_text = "Internal Server Error";
-                    let message = "Internal Server Error";
-                    log_request(&method, &path, &client_addr, status_code, status_text);
+    if config.trailing_slash == TrailingSlash::Redirect {
+        let canonical = if full_path.is_dir() && !path.ends_with('/') {
+            Some(format!("{}/", path))
+        } else if path.ends_with('/') && path != "/" && full_path.is_file() {
+            Some(path.trim_end_matches('/').to_string())
+        } else {
+            None
+        };
+        if let Some(location) = canonical {
+            let location = match req.uri().query() {
+                Some(query) => format!("{}?{}", location, query),
+                None => location,
+            };
+            let status_code = StatusCode::MOVED_PERMANENTLY;
+            return Ok(Response::builder()
+                .status(status_code)
+                .header("Location", location)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+
+    if full_path.is_dir() {
+        if let Some(index) = find_index(&full_path, &config.index_files) {
+            full_path = index;
+        }
+    }
+
+    let path_info = config.script_dirs.iter().find_map(|scripts| split_path_info(scripts, &full_path, &path));
+    if let Some((script, path_info)) = path_info {
+        full_path = script;
+        req.extensions_mut().insert(PathInfo(path_info));
+    }
+
+    // With no script of its own, `/scripts/resource` is answered by
+    // `resource.GET.sh`, `resource.POST.sh`, ... next to where it would be.
+    if !full_path.exists() && config.script_dirs.iter().any(|scripts| full_path.starts_with(scripts)) {
+        let mut scripts = method_scripts(&full_path);
+        if !scripts.is_empty() {
+            if !scripts.contains_key("HEAD") {
+                if let Some(get) = scripts.get("GET").cloned() {
+                    scripts.insert("HEAD".to_string(), get);
+                }
+            }
+            match scripts.remove(method.as_str()) {
+                Some(script) => full_path = script,
+                None => {
+                    let allowed = scripts.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
                     return Ok(Response::builder()
-                        .status(status_code)
-                        .header("Connection", "close")
-                        .body(Body::from(message))
+                        .status(StatusCode::METHOD_NOT_ALLOWED)
+                        .header("Allow", allowed)
+                        .header("Content-Type", "text/html; charset=utf-8")
+                        .body(Body::from("<html>405 Method Not Allowed</html>"))
                         .unwrap());
-                }
+                },
+            }
+        }
+    }
+
+    if full_path.is_dir() || !full_path.starts_with(base) {
+        let status_code = StatusCode::FORBIDDEN;
+        let message = "<html>403 Forbidden</html>"; 
+        return Ok(Response::builder()
+            .status(status_code)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(message))
+            .unwrap());
+    }
+
+    let relative_path = format!("{}/{}", mount, full_path.strip_prefix(base).unwrap_or(&full_path).to_string_lossy());
+    if is_denied(&config.deny, &path) || is_denied(&config.deny, &relative_path) {
+        let status_code = StatusCode::FORBIDDEN;
+        let message = "<html>403 Forbidden</html>";
+        return Ok(Response::builder()
+            .status(status_code)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(message))
+            .unwrap());
+    }
+
+    if config.hotlink.blocks(&req, &path, &relative_path) {
+        let response = match &config.hotlink.placeholder {
+            Some(placeholder) => {
+                let mut response = serve_file(&config, &req, placeholder).await;
+                response.headers_mut().insert("Cache-Control", HeaderValue::from_static("private, no-store"));
+                response
             },
-            Err(_) => {
-                let status_code = StatusCode::NOT_FOUND;
-                let status_text = "Not Found";
-                let message = "<html>404 Not Found</html>";
-                log_request(&method, &path, &client_addr, status_code, status_text);
+            None => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>403 Forbidden</html>"))
+                .unwrap(),
+        };
+        return Ok(without_body_for_head(&method, response));
+    }
+
+    if config.access_files {
+        if let Some(response) = access_file::check(&config, base, &full_path, client_addr.ip(), &mut req).await {
+            return Ok(response);
+        }
+    }
+
+    if let Some(route) = gateway::find_file_route(&config.gateways, &full_path) {
+        let response = gateway::forward(route, req, Some(&full_path), client_addr, &config).await;
+        return Ok(without_body_for_head(&method, response));
+    }
+
+    let is_script = config.script_dirs.iter().any(|scripts| full_path.starts_with(scripts));
+    // A script's sidecar file is neither run nor served, and can refuse a
+    // request before the script starts.
+    if is_script && script_meta::is_sidecar(&full_path) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>404 Not Found</html>"))
+            .unwrap());
+    }
+    if is_script && full_path.is_file() {
+        if let Some(response) = script_meta::check(&config, &full_path, &mut req).await {
+            return Ok(response);
+        }
+    }
+    if method == Method::GET || method == Method::HEAD {
+        // Missing scripts fall through to the file handler's 404.
+        if is_script && full_path.is_file() && method == Method::GET && websocket::is_upgrade(&req) {
+            return Ok(websocket::bridge(req, full_path, client_addr, &config).await);
+        } else if is_script && full_path.is_file() {
+            // Cached uncompressed, as stored responses need a Content-Length.
+            let accept_encoding = req.headers().get("Accept-Encoding").and_then(|v| v.to_str().ok()).map(str::to_string);
+            // A signed-in user's or a cookie's response may be personal, so
+            // those requests never use the shared cache.
+            let personal = req.headers().contains_key(COOKIE)
+                || req.extensions().get::<auth::RemoteUser>().is_some()
+                || req.extensions().get::<tls::Session>().is_some_and(|session| session.client_subject.is_some());
+            let lookup = if personal { Lookup::Bypass } else { cache::lookup(&config.script_cache, &req).await };
+            let key = match lookup {
+                Lookup::Hit(response) => {
+                    let response = compress_response(&config.compression, accept_encoding.as_deref(), response);
+                    return Ok(without_body_for_head(&method, response));
+                },
+                Lookup::Miss(key) => Some(key),
+                Lookup::Bypass => None,
+            };
+            let response = match (handle_script(req, full_path, client_addr, &config).await, key) {
+                (Ok(response), Some(key)) => Ok(cache::store(&config.script_cache, key, response).await),
+                (response, _) => response,
+            };
+            if response.is_ok() {
+                return response.map(|res| without_body_for_head(&method, compress_response(&config.compression, accept_encoding.as_deref(), res)));
+            } else {
+                let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+                let message = "Internal Server Error";
                 return Ok(Response::builder()
                     .status(status_code)
-                    .header("Connection", "close")
-                    .header("Content-Type", "text/html; charset=utf-8")
                     .body(Body::from(message))
                     .unwrap());
-            },
+            }
         }
+
+        let accept_encoding = req.headers().get("Accept-Encoding").and_then(|v| v.to_str().ok());
+        let response = compress_response(&config.compression, accept_encoding, serve_file(&config, &req, &full_path).await);
+        return Ok(without_body_for_head(&method, response));
     }
 
-    if full_path.starts_with(root.join("scripts")) && full_path.is_file() {
-        let method = req.method().clone();
-        let uri_path = req.uri().path().to_string();
-        let response = handle_script(req, full_path).await;
-        if let Ok(ref res) = response {
-            let status_code = res.status();
-            let status_text = res.status().canonical_reason().unwrap_or("Unknown");
-            log_request(&method, &uri_path, &client_addr, status_code, status_text);
-            return response;
+    if is_script && full_path.is_file() {
+        if method == Method::POST && script_meta::runs_async(req.extensions()) {
+            return Ok(jobs::start(config.clone(), req, full_path, client_addr).await);
+        }
+        let accept_encoding = req.headers().get("Accept-Encoding").and_then(|v| v.to_str().ok()).map(str::to_string);
+        let response = handle_script(req, full_path, client_addr, &config).await;
+        if response.is_ok() {
+            return response.map(|res| compress_response(&config.compression, accept_encoding.as_deref(), res));
         } else {
             let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            let status_text = "Internal Server Error";
             let message = "Internal Server Error";
-            log_request(&method, &uri_path, &client_addr, status_code, status_text);
             return Ok(Response::builder()
                 .status(status_code)
-                .header("Connection", "close")
                 .body(Body::from(message))
                 .unwrap());
         }
     }
 
     let status_code = StatusCode::METHOD_NOT_ALLOWED;
-    let status_text = "Method Not Allowed";
     let message = "Method Not Allowed";
-    log_request(&method, &path, &client_addr, status_code, status_text);
     Ok(Response::builder()
         .status(status_code)
-        .header("Connection", "close")
         .body(Body::from(message))
         .unwrap())
 }
 
-async fn handle_script(req: Request<Body>, script_path: PathBuf) -> Result<Response<Body>, hyper::Error> {
-    let (parts, body) = req.into_parts();
-    let method = parts.method.to_string();
-    let path = parts.uri.path().to_string();
+// What the script's sidecar file declares fills in what its response lacks.
+async fn handle_script(req: Request<Body>, script_path: PathBuf, client_addr: SocketAddr, config: &Config) -> Result<Response<Body>, hyper::Error> {
+    let meta = req.extensions().get::<Arc<ScriptMeta>>().cloned();
+    let mut response = run_script(req, script_path, client_addr, config).await?;
+    if let Some(meta) = meta {
+        script_meta::apply(&meta, &mut response);
+    }
+    Ok(response)
+}
 
-    let mut env_vars: HashMap<String, String> = parts.headers.iter()
-        .map(|(key, value)| (key.to_string(), value.to_str().unwrap_or("").to_string()))
-        .collect();
-    env_vars.insert("Method".to_string(), method);
-    env_vars.insert("Path".to_string(), path);
+async fn run_script(req: Request<Body>, script_path: PathBuf, client_addr: SocketAddr, config: &Config) -> Result<Response<Body>, hyper::Error> {
+    let (parts, body) = req.into_parts();
+    let mut env_vars = script_env(&parts, &script_path, client_addr, config);
+    let request_id = parts.extensions.get::<RequestId>().map_or("-", |RequestId(id)| id.as_str());
 
-    if let Some(query) = parts.uri.query() {
-        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
-            env_vars.insert(format!("Query_{}", key), value.to_string());
-        }
+    // A declared length over the limit is refused before the script starts;
+    // chunked bodies are cut off once they pass it.
+    let declared_length = parts.headers.get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > config.max_body_size) {
+        return Ok(payload_too_large());
     }
+    let body = match body_vars(&parts, body, config, &mut env_vars).await {
+        Ok(body) => body,
+        Err(BodyError::TooLarge) => return Ok(payload_too_large()),
+        Err(BodyError::Timeout) => return Ok(request_timeout()),
+        Err(BodyError::Read) => {
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Failed to execute script"))
+                .unwrap());
+        },
+    };
 
-    let mut cmd = TokioCommand::new(&script_path);
-    cmd.envs(&env_vars);
+    // Its own process group, so a timeout also kills what the script started.
+    let mut std_cmd = script_command(&script_path, &env_vars, config);
+    std_cmd.process_group(0);
+    let mut cmd = TokioCommand::from(std_cmd);
+    if let Some(pool) = config.prefork.pool(&script_path) {
+        return Ok(pool.respond(env_vars, body, client_addr, &parts.extensions, config).await);
+    }
+    let has_body = parts.method == Method::POST;
+    let permit = match config.script_limits.acquire(&script_path).await {
+        Some(permit) => permit,
+        None => return Ok(service_unavailable()),
+    };
 
-    if parts.method == Method::POST {
-        if let Ok(body_bytes) = hyper::body::to_bytes(body).await {
-            cmd.stdin(Stdio::piped());
-            cmd.stdout(Stdio::piped());
-            cmd.stderr(Stdio::piped());
+    cmd.stdin(if has_body { Stdio::piped() } else { Stdio::null() });
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
 
-            let mut child = cmd.spawn().expect("Failed to execute script");
-            let mut stdin = child.stdin.take().expect("Failed to open stdin");
-            tokio::spawn(async move {
-                stdin.write_all(&body_bytes).await.expect("Failed to write to stdin");
-            });
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            metrics::script_failed();
+            event::error(&format!("Failed to execute {}: {}", script_path.display(), e));
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>500 Internal Server Error</html>"))
+                .unwrap());
+        },
+    };
+    let started = Instant::now();
+    let mut stop = StopOnDrop::new(child.id(), &script_path);
+    let running = (metrics::script_started(&script_path, client_addr.ip()), permit);
+    let watchdog = script_meta::timeout(&parts.extensions, config).map(|timeout| Watchdog::start(child.id(), timeout));
+    // The body is copied into stdin while the script runs, so it is never
+    // held in memory as a whole.
+    let body_pipe = if has_body {
+        let stdin = child.stdin.take().expect("Failed to open stdin");
+        let (limit, body_timeout) = (config.max_body_size, config.body_timeout);
+        Some(tokio::spawn(async move {
+            let reading = Instant::now();
+            let result = tokio::time::timeout(body_timeout, pipe_body(body, stdin, limit)).await
+                .unwrap_or(Err(BodyError::Timeout));
+            (result, reading.elapsed())
+        }))
+    } else {
+        None
+    };
 
-            let output = child.wait_with_output().await.expect("Failed to read stdout");
-            let response_body = if output.status.success() {
-                output.stdout
-            } else {
-                output.stderr
-            };
+    // Output is collected until the script exits, passes SCRIPT_BUFFER bytes
+    // or has run for SCRIPT_STREAM_AFTER and written something. Quick scripts
+    // so still answer 500 with their stderr when they fail, and silent ones
+    // 504 when they time out; the others are streamed as they write, and a
+    // failure then can only be logged.
+    let stdout = child.stdout.take().expect("Failed to open stdout");
+    // With --script-log-dir, stderr is read, and logged, as the script runs.
+    let run_log = config.script_log.as_ref().map(|log| log.open(&config.root, &script_path, request_id));
+    let mut stdout: Box<dyn AsyncRead + Send + Unpin> = match &run_log {
+        Some(run_log) if run_log.stdout => Box::new(run_log.tee(stdout, "stdout")),
+        _ => Box::new(stdout),
+    };
+    let logged_stderr = run_log.map(|run_log| {
+        let stderr = run_log.tee(child.stderr.take().expect("Failed to open stderr"), "stderr");
+        tokio::spawn(read_capped(stderr, SCRIPT_STDERR_LOGGED))
+    });
+    let stream_after = tokio::time::sleep(SCRIPT_STREAM_AFTER);
+    tokio::pin!(stream_after);
+    let mut head = Vec::new();
+    let mut buf = [0u8; 8192];
+    let mut finished = false;
+    let over_limit = |written: usize| config.max_script_output.is_some_and(|max| written as u64 > max);
+    let preamble = loop {
+        match script_preamble(&head) {
+            Preamble::Incomplete => {},
+            preamble => break preamble,
+        }
+        tokio::select! {
+            read = stdout.read(&mut buf) => match read {
+                Ok(0) | Err(_) => {
+                    finished = true;
+                    break Preamble::Plain;
+                },
+                Ok(n) => head.extend_from_slice(&buf[..n]),
+            },
+            _ = &mut stream_after, if !head.is_empty() => break Preamble::Plain,
+        }
+        if over_limit(head.len()) {
+            return Ok(output_too_large(&mut stop, &script_path));
+        }
+    };
+    let (headers, offset) = match preamble {
+        Preamble::Headers(headers, offset) => (headers, offset),
+        _ => (Vec::new(), 0),
+    };
+    let builder = match script_response(&headers) {
+        Ok(builder) => builder,
+        Err(e) => {
+            event::error(&format!("Script {} sent {}", script_path.display(), e));
+            metrics::script_failed();
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>500 Internal Server Error</html>"))
+                .unwrap());
+        },
+    };
+    let mut response_body = head.split_off(offset);
 
-            let status = if output.status.success() {
-                StatusCode::OK
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
+    // Event streams are sent right away.
+    let event_stream = headers.iter()
+        .any(|(name, value)| name == CONTENT_TYPE && value.as_bytes().starts_with(b"text/event-stream"));
+    if !event_stream {
+        while !finished && response_body.len() < SCRIPT_BUFFER {
+            tokio::select! {
+                read = stdout.read(&mut buf) => match read {
+                    Ok(0) | Err(_) => finished = true,
+                    Ok(n) => response_body.extend_from_slice(&buf[..n]),
+                },
+                _ = &mut stream_after, if offset > 0 || !response_body.is_empty() => break,
+            }
+            if over_limit(offset + response_body.len()) {
+                return Ok(output_too_large(&mut stop, &script_path));
+            }
+        }
+    }
 
-            let content_type = "text/plain; charset=utf-8";
+    if !finished {
+        // Event streams are meant to stay open.
+        let watchdog = if event_stream { None } else { watchdog };
+        // The script is owned by the stream, so it is stopped once the client leaves.
+        let errors = logged_stderr.unwrap_or_else(|| {
+            let stderr = child.stderr.take().expect("Failed to open stderr");
+            tokio::spawn(read_capped(stderr, SCRIPT_STDERR_LOGGED))
+        });
+        let request_id = request_id.to_string();
+        // Past --max-script-output the script is killed and the response
+        // cut off, the only way left to tell the client it is incomplete.
+        let stop = Arc::new(Mutex::new(stop));
+        let stop_early = stop.clone();
+        let limit = config.max_script_output;
+        let path = script_path.clone();
+        let stdout = ReaderStream::new(stdout).scan(Some(offset + response_body.len()), move |written, chunk| {
+            let Some(total) = written else { return future::ready(None) };
+            if let (Ok(chunk), Some(max)) = (&chunk, limit) {
+                *total += chunk.len();
+                if *total as u64 > max {
+                    *written = None;
+                    event::error(&format!("Script {} wrote more than --max-script-output and was killed", path.display()));
+                    stop_early.lock().unwrap().kill();
+                    return future::ready(Some(Err(std::io::Error::other("script output over --max-script-output"))));
+                }
+            }
+            future::ready(Some(chunk))
+        });
+        let exited = async move {
+            let status = child.wait().await;
+            stop.lock().unwrap().disarm();
+            drop(running);
+            if watchdog.is_some_and(|watchdog| watchdog.fired()) {
+                metrics::script_failed();
+                event::warning(&format!("Script {} timed out after its response started and was killed", script_path.display()));
+            } else if !status.as_ref().is_ok_and(|status| status.success()) {
+                metrics::script_failed();
+                let errors = errors.await.unwrap_or_default();
+                let exit = status.map_or_else(|e| e.to_string(), |status| status.to_string());
+                log_script_failure(&script_path, &request_id, &format!("{} after its response started", exit), &errors);
+            }
+            None
+        };
+        let first = Bytes::from(response_body);
+        let output = stream::once(async move { Ok::<_, std::io::Error>(first) })
+            .chain(stdout)
+            .chain(stream::once(exited).filter_map(future::ready));
+        let mut response = builder.body(Body::wrap_stream(output)).unwrap();
+        if event_stream {
+            response.headers_mut().entry(CACHE_CONTROL).or_insert(HeaderValue::from_static("no-cache"));
+        }
+        return Ok(response);
+    }
 
+    let output = match child.wait_with_output().await {
+        Ok(output) => output,
+        Err(e) => {
+            event::error(&format!("Failed to wait for script {}: {}", script_path.display(), e));
+            metrics::script_failed();
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>500 Internal Server Error</html>"))
+                .unwrap());
+        },
+    };
+    let stderr = match logged_stderr {
+        Some(errors) => errors.await.unwrap_or_default(),
+        None => output.stderr,
+    };
+    stop.disarm();
+    timing::record(&parts.extensions, Phase::Script, started.elapsed());
+    drop(running);
+    if watchdog.is_some_and(|watchdog| watchdog.fired()) {
+        metrics::script_failed();
+        event::warning(&format!("Script {} timed out and was killed", script_path.display()));
+        return Ok(Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>504 Gateway Timeout</html>"))
+            .unwrap());
+    }
+    if let Some(body_pipe) = body_pipe {
+        let (result, elapsed) = body_pipe.await.unwrap_or((Err(BodyError::Read), Duration::ZERO));
+        timing::record(&parts.extensions, Phase::Body, elapsed);
+        match result {
+            Ok(()) => {},
+            Err(BodyError::TooLarge) => return Ok(payload_too_large()),
+            Err(BodyError::Timeout) => return Ok(request_timeout()),
+            Err(BodyError::Read) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Failed to execute script"))
+                    .unwrap());
+            },
+        }
+    }
+    // Exit codes given to --script-exit-status are the script's own answer,
+    // not a failure: its output is sent with the status the code stands for,
+    // unless it chose one in a Status header.
+    let mapped = output.status.code().and_then(|code| config.script_exit_status.get(&code));
+    if let Some(&status) = mapped {
+        if response_body.is_empty() {
             return Ok(Response::builder()
                 .status(status)
-                .header("Content-Type", content_type)
-                .header("Content-Length", response_body.len().to_string())
-                .header("Connection", "close")
-                .body(Body::from(response_body))
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from(format!("<html>{} {}</html>", status.as_u16(), status.canonical_reason().unwrap_or(""))))
                 .unwrap());
         }
-    } else {
-        let output = cmd.output().await.expect("Failed to execute script");
+        let builder = if headers.iter().any(|(name, _)| name == "status") { builder } else { builder.status(status) };
+        return Ok(builder
+            .header("Content-Length", response_body.len().to_string())
+            .body(Body::from(response_body))
+            .unwrap());
+    }
+    if !output.status.success() {
+        metrics::script_failed();
+        log_script_failure(&script_path, request_id, &output.status.to_string(), &stderr);
+        // Stderr is the body unless --script-hide-errors is set; it may well
+        // give away paths, queries or credentials.
+        if !config.script_hide_errors {
+            return Ok(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .header("Content-Length", stderr.len().to_string())
+                .body(Body::from(stderr))
+                .unwrap());
+        }
+        return Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>500 Internal Server Error</html>"))
+            .unwrap());
+    }
 
-        let response_body = if output.status.success() {
-            output.stdout
-        } else {
-            output.stderr
-        };
+    Ok(builder
+        .header("Content-Length", response_body.len().to_string())
+        .body(Body::from(response_body))
+        .unwrap())
+}
 
-        let status = if output.status.success() {
-            StatusCode::OK
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
+/// Kills a script's process group once `--script-timeout` has passed,
+/// unless dropped first.
+struct Watchdog {
+    task: tokio::task::JoinHandle<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn start(pid: Option<u32>, timeout: Duration) -> Watchdog {
+        let fired = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn({
+            let fired = fired.clone();
+            async move {
+                tokio::time::sleep(timeout).await;
+                if let Some(pid) = pid {
+                    fired.store(true, Ordering::Relaxed);
+                    // SAFETY: kill only sends a signal. The group id is the script's
+                    // pid, which its owner drops the watchdog right after reaping.
+                    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                }
+            }
+        });
+        Watchdog { task, fired }
+    }
+
+    fn fired(&self) -> bool {
+        self.fired.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Stops a script's process group when the request ends before the script
+/// does, e.g. because the client disconnected: SIGTERM, then SIGKILL after
+/// SCRIPT_STOP_GRACE. Disarmed once the script has been reaped.
+struct StopOnDrop {
+    pid: Option<u32>,
+    script_path: PathBuf,
+}
+
+impl StopOnDrop {
+    fn new(pid: Option<u32>, script_path: &Path) -> StopOnDrop {
+        StopOnDrop { pid, script_path: script_path.to_path_buf() }
+    }
+
+    fn disarm(&mut self) {
+        self.pid = None;
+    }
+
+    /// SIGKILL right away, without the grace period.
+    fn kill(&mut self) {
+        if let Some(pid) = self.pid.take() {
+            // SAFETY: as in drop below.
+            unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+        }
+    }
+}
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        let Some(pid) = self.pid else { return };
+        event::warning(&format!("Request for {} ended before the script did; stopping it", self.script_path.display()));
+        let group = -(pid as libc::pid_t);
+        // SAFETY: kill only sends a signal. The script has not been reaped,
+        // so the group id is still its own.
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        tokio::spawn(async move {
+            tokio::time::sleep(SCRIPT_STOP_GRACE).await;
+            // SAFETY: as above; a group whose processes all exited in the
+            // meantime is not handed out again this soon.
+            unsafe { libc::kill(group, libc::SIGKILL) };
+        });
+    }
+}
+
+// A script whose output passed --max-script-output before its response
+// started: it is killed at once and the client gets a 502.
+fn output_too_large(stop: &mut StopOnDrop, script_path: &Path) -> Response<Body> {
+    metrics::script_failed();
+    event::error(&format!("Script {} wrote more than --max-script-output and was killed", script_path.display()));
+    stop.kill();
+    proxy::bad_gateway()
+}
+
+// Time a script stopped by StopOnDrop gets to exit after SIGTERM.
+const SCRIPT_STOP_GRACE: Duration = Duration::from_secs(5);
+
+// Output buffered before a script's response is streamed instead.
+const SCRIPT_BUFFER: usize = 64 * 1024;
+const SCRIPT_STREAM_AFTER: Duration = Duration::from_secs(1);
+// Stderr logged when a script fails.
+const SCRIPT_STDERR_LOGGED: u64 = 4096;
+
+// The exit status, then each line of stderr up to SCRIPT_STDERR_LOGGED bytes,
+// as errors tagged with the request ID.
+fn log_script_failure(script_path: &Path, request_id: &str, exit: &str, stderr: &[u8]) {
+    event::error(&format!("Script {} failed for request {}: {}", script_path.display(), request_id, exit));
+    let stderr = String::from_utf8_lossy(&stderr[..stderr.len().min(SCRIPT_STDERR_LOGGED as usize)]);
+    for line in stderr.lines().filter(|line| !line.trim().is_empty()) {
+        event::error(&format!("Script {} stderr for request {}: {}", script_path.display(), request_id, line));
+    }
+}
+
+async fn read_capped(reader: impl AsyncRead + Unpin, limit: u64) -> Vec<u8> {
+    let mut kept = Vec::new();
+    let mut reader = reader.take(limit);
+    let _ = reader.read_to_end(&mut kept).await;
+    // Keep draining so the script never blocks on a full pipe.
+    let _ = tokio::io::copy(&mut reader.into_inner(), &mut tokio::io::sink()).await;
+    kept
+}
+
+// Longest header block read from a script before its output is taken as plain.
+const MAX_SCRIPT_HEAD: usize = 16 * 1024;
+
+enum Preamble {
+    /// CGI style headers and the offset of the body after the blank line.
+    Headers(Vec<(HeaderName, HeaderValue)>, usize),
+    Plain,
+    Incomplete,
+}
+
+// A script may start its output with CGI style header lines (`Status: 404`,
+// `Content-Type: application/json`, ...) and a blank line. To tell them from
+// plain output, the block needs one of Status, Content-Type or Location, as
+// in RFC 3875. `Content-Type: text/event-stream` makes the rest an event stream.
+fn script_preamble(head: &[u8]) -> Preamble {
+    let mut headers = Vec::new();
+    let mut offset = 0;
+    loop {
+        let rest = &head[offset..];
+        let end = match rest.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None => {
+                // Incomplete as long as the line could still become a header.
+                let name = rest.split(|b| *b == b':').next().unwrap_or(rest);
+                let plausible = name.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_');
+                return if plausible && head.len() < MAX_SCRIPT_HEAD { Preamble::Incomplete } else { Preamble::Plain };
+            },
         };
+        let line = rest[..end].strip_suffix(b"\r").unwrap_or(&rest[..end]);
+        offset += end + 1;
+        if line.is_empty() {
+            let required = headers.iter().any(|(name, _): &(HeaderName, HeaderValue)| {
+                name == "status" || name == CONTENT_TYPE || name == LOCATION
+            });
+            return if required { Preamble::Headers(headers, offset) } else { Preamble::Plain };
+        }
+        let header = line.iter().position(|b| *b == b':').and_then(|colon| {
+            let name = HeaderName::from_bytes(&line[..colon]).ok()?;
+            let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).ok()?;
+            Some((name, value))
+        });
+        match header {
+            Some(header) => headers.push(header),
+            None => return Preamble::Plain,
+        }
+    }
+}
 
-        let content_type = "text/plain; charset=utf-8";
+// The response head for a script's headers: `Status` sets the status,
+// `Location` alone redirects with 302, and text/plain is the default type.
+fn script_response(headers: &[(HeaderName, HeaderValue)]) -> Result<hyper::http::response::Builder, String> {
+    let mut status = None;
+    let mut builder = Response::builder();
+    for (name, value) in headers {
+        if name == "status" {
+            let code = value.to_str().ok()
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|code| code.parse::<u16>().ok())
+                .and_then(|code| StatusCode::from_u16(code).ok())
+                .filter(|code| !code.is_informational())
+                .ok_or_else(|| format!("an invalid Status header: {:?}", value))?;
+            status = Some(code);
+        } else if name != CONTENT_LENGTH && name != CONNECTION && name != TRANSFER_ENCODING {
+            builder = builder.header(name, value);
+        }
+    }
+    let redirect = headers.iter().any(|(name, _)| name == LOCATION);
+    let status = status.unwrap_or(if redirect { StatusCode::FOUND } else { StatusCode::OK });
+    if !headers.iter().any(|(name, _)| name == CONTENT_TYPE) {
+        builder = builder.header(CONTENT_TYPE, "text/plain; charset=utf-8").extension(DefaultContentType);
+    }
+    Ok(builder.status(status))
+}
 
-        return Ok(Response::builder()
-            .status(status)
-            .header("Content-Type", content_type)
-            .header("Content-Length", response_body.len().to_string())
-            .header("Connection", "close")
-            .body(Body::from(response_body))
-            .unwrap());
+// Scripts see only `env_vars`, --script-path as PATH and the variables
+// named with --script-pass-env, none of the rest of the server's environment.
+// With --script-sandbox, scripts start in the sandbox, as --script-user and
+// under the --script-max-* limits.
+fn script_command(script_path: &Path, env_vars: &HashMap<String, String>, config: &Config) -> std::process::Command {
+    // The script starts in its own folder, or --script-cwd, so a path under
+    // a relative --script-dir mount has to hold from there too.
+    let script_path = &std::path::absolute(script_path).unwrap_or_else(|_| script_path.to_path_buf());
+    let cwd = config.script_cwd.as_deref().or(script_path.parent());
+    let mut cmd = std::process::Command::new(script_path);
+    cmd.env_clear().env("PATH", &config.script_env_path);
+    for name in &config.script_pass_env {
+        if let Some(value) = std::env::var_os(name) {
+            cmd.env(name, value);
+        }
+    }
+    cmd.envs(env_vars);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
     }
+    // Before the sandbox is entered: std switches IDs ahead of pre_exec hooks.
+    if let Some(credentials) = config.script_credentials {
+        cmd.uid(credentials.uid).gid(credentials.gid);
+    }
+    if let Some(sandbox) = &config.sandbox {
+        sandbox.apply(&mut cmd);
+    }
+    if config.script_rlimits.is_set() {
+        config.script_rlimits.apply(&mut cmd);
+    }
+    cmd
+}
 
-    Ok(Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
+/// The part of a script URL after the script itself, e.g. `/extra/path` in
+/// `/scripts/app.sh/extra/path`.
+#[derive(Clone)]
+struct PathInfo(String);
+
+// The deepest existing file above a missing `full_path` under a scripts
+// folder is the script to run; the rest of the URL becomes its PATH_INFO.
+fn split_path_info(scripts: &Path, full_path: &Path, path: &str) -> Option<(PathBuf, String)> {
+    if !full_path.starts_with(scripts) || full_path.exists() {
+        return None;
+    }
+    let script = full_path.ancestors().skip(1)
+        .take_while(|ancestor| ancestor.starts_with(scripts) && *ancestor != scripts)
+        .find(|ancestor| ancestor.is_file())?;
+    let rest = full_path.strip_prefix(script).ok()?;
+    let trailing_slash = if path.ends_with('/') { "/" } else { "" };
+    Some((script.to_path_buf(), format!("/{}{}", rest.to_string_lossy(), trailing_slash)))
+}
+
+// The scripts answering a missing `full_path` by method, found by the method
+// name as a part of their own: `resource.GET.sh` serves both `resource` and
+// `resource.sh`.
+fn method_scripts(full_path: &Path) -> BTreeMap<String, PathBuf> {
+    let mut scripts = BTreeMap::new();
+    let (Some(dir), Some(name)) = (full_path.parent(), full_path.file_name().and_then(|name| name.to_str())) else {
+        return scripts;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return scripts;
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else { continue };
+        let parts: Vec<&str> = file_name.split('.').collect();
+        for i in 1..parts.len() {
+            let method = parts[i];
+            if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) || Method::from_bytes(method.as_bytes()).is_err() {
+                continue;
+            }
+            let without = [&parts[..i], &parts[i + 1..]].concat().join(".");
+            if (without == name || parts[..i].join(".") == name) && entry.path().is_file() {
+                scripts.insert(method.to_string(), entry.path());
+                break;
+            }
+        }
+    }
+    scripts
+}
+
+// Scripts get the CGI/1.1 variables (RFC 3875) with the request headers as
+// HTTP_<NAME>, each cookie as COOKIE_<name>, a --route's path parameters as
+// ROUTE_<name> and a --cron run's schedule as CRON_SCHEDULE. The older names
+// stay for scripts written against them: the headers as-is (lowercase, such
+// as user-agent) plus Method, Path, Remote_addr, Remote_user, Jwt_<claim> and
+// Query_<name>, the last is a repeated parameter's last value (see
+// `field_vars`). Credentials the server already checked are not passed on.
+fn script_env(parts: &hyper::http::request::Parts, script_path: &Path, client_addr: SocketAddr, config: &Config) -> HashMap<String, String> {
+    let remote_user = parts.extensions.get::<auth::RemoteUser>();
+    let jwt_claims = parts.extensions.get::<jwt::JwtClaims>();
+    let authenticated = remote_user.is_some() || jwt_claims.is_some();
+    // A name with an underscore would become the same variable as its
+    // dashed twin, and could stand in for it past a proxy that checks one.
+    let headers: Vec<(&HeaderName, String)> = parts.headers.iter()
+        .filter(|(key, _)| !authenticated || (*key != hyper::header::AUTHORIZATION && *key != api_key::HEADER))
+        .filter(|(key, _)| key.as_str().bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+        .map(|(key, value)| (key, value.to_str().unwrap_or("").to_string()))
+        .collect();
+    let mut env_vars: HashMap<String, String> = headers.iter()
+        .filter(|(key, _)| key.as_str() != "proxy")
+        .map(|(key, value)| (key.to_string(), value.clone()))
+        .collect();
+    for (key, value) in &headers {
+        if **key == CONTENT_TYPE || **key == CONTENT_LENGTH {
+            env_vars.insert(key.as_str().to_ascii_uppercase().replace('-', "_"), value.clone());
+        } else if **key != TRANSFER_ENCODING && key.as_str() != "proxy" {
+            // A `Proxy` header would become HTTP_PROXY, which HTTP clients
+            // in the script take as their proxy (httpoxy).
+            env_vars.insert(format!("HTTP_{}", key.as_str().to_ascii_uppercase().replace('-', "_")), value.clone());
+        }
+    }
+    // The first of a repeated name wins: browsers send the cookie with the
+    // most specific path first.
+    let cookies = parts.headers.get_all(COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .filter(|(name, _)| !name.is_empty());
+    for (name, value) in cookies {
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        env_vars.entry(format!("COOKIE_{}", name)).or_insert_with(|| value.to_string());
+    }
+
+    // A checked API key stays out of the query string too.
+    let query = parts.uri.query().map(|query| query.split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or("");
+            !authenticated || form_urlencoded::parse(key.as_bytes()).next().is_none_or(|(key, _)| key != api_key::QUERY_PARAM)
+        })
+        .collect::<Vec<_>>()
+        .join("&"));
+    let host = parts.headers.get(HOST).and_then(|v| v.to_str().ok()).and_then(|host| host.parse::<hyper::http::uri::Authority>().ok());
+    let server_port = host.as_ref().and_then(|host| host.port_u16()).or(config.port).unwrap_or(80);
+    let server_name = host.as_ref().map(|host| host.host().to_string())
+        .or_else(|| config.bind.first().cloned())
+        .unwrap_or_else(|| "0.0.0.0".to_string());
+    let request_uri = match &query {
+        Some(query) if !query.is_empty() => format!("{}?{}", parts.uri.path(), query),
+        _ => parts.uri.path().to_string(),
+    };
+    let path_info = parts.extensions.get::<PathInfo>().map_or("", |PathInfo(path_info)| path_info.as_str());
+    let script_name = parts.uri.path().strip_suffix(path_info).unwrap_or(parts.uri.path());
+    let cgi = [
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", format!("rustywebserver/{}", env!("CARGO_PKG_VERSION"))),
+        ("SERVER_NAME", server_name),
+        ("SERVER_PORT", server_port.to_string()),
+        ("SERVER_PROTOCOL", format!("{:?}", parts.version)),
+        ("REQUEST_METHOD", parts.method.to_string()),
+        ("REQUEST_URI", request_uri),
+        ("QUERY_STRING", query.unwrap_or_default()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("SCRIPT_FILENAME", script_path.display().to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("DOCUMENT_ROOT", config.root.display().to_string()),
+        ("REMOTE_ADDR", client_addr.ip().to_string()),
+        ("REMOTE_PORT", client_addr.port().to_string()),
+    ];
+    env_vars.extend(cgi.into_iter().map(|(name, value)| (name.to_string(), value)));
+    if !path_info.is_empty() {
+        env_vars.insert("PATH_TRANSLATED".to_string(), config.root.join(path_info.trim_start_matches('/')).display().to_string());
+    }
+
+    env_vars.insert("Method".to_string(), parts.method.to_string());
+    env_vars.insert("Path".to_string(), parts.uri.path().to_string());
+    env_vars.insert("Remote_addr".to_string(), client_addr.ip().to_string());
+    if let Some(country) = geoip::country(client_addr.ip()) {
+        env_vars.insert("Geoip_country".to_string(), country);
+    }
+    if let Some(auth::RemoteUser(user)) = remote_user {
+        env_vars.insert("Remote_user".to_string(), user.clone());
+        env_vars.insert("REMOTE_USER".to_string(), user.clone());
+    }
+    if let Some(jwt::JwtClaims(claims)) = jwt_claims {
+        for (name, value) in claims {
+            env_vars.insert(format!("Jwt_{}", name), value.clone());
+        }
+    }
+    if let Some(route::RouteParams(params)) = parts.extensions.get::<route::RouteParams>() {
+        for (name, value) in params {
+            env_vars.insert(format!("ROUTE_{}", name), value.clone());
+        }
+    }
+    if let Some(cron::Scheduled(schedule)) = parts.extensions.get::<cron::Scheduled>() {
+        env_vars.insert("CRON_SCHEDULE".to_string(), schedule.clone());
+    }
+    // As mod_ssl sets them.
+    if let Some(session) = parts.extensions.get::<tls::Session>() {
+        env_vars.insert("HTTPS".to_string(), "on".to_string());
+        env_vars.insert("SSL_PROTOCOL".to_string(), session.protocol.clone());
+        if let Some(server_name) = &session.server_name {
+            env_vars.insert("SSL_TLS_SNI".to_string(), server_name.clone());
+        }
+        // Only verified certificates get past the handshake.
+        if let Some(subject) = &session.client_subject {
+            env_vars.insert("SSL_CLIENT_S_DN".to_string(), subject.clone());
+            env_vars.insert("SSL_CLIENT_VERIFY".to_string(), "SUCCESS".to_string());
+        }
+    }
+
+    if let Some(query) = parts.uri.query() {
+        let params = form_urlencoded::parse(query.as_bytes())
+            .filter(|(key, _)| !authenticated || key != api_key::QUERY_PARAM);
+        field_vars("Query_", params, &mut env_vars);
+    }
+    env_vars.retain(|name, value| allowed_env_var(name, value));
+    env_vars
+}
+
+// Query parameters and form fields as <prefix><name>, holding the last value
+// of a repeated name, with every value in order as <prefix><name>_0,
+// <prefix><name>_1, ... and their number as <prefix><name>_count, so a
+// multi-select field loses none of its choices.
+fn field_vars<'a>(prefix: &str, fields: impl Iterator<Item = (Cow<'a, str>, Cow<'a, str>)>, env_vars: &mut HashMap<String, String>) {
+    let mut names: Vec<Cow<str>> = Vec::new();
+    let mut values: HashMap<Cow<str>, Vec<Cow<str>>> = HashMap::new();
+    for (name, value) in fields {
+        if !values.contains_key(&name) {
+            names.push(name.clone());
+        }
+        values.entry(name).or_default().push(value);
+    }
+    for name in names {
+        let values = &values[&name];
+        for (i, value) in values.iter().enumerate() {
+            env_vars.insert(format!("{}{}_{}", prefix, name, i), value.to_string());
+        }
+        env_vars.insert(format!("{}{}_count", prefix, name), values.len().to_string());
+        env_vars.insert(format!("{}{}", prefix, name), values[values.len() - 1].to_string());
+    }
+}
+
+// The only names a script's environment is built from: the CGI/1.1
+// variables and the older names the server sets itself, and the prefixes
+// that namespace what comes from the request, and the headers as-is, whose
+// names are lowercase and never hold an underscore. Anything else, such as
+// PATH, LD_PRELOAD or BASH_ENV, can never be set by a request.
+const SCRIPT_ENV: [&str; 29] = [
+    "CONTENT_LENGTH", "CONTENT_TYPE", "CRON_SCHEDULE", "DOCUMENT_ROOT", "GATEWAY_INTERFACE",
+    "Geoip_country", "HTTPS", "Method", "PATH_INFO", "PATH_TRANSLATED", "Path", "QUERY_STRING",
+    "REMOTE_ADDR", "REMOTE_PORT", "REMOTE_USER", "REQUEST_METHOD", "REQUEST_URI", "Remote_addr",
+    "Remote_user", "SCRIPT_FILENAME", "SCRIPT_NAME", "SERVER_NAME", "SERVER_PORT",
+    "SERVER_PROTOCOL", "SERVER_SOFTWARE", "SSL_CLIENT_S_DN", "SSL_CLIENT_VERIFY", "SSL_PROTOCOL",
+    "SSL_TLS_SNI",
+];
+const SCRIPT_ENV_PREFIXES: [&str; 7] = ["COOKIE_", "FORM_", "HTTP_", "JSON_", "Jwt_", "Query_", "ROUTE_"];
+
+// Also keeps out what `Command` cannot pass: an `=` or NUL byte in a name,
+// from a query parameter or claim, or a NUL in a value.
+fn allowed_env_var(name: &str, value: &str) -> bool {
+    let allowed = SCRIPT_ENV.contains(&name)
+        || SCRIPT_ENV_PREFIXES.iter().any(|prefix| name.strip_prefix(prefix).is_some_and(|rest| !rest.is_empty()))
+        || is_header_name(name);
+    allowed && !name.contains(['=', '\0']) && !value.contains('\0')
+}
+
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+enum BodyError {
+    TooLarge,
+    Read,
+    Timeout,
+}
+
+// Chunked bodies carry no Content-Length, so the limit is enforced as they
+// arrive. A script that exits without reading all of its input is not an error.
+// Bodies up to this size are read before the script starts when their fields
+// become variables; larger ones only reach it on stdin.
+const BODY_VARS_MAX: usize = 64 * 1024;
+
+// With --script-form-vars, the fields of a form POST become FORM_<name>
+// variables too, as query parameters become Query_<name>; with
+// --script-json-vars, the top-level members of a JSON object JSON_<key>. The
+// body is read ahead for that and handed back whole for stdin.
+async fn body_vars(parts: &hyper::http::request::Parts, mut body: Body, config: &Config, env_vars: &mut HashMap<String, String>) -> Result<Body, BodyError> {
+    let essence = parts.headers.get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_ascii_lowercase());
+    let form = config.script_form_vars && essence.as_deref() == Some("application/x-www-form-urlencoded");
+    let json = config.script_json_vars && essence.as_deref() == Some("application/json");
+    if parts.method != Method::POST || !(form || json) {
+        return Ok(body);
+    }
+
+    let read_ahead = async {
+        let mut received = Vec::new();
+        while received.len() <= BODY_VARS_MAX {
+            match body.data().await {
+                Some(chunk) => received.extend_from_slice(&chunk.map_err(|_| BodyError::Read)?),
+                None => return Ok((received, true)),
+            }
+        }
+        Ok((received, false))
+    };
+    let (received, complete) = tokio::time::timeout(config.body_timeout, read_ahead).await
+        .unwrap_or(Err(BodyError::Timeout))?;
+    if received.len() as u64 > config.max_body_size {
+        return Err(BodyError::TooLarge);
+    }
+    if !complete {
+        let first = Bytes::from(received);
+        return Ok(Body::wrap_stream(stream::once(async move { Ok::<_, hyper::Error>(first) }).chain(body)));
+    }
+
+    let mut fields = HashMap::new();
+    if form {
+        field_vars("FORM_", form_urlencoded::parse(&received), &mut fields);
+    } else {
+        // Strings as they are, null as empty and anything else as JSON text.
+        // A body that is not an object still reaches stdin.
+        if let Some(json::Json::Object(members)) = std::str::from_utf8(&received).ok().and_then(|text| json::Json::parse(text).ok()) {
+            fields.extend(members.into_iter()
+                .filter(|(key, _)| key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                .map(|(key, value)| (format!("JSON_{}", key), match value {
+                    json::Json::String(s) => s,
+                    json::Json::Null => String::new(),
+                    value => value.to_string(),
+                })));
+        }
+    }
+    for (name, value) in fields {
+        if allowed_env_var(&name, &value) {
+            env_vars.insert(name, value);
+        }
+    }
+    Ok(Body::from(received))
+}
+
+async fn pipe_body(mut body: Body, mut stdin: ChildStdin, limit: u64) -> Result<(), BodyError> {
+    let mut received = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| BodyError::Read)?;
+        received += chunk.len() as u64;
+        if received > limit {
+            return Err(BodyError::TooLarge);
+        }
+        if stdin.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn payload_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
         .header("Connection", "close")
-        .body(Body::from("Failed to execute script"))
-        .unwrap())
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>413 Payload Too Large</html>"))
+        .unwrap()
+}
+
+fn service_unavailable() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", "1")
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>503 Service Unavailable</html>"))
+        .unwrap()
+}
+
+fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Retry-After", retry_after.as_secs_f64().ceil().max(1.0).to_string())
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>429 Too Many Requests</html>"))
+        .unwrap()
 }
 
-fn log_request(method: &Method, path: &str, client_addr: &SocketAddr, status_code: StatusCode, status_text: &str) {
-    let client_ip = client_addr.ip();
-    println!("{} {} {} -> {} ({})", method, client_ip, path, status_code.as_u16(), status_text);
+fn request_timeout() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::REQUEST_TIMEOUT)
+        .header("Connection", "close")
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>408 Request Timeout</html>"))
+        .unwrap()
+}
+
+// HEAD responses carry the same headers as GET, including Content-Length.
+fn without_body_for_head(method: &Method, response: Response<Body>) -> Response<Body> {
+    if method != Method::HEAD {
+        return response;
+    }
+    let (parts, _) = response.into_parts();
+    Response::from_parts(parts, Body::empty())
 }
 
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: rustwebserver <PORT> <ROOT_FOLDER>");
-        return;
+    let config = match Config::from_args(&args[1..]) {
+        Ok(Cli::Run(config)) => Arc::<Config>::from(config),
+        Ok(Cli::Help) => {
+            print!("{}", HELP);
+            return;
+        },
+        Ok(Cli::Version) => {
+            println!("rustywebserver {}", env!("CARGO_PKG_VERSION"));
+            return;
+        },
+        Err(e) => {
+            eprintln!("error: {}\n\n{}\n\nFor more information, try '--help'.", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    event::use_json(config.access_log.format.is_json());
+    metrics::start();
+
+    if let Some(geoip_db) = &config.geoip_db {
+        if let Err(e) = geoip::open(geoip_db) {
+            event::error(&e);
+            process::exit(1);
+        }
+    }
+    if let Some(log_sink) = &config.log_sink {
+        if let Err(e) = syslog::open(log_sink) {
+            event::error(&e);
+            process::exit(1);
+        }
+    }
+    if let Err(e) = access_log::open(&config.access_log) {
+        event::error(&e);
+        process::exit(1);
     }
 
-    let port: u16 = args[1].parse().expect("Invalid port number");
-    let root = PathBuf::from(&args[2]);
-    let root_abs = root.canonicalize().expect("Failed to get absolute path");
+    if let Err(e) = config.jwt.keys.fetch().await {
+        event::error(&e);
+        process::exit(1);
+    }
+    if let Err(e) = config.oidc.discover().await {
+        event::error(&e);
+        process::exit(1);
+    }
 
-    println!("Root folder: {}", root_abs.display());
-    println!("Server listening on 0.0.0.0:{}", port);
+    let root_abs = config.root.canonicalize().unwrap_or_else(|_| config.root.clone());
+    event::info(&format!("Root folder: {}", root_abs.display()));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let mut listeners = match listen::systemd_listeners() {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            event::error(&format!("Server error: {}", e));
+            process::exit(1);
+        }
+    };
+    if listeners.is_empty() {
+        match listen::bind_tcp(&config).await {
+            Ok(tcp) => listeners.extend(tcp.into_iter().map(Listener::Tcp)),
+            Err(e) => {
+                event::error(&format!("Server error: {}", e));
+                process::exit(1);
+            }
+        }
+    } else {
+        event::info(&format!("Using {} socket(s) passed by systemd", listeners.len()));
+    }
+    if config.https_redirect_port.is_some() {
+        match listen::bind_redirect(&config).await {
+            Ok(tcp) => listeners.extend(tcp.into_iter().map(Listener::HttpsRedirect)),
+            Err(e) => {
+                event::error(&format!("Server error: {}", e));
+                process::exit(1);
+            }
+        }
+    }
+    if config.metrics.port.is_some() {
+        match listen::bind_metrics(&config).await {
+            Ok(tcp) => listeners.extend(tcp.into_iter().map(Listener::Metrics)),
+            Err(e) => {
+                event::error(&format!("Server error: {}", e));
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &config.unix_socket {
+        match listen::bind_unix(path, config.unix_socket_mode) {
+            Ok(listener) => listeners.push(Listener::Unix(listener)),
+            Err(e) => {
+                event::error(&format!("Server error: {}", e));
+                process::exit(1);
+            }
+        }
+    }
+    // SO_REUSEPORT acceptors share an address; announce each address once.
+    let mut announced = Vec::new();
+    for listener in &listeners {
+        let name = listener.to_string();
+        if !announced.contains(&name) {
+            event::info(&format!("Server listening on {}", name));
+            announced.push(name);
+        }
+    }
 
-    let make_svc = make_service_fn(|conn: &hyper::server::conn::AddrStream| {
-        let root = root.clone();
-        let client_addr = conn.remote_addr();
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                handle_request(req, root.clone(), client_addr)
-            }))
+    if !hooks::run(&config, hooks::Event::Start, &announced).await && config.hooks.abort_on_failure {
+        event::error("A start hook failed, shutting down");
+        if let Some(path) = &config.unix_socket {
+            let _ = std::fs::remove_file(path);
         }
-    });
+        process::exit(1);
+    }
+
+    cron::start(config.clone());
+    let mut stop_hooks_failed = false;
+    let shutdown = async {
+        shutdown_signal().await;
+        stop_hooks_failed = !hooks::run(&config, hooks::Event::Stop, &announced).await;
+    };
+    server::serve(listeners, config.clone(), shutdown).await;
+    access_log::flush();
 
-    let server = Server::bind(&addr).serve(make_svc);
+    if let Some(path) = &config.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    if stop_hooks_failed && config.hooks.abort_on_failure {
+        process::exit(1);
+    }
+}
 
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
 }
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::auth;
+
+/// `--metrics`: Prometheus counters at `path`, on the main port or, with
+/// `--metrics-port`, only on a port of their own.
+#[derive(Default)]
+pub struct MetricsConfig {
+    pub path: Option<String>,
+    pub port: Option<u16>,
+    /// `--metrics-route` prefixes requests are labelled with; `/` covers the rest.
+    pub routes: Vec<String>,
+}
+
+impl MetricsConfig {
+    /// Whether the main port answers `path` with the metrics.
+    pub fn is_endpoint(&self, path: &str) -> bool {
+        self.port.is_none() && self.path.as_deref() == Some(path)
+    }
+
+    /// The longest `--metrics-route` covering `path`, else `/`.
+    pub fn route(&self, path: &str) -> &str {
+        let segments = auth::segments(path);
+        self.routes.iter()
+            .filter(|route| segments.starts_with(&auth::segments(route)))
+            .max_by_key(|route| route.len())
+            .map_or("/", |route| route.as_str())
+    }
+}
+
+// Requests by method, route and status.
+type RequestCounts = BTreeMap<(&'static str, String, u16), u64>;
+
+// Recent (finished at, duration, server error) per route.
+type RouteLatencies = BTreeMap<String, VecDeque<(Instant, Duration, bool)>>;
+
+// Request rates are averaged over up to this many one-second buckets.
+const RATE_WINDOW: usize = 900;
+// Errors kept for the status page.
+const RECENT_ERRORS: usize = 20;
+// Per-route statistics cover the requests finished this long ago at most,
+// and at most this many of them per route.
+const ROUTE_WINDOW: Duration = Duration::from_secs(300);
+const ROUTE_SAMPLES: usize = 1000;
+
+static START: OnceLock<SystemTime> = OnceLock::new();
+static REQUESTS: Mutex<RequestCounts> = Mutex::new(BTreeMap::new());
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static SCRIPT_RUNS: AtomicU64 = AtomicU64::new(0);
+static SCRIPT_FAILURES: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_OPEN: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+// (second since the epoch, requests finished in it), indexed by second modulo the window.
+static RATE: Mutex<[(u64, u64); RATE_WINDOW]> = Mutex::new([(0, 0); RATE_WINDOW]);
+static ERRORS: Mutex<VecDeque<(SystemTime, String)>> = Mutex::new(VecDeque::new());
+static RUNNING: Mutex<BTreeMap<u64, RunningScript>> = Mutex::new(BTreeMap::new());
+static ROUTE_LATENCIES: Mutex<RouteLatencies> = Mutex::new(BTreeMap::new());
+static NEXT_SCRIPT: AtomicU64 = AtomicU64::new(0);
+
+pub fn start() {
+    START.get_or_init(SystemTime::now);
+}
+
+/// Counts a request as in flight until dropped.
+pub struct InFlight(());
+
+pub fn request_started() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    InFlight(())
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn request_finished(method: &Method, route: &str, status: StatusCode, bytes_sent: u64, duration: Duration) {
+    // Arbitrary methods would make the label set unbounded.
+    let method = match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::PATCH => "PATCH",
+        _ => "OTHER",
+    };
+    *REQUESTS.lock().unwrap().entry((method, route.to_string(), status.as_u16())).or_insert(0) += 1;
+    BYTES_SENT.fetch_add(bytes_sent, Ordering::Relaxed);
+    let second = epoch_seconds(SystemTime::now());
+    let mut rate = RATE.lock().unwrap();
+    let bucket = &mut rate[second as usize % RATE_WINDOW];
+    if bucket.0 != second {
+        *bucket = (second, 0);
+    }
+    bucket.1 += 1;
+    drop(rate);
+
+    let now = Instant::now();
+    let mut routes = ROUTE_LATENCIES.lock().unwrap();
+    let samples = routes.entry(route.to_string()).or_default();
+    if samples.len() == ROUTE_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back((now, duration, status.is_server_error()));
+}
+
+/// Remembers an error for the status page, e.g. a 5xx response or a failed upstream.
+pub fn record_error(message: &str) {
+    let mut errors = ERRORS.lock().unwrap();
+    if errors.len() == RECENT_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back((SystemTime::now(), message.to_string()));
+}
+
+struct RunningScript {
+    path: PathBuf,
+    client: IpAddr,
+    started: Instant,
+}
+
+/// Lists a script on the status page while it runs.
+pub struct ScriptGuard(u64);
+
+pub fn script_started(path: &Path, client: IpAddr) -> ScriptGuard {
+    SCRIPT_RUNS.fetch_add(1, Ordering::Relaxed);
+    let id = NEXT_SCRIPT.fetch_add(1, Ordering::Relaxed);
+    let script = RunningScript { path: path.to_path_buf(), client, started: Instant::now() };
+    RUNNING.lock().unwrap().insert(id, script);
+    ScriptGuard(id)
+}
+
+impl Drop for ScriptGuard {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// A script that could not be started or exited unsuccessfully.
+pub fn script_failed() {
+    SCRIPT_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a connection as open until dropped.
+pub struct OpenConnection(());
+
+pub fn connection_opened() -> OpenConnection {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    CONNECTIONS_OPEN.fetch_add(1, Ordering::Relaxed);
+    OpenConnection(())
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        CONNECTIONS_OPEN.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection closed right away for being over a connection cap.
+pub fn connection_rejected() {
+    CONNECTIONS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current values for the status page.
+pub struct Snapshot {
+    pub uptime: Duration,
+    pub requests: u64,
+    /// Requests by status class, 1xx to 5xx.
+    pub by_class: [u64; 5],
+    pub in_flight: u64,
+    /// Requests per second over the last 1, 5 and 15 minutes.
+    pub rates: [f64; 3],
+    pub bytes_sent: u64,
+    pub connections_open: u64,
+    pub connections: u64,
+    pub connections_rejected: u64,
+    pub script_runs: u64,
+    pub script_failures: u64,
+    pub running_scripts: Vec<(PathBuf, IpAddr, Duration)>,
+    pub recent_errors: Vec<(SystemTime, String)>,
+    pub routes: Vec<RouteStats>,
+}
+
+/// Requests finished under one `--metrics-route` in the last five minutes
+/// (the last thousand at most).
+pub struct RouteStats {
+    pub route: String,
+    pub count: usize,
+    /// 5xx responses.
+    pub errors: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+}
+
+fn route_stats() -> Vec<RouteStats> {
+    let now = Instant::now();
+    let mut routes = ROUTE_LATENCIES.lock().unwrap();
+    routes.retain(|_, samples| {
+        while samples.front().is_some_and(|(finished, _, _)| now.duration_since(*finished) > ROUTE_WINDOW) {
+            samples.pop_front();
+        }
+        !samples.is_empty()
+    });
+    routes.iter().map(|(route, samples)| {
+        let mut durations: Vec<Duration> = samples.iter().map(|(_, duration, _)| *duration).collect();
+        durations.sort_unstable();
+        // Nearest rank: the smallest duration at least `p` of the requests took no longer than.
+        let percentile = |p: usize| durations[(durations.len() * p).div_ceil(100).max(1) - 1];
+        RouteStats {
+            route: route.clone(),
+            count: samples.len(),
+            errors: samples.iter().filter(|(_, _, error)| *error).count(),
+            p50: percentile(50),
+            p95: percentile(95),
+        }
+    }).collect()
+}
+
+pub fn snapshot() -> Snapshot {
+    let start = START.get().copied().unwrap_or(UNIX_EPOCH);
+    let mut by_class = [0; 5];
+    let mut requests = 0;
+    for ((_, _, status), count) in REQUESTS.lock().unwrap().iter() {
+        requests += count;
+        if let Some(class) = by_class.get_mut((*status as usize / 100).wrapping_sub(1)) {
+            *class += count;
+        }
+    }
+    // The current second is still filling up, so windows end at the one before.
+    let now = epoch_seconds(SystemTime::now());
+    let rate = RATE.lock().unwrap();
+    let rates = [60, 300, 900].map(|window: u64| {
+        let window = window.min(now.saturating_sub(epoch_seconds(start)).max(1));
+        let count: u64 = rate.iter()
+            .filter(|(second, _)| *second < now && *second >= now - window)
+            .map(|(_, count)| count)
+            .sum();
+        count as f64 / window as f64
+    });
+    Snapshot {
+        uptime: SystemTime::now().duration_since(start).unwrap_or_default(),
+        requests,
+        by_class,
+        in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+        rates,
+        bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+        connections_open: CONNECTIONS_OPEN.load(Ordering::Relaxed),
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+        connections_rejected: CONNECTIONS_REJECTED.load(Ordering::Relaxed),
+        script_runs: SCRIPT_RUNS.load(Ordering::Relaxed),
+        script_failures: SCRIPT_FAILURES.load(Ordering::Relaxed),
+        running_scripts: RUNNING.lock().unwrap().values()
+            .map(|script| (script.path.clone(), script.client, script.started.elapsed()))
+            .collect(),
+        recent_errors: ERRORS.lock().unwrap().iter().rev().cloned().collect(),
+        routes: route_stats(),
+    }
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The metrics in the Prometheus text format, version 0.0.4.
+pub fn endpoint(req: &Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, HeaderValue::from_static("GET, HEAD"))
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>405 Method Not Allowed</html>"))
+            .unwrap();
+    }
+    Response::builder()
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(render()))
+        .unwrap()
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = write!(out, "# HELP {0} {2}\n# TYPE {0} {1}\n{0} {3}\n", name, kind, help, value);
+    };
+    let start = START.get().copied().unwrap_or(UNIX_EPOCH);
+    metric("rustywebserver_start_time_seconds", "gauge", "Start time of the server since the Unix epoch.",
+        start.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    metric("rustywebserver_requests_in_flight", "gauge", "Requests being handled or sent.", IN_FLIGHT.load(Ordering::Relaxed));
+    metric("rustywebserver_response_bytes_total", "counter", "Response body bytes sent.", BYTES_SENT.load(Ordering::Relaxed));
+    metric("rustywebserver_script_executions_total", "counter", "Scripts started.", SCRIPT_RUNS.load(Ordering::Relaxed));
+    metric("rustywebserver_script_failures_total", "counter", "Scripts that failed to start or exited unsuccessfully.",
+        SCRIPT_FAILURES.load(Ordering::Relaxed));
+    metric("rustywebserver_connections_total", "counter", "Connections accepted.", CONNECTIONS.load(Ordering::Relaxed));
+    metric("rustywebserver_connections_open", "gauge", "Connections open.", CONNECTIONS_OPEN.load(Ordering::Relaxed));
+    metric("rustywebserver_connections_rejected_total", "counter", "Connections closed for being over a connection limit.",
+        CONNECTIONS_REJECTED.load(Ordering::Relaxed));
+
+    out.push_str("# HELP rustywebserver_requests_total Requests answered, by method, route and status.\n");
+    out.push_str("# TYPE rustywebserver_requests_total counter\n");
+    for ((method, route, status), count) in REQUESTS.lock().unwrap().iter() {
+        let route = route.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(out, "rustywebserver_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, route, status, count);
+    }
+    out
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::path::Path;
+use mime_guess::from_path;
+
+/// Extension to media type overrides loaded from a `mime.types` file. Both the
+/// nginx form (`types { text/html html htm; }`) and the Apache/Debian form
+/// (`text/html html htm`, one type per line) are accepted.
+#[derive(Default)]
+pub struct MimeMap {
+    types: HashMap<String, String>,
+}
+
+impl MimeMap {
+    pub fn load(path: &Path) -> Result<MimeMap, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let contents: String = contents.lines()
+            .map(|line| line.split('#').next().unwrap_or(""))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let statements: Vec<&str> = match (contents.find('{'), contents.rfind('}')) {
+            (Some(open), Some(close)) if open < close => contents[open + 1..close].split(';').collect(),
+            _ => contents.lines().collect(),
+        };
+
+        let mut map = MimeMap::default();
+        for statement in statements {
+            let mut words = statement.split_whitespace();
+            let media_type = match words.next() {
+                Some(media_type) => media_type,
+                None => continue,
+            };
+            if !media_type.contains('/') {
+                return Err(format!("Invalid media type in {}: {}", path.display(), media_type));
+            }
+            for extension in words {
+                map.types.insert(extension.trim_start_matches('.').to_ascii_lowercase(), media_type.to_string());
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn lookup(&self, path: &Path) -> String {
+        let extension = path.extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        if let Some(media_type) = extension.and_then(|e| self.types.get(&e)) {
+            return media_type.clone();
+        }
+        from_path(path).first_or_octet_stream().as_ref().to_string()
+    }
+}
+
+pub fn with_charset(media_type: String, charset: Option<&str>, charset_types: &[String]) -> String {
+    match charset {
+        Some(charset) if charset_types.iter().any(|t| t.eq_ignore_ascii_case(&media_type)) => {
+            format!("{}; charset={}", media_type, charset)
+        },
+        _ => media_type,
+    }
+}
@@ -0,0 +1,33 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::net::TcpStream;
+
+// Stands in for tls.rs in builds without the `tls` feature, which link no
+// OpenSSL: the TLS options are still known, and refused at startup with a
+// pointer to the feature.
+
+/// Never made: `new` fails, so there is never a handshake to run.
+pub enum Acceptor {}
+
+/// What a handshake settled, kept in the request extensions for scripts.
+#[derive(Clone)]
+pub struct Session {
+    pub protocol: String,
+    pub server_name: Option<String>,
+    pub client_subject: Option<String>,
+}
+
+impl Acceptor {
+    pub fn new(_pairs: &[(PathBuf, PathBuf)], _client_ca: Option<&Path>) -> Result<Acceptor, String> {
+        Err("'--tls-cert' needs a build with TLS support: cargo build --features tls".to_string())
+    }
+
+    pub async fn accept<I>(&self, _io: I) -> io::Result<(I, Session)> {
+        match *self {}
+    }
+}
+
+/// Fails: https:// URLs need the `tls` feature too.
+pub async fn connect(_host: &str, _port: u16) -> io::Result<TcpStream> {
+    Err(io::Error::other("https:// URLs need a build with TLS support: cargo build --features tls"))
+}
@@ -0,0 +1,311 @@
+use std::io::Read;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE};
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use url::form_urlencoded;
+use crate::event;
+use crate::auth::{self, RemoteUser};
+use crate::json::Json;
+use crate::jwt::{self, JwtClaims, KeySet};
+use crate::password::constant_time_eq;
+use crate::sha256::hmac_sha256;
+use crate::base64;
+
+const SESSION_COOKIE: &str = "rws_session";
+const STATE_COOKIE: &str = "rws_oidc_state";
+// How long a user may take to log in at the provider.
+const STATE_TTL: u64 = 600;
+// Claims copied from the ID token into the session.
+const SESSION_CLAIMS: [&str; 3] = ["sub", "email", "name"];
+
+/// OpenID Connect relying party for the `--oidc-auth` prefixes. Browsers
+/// without a session are sent to the provider's login page, the authorization
+/// code is exchanged at the redirect URI and a signed session cookie issued.
+pub struct OidcConfig {
+    pub prefixes: Vec<String>,
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_uri: Option<Uri>,
+    pub session_ttl: Duration,
+    /// Signs the cookies; random unless set, so sessions end on restart.
+    pub cookie_key: Vec<u8>,
+    provider: OnceLock<Provider>,
+}
+
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: Uri,
+    keys: KeySet,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            prefixes: Vec::new(),
+            issuer: None,
+            client_id: None,
+            client_secret: None,
+            redirect_uri: None,
+            session_ttl: Duration::from_secs(8 * 3600),
+            cookie_key: random_bytes(32),
+            provider: OnceLock::new(),
+        }
+    }
+}
+
+impl OidcConfig {
+    pub fn enabled(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    /// Names the first missing option, if any.
+    pub fn missing_option(&self) -> Option<&'static str> {
+        if self.issuer.is_none() {
+            Some("--oidc-issuer")
+        } else if self.client_id.is_none() {
+            Some("--oidc-client-id")
+        } else if self.client_secret.is_none() {
+            Some("--oidc-client-secret-file")
+        } else if self.redirect_uri.is_none() {
+            Some("--oidc-redirect-uri")
+        } else {
+            None
+        }
+    }
+
+    pub fn covers(&self, path: &str) -> bool {
+        let segments = auth::segments(path);
+        self.prefixes.iter().any(|prefix| segments.starts_with(&auth::segments(prefix)))
+    }
+
+    pub fn is_callback(&self, path: &str) -> bool {
+        self.enabled() && self.redirect_uri.as_ref().is_some_and(|uri| uri.path() == path)
+    }
+
+    /// Reads the provider's endpoints from its discovery document
+    /// (OpenID Connect Discovery 1.0) and fetches its signing keys.
+    pub async fn discover(&self) -> Result<(), String> {
+        let issuer = match (&self.issuer, self.enabled()) {
+            (Some(issuer), true) => issuer,
+            _ => return Ok(()),
+        };
+        let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let url = jwt::parse_url("--oidc-issuer", &url)?;
+        let document = jwt::fetch(Request::get(url.clone()).body(Body::empty()).unwrap()).await?;
+        let document = Json::parse(&document).map_err(|e| format!("{}: {}", url, e))?;
+        let field = |name| document.get(name).and_then(Json::as_str)
+            .ok_or_else(|| format!("{}: missing '{}'", url, name));
+        if field("issuer")? != issuer {
+            return Err(format!("{}: issuer '{}' does not match '{}'", url, field("issuer")?, issuer));
+        }
+        let provider = Provider {
+            authorization_endpoint: field("authorization_endpoint")?.to_string(),
+            token_endpoint: jwt::parse_url("token_endpoint", field("token_endpoint")?)?,
+            keys: KeySet::from_url(jwt::parse_url("jwks_uri", field("jwks_uri")?)?),
+        };
+        provider.keys.fetch().await?;
+        let _ = self.provider.set(provider);
+        Ok(())
+    }
+
+    fn seal(&self, kind: &str, ttl: u64, fields: &[&str]) -> String {
+        let expiry = (now() + ttl).to_string();
+        let mut all = vec![kind, expiry.as_str()];
+        all.extend_from_slice(fields);
+        let payload = base64::encode_url(all.join("\n").as_bytes());
+        let mac = base64::encode_url(&hmac_sha256(&self.cookie_key, payload.as_bytes()));
+        format!("{}.{}", payload, mac)
+    }
+
+    // The fields after the kind and expiry of a cookie made by `seal`.
+    fn unseal(&self, kind: &str, value: &str) -> Option<Vec<String>> {
+        let (payload, mac) = value.split_once('.')?;
+        let expected = base64::encode_url(&hmac_sha256(&self.cookie_key, payload.as_bytes()));
+        if !constant_time_eq(expected.as_bytes(), mac.as_bytes()) {
+            return None;
+        }
+        let payload = String::from_utf8(base64::decode_url(payload)?).ok()?;
+        let mut fields = payload.split('\n').map(|field| field.to_string());
+        let expiry: u64 = match (fields.next(), fields.next()) {
+            (Some(found), Some(expiry)) if found == kind => expiry.parse().ok()?,
+            _ => return None,
+        };
+        if expiry < now() {
+            return None;
+        }
+        Some(fields.collect())
+    }
+
+    fn set_cookie(&self, name: &str, value: &str, path: &str, max_age: u64) -> HeaderValue {
+        let secure = match self.redirect_uri.as_ref().and_then(|uri| uri.scheme_str()) {
+            Some("https") => "; Secure",
+            _ => "",
+        };
+        let cookie = format!("{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}", name, value, path, max_age, secure);
+        HeaderValue::from_str(&cookie).unwrap()
+    }
+}
+
+/// Lets requests with a valid session through (`None`), recording the user in
+/// the request extensions. Other GET and HEAD requests are redirected to the
+/// provider, with `original` as the page to come back to; the rest get a 401.
+pub fn authenticate(config: &OidcConfig, original: &Uri, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let session = cookie(req, SESSION_COOKIE).and_then(|value| config.unseal("session", &value));
+    if let Some(fields) = session {
+        let claims: Vec<(String, String)> = SESSION_CLAIMS.iter()
+            .zip(fields)
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        if let Some((_, sub)) = claims.first() {
+            req.extensions_mut().insert(RemoteUser(sub.clone()));
+        }
+        req.extensions_mut().insert(JwtClaims(claims));
+        return None;
+    }
+
+    let provider = match config.provider.get() {
+        Some(provider) if req.method() == Method::GET || req.method() == Method::HEAD => provider,
+        _ => return Some(unauthorized()),
+    };
+    let redirect_uri = config.redirect_uri.as_ref().unwrap();
+    let state = base64::encode_url(&random_bytes(16));
+    let nonce = base64::encode_url(&random_bytes(16));
+    let return_to = original.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("response_type", "code")
+        .append_pair("client_id", config.client_id.as_deref().unwrap_or(""))
+        .append_pair("redirect_uri", &redirect_uri.to_string())
+        .append_pair("scope", "openid email profile")
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .finish();
+    let separator = if provider.authorization_endpoint.contains('?') { '&' } else { '?' };
+    let location = format!("{}{}{}", provider.authorization_endpoint, separator, query);
+
+    let state_cookie = config.seal("state", STATE_TTL, &[&state, &nonce, return_to]);
+    Some(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .header(SET_COOKIE, config.set_cookie(STATE_COOKIE, &state_cookie, redirect_uri.path(), STATE_TTL))
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Handles the provider's redirect back: checks the state, exchanges the code
+/// for an ID token, validates it and starts the session.
+pub async fn callback(config: &OidcConfig, req: &Request<Body>) -> Response<Body> {
+    match finish_login(config, req).await {
+        Ok((session, return_to)) => {
+            let redirect_path = config.redirect_uri.as_ref().unwrap().path();
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, return_to)
+                .header(SET_COOKIE, config.set_cookie(SESSION_COOKIE, &session, "/", config.session_ttl.as_secs()))
+                .header(SET_COOKIE, config.set_cookie(STATE_COOKIE, "", redirect_path, 0))
+                .body(Body::empty())
+                .unwrap()
+        },
+        Err(e) => {
+            event::error(&format!("OpenID Connect login failed: {}", e));
+            unauthorized()
+        },
+    }
+}
+
+async fn finish_login(config: &OidcConfig, req: &Request<Body>) -> Result<(String, String), String> {
+    let provider = config.provider.get().ok_or("the provider has not been discovered")?;
+    let query: Vec<(String, String)> = form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let param = |name| query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    if let Some(error) = param("error") {
+        return Err(format!("the provider answered '{}'", error));
+    }
+    let (code, state) = param("code").zip(param("state")).ok_or("missing code or state")?;
+
+    let saved = cookie(req, STATE_COOKIE)
+        .and_then(|value| config.unseal("state", &value))
+        .ok_or("missing or expired state cookie")?;
+    let (saved_state, nonce, return_to) = match saved.as_slice() {
+        [state, nonce, return_to] => (state, nonce, return_to),
+        _ => return Err("malformed state cookie".to_string()),
+    };
+    if !constant_time_eq(saved_state.as_bytes(), state.as_bytes()) {
+        return Err("state mismatch".to_string());
+    }
+
+    // client_secret_basic: both parts are form-encoded before base64 (RFC 6749 section 2.3.1).
+    let client_id = config.client_id.as_deref().unwrap_or("");
+    let encode = |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+    let credentials = format!("{}:{}", encode(client_id), encode(config.client_secret.as_deref().unwrap_or("")));
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "authorization_code")
+        .append_pair("code", code)
+        .append_pair("redirect_uri", &config.redirect_uri.as_ref().unwrap().to_string())
+        .append_pair("client_id", client_id)
+        .finish();
+    let request = Request::post(provider.token_endpoint.clone())
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json")
+        .header(AUTHORIZATION, format!("Basic {}", base64::encode(credentials.as_bytes())))
+        .body(Body::from(body))
+        .unwrap();
+    let response = Json::parse(&jwt::fetch(request).await?)?;
+    let id_token = response.get("id_token").and_then(Json::as_str).ok_or("no id_token in the token response")?;
+
+    let claims = jwt::verify_token(None, &provider.keys, id_token).await?;
+    if claims.get("iss").and_then(Json::as_str) != config.issuer.as_deref() {
+        return Err("wrong issuer".to_string());
+    }
+    if !jwt::has_audience(&claims, client_id) {
+        return Err("wrong audience".to_string());
+    }
+    if claims.get("nonce").and_then(Json::as_str) != Some(nonce) {
+        return Err("nonce mismatch".to_string());
+    }
+
+    // Newlines separate the session fields.
+    let values: Vec<String> = SESSION_CLAIMS.iter()
+        .map(|name| claims.get(name).and_then(Json::as_str).unwrap_or("").replace(['\n', '\0'], " "))
+        .collect();
+    if values[0].is_empty() {
+        return Err("the ID token has no sub claim".to_string());
+    }
+    let values: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+    let session = config.seal("session", config.session_ttl.as_secs(), &values);
+    // Only ever return to a local path.
+    let return_to = if return_to.starts_with('/') && !return_to.starts_with("//") { return_to.clone() } else { "/".to_string() };
+    Ok((session, return_to))
+}
+
+fn cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers().get_all(COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .expect("Failed to read /dev/urandom");
+    bytes
+}
+
+fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>401 Unauthorized</html>"))
+        .unwrap()
+}
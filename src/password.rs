@@ -0,0 +1,83 @@
+// Password hash formats found in htpasswd files.
+
+use crate::md5::md5;
+use crate::sha1::sha1;
+use crate::{argon2, base64, bcrypt};
+
+const CRYPT_ALPHABET: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Checks `password` against a stored hash: bcrypt (`$2y$`), Argon2
+/// (`$argon2id$`), Apache MD5 (`$apr1$`) or `{SHA}`. Unknown formats never match.
+pub fn verify(password: &str, hash: &str) -> bool {
+    let password = password.as_bytes();
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash)
+    } else if hash.starts_with("$argon2") {
+        argon2::verify(password, hash)
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        match rest.split_once('$') {
+            Some((salt, _)) => constant_time_eq(apr1(password, salt.as_bytes()).as_bytes(), hash.as_bytes()),
+            None => false,
+        }
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        constant_time_eq(base64::encode(&sha1(password)).as_bytes(), digest.as_bytes())
+    } else {
+        false
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Apache's variant of the FreeBSD MD5-crypt algorithm.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+    let alternate = md5(&[password, salt, password].concat());
+
+    let mut input = [password, b"$apr1$", salt].concat();
+    input.extend(alternate.iter().cycle().take(password.len()));
+    let mut length = password.len();
+    while length > 0 {
+        input.push(if length & 1 == 1 { 0 } else { password[0] });
+        length >>= 1;
+    }
+    let mut digest = md5(&input);
+
+    for round in 0..1000 {
+        let mut input = Vec::new();
+        if round & 1 == 1 {
+            input.extend_from_slice(password);
+        } else {
+            input.extend_from_slice(&digest);
+        }
+        if round % 3 != 0 {
+            input.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            input.extend_from_slice(password);
+        }
+        if round & 1 == 1 {
+            input.extend_from_slice(&digest);
+        } else {
+            input.extend_from_slice(password);
+        }
+        digest = md5(&input);
+    }
+
+    let mut out = format!("$apr1${}$", String::from_utf8_lossy(salt));
+    let groups = [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)];
+    for (a, b, c) in groups {
+        push_crypt64(&mut out, (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32, 4);
+    }
+    push_crypt64(&mut out, digest[11] as u32, 2);
+    out
+}
+
+// crypt(3)'s base64: least significant six bits first.
+fn push_crypt64(out: &mut String, mut value: u32, chars: usize) {
+    for _ in 0..chars {
+        out.push(CRYPT_ALPHABET[(value & 63) as usize] as char);
+        value >>= 6;
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Instant;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use hyper::http::Extensions;
+use hyper::{Body, Response, StatusCode};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+use crate::config::Config;
+use crate::files::map_path;
+use crate::proxy::bad_gateway;
+use crate::script_meta;
+use crate::timing::{self, Phase};
+use crate::{event, metrics, payload_too_large, request_timeout, script_command, script_preamble, script_response};
+use crate::{BodyError, Preamble, MAX_SCRIPT_HEAD};
+
+/// Scripts given to `--prefork`, each run as a pool of long-lived workers
+/// that serve one request after another.
+///
+/// A worker gets each request on stdin as `NAME=VALUE` lines holding the CGI
+/// environment, a blank line and CONTENT_LENGTH bytes of body. It answers on
+/// stdout with CGI style headers, which must include Content-Length, a blank
+/// line and that many bytes of body, then waits for the next request; stdin
+/// closing means the server is gone. What it writes to stderr is logged.
+#[derive(Default)]
+pub struct PreforkConfig {
+    requested: Vec<(String, usize)>,
+    pools: HashMap<PathBuf, Pool>,
+}
+
+impl PreforkConfig {
+    /// `/scripts/app.sh=4`: the script's URL path and how many workers run it.
+    pub fn parse(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (path, workers) = value.rsplit_once('=').ok_or_else(|| invalid("expected PATH=WORKERS"))?;
+        if !path.starts_with('/') {
+            return Err(invalid("the path must start with '/'"));
+        }
+        let workers = workers.parse::<usize>().ok().filter(|&n| n > 0).ok_or_else(|| invalid("expected at least 1 worker"))?;
+        self.requested.push((path.to_string(), workers));
+        Ok(())
+    }
+
+    /// Finds the scripts behind the requested URL paths, once the root,
+    /// aliases and script folders are known.
+    pub fn resolve(config: &mut Config) -> Result<(), String> {
+        for (path, workers) in std::mem::take(&mut config.prefork.requested) {
+            let script = map_path(config, &path).map(|(_, _, full_path)| full_path)
+                .filter(|script| script.is_file())
+                .ok_or_else(|| format!("invalid value '{}' for '--prefork': no such script", path))?;
+            if !config.script_dirs.iter().any(|dir| script.starts_with(dir)) {
+                return Err(format!("invalid value '{}' for '--prefork': not in a script folder", path));
+            }
+            let pool = Pool { script: script.clone(), idle: Mutex::new(Vec::new()), slots: Semaphore::new(workers) };
+            config.prefork.pools.insert(script, pool);
+        }
+        Ok(())
+    }
+
+    pub fn pool(&self, script: &Path) -> Option<&Pool> {
+        self.pools.get(script)
+    }
+}
+
+/// The workers of one script. They are started as requests need them and
+/// replaced when they die, time out or break the protocol.
+pub struct Pool {
+    script: PathBuf,
+    idle: Mutex<Vec<Worker>>,
+    slots: Semaphore,
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+enum Failure {
+    Timeout,
+    TooLarge,
+    Broken(String),
+}
+
+impl Pool {
+    /// Hands a request to a free worker, waiting for one when all are busy.
+    pub async fn respond(&self, env_vars: HashMap<String, String>, body: Body, client_addr: SocketAddr, extensions: &Extensions, config: &Config) -> Response<Body> {
+        let reading = Instant::now();
+        let body = match tokio::time::timeout(config.body_timeout, read_body(body, config.max_body_size)).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(BodyError::TooLarge)) => return payload_too_large(),
+            Ok(Err(BodyError::Read)) => return internal_server_error(),
+            Ok(Err(BodyError::Timeout)) | Err(_) => return request_timeout(),
+        };
+        timing::record(extensions, Phase::Body, reading.elapsed());
+
+        let _slot = self.slots.acquire().await.expect("worker slots are never closed");
+        let worker = self.idle.lock().unwrap().pop();
+        let mut worker = match worker {
+            Some(worker) => worker,
+            None => match self.spawn(config) {
+                Ok(worker) => worker,
+                Err(e) => {
+                    metrics::script_failed();
+                    event::error(&format!("Failed to start a worker for {}: {}", self.script.display(), e));
+                    return internal_server_error();
+                },
+            },
+        };
+
+        let started = Instant::now();
+        let _running = metrics::script_started(&self.script, client_addr.ip());
+        let exchange = worker.exchange(&env_vars, &body, config.max_script_output);
+        let result = match script_meta::timeout(extensions, config) {
+            Some(timeout) => tokio::time::timeout(timeout, exchange).await.unwrap_or(Err(Failure::Timeout)),
+            None => exchange.await,
+        };
+        timing::record(extensions, Phase::Script, started.elapsed());
+        let (headers, output) = match result {
+            Ok(response) => response,
+            // The worker is killed as it is dropped; the next request starts another.
+            Err(Failure::Timeout) => {
+                metrics::script_failed();
+                event::warning(&format!("Worker for {} timed out and was killed", self.script.display()));
+                return Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .header("Content-Type", "text/html; charset=utf-8")
+                    .body(Body::from("<html>504 Gateway Timeout</html>"))
+                    .unwrap();
+            },
+            Err(Failure::TooLarge) => {
+                metrics::script_failed();
+                event::error(&format!("Worker for {} sent more than --max-script-output and was killed", self.script.display()));
+                return bad_gateway();
+            },
+            Err(Failure::Broken(e)) => {
+                metrics::script_failed();
+                event::error(&format!("Worker for {} failed and was killed: {}", self.script.display(), e));
+                return internal_server_error();
+            },
+        };
+        self.idle.lock().unwrap().push(worker);
+
+        match script_response(&headers) {
+            Ok(builder) => builder
+                .header("Content-Length", output.len().to_string())
+                .body(Body::from(output))
+                .unwrap(),
+            Err(e) => {
+                event::error(&format!("Worker for {} sent {}", self.script.display(), e));
+                metrics::script_failed();
+                internal_server_error()
+            },
+        }
+    }
+
+    fn spawn(&self, config: &Config) -> std::io::Result<Worker> {
+        let mut child = Command::from(script_command(&self.script, &HashMap::new(), config))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("Failed to open stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
+        let stderr = child.stderr.take().expect("Failed to open stderr");
+        let script = self.script.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                event::error(&format!("Worker for {} stderr: {}", script.display(), line));
+            }
+        });
+        Ok(Worker { child, stdin, stdout })
+    }
+}
+
+impl Worker {
+    async fn exchange(&mut self, env_vars: &HashMap<String, String>, body: &[u8], max_output: Option<u64>) -> Result<(Vec<(HeaderName, HeaderValue)>, Vec<u8>), Failure> {
+        let broken = |e: std::io::Error| Failure::Broken(e.to_string());
+        if let Ok(Some(status)) = self.child.try_wait() {
+            return Err(Failure::Broken(format!("exited with {}", status)));
+        }
+
+        // Values spanning lines cannot be framed, and so are left out.
+        let mut request = Vec::new();
+        for (name, value) in env_vars {
+            if name != "CONTENT_LENGTH" && !value.contains('\n') {
+                request.extend_from_slice(format!("{}={}\n", name, value).as_bytes());
+            }
+        }
+        request.extend_from_slice(format!("CONTENT_LENGTH={}\n\n", body.len()).as_bytes());
+        request.extend_from_slice(body);
+        self.stdin.write_all(&request).await.map_err(broken)?;
+        self.stdin.flush().await.map_err(broken)?;
+
+        let mut head = Vec::new();
+        loop {
+            let start = head.len();
+            let limit = (MAX_SCRIPT_HEAD - start) as u64;
+            if (&mut self.stdout).take(limit).read_until(b'\n', &mut head).await.map_err(broken)? == 0 {
+                return Err(Failure::Broken("no complete response head".to_string()));
+            }
+            if matches!(&head[start..], b"\n" | b"\r\n") {
+                break;
+            }
+        }
+        let headers = match script_preamble(&head) {
+            Preamble::Headers(headers, _) => headers,
+            _ => return Err(Failure::Broken("a response head without Status, Content-Type or Location".to_string())),
+        };
+        let length = headers.iter()
+            .find(|(name, _)| name == CONTENT_LENGTH)
+            .and_then(|(_, value)| value.to_str().ok()?.parse::<usize>().ok())
+            .ok_or_else(|| Failure::Broken("a response without a valid Content-Length".to_string()))?;
+        if max_output.is_some_and(|max| (head.len() + length) as u64 > max) {
+            return Err(Failure::TooLarge);
+        }
+        let mut output = vec![0; length];
+        self.stdout.read_exact(&mut output).await.map_err(broken)?;
+        Ok((headers, output))
+    }
+}
+
+// Workers need the body's length up front, so it is read whole, up to the
+// --max-body-size limit.
+async fn read_body(mut body: Body, limit: u64) -> Result<Vec<u8>, BodyError> {
+    let mut received = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| BodyError::Read)?;
+        if (received.len() + chunk.len()) as u64 > limit {
+            return Err(BodyError::TooLarge);
+        }
+        received.extend_from_slice(&chunk);
+    }
+    Ok(received)
+}
+
+fn internal_server_error() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>500 Internal Server Error</html>"))
+        .unwrap()
+}
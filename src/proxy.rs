@@ -0,0 +1,291 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use futures_util::stream::StreamExt;
+use tokio::net::TcpStream;
+use crate::event;
+use crate::tls::Session;
+use crate::cache::{self, CacheConfig, Lookup};
+use crate::timing::{Phase, Timings};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, HOST};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+
+static CLIENT: OnceLock<Client<HttpConnector>> = OnceLock::new();
+
+// Hop-by-hop headers describe a single connection and are never forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A `--proxy /api=http://127.0.0.1:9000` route. Several comma-separated
+/// upstreams are balanced with the strategy named before the first URL, e.g.
+/// `/api=least-conn:http://10.0.0.1:9000,http://10.0.0.2:9000*2`.
+pub struct ProxyRoute {
+    prefix: String,
+    balance: Balance,
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,
+}
+
+#[derive(Clone, Copy)]
+enum Balance {
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+}
+
+struct Upstream {
+    authority: String,
+    // Replaces the route prefix in forwarded paths when the upstream URL has a
+    // path, so `/api=http://h/` maps `/api/x` to `/x` but `/api=http://h` keeps it.
+    base_path: Option<String>,
+    weight: usize,
+    // Requests whose response body is still being relayed.
+    active: AtomicUsize,
+}
+
+impl ProxyRoute {
+    pub fn parse(option: &str, value: &str) -> Result<ProxyRoute, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (prefix, upstreams) = value.split_once('=').ok_or_else(|| invalid("expected PREFIX=URL"))?;
+        let prefix = prefix.trim_end_matches('*').trim_end_matches('/');
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            return Err(invalid("the prefix must start with '/'"));
+        }
+
+        let (balance, upstreams) = match upstreams.split_once(':') {
+            Some(("round-robin", rest)) => (Balance::RoundRobin, rest),
+            Some(("least-conn", rest)) => (Balance::LeastConnections, rest),
+            Some(("weighted", rest)) => (Balance::Weighted, rest),
+            _ => (Balance::RoundRobin, upstreams),
+        };
+        let upstreams = upstreams.split(',')
+            .map(|upstream| Upstream::parse(upstream.trim()).map(Arc::new).map_err(invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ProxyRoute {
+            prefix: prefix.to_string(),
+            balance,
+            upstreams,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    fn pick(&self) -> &Arc<Upstream> {
+        match self.balance {
+            Balance::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed);
+                &self.upstreams[n % self.upstreams.len()]
+            },
+            Balance::Weighted => {
+                let total: usize = self.upstreams.iter().map(|u| u.weight).sum();
+                let mut n = self.next.fetch_add(1, Ordering::Relaxed) % total;
+                for upstream in &self.upstreams {
+                    if n < upstream.weight {
+                        return upstream;
+                    }
+                    n -= upstream.weight;
+                }
+                &self.upstreams[0]
+            },
+            // Compares active/weight without dividing: a/wa < b/wb <=> a*wb < b*wa.
+            Balance::LeastConnections => self.upstreams.iter()
+                .min_by(|a, b| {
+                    let a_load = a.active.load(Ordering::Relaxed) * b.weight;
+                    let b_load = b.active.load(Ordering::Relaxed) * a.weight;
+                    a_load.cmp(&b_load)
+                })
+                .unwrap(),
+        }
+    }
+}
+
+impl Upstream {
+    fn parse(value: &str) -> Result<Upstream, &'static str> {
+        let (url, weight) = match value.rsplit_once('*') {
+            Some((url, weight)) => (url, weight.parse().ok().filter(|w| *w > 0).ok_or("weights must be positive integers")?),
+            None => (value, 1),
+        };
+        let has_path = url.split_once("://").is_some_and(|(_, rest)| rest.contains('/'));
+        let uri: Uri = url.parse().map_err(|_| "malformed upstream URL")?;
+        if uri.scheme_str() != Some("http") {
+            return Err("only http:// upstreams are supported");
+        }
+        let authority = uri.authority().ok_or("the upstream URL needs a host")?;
+        Ok(Upstream {
+            authority: authority.to_string(),
+            base_path: has_path.then(|| uri.path().trim_end_matches('/').to_string()),
+            weight,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    fn uri(&self, prefix: &str, uri: &Uri) -> Result<Uri, hyper::http::Error> {
+        let path_and_query = match &self.base_path {
+            Some(base_path) => format!("{}{}", base_path, &uri.path()[prefix.len()..]),
+            None => uri.path().to_string(),
+        };
+        let path_and_query = match (path_and_query.is_empty(), uri.query()) {
+            (true, Some(query)) => format!("/?{}", query),
+            (true, None) => "/".to_string(),
+            (false, Some(query)) => format!("{}?{}", path_and_query, query),
+            (false, None) => path_and_query,
+        };
+        Uri::builder()
+            .scheme("http")
+            .authority(self.authority.as_str())
+            .path_and_query(path_and_query)
+            .build()
+    }
+}
+
+struct ActiveGuard(Arc<Upstream>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether `path` has a `..` segment, plain or percent-encoded. Upstreams
+/// resolve those themselves, so `/public/../admin` would be matched against
+/// one prefix and served from another.
+pub fn has_dot_dot(path: &str) -> bool {
+    path.split('/').any(|segment| segment.to_ascii_lowercase().replace("%2e", ".") == "..")
+}
+
+/// The longest prefix wins, so `/api/v2` can be routed apart from `/api`.
+pub fn find_route<'a>(routes: &'a [ProxyRoute], path: &str) -> Option<&'a ProxyRoute> {
+    routes.iter()
+        .filter(|route| route.matches(path))
+        .max_by_key(|route| route.prefix.len())
+}
+
+/// Forwards the request upstream and relays the response; bodies are streamed
+/// in both directions.
+pub async fn forward(route: &ProxyRoute, cache: &CacheConfig, req: Request<Body>, client_addr: SocketAddr) -> Response<Body> {
+    let key = match cache::lookup(cache, &req).await {
+        Lookup::Hit(response) => return response,
+        Lookup::Miss(key) => Some(key),
+        Lookup::Bypass => None,
+    };
+    let upstream = route.pick().clone();
+    let (mut parts, body) = req.into_parts();
+    parts.uri = match upstream.uri(&route.prefix, &parts.uri) {
+        Ok(uri) => uri,
+        Err(_) => return bad_gateway(),
+    };
+
+    let original_host = parts.headers.remove(HOST);
+    remove_hop_by_hop(&mut parts.headers);
+    let forwarded_for = match parts.headers.get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, client_addr.ip()),
+        None => client_addr.ip().to_string(),
+    };
+    parts.headers.insert("X-Forwarded-For", HeaderValue::from_str(&forwarded_for).unwrap());
+    let proto = if parts.extensions.get::<Session>().is_some() { "https" } else { "http" };
+    parts.headers.insert("X-Forwarded-Proto", HeaderValue::from_static(proto));
+    if let Some(host) = original_host {
+        parts.headers.insert("X-Forwarded-Host", host);
+    }
+
+    upstream.active.fetch_add(1, Ordering::Relaxed);
+    let guard = ActiveGuard(upstream.clone());
+    let client = CLIENT.get_or_init(Client::new);
+    let timings = parts.extensions.get::<Arc<Timings>>().cloned();
+    let waiting = Instant::now();
+    let response = client.request(Request::from_parts(parts, body)).await;
+    if let Some(timings) = timings {
+        timings.add(Phase::Upstream, waiting.elapsed());
+    }
+    match response {
+        Ok(mut response) if key.is_some() => {
+            remove_hop_by_hop(response.headers_mut());
+            let response = cache::store(cache, key.unwrap(), response).await;
+            drop(guard);
+            response
+        },
+        Ok(response) => {
+            let (mut parts, body) = response.into_parts();
+            remove_hop_by_hop(&mut parts.headers);
+            // The upstream stays busy until its body has been relayed.
+            let body = body.map(move |chunk| {
+                let _ = &guard;
+                chunk
+            });
+            Response::from_parts(parts, Body::wrap_stream(body))
+        },
+        Err(e) => {
+            event::error(&format!("Proxy error for {}: {}", upstream.authority, e));
+            bad_gateway()
+        }
+    }
+}
+
+/// Prefixes of the routes none of whose upstreams accept a TCP connection
+/// within `timeout`.
+pub async fn unreachable(routes: &[ProxyRoute], timeout: Duration) -> Vec<String> {
+    let mut unreachable = Vec::new();
+    'routes: for route in routes {
+        for upstream in &route.upstreams {
+            let connect = TcpStream::connect(upstream.authority.as_str());
+            if let Ok(Ok(_)) = tokio::time::timeout(timeout, connect).await {
+                continue 'routes;
+            }
+        }
+        unreachable.push(if route.prefix.is_empty() { "/".to_string() } else { route.prefix.clone() });
+    }
+    unreachable
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers.get_all(CONNECTION).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+pub fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from("<html>502 Bad Gateway</html>"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_dot_dot_segments() {
+        assert!(has_dot_dot("/public/../admin"));
+        assert!(has_dot_dot("/public/%2E%2e/admin"));
+        assert!(has_dot_dot("/public/.%2e"));
+        assert!(!has_dot_dot("/public/..hidden/a.b"));
+        assert!(!has_dot_dot("/public/./admin"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY TCP6 <39 chars> <39 chars> 65535 65535\r\n" is the longest v1 line.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol (v1 or v2) preamble that haproxy and most cloud
+/// load balancers send ahead of the HTTP request. Returns the original client
+/// address, or `None` for health checks sent as LOCAL / UNKNOWN.
+pub async fn read_header<I: AsyncRead + Unpin>(io: &mut I) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 8];
+    io.read_exact(&mut start).await?;
+    if start.starts_with(b"PROXY ") {
+        read_v1(io, &start).await
+    } else if start == V2_SIGNATURE[..8] {
+        read_v2(io).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+// Only read byte by byte so nothing of the HTTP request that follows is consumed.
+async fn read_v1<I: AsyncRead + Unpin>(io: &mut I, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("malformed PROXY v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("malformed PROXY v1 source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("malformed PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<I: AsyncRead + Unpin>(io: &mut I) -> io::Result<Option<SocketAddr>> {
+    let mut rest = [0u8; 8];
+    io.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    let version_command = rest[4];
+    let family = rest[5];
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    let mut body = vec![0u8; len];
+    io.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // LOCAL connections come from the proxy itself, e.g. health checks.
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        },
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        },
+        // AF_UNSPEC and AF_UNIX carry no client IP.
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
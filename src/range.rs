@@ -0,0 +1,55 @@
+pub enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+pub fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) => spec.trim(),
+        None => return ByteRange::Full,
+    };
+
+    // Multiple ranges would need a multipart/byteranges body, so they are
+    // answered with the whole file instead.
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    if start.is_empty() {
+        let suffix: u64 = match end.parse() {
+            Ok(suffix) => suffix,
+            Err(_) => return ByteRange::Full,
+        };
+        if suffix == 0 || len == 0 {
+            return ByteRange::Unsatisfiable;
+        }
+        return ByteRange::Partial(len.saturating_sub(suffix), len - 1);
+    }
+
+    let start: u64 = match start.parse() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+    let end: u64 = if end.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match end.parse() {
+            // A last byte before the first makes the range invalid, which
+            // means it is ignored rather than unsatisfiable.
+            Ok(end) if end < start => return ByteRange::Full,
+            Ok(end) => end,
+            Err(_) => return ByteRange::Full,
+        }
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.min(len - 1))
+}
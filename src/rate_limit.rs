@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::auth;
+
+// Past this many tracked clients, those whose bucket has refilled are forgotten.
+const MAX_IDLE_CLIENTS: usize = 10_000;
+
+/// A token bucket per client IP: `rate` requests per second on average, with
+/// up to `burst` in a row.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter { rate, burst: burst.max(1.0), buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token for `ip`, or says how long until one is available.
+    pub fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_IDLE_CLIENTS && !buckets.contains_key(&ip.to_canonical()) {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(ip.to_canonical())
+            .or_insert(Bucket { tokens: self.burst, updated: now });
+        let tokens = self.refill(bucket, now);
+        if tokens >= 1.0 {
+            *bucket = Bucket { tokens: tokens - 1.0, updated: now };
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// A `--rate-limit-route PREFIX=REQS/SEC[:BURST]` limiter, applied on top of
+/// the global one to requests under PREFIX.
+pub struct RouteLimit {
+    prefix: String,
+    limiter: RateLimiter,
+}
+
+impl RouteLimit {
+    pub fn parse(option: &str, value: &str) -> Result<RouteLimit, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let (prefix, limit) = value.split_once('=').ok_or_else(|| invalid("expected PREFIX=REQS/SEC[:BURST]"))?;
+        if !prefix.starts_with('/') {
+            return Err(invalid("the prefix must start with '/'"));
+        }
+        let positive = |n: &str| n.parse::<f64>().ok().filter(|n| *n > 0.0 && n.is_finite());
+        let (rate, burst) = match limit.split_once(':') {
+            Some((rate, burst)) => (positive(rate), positive(burst)),
+            None => (positive(limit), positive(limit)),
+        };
+        match (rate, burst) {
+            (Some(rate), Some(burst)) => Ok(RouteLimit {
+                prefix: prefix.trim_end_matches('/').to_string(),
+                limiter: RateLimiter::new(rate, burst),
+            }),
+            _ => Err(invalid("the rate and burst must be positive numbers")),
+        }
+    }
+}
+
+/// Takes a token from the global limiter and from the route with the longest
+/// prefix covering `path`; the longer wait wins when either is empty.
+pub fn acquire(global: Option<&RateLimiter>, routes: &[RouteLimit], path: &str, ip: IpAddr) -> Result<(), Duration> {
+    let segments = auth::segments(path);
+    let route = routes.iter()
+        .filter(|route| segments.starts_with(&auth::segments(&route.prefix)))
+        .max_by_key(|route| route.prefix.len());
+    let global = global.map_or(Ok(()), |limiter| limiter.acquire(ip));
+    let route = route.map_or(Ok(()), |route| route.limiter.acquire(ip));
+    match (global, route) {
+        (Err(a), Err(b)) => Err(a.max(b)),
+        (Err(wait), _) | (_, Err(wait)) => Err(wait),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,111 @@
+use hyper::header::{HOST, LOCATION};
+use std::path::Path;
+use hyper::{Body, Request, Response, StatusCode};
+
+/// A `--redirect '/old/* /new/* 308'` entry. Patterns are exact paths or end
+/// in `*`, which matches the rest of the path and is substituted for a `*`
+/// in the target.
+pub struct RedirectRule {
+    pattern: String,
+    target: String,
+    status: StatusCode,
+}
+
+impl RedirectRule {
+    pub fn parse(option: &str, value: &str) -> Result<RedirectRule, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let (pattern, target, status) = match words.as_slice() {
+            [pattern, target] => (pattern, target, StatusCode::MOVED_PERMANENTLY),
+            [pattern, target, status] => {
+                let status = match *status {
+                    "301" => StatusCode::MOVED_PERMANENTLY,
+                    "302" => StatusCode::FOUND,
+                    "307" => StatusCode::TEMPORARY_REDIRECT,
+                    "308" => StatusCode::PERMANENT_REDIRECT,
+                    _ => return Err(invalid("the status must be 301, 302, 307 or 308")),
+                };
+                (pattern, target, status)
+            },
+            _ => return Err(invalid("expected '<PATH> <TARGET> [STATUS]'")),
+        };
+        if !pattern.starts_with('/') || pattern[..pattern.len() - 1].contains('*') {
+            return Err(invalid("the path must start with '/' and may only end in '*'"));
+        }
+        Ok(RedirectRule { pattern: pattern.to_string(), target: target.to_string(), status })
+    }
+
+    /// Reads one rule per line; blank lines and `#` comments are skipped.
+    pub fn load(path: &Path) -> Result<Vec<RedirectRule>, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        contents.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| RedirectRule::parse(&path.display().to_string(), line))
+            .collect()
+    }
+}
+
+/// Exact paths win over wildcards, and longer wildcard prefixes over shorter ones.
+pub fn find_redirect(rules: &[RedirectRule], path: &str, query: Option<&str>) -> Option<(StatusCode, String)> {
+    let exact = rules.iter().find(|rule| rule.pattern == path);
+    let (rule, target) = match exact {
+        Some(rule) => (rule, rule.target.clone()),
+        None => rules.iter()
+            .filter_map(|rule| {
+                let prefix = rule.pattern.strip_suffix('*')?;
+                let rest = path.strip_prefix(prefix)?;
+                Some((rule, prefix.len(), rest))
+            })
+            .max_by_key(|(_, prefix_len, _)| *prefix_len)
+            .map(|(rule, _, rest)| (rule, rule.target.replacen('*', rest, 1)))?,
+    };
+    let location = match query {
+        Some(query) if !target.contains('?') => format!("{}?{}", target, query),
+        _ => target,
+    };
+    Some((rule.status, location))
+}
+
+/// Answers every request on the plain-HTTP redirect listener with a 301 to the
+/// same host, path and query on the HTTPS origin.
+pub fn https_redirect(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .map(strip_port)
+        .filter(|host| !host.is_empty());
+
+    let host = match host {
+        Some(host) => host,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>400 Bad Request</html>"))
+                .unwrap();
+        }
+    };
+
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path_and_query),
+        port => format!("https://{}:{}{}", host, port, path_and_query),
+    };
+
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(LOCATION, &location)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(format!("<html>301 Moved Permanently: <a href=\"{0}\">{0}</a></html>", location)))
+        .unwrap()
+}
+
+// `example.com:80` -> `example.com`, `[::1]:80` -> `[::1]`.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
@@ -0,0 +1,468 @@
+// A small backtracking regular expression engine for rewrite rules. It knows
+// literals, `.`, classes (`[a-z]`, `[^/]`, `\d`, `\w`, `\s`), anchors, groups,
+// alternation and the `*`, `+`, `?`, `{m,n}` quantifiers with lazy variants.
+// Matching remembers failed (instruction, position) pairs, so it runs in
+// O(pattern * text) time however the pattern is written.
+
+pub struct Regex {
+    program: Vec<Inst>,
+    groups: usize,
+}
+
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>, bool),
+}
+
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    // Tries the first target before the second.
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Match,
+}
+
+// Keeps patterns like `(a{1000}){1000}` from exhausting memory.
+const MAX_PROGRAM: usize = 2_000;
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, groups: 0 };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("unmatched ')' at offset {}", parser.pos));
+        }
+        let mut program = vec![Inst::Save(0)];
+        compile(&node, &mut program)?;
+        program.push(Inst::Save(1));
+        program.push(Inst::Match);
+        Ok(Regex { program, groups: parser.groups + 1 })
+    }
+
+    /// Leftmost match; group 0 is the whole match.
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<String>>> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut failed = vec![false; self.program.len() * (chars.len() + 1)];
+        for start in 0..=chars.len() {
+            if let Some(slots) = self.run(&chars, start, &mut failed) {
+                let captures = (0..self.groups)
+                    .map(|group| match (slots[2 * group], slots[2 * group + 1]) {
+                        (Some(from), Some(to)) => Some(chars[from..to].iter().collect()),
+                        _ => None,
+                    })
+                    .collect();
+                return Some(captures);
+            }
+        }
+        None
+    }
+
+    fn run(&self, chars: &[char], start: usize, failed: &mut [bool]) -> Option<Vec<Option<usize>>> {
+        let mut stack = vec![(0, start, vec![None; 2 * self.groups])];
+        while let Some((mut pc, mut pos, mut slots)) = stack.pop() {
+            loop {
+                let state = pc * (chars.len() + 1) + pos;
+                if failed[state] {
+                    break;
+                }
+                failed[state] = true;
+                match &self.program[pc] {
+                    Inst::Char(c) if chars.get(pos) == Some(c) => {
+                        pc += 1;
+                        pos += 1;
+                    },
+                    Inst::Any if pos < chars.len() => {
+                        pc += 1;
+                        pos += 1;
+                    },
+                    Inst::Class(ranges, negated) if pos < chars.len() && in_class(ranges, chars[pos]) != *negated => {
+                        pc += 1;
+                        pos += 1;
+                    },
+                    Inst::Start if pos == 0 => pc += 1,
+                    Inst::End if pos == chars.len() => pc += 1,
+                    Inst::Split(first, second) => {
+                        stack.push((*second, pos, slots.clone()));
+                        pc = *first;
+                    },
+                    Inst::Jump(target) => pc = *target,
+                    Inst::Save(slot) => {
+                        slots[*slot] = Some(pos);
+                        pc += 1;
+                    },
+                    Inst::Match => return Some(slots),
+                    _ => break,
+                }
+            }
+        }
+        None
+    }
+}
+
+fn in_class(ranges: &[(char, char)], c: char) -> bool {
+    ranges.iter().any(|&(from, to)| from <= c && c <= to)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat('|') {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { Node::Alternate(branches) })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            items.push(self.quantified(atom)?);
+        }
+        Ok(match items.len() {
+            0 => Node::Empty,
+            1 => items.pop().unwrap(),
+            _ => Node::Concat(items),
+        })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let start = self.pos;
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => match self.counted()? {
+                Some(bounds) => bounds,
+                None => return Ok(atom),
+            },
+            _ => return Ok(atom),
+        };
+        if self.pos == start {
+            self.pos += 1;
+        }
+        if matches!(atom, Node::Start | Node::End) {
+            return Err(format!("nothing to repeat at offset {}", start));
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat(Box::new(atom), min, max, greedy))
+    }
+
+    // Parses `{m}`, `{m,}` or `{m,n}`; a `{` that starts none of them is a literal.
+    fn counted(&mut self) -> Result<Option<(u32, Option<u32>)>, String> {
+        let rest: String = self.chars[self.pos..].iter().collect();
+        let close = match rest.find('}') {
+            Some(close) => close,
+            None => return Ok(None),
+        };
+        let inner = &rest[1..close];
+        let number = |s: &str| s.parse::<u32>().ok().filter(|n| *n <= 1000);
+        let bounds = match inner.split_once(',') {
+            Some((min, "")) => number(min).map(|min| (min, None)),
+            Some((min, max)) => number(min).zip(number(max)).map(|(min, max)| (min, Some(max))),
+            None => number(inner).map(|n| (n, Some(n))),
+        };
+        match bounds {
+            Some((min, Some(max))) if min > max => Err(format!("invalid repetition {{{}}}", inner)),
+            Some(bounds) => {
+                self.pos += inner.chars().count() + 2;
+                Ok(Some(bounds))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        match c {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '(' => {
+                let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(Node::Group(Box::new(inner), index))
+            },
+            '[' => self.class(),
+            '\\' => self.escape(),
+            '*' | '+' | '?' => Err(format!("nothing to repeat at offset {}", self.pos - 1)),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("trailing '\\'")?;
+        self.pos += 1;
+        Ok(match shorthand_class(c) {
+            Some((ranges, negated)) => Node::Class(ranges, negated),
+            None => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("missing ']'")?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let from = if c == '\\' {
+                let escaped = self.peek().ok_or("missing ']'")?;
+                self.pos += 1;
+                if let Some((shorthand, false)) = shorthand_class(escaped) {
+                    ranges.extend(shorthand);
+                    continue;
+                }
+                escaped
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let mut to = self.peek().unwrap();
+                self.pos += 1;
+                if to == '\\' {
+                    to = self.peek().ok_or("missing ']'")?;
+                    self.pos += 1;
+                }
+                if to < from {
+                    return Err(format!("invalid range {}-{}", from, to));
+                }
+                ranges.push((from, to));
+            } else {
+                ranges.push((from, from));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+}
+
+fn shorthand_class(c: char) -> Option<(Vec<(char, char)>, bool)> {
+    let digits = vec![('0', '9')];
+    let word = vec![('0', '9'), ('A', 'Z'), ('a', 'z'), ('_', '_')];
+    let space = vec![(' ', ' '), ('\t', '\r')];
+    match c {
+        'd' => Some((digits, false)),
+        'D' => Some((digits, true)),
+        'w' => Some((word, false)),
+        'W' => Some((word, true)),
+        's' => Some((space, false)),
+        'S' => Some((space, true)),
+        _ => None,
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM {
+        return Err("pattern too large".to_string());
+    }
+    match node {
+        Node::Empty => {},
+        Node::Char(c) => program.push(Inst::Char(*c)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(ranges, negated) => program.push(Inst::Class(ranges.clone(), *negated)),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Group(inner, index) => {
+            if let Some(index) = index {
+                program.push(Inst::Save(2 * index));
+            }
+            compile(inner, program)?;
+            if let Some(index) = index {
+                program.push(Inst::Save(2 * index + 1));
+            }
+        },
+        Node::Concat(items) => {
+            for item in items {
+                compile(item, program)?;
+            }
+        },
+        Node::Alternate(branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 < branches.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(branch, program)?;
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let next = program.len();
+                    program[split] = Inst::Split(split + 1, next);
+                } else {
+                    compile(branch, program)?;
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        },
+        Node::Repeat(inner, min, max, greedy) => {
+            for _ in 0..*min {
+                compile(inner, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Jump(0));
+                    compile(inner, program)?;
+                    program.push(Inst::Jump(split));
+                    let end = program.len();
+                    program[split] = split_for(*greedy, split + 1, end);
+                },
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Jump(0));
+                        compile(inner, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = split_for(*greedy, split + 1, end);
+                    }
+                },
+            }
+        },
+    }
+    Ok(())
+}
+
+fn split_for(greedy: bool, body: usize, skip: usize) -> Inst {
+    if greedy {
+        Inst::Split(body, skip)
+    } else {
+        Inst::Split(skip, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        Regex::new(pattern).unwrap().captures(text)
+    }
+
+    fn matched(pattern: &str, text: &str) -> Option<String> {
+        captures(pattern, text).and_then(|groups| groups[0].clone())
+    }
+
+    #[test]
+    fn literals_anchors_and_classes() {
+        assert_eq!(matched("b.d", "abcde").as_deref(), Some("bcd"));
+        assert_eq!(matched("^abc$", "abc").as_deref(), Some("abc"));
+        assert_eq!(matched("^abc$", "abcd"), None);
+        assert_eq!(matched("[a-c]+", "xxbcaby").as_deref(), Some("bcab"));
+        assert_eq!(matched("[^/]+$", "/a/b/file.txt").as_deref(), Some("file.txt"));
+        assert_eq!(matched(r"\d+", "id=0042;").as_deref(), Some("0042"));
+        assert_eq!(matched(r"\w+", "  snake_case1 ").as_deref(), Some("snake_case1"));
+        assert_eq!(matched(r"\s\S", "ab c").as_deref(), Some(" c"));
+        assert_eq!(matched(r"[\d.]+", "v1.2.3").as_deref(), Some("1.2.3"));
+        assert_eq!(matched(r"\.html$", "index.html").as_deref(), Some(".html"));
+        assert_eq!(matched(r"\.html$", "indexhtml"), None);
+        // A `]` first in a class and a trailing `-` are literals.
+        assert_eq!(matched("[]a]+", "x]a]").as_deref(), Some("]a]"));
+        assert_eq!(matched("[a-]+", "x-a-").as_deref(), Some("-a-"));
+        assert_eq!(matched("é+", "café").as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn groups_and_alternation() {
+        let groups = captures(r"^/(\w+)/(\d+)(/edit)?$", "/users/42").unwrap();
+        assert_eq!(groups, vec![Some("/users/42".to_string()), Some("users".to_string()), Some("42".to_string()), None]);
+        let groups = captures("(?:ab)+(c)", "ababc").unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[1].as_deref(), Some("c"));
+        // The first alternative that leads to a match wins, not the longest.
+        assert_eq!(matched("a|ab", "ab").as_deref(), Some("a"));
+        assert_eq!(matched("(a|ab)c", "abc").as_deref(), Some("abc"));
+        assert_eq!(matched("x(|y)z", "xz").as_deref(), Some("xz"));
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert_eq!(matched("<.+>", "<a><b>").as_deref(), Some("<a><b>"));
+        assert_eq!(matched("<.+?>", "<a><b>").as_deref(), Some("<a>"));
+        assert_eq!(matched("a{2,3}", "aaaa").as_deref(), Some("aaa"));
+        assert_eq!(matched("a{2,3}?", "aaaa").as_deref(), Some("aa"));
+        assert_eq!(matched("^a{2}$", "aa").as_deref(), Some("aa"));
+        assert_eq!(matched("^a{2,}$", "aaaaa").as_deref(), Some("aaaaa"));
+        assert_eq!(matched("^a{2,}$", "a"), None);
+        assert_eq!(matched("colou?r", "color").as_deref(), Some("color"));
+        // `{` that does not start a repetition is a literal.
+        assert_eq!(matched("a{x}", "a{x}").as_deref(), Some("a{x}"));
+        assert_eq!(matched("a{", "a{").as_deref(), Some("a{"));
+    }
+
+    #[test]
+    fn empty_matches() {
+        assert_eq!(matched("", "abc").as_deref(), Some(""));
+        assert_eq!(matched("x*", "abc").as_deref(), Some(""));
+        assert_eq!(matched("^$", "").as_deref(), Some(""));
+        assert_eq!(matched("(a*)*b", "aab").as_deref(), Some("aab"));
+    }
+
+    #[test]
+    fn pathological_patterns_stay_linear() {
+        let text = "a".repeat(5000);
+        assert_eq!(matched("^(a+)+$", &format!("{}b", text)), None);
+        assert_eq!(matched("^(a|aa)*c", &text), None);
+        assert_eq!(matched("(.*)*x", &text), None);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in ["(", "(a", "a)", "*a", "a**", "+", "[a", "[z-a]", "a{3,1}", "\\", "^*", "(a{1000}){1000}"] {
+            assert!(Regex::new(pattern).is_err(), "{:?} compiled", pattern);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use hyper::StatusCode;
+use crate::regex::Regex;
+
+// `last` restarts the rules with the rewritten URL; this bounds the loops.
+const MAX_CYCLES: usize = 10;
+
+/// A `--rewrite '<REGEX> <REPLACEMENT> [last|break|redirect|permanent]'` rule,
+/// matched against the URL path the way nginx's `rewrite` directive is.
+pub struct RewriteRule {
+    regex: Regex,
+    replacement: String,
+    flag: Flag,
+}
+
+#[derive(Clone, Copy)]
+enum Flag {
+    // Keep applying the following rules to the rewritten URL.
+    Continue,
+    Last,
+    Break,
+    Redirect,
+    Permanent,
+}
+
+pub enum Rewrite {
+    Unchanged,
+    /// New path and query to route the request with.
+    Internal(String),
+    Redirect(StatusCode, String),
+}
+
+impl RewriteRule {
+    pub fn parse(option: &str, value: &str) -> Result<RewriteRule, String> {
+        let invalid = |reason: String| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let (pattern, replacement, flag) = match words.as_slice() {
+            [pattern, replacement] => (pattern, replacement, Flag::Continue),
+            [pattern, replacement, flag] => {
+                let flag = match *flag {
+                    "last" => Flag::Last,
+                    "break" => Flag::Break,
+                    "redirect" => Flag::Redirect,
+                    "permanent" => Flag::Permanent,
+                    flag => return Err(invalid(format!("unknown flag '{}'", flag))),
+                };
+                (pattern, replacement, flag)
+            },
+            _ => return Err(invalid("expected '<REGEX> <REPLACEMENT> [FLAG]'".to_string())),
+        };
+        Ok(RewriteRule {
+            regex: Regex::new(pattern).map_err(invalid)?,
+            replacement: replacement.to_string(),
+            flag,
+        })
+    }
+}
+
+pub fn apply(rules: &[RewriteRule], path: &str, query: Option<&str>) -> Rewrite {
+    if rules.is_empty() {
+        return Rewrite::Unchanged;
+    }
+    let mut path = path.to_string();
+    let mut query = query.map(|q| q.to_string());
+    let mut changed = false;
+
+    'cycles: for _ in 0..MAX_CYCLES {
+        for rule in rules {
+            let captures = match rule.regex.captures(&path) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            let target = expand(&rule.replacement, &captures);
+            // The original query string is kept unless the replacement sets one.
+            let (new_path, new_query) = match target.split_once('?') {
+                Some((new_path, new_query)) => (new_path.to_string(), Some(new_query.to_string())),
+                None => (target.clone(), query.clone()),
+            };
+            let location = match &new_query {
+                Some(new_query) if !new_query.is_empty() => format!("{}?{}", new_path, new_query),
+                _ => new_path.clone(),
+            };
+
+            let absolute = new_path.starts_with("http://") || new_path.starts_with("https://");
+            match rule.flag {
+                Flag::Permanent => return Rewrite::Redirect(StatusCode::MOVED_PERMANENTLY, location),
+                Flag::Redirect => return Rewrite::Redirect(StatusCode::FOUND, location),
+                _ if absolute => return Rewrite::Redirect(StatusCode::FOUND, location),
+                _ => {},
+            }
+
+            path = new_path;
+            query = new_query;
+            changed = true;
+            match rule.flag {
+                Flag::Last => continue 'cycles,
+                Flag::Break => break 'cycles,
+                _ => {},
+            }
+        }
+        break;
+    }
+
+    if !changed {
+        return Rewrite::Unchanged;
+    }
+    match query {
+        Some(query) if !query.is_empty() => Rewrite::Internal(format!("{}?{}", path, query)),
+        _ => Rewrite::Internal(path),
+    }
+}
+
+// Substitutes `$0`-`$9` with the captured groups; `$$` is a literal dollar.
+fn expand(replacement: &str, captures: &[Option<String>]) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek().copied() {
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            },
+            Some(digit @ '0'..='9') => {
+                chars.next();
+                let group = digit as usize - '0' as usize;
+                if let Some(Some(text)) = captures.get(group) {
+                    out.push_str(text);
+                }
+            },
+            _ => out.push('$'),
+        }
+    }
+    out
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+
+/// Kernel resource limits set on each script process, inherited by whatever
+/// it starts.
+#[derive(Clone, Copy, Default)]
+pub struct ResourceLimits {
+    /// Address space in bytes; allocations past it fail.
+    pub memory: Option<u64>,
+    /// CPU seconds; the script gets SIGXCPU, then SIGKILL a second later.
+    pub cpu: Option<u64>,
+    pub open_files: Option<u64>,
+    /// Processes of the script's user, the script's own included.
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_set(&self) -> bool {
+        self.memory.is_some() || self.cpu.is_some() || self.open_files.is_some() || self.processes.is_some()
+    }
+
+    /// Makes `cmd` lower its limits between fork and exec.
+    pub fn apply(&self, cmd: &mut std::process::Command) {
+        let limits = *self;
+        // SAFETY: setrlimit is async-signal-safe and nothing is allocated.
+        unsafe {
+            cmd.pre_exec(move || limits.set());
+        }
+    }
+
+    fn set(&self) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_AS, self.memory.map(|bytes| (bytes, bytes))),
+            (libc::RLIMIT_CPU, self.cpu.map(|secs| (secs, secs + 1))),
+            (libc::RLIMIT_NOFILE, self.open_files.map(|files| (files, files))),
+            (libc::RLIMIT_NPROC, self.processes.map(|processes| (processes, processes))),
+        ];
+        for (resource, limit) in limits {
+            let Some((soft, hard)) = limit else { continue };
+            // Without privileges a hard limit can only go down, so one
+            // already lower than asked for is kept.
+            let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let hard = (hard as libc::rlim_t).min(current.rlim_max);
+            let limit = libc::rlimit { rlim_cur: (soft as libc::rlim_t).min(hard), rlim_max: hard };
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use hyper::Method;
+
+/// A `--route 'GET /api/users/{id} -> scripts/user_get.sh'` entry: requests
+/// whose method and path match run the script, with each `{name}` segment
+/// passed to it as ROUTE_<name>. The method may be `*` for any.
+pub struct Route {
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    /// The script, relative to the root folder.
+    pub script: PathBuf,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+/// The `{name}` segments of the route a request matched, percent-decoded.
+#[derive(Clone)]
+pub struct RouteParams(pub Vec<(String, String)>);
+
+pub enum Match<'a> {
+    Found(&'a Route, RouteParams),
+    /// The path matched routes for other methods only, listed for `Allow`.
+    WrongMethod(Vec<String>),
+    None,
+}
+
+impl Route {
+    pub fn parse(option: &str, value: &str) -> Result<Route, String> {
+        let invalid = |reason: &str| format!("invalid value '{}' for '{}': {}", value, option, reason);
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let [method, pattern, "->", script] = words.as_slice() else {
+            return Err(invalid("expected '<METHOD> <PATH> -> <SCRIPT>'"));
+        };
+        let method = match *method {
+            "*" => None,
+            method => Some(Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|_| invalid("an invalid method"))?),
+        };
+        let segments = pattern.strip_prefix('/').ok_or_else(|| invalid("the path must start with '/'"))?
+            .split('/')
+            .map(|segment| match segment.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+                Some(name) if !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') => Ok(Segment::Param(name.to_string())),
+                Some(_) => Err(invalid("parameter names are letters, digits and '_'")),
+                None if segment.contains(['{', '}']) => Err(invalid("a parameter must be a whole segment")),
+                None => Ok(Segment::Literal(segment.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        let script = PathBuf::from(script.trim_start_matches('/'));
+        if script.as_os_str().is_empty() || script.components().any(|c| c == std::path::Component::ParentDir) {
+            return Err(invalid("the script must be a path inside the root folder"));
+        }
+        Ok(Route { method, segments, script })
+    }
+
+    /// Reads one route per line; blank lines and `#` comments are skipped.
+    pub fn load(path: &Path) -> Result<Vec<Route>, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        contents.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| Route::parse(&path.display().to_string(), line))
+            .collect()
+    }
+
+    fn captures(&self, path: &str) -> Option<RouteParams> {
+        let parts: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {},
+                Segment::Param(name) if !part.is_empty() => params.push((name.clone(), percent_decode(part)?)),
+                _ => return None,
+            }
+        }
+        Some(RouteParams(params))
+    }
+}
+
+/// The first route, in the order given, matching both path and method; HEAD
+/// requests also match GET routes.
+pub fn find<'a>(routes: &'a [Route], method: &Method, path: &str) -> Match<'a> {
+    let mut allowed = Vec::new();
+    for route in routes {
+        let Some(params) = route.captures(path) else { continue };
+        match &route.method {
+            None => return Match::Found(route, params),
+            Some(m) if m == method || (method == Method::HEAD && m == Method::GET) => return Match::Found(route, params),
+            Some(m) => {
+                allowed.push(m.to_string());
+                if m == Method::GET {
+                    allowed.push("HEAD".to_string());
+                }
+            },
+        }
+    }
+    if allowed.is_empty() {
+        return Match::None;
+    }
+    allowed.sort();
+    allowed.dedup();
+    Match::WrongMethod(allowed)
+}
+
+// A segment with `%XX` escapes decoded; None when they do not make UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,213 @@
+// RSASSA-PKCS1-v1_5 signature verification with SHA-256 (RFC 8017 section
+// 8.2.2), the RS256 algorithm of JSON Web Tokens. Only public-key operations
+// are done here, so the arithmetic does not need to be constant time.
+
+use crate::sha256::sha256;
+
+// DER encoding of the SHA-256 AlgorithmIdentifier that prefixes the digest.
+const DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+];
+// RFC 7518 section 3.3 requires keys of at least 2048 bits.
+const MIN_MODULUS_BITS: usize = 2048;
+const MAX_MODULUS_BITS: usize = 8192;
+
+pub struct RsaPublicKey {
+    modulus: Vec<u32>,
+    exponent: Vec<u32>,
+    size: usize,
+}
+
+impl RsaPublicKey {
+    /// Takes the big-endian modulus and exponent, as found in a JWK's `n` and `e`.
+    pub fn new(modulus: &[u8], exponent: &[u8]) -> Result<RsaPublicKey, String> {
+        let modulus_bytes = trim(modulus);
+        let bits = modulus_bytes.len() * 8 - modulus_bytes.first().map_or(8, |b| b.leading_zeros() as usize);
+        if !(MIN_MODULUS_BITS..=MAX_MODULUS_BITS).contains(&bits) {
+            return Err(format!("RSA keys must have {} to {} bits, not {}", MIN_MODULUS_BITS, MAX_MODULUS_BITS, bits));
+        }
+        let exponent = trim(exponent);
+        if exponent.is_empty() || exponent.len() > modulus_bytes.len() {
+            return Err("invalid RSA exponent".to_string());
+        }
+        Ok(RsaPublicKey {
+            modulus: from_bytes(modulus_bytes),
+            exponent: from_bytes(exponent),
+            size: modulus_bytes.len(),
+        })
+    }
+
+    pub fn verify_sha256(&self, message: &[u8], signature: &[u8]) -> bool {
+        if signature.len() != self.size {
+            return false;
+        }
+        let s = from_bytes(signature);
+        if compare(&s, &self.modulus) != std::cmp::Ordering::Less {
+            return false;
+        }
+        let encoded = to_bytes(&mod_pow(&s, &self.exponent, &self.modulus), self.size);
+
+        // 0x00 0x01 0xFF.. 0x00 DigestInfo digest
+        let mut expected = vec![0x00, 0x01];
+        expected.resize(self.size - DIGEST_INFO.len() - 32 - 1, 0xFF);
+        expected.push(0x00);
+        expected.extend_from_slice(&DIGEST_INFO);
+        expected.extend_from_slice(&sha256(message));
+        encoded == expected
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let zeros = bytes.iter().take_while(|&&b| b == 0).count();
+    &bytes[zeros..]
+}
+
+// Numbers are little-endian vectors of 32-bit limbs without high zero limbs.
+fn from_bytes(bytes: &[u8]) -> Vec<u32> {
+    let mut limbs: Vec<u32> = bytes.rchunks(4)
+        .map(|chunk| chunk.iter().fold(0u32, |limb, &b| limb << 8 | b as u32))
+        .collect();
+    normalize(&mut limbs);
+    limbs
+}
+
+fn to_bytes(limbs: &[u32], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    for (i, limb) in limbs.iter().enumerate() {
+        for (j, byte) in limb.to_le_bytes().iter().enumerate() {
+            if let Some(slot) = len.checked_sub(4 * i + j + 1) {
+                out[slot] = *byte;
+            }
+        }
+    }
+    out
+}
+
+fn normalize(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+fn compare(a: &[u32], b: &[u32]) -> std::cmp::Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn multiply(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let t = x as u64 * y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    normalize(&mut out);
+    out
+}
+
+// Shift-and-subtract reduction, one bit of `x` at a time.
+fn remainder(x: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut r: Vec<u32> = Vec::with_capacity(modulus.len() + 1);
+    for i in (0..x.len() * 32).rev() {
+        let bit = x[i / 32] >> (i % 32) & 1;
+        let mut carry = bit;
+        for limb in r.iter_mut() {
+            let next = *limb >> 31;
+            *limb = *limb << 1 | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            r.push(carry);
+        }
+        if compare(&r, modulus) != std::cmp::Ordering::Less {
+            subtract(&mut r, modulus);
+        }
+    }
+    r
+}
+
+fn subtract(a: &mut Vec<u32>, b: &[u32]) {
+    let mut borrow = 0i64;
+    for (i, limb) in a.iter_mut().enumerate() {
+        let t = *limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        *limb = t as u32;
+        borrow = i64::from(t < 0);
+    }
+    normalize(a);
+}
+
+fn mod_pow(base: &[u32], exponent: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut result = vec![1u32];
+    for i in (0..exponent.len() * 32).rev() {
+        result = remainder(&multiply(&result, &result), modulus);
+        if exponent[i / 32] >> (i % 32) & 1 == 1 {
+            result = remainder(&multiply(&result, base), modulus);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(lines: &[&str]) -> Vec<u8> {
+        let hex = lines.concat();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // A 2048-bit key and its signature of MESSAGE, made with
+    // `openssl dgst -sha256 -sign`.
+    const MESSAGE: &[u8] = b"known answer";
+    const MODULUS: [&str; 8] = [
+        "96f2d9b419d147059ac96b55f9aedd8b1baea43537167be6e62a6bd758d82a3e",
+        "c33b93400629d20186339518883314a45e217ed1b1a0dfafe20e68a806730da2",
+        "da7dfb5e4c4ba2b739fdd5f4d06d1c169f832f5e48859f356776ab61ab704e0e",
+        "4e443ba5c4873f8f92ff0644b552211f55b3b1d203d5a6fe814675524b603e82",
+        "9de83b89bc561ba433f54189da86ac5840fb4ec4e68989b371246f98b79abdbf",
+        "c088f59e2ed408d7869176a89c5bda239a09124612f37b702b9340bdad5eed37",
+        "edd777806c2fdb1fd7951a472904cb7e4441f95c49caff72b95077a9c081ab1f",
+        "e77094b1f1113ead90e8291a2e8a61660620f7647d74beeab9391cd6ae3b9ccb",
+    ];
+    const SIGNATURE: [&str; 8] = [
+        "07097577ee10492cbec7ccf3d05b626b977ee7b0f8eacefccc84fc92ab658d3a",
+        "55f64325bf102310d220c5981f18b7ec741b98b380505e57c091d74e801045e9",
+        "1b57ac05395367b7e88f6b264a100ac0372ede19537ac56b1eeb5000456e7412",
+        "b20b8c9a9eda9a267f0a8fb310c94f9e3ffb34c1e84784e872093829cefdd573",
+        "a7fe8da84e0e6598d0818ba14a3bff750f3f99cb082d4a3f4574721515620120",
+        "d78fad4930d60a36d9bab7902988185c472af7b7f910a7e44e1af0646cd8d04d",
+        "688a9fe12054ff35131eafe4df4528427f5d8b7b8543124ddb502310154043d1",
+        "c3cbe0edc14ee491a4522af49135562256e01389336751bcbdcf34f4ec94d855",
+    ];
+
+    fn key() -> RsaPublicKey {
+        RsaPublicKey::new(&unhex(&MODULUS), &[1, 0, 1]).unwrap()
+    }
+
+    #[test]
+    fn verifies_pkcs1_v15_signature() {
+        assert!(key().verify_sha256(MESSAGE, &unhex(&SIGNATURE)));
+    }
+
+    #[test]
+    fn rejects_altered_message_or_signature() {
+        let signature = unhex(&SIGNATURE);
+        assert!(!key().verify_sha256(b"known answer.", &signature));
+        let mut flipped = signature.clone();
+        flipped[100] ^= 1;
+        assert!(!key().verify_sha256(MESSAGE, &flipped));
+        assert!(!key().verify_sha256(MESSAGE, &signature[1..]));
+        // A signature that is not less than the modulus is invalid.
+        assert!(!key().verify_sha256(MESSAGE, &[0xff; 256]));
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        let modulus = unhex(&MODULUS);
+        assert!(RsaPublicKey::new(&modulus[..128], &[1, 0, 1]).is_err());
+        assert!(RsaPublicKey::new(&modulus, &[]).is_err());
+    }
+}
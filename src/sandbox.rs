@@ -0,0 +1,198 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+use crate::credentials::Credentials;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+// System calls a script has no business making; they fail with EPERM.
+const DENIED: [libc::c_long; 28] = [
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_userfaultfd,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+];
+
+/// `--script-sandbox`: scripts start in new mount, PID, network and IPC
+/// namespaces (and a user namespace when the server is not root), see the
+/// root folder read-only, have no network but loopback and run under a
+/// seccomp filter refusing DENIED.
+pub struct Sandbox {
+    root: CString,
+    // Flags the root's mount already has, which a read-only remount inside
+    // a user namespace has to keep.
+    root_flags: libc::c_ulong,
+    // uid_map and gid_map contents mapping the scripts' IDs to themselves.
+    id_maps: Option<(Vec<u8>, Vec<u8>)>,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl Sandbox {
+    /// `credentials` are those scripts run as.
+    pub fn new(root: &Path, credentials: Credentials) -> Result<Arc<Sandbox>, String> {
+        let root = std::fs::canonicalize(root).map_err(|e| format!("'--script-sandbox': {}: {}", root.display(), e))?;
+        let root = CString::new(root.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(root.as_ptr(), &mut stat) } != 0 {
+            return Err(format!("'--script-sandbox': {}", io::Error::last_os_error()));
+        }
+        let root_flags = [
+            (libc::ST_NOSUID, libc::MS_NOSUID),
+            (libc::ST_NODEV, libc::MS_NODEV),
+            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+            (libc::ST_NOATIME, libc::MS_NOATIME),
+            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+            (libc::ST_RELATIME, libc::MS_RELATIME),
+        ].iter()
+            .filter(|(statvfs_flag, _)| stat.f_flag & statvfs_flag != 0)
+            .fold(0, |flags, (_, mount_flag)| flags | mount_flag);
+        let Credentials { uid, gid } = credentials;
+        let id_maps = (uid != 0).then(|| (format!("{} {} 1", uid, uid).into_bytes(), format!("{} {} 1", gid, gid).into_bytes()));
+        Ok(Arc::new(Sandbox { root, root_flags, id_maps, filter: seccomp_filter() }))
+    }
+
+    /// Makes `cmd` enter the sandbox between fork and exec.
+    pub fn apply(self: &Arc<Sandbox>, cmd: &mut std::process::Command) {
+        let sandbox = self.clone();
+        // SAFETY: enter() only makes system calls on memory prepared
+        // beforehand; it does not allocate or take locks.
+        unsafe {
+            cmd.pre_exec(move || sandbox.enter());
+        }
+    }
+
+    fn enter(&self) -> io::Result<()> {
+        let mut namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWNET | libc::CLONE_NEWIPC;
+        if self.id_maps.is_some() {
+            namespaces |= libc::CLONE_NEWUSER;
+        }
+        check(unsafe { libc::unshare(namespaces) })?;
+        if let Some((uid_map, gid_map)) = &self.id_maps {
+            // Switching from root to --script-user made the process
+            // undumpable, which leaves its /proc files owned by root.
+            check(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1) })?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", uid_map)?;
+            write_file(c"/proc/self/gid_map", gid_map)?;
+        }
+
+        // Mounts made from here on stay in the namespace.
+        check(unsafe { libc::mount(ptr::null(), c"/".as_ptr(), ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null()) })?;
+        let root = self.root.as_ptr();
+        check(unsafe { libc::mount(root, root, ptr::null(), libc::MS_BIND | libc::MS_REC, ptr::null()) })?;
+        let read_only = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | self.root_flags;
+        check(unsafe { libc::mount(ptr::null(), root, ptr::null(), read_only, ptr::null()) })?;
+        // The working directory was entered before the bind mount, so inside
+        // the root it would still be on the writable mount underneath.
+        let mut cwd = [0 as libc::c_char; libc::PATH_MAX as usize];
+        if !unsafe { libc::getcwd(cwd.as_mut_ptr(), cwd.len()) }.is_null() {
+            check(unsafe { libc::chdir(cwd.as_ptr()) })?;
+        }
+
+        // Only children join the new PID namespace, so this process stays
+        // behind to wait for the script and pass on how it ended.
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {},
+            script => unsafe {
+                // Signals sent to the process group are for the script; this
+                // one only goes once the script has.
+                libc::signal(libc::SIGTERM, libc::SIG_IGN);
+                libc::signal(libc::SIGINT, libc::SIG_IGN);
+                libc::signal(libc::SIGHUP, libc::SIG_IGN);
+                // Holding no descriptors, it neither keeps the script's pipes
+                // open nor delays the spawn, which waits for exec.
+                if libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) != 0 {
+                    for fd in 0..1024 {
+                        libc::close(fd);
+                    }
+                }
+                let mut status = 0;
+                while libc::waitpid(script, &mut status, 0) == -1 && *libc::__errno_location() == libc::EINTR {}
+                let code = if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 128 + libc::WTERMSIG(status) };
+                libc::_exit(code);
+            },
+        }
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+        // A /proc of its own, listing only the script's processes.
+        unsafe {
+            libc::mount(c"proc".as_ptr(), c"/proc".as_ptr(), c"proc".as_ptr(), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, ptr::null());
+        }
+
+        let program = libc::sock_fprog { len: self.filter.len() as u16, filter: self.filter.as_ptr() as *mut libc::sock_filter };
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        check(unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog) })?;
+        Ok(())
+    }
+}
+
+// Kills processes of another architecture, whose system call numbers differ,
+// refuses DENIED and allows the rest.
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let statement = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |k: u32, jt: u8, jf: u8| libc::sock_filter { code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16, jt, jf, k };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    // Offsets of `arch` and `nr` in struct seccomp_data.
+    let mut filter = vec![
+        statement(load, 4),
+        jump(AUDIT_ARCH, 1, 0),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, 0),
+    ];
+    for call in DENIED {
+        filter.push(jump(call as u32, 0, 1));
+        filter.push(statement(ret, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+    filter.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = check(unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) })?;
+    let written = unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) };
+    unsafe { libc::close(fd) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
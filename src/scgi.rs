@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::io;
+use hyper::Body;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use crate::event;
+use crate::gateway::{self, Connection, Output};
+
+/// Runs one request over a fresh connection: the parameters as a netstring
+/// with CONTENT_LENGTH first, then the body. The application answers with
+/// CGI output and closes the connection.
+pub fn exchange(connection: Box<dyn Connection>, params: HashMap<String, String>, body: Body, limit: u64, upstream: String) -> Output {
+    let (reader, mut writer) = tokio::io::split(connection);
+    tokio::spawn(async move {
+        // The application may answer without reading all of its input, so
+        // a failed write only matters when the body was too large.
+        if let Err(e) = send_request(&mut writer, params, body, limit).await {
+            if e.kind() == io::ErrorKind::InvalidData {
+                event::warning(&format!("SCGI request to {} cut off: {}", upstream, e));
+                let _ = writer.shutdown().await;
+            }
+        }
+    });
+    Box::pin(ReaderStream::new(reader))
+}
+
+async fn send_request(writer: &mut (impl AsyncWrite + Unpin), mut params: HashMap<String, String>, mut body: Body, limit: u64) -> io::Result<()> {
+    let (length, buffered) = gateway::sized_body(&params, &mut body, limit).await?;
+    params.remove("CONTENT_LENGTH");
+    let mut headers = format!("CONTENT_LENGTH\0{}\0SCGI\01\0", length).into_bytes();
+    for (name, value) in &params {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut request = format!("{}:", headers.len()).into_bytes();
+    request.extend_from_slice(&headers);
+    request.push(b',');
+    writer.write_all(&request).await?;
+    gateway::send_body(writer, body, buffered, limit).await?;
+    writer.flush().await
+}
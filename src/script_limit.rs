@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps on script processes running at once, overall and per script file.
+#[derive(Default)]
+pub struct ScriptLimits {
+    pub max_total: Option<usize>,
+    pub max_per_script: Option<usize>,
+    /// How long a request waits for a free slot before it is answered 503;
+    /// zero answers at once.
+    pub queue_timeout: Duration,
+    total: OnceLock<Arc<Semaphore>>,
+    per_script: Mutex<HashMap<PathBuf, Arc<Semaphore>>>,
+}
+
+/// Holds a script's slots until it is dropped.
+pub struct ScriptPermit {
+    _script: Option<OwnedSemaphorePermit>,
+    _total: Option<OwnedSemaphorePermit>,
+}
+
+impl ScriptLimits {
+    /// Waits up to `queue_timeout` for a slot to run `path`, or `None`.
+    pub async fn acquire(&self, path: &Path) -> Option<ScriptPermit> {
+        let total = self.max_total.map(|max| self.total.get_or_init(|| Arc::new(Semaphore::new(max))).clone());
+        let script = self.max_per_script.map(|max| {
+            self.per_script.lock().unwrap()
+                .entry(path.to_path_buf())
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        });
+        // The script's own slot first, so a request queued behind a busy
+        // script does not hold one of the overall slots meanwhile.
+        let acquire = async {
+            let script = match script {
+                Some(semaphore) => Some(semaphore.acquire_owned().await.ok()?),
+                None => None,
+            };
+            let total = match total {
+                Some(semaphore) => Some(semaphore.acquire_owned().await.ok()?),
+                None => None,
+            };
+            Some(ScriptPermit { _script: script, _total: total })
+        };
+        // A zero timeout still polls once, taking a slot that is free right away.
+        tokio::time::timeout(self.queue_timeout, acquire).await.ok().flatten()
+    }
+}
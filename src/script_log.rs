@@ -0,0 +1,121 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use crate::{event, log_format};
+
+// A line longer than this is logged in pieces.
+const MAX_LINE: usize = 16 * 1024;
+
+/// `--script-log-dir`: what each script writes to stderr, and with
+/// `--script-log-stdout` its output too, is appended line by line to
+/// `DIR/<script path>.log`, e.g. `logs/scripts/report.sh.log`, as
+/// `<time> <request id> <stream>: <line>`.
+pub struct ScriptLog {
+    pub dir: PathBuf,
+    pub stdout: bool,
+}
+
+/// The log of one run, to `tee` the script's streams into.
+pub struct RunLog {
+    lines: mpsc::UnboundedSender<String>,
+    request_id: String,
+    pub stdout: bool,
+}
+
+impl ScriptLog {
+    /// Starts writing the log of a run of `script_path`; the file is opened
+    /// by a task of its own, which ends once the streams are done.
+    pub fn open(&self, root: &Path, script_path: &Path, request_id: &str) -> RunLog {
+        let relative = script_path.strip_prefix(root).unwrap_or(script_path);
+        let mut path = self.dir.clone();
+        path.extend(relative.components().filter(|c| matches!(c, Component::Normal(_))));
+        path.as_mut_os_string().push(".log");
+        let (lines, mut received) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            let Some(mut first) = received.recv().await else { return };
+            if let Some(dir) = path.parent() {
+                let _ = tokio::fs::create_dir_all(dir).await;
+            }
+            let mut file = match tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    event::error(&format!("Failed to open the script log {}: {}", path.display(), e));
+                    return;
+                },
+            };
+            loop {
+                // Each line in one write, so runs of the same script at once
+                // do not cut into each other's lines.
+                if let Err(e) = file.write_all(first.as_bytes()).await {
+                    event::error(&format!("Failed to write the script log {}: {}", path.display(), e));
+                    return;
+                }
+                match received.recv().await {
+                    Some(line) => first = line,
+                    None => return,
+                }
+            }
+        });
+        RunLog { lines, request_id: request_id.to_string(), stdout: self.stdout }
+    }
+}
+
+impl RunLog {
+    /// `reader`, with everything read from it also logged as `stream`.
+    pub fn tee<R: AsyncRead + Unpin>(&self, reader: R, stream: &'static str) -> Tee<R> {
+        Tee { inner: reader, lines: self.lines.clone(), request_id: self.request_id.clone(), stream, partial: Vec::new() }
+    }
+}
+
+pub struct Tee<R> {
+    inner: R,
+    lines: mpsc::UnboundedSender<String>,
+    request_id: String,
+    stream: &'static str,
+    partial: Vec<u8>,
+}
+
+impl<R> Tee<R> {
+    fn log(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if b == b'\n' || self.partial.len() >= MAX_LINE {
+                self.flush();
+            }
+            if b != b'\n' {
+                self.partial.push(b);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let line = String::from_utf8_lossy(&self.partial);
+        let line = line.trim_end_matches('\r');
+        let time = log_format::time_iso8601(SystemTime::now());
+        let _ = self.lines.send(format!("{} {} {}: {}\n", time, self.request_id, self.stream, line));
+        self.partial.clear();
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tee<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = buf.filled()[before..].to_vec();
+            self.log(&read);
+        }
+        result
+    }
+}
+
+impl<R> Drop for Tee<R> {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            self.flush();
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use hyper::header::{HeaderValue, ALLOW, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::auth::{self, AuthZone};
+use crate::config::Config;
+use crate::event;
+
+/// Extension of the sidecar file next to a script, e.g. `report.sh.meta`.
+pub const EXTENSION: &str = "meta";
+
+// A sidecar file is a small TOML document of top-level keys, all optional:
+//
+//     content-type = "application/json"   # when the script sends none
+//     timeout = 5                         # seconds, overrides --script-timeout
+//     methods = ["GET", "POST"]           # others are answered 405
+//     cache-ttl = 60                      # Cache-Control: max-age, unless sent
+//     auth-basic = ".htpasswd"            # relative to the script's folder
+//     auth-ldap = true                    # or check against --ldap-url
+//     auth-realm = "Reports"
+//     async = true                        # POSTs become --jobs
+pub struct ScriptMeta {
+    content_type: Option<HeaderValue>,
+    timeout: Option<Duration>,
+    methods: Option<Vec<Method>>,
+    cache_ttl: Option<u64>,
+    auth: Option<AuthZone>,
+    realm: Option<String>,
+    async_job: bool,
+}
+
+/// Marks a script response whose Content-Type was not chosen by the script.
+#[derive(Clone, Copy)]
+pub struct DefaultContentType;
+
+// Parsed files by path, with the modification time they were read at.
+type Cache = HashMap<PathBuf, (SystemTime, Arc<ScriptMeta>)>;
+
+static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+
+/// Applies the sidecar file of `script_path`, if there is one, and keeps it
+/// in the request extensions for the runner; a 401, 405 or, for a file that
+/// does not parse, 500 response when the request is refused.
+pub async fn check(config: &Config, script_path: &Path, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let meta = match load(script_path) {
+        Ok(Some(meta)) => meta,
+        Ok(None) => return None,
+        Err(e) => {
+            event::error(&e);
+            return Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"));
+        },
+    };
+    if let Some(methods) = &meta.methods {
+        if !methods.contains(req.method()) {
+            let allowed = methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+            let mut response = error(StatusCode::METHOD_NOT_ALLOWED, "405 Method Not Allowed");
+            response.headers_mut().insert(ALLOW, HeaderValue::from_str(&allowed).unwrap());
+            return Some(response);
+        }
+    }
+    if meta.async_job && !config.jobs.enabled() {
+        event::error(&format!("'async' in {}.{} requires '--jobs'", script_path.display(), EXTENSION));
+        return Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"));
+    }
+    if let Some(response) = sidecar_auth(&meta, config, script_path, req).await {
+        return Some(response);
+    }
+    req.extensions_mut().insert(meta);
+    None
+}
+
+/// Only the sidecar's auth-* keys, for requests that reach what the script
+/// wrote some other way, such as its --jobs URLs.
+pub async fn authenticate(config: &Config, script_path: &Path, req: &mut Request<Body>) -> Option<Response<Body>> {
+    match load(script_path) {
+        Ok(Some(meta)) => sidecar_auth(&meta, config, script_path, req).await,
+        Ok(None) => None,
+        Err(e) => {
+            event::error(&e);
+            Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"))
+        },
+    }
+}
+
+async fn sidecar_auth(meta: &ScriptMeta, config: &Config, script_path: &Path, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let zone = meta.auth.as_ref()?;
+    if zone.uses_ldap() && (config.ldap.server.is_none() || config.ldap.bind_dn.is_none()) {
+        event::error(&format!("'auth-ldap' in {}.{} requires '--ldap-url' and '--ldap-bind-dn'", script_path.display(), EXTENSION));
+        return Some(error(StatusCode::INTERNAL_SERVER_ERROR, "500 Internal Server Error"));
+    }
+    let realm = meta.realm.as_deref().unwrap_or(&config.auth_realm);
+    auth::authenticate(zone, realm, &config.ldap, req).await.err()
+}
+
+/// The script's time limit: the sidecar's, where 0 disables it, or --script-timeout.
+pub fn timeout(extensions: &hyper::http::Extensions, config: &Config) -> Option<Duration> {
+    match extensions.get::<Arc<ScriptMeta>>().and_then(|meta| meta.timeout) {
+        Some(timeout) => Some(timeout).filter(|timeout| !timeout.is_zero()),
+        None => config.script_timeout,
+    }
+}
+
+/// Whether a POST to the script runs as a background job.
+pub fn runs_async(extensions: &hyper::http::Extensions) -> bool {
+    extensions.get::<Arc<ScriptMeta>>().is_some_and(|meta| meta.async_job)
+}
+
+/// Fills in what the script left out of a successful response.
+pub fn apply(meta: &ScriptMeta, response: &mut Response<Body>) {
+    if !response.status().is_success() {
+        return;
+    }
+    if let Some(content_type) = &meta.content_type {
+        if response.extensions().get::<DefaultContentType>().is_some() {
+            response.headers_mut().insert(CONTENT_TYPE, content_type.clone());
+        }
+    }
+    if let Some(ttl) = meta.cache_ttl {
+        response.headers_mut().entry(CACHE_CONTROL).or_insert_with(|| HeaderValue::from_str(&format!("max-age={}", ttl)).unwrap());
+    }
+}
+
+/// Whether `path` is the sidecar file of a script next to it, which is
+/// neither run nor served.
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == EXTENSION) && path.with_extension("").is_file()
+}
+
+/// The sidecar file of `script_path`, if there is one. Parsed files are
+/// kept until their modification time changes.
+pub fn load(script_path: &Path) -> Result<Option<Arc<ScriptMeta>>, String> {
+    let mut path = script_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(EXTENSION);
+    let path = PathBuf::from(path);
+    let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return Ok(None),
+    };
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some((cached, meta)) = cache.lock().unwrap().get(&path) {
+        if *cached == modified {
+            return Ok(Some(meta.clone()));
+        }
+    }
+    let dir = script_path.parent().unwrap_or(Path::new("."));
+    let meta = Arc::new(parse(&path, dir)?);
+    cache.lock().unwrap().insert(path, (modified, meta.clone()));
+    Ok(Some(meta))
+}
+
+enum Value {
+    String(String),
+    Integer(u64),
+    Boolean(bool),
+    Array(Vec<String>),
+}
+
+fn parse(path: &Path, dir: &Path) -> Result<ScriptMeta, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut meta = ScriptMeta { content_type: None, timeout: None, methods: None, cache_ttl: None, auth: None, realm: None, async_job: false };
+    for (number, line) in contents.lines().enumerate() {
+        let invalid = |reason: String| format!("{}:{}: {}", path.display(), number + 1, reason);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("expected KEY = VALUE".to_string()))?;
+        let key = key.trim();
+        let value = parse_value(value.trim()).ok_or_else(|| invalid(format!("an invalid value for '{}'", key)))?;
+        match (key, value) {
+            ("content-type", Value::String(content_type)) => {
+                meta.content_type = Some(HeaderValue::from_str(&content_type).map_err(|_| invalid("an invalid content-type".to_string()))?);
+            },
+            ("timeout", Value::Integer(secs)) => meta.timeout = Some(Duration::from_secs(secs)),
+            ("methods", Value::Array(methods)) => {
+                let methods = methods.iter()
+                    .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid("an invalid method".to_string()))?;
+                meta.methods = Some(methods);
+            },
+            ("cache-ttl", Value::Integer(secs)) => meta.cache_ttl = Some(secs),
+            ("auth-basic", Value::String(htpasswd)) => {
+                meta.auth = Some(AuthZone::directory(Some(&dir.join(htpasswd))).map_err(invalid)?);
+            },
+            ("auth-ldap", Value::Boolean(true)) => meta.auth = Some(AuthZone::directory(None).map_err(invalid)?),
+            ("auth-ldap", Value::Boolean(false)) => {},
+            ("auth-realm", Value::String(realm)) => {
+                if realm.contains(['"', '\\']) || realm.chars().any(|c| c.is_control()) {
+                    return Err(invalid("the realm cannot contain quotes, backslashes or control characters".to_string()));
+                }
+                meta.realm = Some(realm);
+            },
+            ("async", Value::Boolean(async_job)) => meta.async_job = async_job,
+            (key, _) => return Err(invalid(format!("unknown key '{}' or a value of the wrong type", key))),
+        }
+    }
+    Ok(meta)
+}
+
+// A basic string, a non-negative integer, a boolean or an array of strings,
+// optionally followed by a comment.
+fn parse_value(text: &str) -> Option<Value> {
+    let (value, rest) = if text.starts_with('"') {
+        let (string, rest) = parse_string(text)?;
+        (Value::String(string), rest)
+    } else if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                break (Value::Array(items), after);
+            }
+            let (item, after) = parse_string(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    } else {
+        let end = text.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(text.len());
+        let value = match &text[..end] {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            number => Value::Integer(number.replace('_', "").parse().ok()?),
+        };
+        (value, &text[end..])
+    };
+    let rest = rest.trim_start();
+    (rest.is_empty() || rest.starts_with('#')).then_some(value)
+}
+
+// A double-quoted string at the start of `text` and what follows it.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &text[i + 2..])),
+            '\\' => string.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+fn error(status_code: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(format!("<html>{}</html>", message)))
+        .unwrap()
+}
@@ -0,0 +1,53 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Headers added to every response unless the handler already set them.
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            headers: vec![
+                (HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff")),
+                (HeaderName::from_static("x-frame-options"), HeaderValue::from_static("SAMEORIGIN")),
+                (HeaderName::from_static("referrer-policy"), HeaderValue::from_static("strict-origin-when-cross-origin")),
+            ],
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn clear(&mut self) {
+        self.headers.clear();
+    }
+
+    /// Replaces any earlier value for `name`; `None` drops the header.
+    pub fn set(&mut self, name: HeaderName, value: Option<HeaderValue>) {
+        self.headers.retain(|(existing, _)| *existing != name);
+        if let Some(value) = value {
+            self.headers.push((name, value));
+        }
+    }
+
+    /// Parses `Name: value`. An empty value (`Name:`) removes a default header.
+    pub fn parse(&mut self, option: &str, header: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value '{}' for '{}': expected 'Name: value'", header, option);
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| invalid())?;
+        let value = match value.trim() {
+            "" => None,
+            value => Some(HeaderValue::from_str(value).map_err(|_| invalid())?),
+        };
+        self.set(name, value);
+        Ok(())
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.headers {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
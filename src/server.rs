@@ -0,0 +1,479 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Sleep;
+use futures_util::future::{self, Either};
+use hyper::header::{CONTENT_TYPE, ORIGIN};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use crate::config::Config;
+use crate::conn_limit::PendingGuard;
+use crate::{conn_limit, cors, event, metrics, proxy_protocol, throttle};
+use crate::redirect::https_redirect;
+use crate::log_format::{Entry, LoggedBody, RequestId};
+use crate::timing::Timings;
+use crate::tls::Session;
+use crate::{handle_request, request_timeout as request_timeout_response, without_body_for_head};
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    /// Plain-HTTP socket whose requests are all redirected to HTTPS.
+    HttpsRedirect(TcpListener),
+    /// `--metrics-port` socket answering only the metrics path.
+    Metrics(TcpListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(listener) => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_path_buf())) {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix"),
+            },
+            Listener::HttpsRedirect(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{} (redirecting to HTTPS)", addr),
+                Err(_) => write!(f, "tcp (redirecting to HTTPS)"),
+            },
+            Listener::Metrics(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{} (metrics)", addr),
+                Err(_) => write!(f, "tcp (metrics)"),
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Config>,
+    shutdown: watch::Receiver<bool>,
+    // Every connection task holds a sender; recv() returns None once all are gone.
+    done: mpsc::Sender<()>,
+}
+
+pub async fn serve(listeners: Vec<Listener>, config: Arc<Config>, shutdown: impl Future<Output = ()>) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let shared = Shared { config: config.clone(), shutdown: shutdown_rx, done: done_tx };
+
+    let acceptors: Vec<_> = listeners.into_iter()
+        .map(|listener| tokio::spawn(accept_loop(listener, shared.clone())))
+        .collect();
+    drop(shared);
+
+    shutdown.await;
+
+    for acceptor in acceptors {
+        acceptor.abort();
+    }
+    let _ = shutdown_tx.send(true);
+    event::info(&format!("Shutting down, waiting up to {}s for open connections", config.shutdown_timeout.as_secs()));
+    match tokio::time::timeout(config.shutdown_timeout, done_rx.recv()).await {
+        Ok(_) => event::info("All connections closed"),
+        Err(_) => event::warning("Shutdown timeout elapsed, dropping remaining connections"),
+    }
+}
+
+async fn accept_loop(listener: Listener, shared: Shared) {
+    loop {
+        let result = match &listener {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, client_addr)| {
+                spawn_connection(stream, canonical(client_addr), Role::Serve, true, &shared);
+            }),
+            // Unix socket peers have no IP address; they are logged as 0.0.0.0
+            // rather than loopback so IP based rules never trust them by accident.
+            Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| {
+                spawn_connection(stream, SocketAddr::from(([0, 0, 0, 0], 0)), Role::Serve, false, &shared);
+            }),
+            Listener::HttpsRedirect(listener) => listener.accept().await.map(|(stream, client_addr)| {
+                spawn_connection(stream, canonical(client_addr), Role::HttpsRedirect, false, &shared);
+            }),
+            Listener::Metrics(listener) => listener.accept().await.map(|(stream, client_addr)| {
+                spawn_connection(stream, canonical(client_addr), Role::Metrics, false, &shared);
+            }),
+        };
+        if let Err(e) = result {
+            event::error(&format!("Accept error: {}", e));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+// Dual-stack sockets report IPv4 clients as ::ffff:a.b.c.d.
+fn canonical(client_addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(client_addr.ip().to_canonical(), client_addr.port())
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Serve,
+    HttpsRedirect,
+    Metrics,
+}
+
+// With `--tls-cert`, connections to the `tls` listeners start with a handshake.
+fn spawn_connection<I>(io: I, client_addr: SocketAddr, role: Role, tls: bool, shared: &Shared)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let accepted = Instant::now();
+        let mut io = io;
+        let mut client_addr = client_addr;
+        if shared.config.proxy_protocol {
+            let header = proxy_protocol::read_header(&mut io);
+            let header = match head_deadline(&shared.config, accepted) {
+                Some(deadline) => tokio::time::timeout_at(deadline.into(), header).await,
+                None => Ok(header.await),
+            };
+            match header {
+                Ok(Ok(Some(source))) => client_addr = canonical(source),
+                Ok(Ok(None)) => {},
+                Ok(Err(e)) => {
+                    event::error(&format!("PROXY protocol error from {}: {}", client_addr.ip(), e));
+                    return;
+                },
+                Err(_) => return,
+            }
+        }
+        let config = shared.config.clone();
+        if config.bans.is_banned(client_addr.ip()) {
+            return;
+        }
+        let acceptor = if tls { config.tls.as_ref() } else { None };
+        let slot = config.connection_limits.acquire(client_addr.ip());
+        let pending = config.connection_limits.acquire_pending(client_addr.ip());
+        let pending = match (slot.is_some(), pending) {
+            (true, Ok(pending)) => pending,
+            _ => {
+                metrics::connection_rejected();
+                // A TLS client expects a handshake, and would only see the
+                // plaintext 503 as a broken record, so it is just closed.
+                if acceptor.is_none() {
+                    let _ = tokio::time::timeout(Duration::from_secs(1), io.write_all(conn_limit::REJECT_RESPONSE)).await;
+                }
+                return;
+            },
+        };
+        let open = metrics::connection_opened();
+        match acceptor {
+            Some(acceptor) => {
+                let handshake = acceptor.accept(io);
+                let handshake = match head_deadline(&config, accepted) {
+                    Some(deadline) => tokio::time::timeout_at(deadline.into(), handshake).await,
+                    None => Ok(handshake.await),
+                };
+                match handshake {
+                    Ok(Ok((stream, session))) => {
+                        if let Some(subject) = &session.client_subject {
+                            event::info(&format!("TLS client {} presented {}", client_addr.ip(), subject));
+                        }
+                        serve_connection(stream, client_addr, role, Some(session), accepted, pending, &shared).await;
+                    },
+                    Ok(Err(e)) => event::warning(&format!("TLS handshake with {} failed: {}", client_addr.ip(), e)),
+                    Err(_) => {},
+                }
+            },
+            None => serve_connection(io, client_addr, role, None, accepted, pending, &shared).await,
+        }
+        drop(open);
+        drop(slot);
+        drop(shared.done);
+    });
+}
+
+// The PROXY header and the TLS handshake are held to the same limits as the
+// request head that follows them.
+fn head_deadline(config: &Config, accepted: Instant) -> Option<Instant> {
+    if !config.header_timeout.is_zero() {
+        Some(accepted + config.header_timeout)
+    } else if config.header_min_rate > 0 {
+        Some(accepted + MIN_RATE_GRACE)
+    } else {
+        None
+    }
+}
+
+async fn serve_connection<I>(io: I, client_addr: SocketAddr, role: Role, tls: Option<Session>, accepted: Instant, pending: Option<PendingGuard>, shared: &Shared)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = shared.config.clone();
+    let mut shutdown = shared.shutdown.clone();
+    let activity = Arc::new(Activity::new(accepted, pending));
+    let mut io = TrackedStream {
+        inner: io,
+        activity: activity.clone(),
+        header_timeout: config.header_timeout,
+        header_min_rate: config.header_min_rate,
+        header_deadline: None,
+        header_started: accepted,
+        header_bytes: 0,
+        deadline_generation: 0,
+    };
+    // The first request's deadline runs from the accept, so a client that
+    // connects and never sends a byte is timed out like a slow one.
+    if io.limited() {
+        io.header_deadline = Some(Box::pin(tokio::time::sleep_until(io.header_due().into())));
+    }
+    let keep_alive = !config.keep_alive_timeout.is_zero();
+    let idle_timeout = config.keep_alive_timeout;
+    // hyper answers 431 itself once the head outgrows its read buffer.
+    let max_header_size = config.max_header_size;
+
+    let service_activity = activity.clone();
+    let service = service_fn(move |mut req: Request<Body>| {
+        let guard = service_activity.begin_request();
+        if config.slow_request.is_some() {
+            Timings::start(&mut req);
+        }
+        RequestId::assign(&mut req);
+        if let Some(session) = &tls {
+            req.extensions_mut().insert(session.clone());
+        }
+        let service_activity = service_activity.clone();
+        let entry = Entry::new(config.clone(), &req, client_addr);
+        let path = req.uri().path().to_string();
+        let origin = req.headers().get(ORIGIN).cloned();
+        let request_timeout = config.request_timeout;
+        let config = config.clone();
+        let response = match role {
+            Role::Serve => Either::Left(handle_request(req, config.clone(), client_addr)),
+            Role::HttpsRedirect => Either::Right(future::ready(Ok(https_redirect(&req, config.https_port)))),
+            Role::Metrics => Either::Right(future::ready(Ok(metrics_only(&req, &config)))),
+        };
+        async move {
+            let response = match tokio::time::timeout(request_timeout, response).await {
+                Ok(response) => response,
+                Err(_) => Ok(request_timeout_response()),
+            };
+            drop(guard);
+            response.map(|mut response| {
+                config.bans.record(client_addr.ip(), response.status());
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    service_activity.upgraded.store(true, Ordering::Relaxed);
+                }
+                config.security_headers.apply(response.headers_mut());
+                if let (Role::Serve, Some(origin)) = (role, &origin) {
+                    if let Some(rule) = cors::find_rule(&config.cors, &path) {
+                        cors::apply(rule, origin, response.headers_mut());
+                    }
+                }
+                let limit = throttle::find_limit(config.limit_rate, &config.limit_rate_routes, &path);
+                let response = match limit {
+                    Some(limit) if response.status() != StatusCode::SWITCHING_PROTOCOLS => response.map(|body| throttle::throttle(body, limit)),
+                    _ => response,
+                };
+                let mut entry = entry;
+                if response.headers().get(CONTENT_TYPE).is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream")) {
+                    entry.untimed();
+                }
+                let status = response.status();
+                response.map(|body| LoggedBody::new(body, entry, status))
+            })
+        }
+    });
+
+    let conn = Http::new()
+        .http1_keep_alive(keep_alive)
+        .max_buf_size(max_header_size)
+        .serve_connection(io, service)
+        .with_upgrades();
+    tokio::pin!(conn);
+
+    let mut ticker = tokio::time::interval((idle_timeout / 4).max(Duration::from_millis(100)));
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            _ = conn.as_mut() => break,
+            _ = ticker.tick(), if keep_alive && !closing => {
+                // Idle keep-alive connections are closed; a request that is
+                // still being handled is allowed to finish first.
+                if activity.is_idle_for(idle_timeout) {
+                    conn.as_mut().graceful_shutdown();
+                    closing = true;
+                }
+            },
+            _ = shutdown.changed(), if !closing => {
+                conn.as_mut().graceful_shutdown();
+                closing = true;
+            },
+        }
+    }
+}
+
+fn metrics_only(req: &Request<Body>, config: &Config) -> Response<Body> {
+    if config.metrics.path.as_deref() != Some(req.uri().path()) {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>404 Not Found</html>"))
+            .unwrap();
+    }
+    without_body_for_head(req.method(), metrics::endpoint(req))
+}
+
+struct Activity {
+    start: Instant,
+    last_millis: AtomicU64,
+    in_flight: AtomicUsize,
+    requests: AtomicU64,
+    // Set once the connection switched protocols (WebSocket) and is no longer HTTP.
+    upgraded: AtomicBool,
+    // Released when the first request arrives.
+    pending: Mutex<Option<PendingGuard>>,
+}
+
+impl Activity {
+    // A connection counts as idle from `accepted` until it is first used.
+    fn new(accepted: Instant, pending: Option<PendingGuard>) -> Self {
+        Activity {
+            start: accepted,
+            last_millis: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            upgraded: AtomicBool::new(false),
+            pending: Mutex::new(pending),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_millis.store(now, Ordering::Relaxed);
+    }
+
+    fn is_idle_for(&self, timeout: Duration) -> bool {
+        let idle = self.start.elapsed().as_millis() as u64 - self.last_millis.load(Ordering::Relaxed);
+        self.in_flight.load(Ordering::Relaxed) == 0 && idle >= timeout.as_millis() as u64
+    }
+
+    fn begin_request(self: &Arc<Self>) -> RequestGuard {
+        self.pending.lock().unwrap().take();
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard { activity: self.clone() }
+    }
+}
+
+struct RequestGuard {
+    activity: Arc<Activity>,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.activity.touch();
+        self.activity.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Wraps a client connection to record activity for the keep-alive timer and
+// to answer 408 when a request's headers take longer than `header_timeout`
+// to arrive, or arrive slower than `header_min_rate` bytes per second after a
+// short grace period. Both count from the accept for the first request and
+// from its first byte for later ones on a keep-alive connection.
+struct TrackedStream<I> {
+    inner: I,
+    activity: Arc<Activity>,
+    header_timeout: Duration,
+    header_min_rate: u64,
+    header_deadline: Option<Pin<Box<Sleep>>>,
+    header_started: Instant,
+    header_bytes: u64,
+    deadline_generation: u64,
+}
+
+// Time every request head gets before `header_min_rate` is enforced.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(1);
+
+const TIMEOUT_RESPONSE: &[u8] = b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncRead for TrackedStream<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(deadline) = this.header_deadline.as_mut() {
+            if this.activity.requests.load(Ordering::Relaxed) != this.deadline_generation {
+                this.header_deadline = None;
+            } else if deadline.as_mut().poll(cx).is_ready() {
+                let _ = Pin::new(&mut this.inner).poll_write(cx, TIMEOUT_RESPONSE);
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "request header timeout")));
+            }
+        }
+
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let received = buf.filled().len() - filled;
+        if received > 0 {
+            this.activity.touch();
+            let awaiting_headers = this.activity.in_flight.load(Ordering::Relaxed) == 0
+                && !this.activity.upgraded.load(Ordering::Relaxed);
+            if awaiting_headers && this.limited() {
+                if this.header_deadline.is_none() {
+                    this.header_started = Instant::now();
+                    this.header_bytes = 0;
+                    this.deadline_generation = this.activity.requests.load(Ordering::Relaxed);
+                }
+                this.header_bytes += received as u64;
+                let due = this.header_due();
+                let deadline = this.header_deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due.into())));
+                deadline.as_mut().reset(due.into());
+                let _ = deadline.as_mut().poll(cx);
+            }
+        }
+        result
+    }
+}
+
+impl<I> TrackedStream<I> {
+    fn limited(&self) -> bool {
+        !self.header_timeout.is_zero() || self.header_min_rate > 0
+    }
+
+    // Each byte received buys another 1/header_min_rate seconds.
+    fn header_due(&self) -> Instant {
+        let mut due = None;
+        if !self.header_timeout.is_zero() {
+            due = Some(self.header_started + self.header_timeout);
+        }
+        if self.header_min_rate > 0 {
+            let earned = Duration::from_secs_f64(self.header_bytes as f64 / self.header_min_rate as f64);
+            let by_rate = self.header_started + MIN_RATE_GRACE + earned;
+            due = Some(due.map_or(by_rate, |due: Instant| due.min(by_rate)));
+        }
+        due.unwrap_or_else(Instant::now)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for TrackedStream<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.activity.touch();
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
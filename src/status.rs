@@ -0,0 +1,181 @@
+use std::fmt::Write;
+use std::time::Duration;
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::config::Config;
+use crate::json::Json;
+use crate::{cache, log_format, metrics};
+
+/// The `--status-page`: an HTML overview of the running server, or the same
+/// figures as JSON with `?format=json`.
+pub fn page(config: &Config, req: &Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, HeaderValue::from_static("GET, HEAD"))
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from("<html>405 Method Not Allowed</html>"))
+            .unwrap();
+    }
+    let json = url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .any(|(name, value)| name == "format" && value == "json");
+    let stats = metrics::snapshot();
+    let cache = config.proxy_cache.enabled().then(|| config.proxy_cache.stats());
+    let (content_type, body) = if json {
+        ("application/json", to_json(&stats, cache.as_ref()).to_string())
+    } else {
+        ("text/html; charset=utf-8", to_html(&stats, cache.as_ref()))
+    };
+    Response::builder()
+        .header("Content-Type", content_type)
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn to_json(stats: &metrics::Snapshot, cache: Option<&cache::CacheStats>) -> Json {
+    let number = |n: u64| Json::Number(n as f64);
+    let string = |s: String| Json::String(s);
+    let object = |fields: Vec<(&str, Json)>| Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    let round = |rate: f64| Json::Number((rate * 100.0).round() / 100.0);
+    let mut fields = vec![
+        ("uptime_seconds", number(stats.uptime.as_secs())),
+        ("connections", object(vec![
+            ("open", number(stats.connections_open)),
+            ("total", number(stats.connections)),
+            ("rejected", number(stats.connections_rejected)),
+        ])),
+        ("requests", object(vec![
+            ("total", number(stats.requests)),
+            ("in_flight", number(stats.in_flight)),
+            ("per_second_1m", round(stats.rates[0])),
+            ("per_second_5m", round(stats.rates[1])),
+            ("per_second_15m", round(stats.rates[2])),
+            ("1xx", number(stats.by_class[0])),
+            ("2xx", number(stats.by_class[1])),
+            ("3xx", number(stats.by_class[2])),
+            ("4xx", number(stats.by_class[3])),
+            ("5xx", number(stats.by_class[4])),
+            ("bytes_sent", number(stats.bytes_sent)),
+        ])),
+        ("scripts", object(vec![
+            ("executions", number(stats.script_runs)),
+            ("failures", number(stats.script_failures)),
+            ("running", Json::Array(stats.running_scripts.iter().map(|(path, client, running)| object(vec![
+                ("path", string(path.display().to_string())),
+                ("client", string(client.to_string())),
+                ("running_seconds", number(running.as_secs())),
+            ])).collect())),
+        ])),
+        ("routes", Json::Array(stats.routes.iter().map(|route| object(vec![
+            ("route", string(route.route.clone())),
+            ("count", number(route.count as u64)),
+            ("errors", number(route.errors as u64)),
+            ("error_rate", round(route.errors as f64 / route.count as f64)),
+            ("p50_ms", round(route.p50.as_secs_f64() * 1000.0)),
+            ("p95_ms", round(route.p95.as_secs_f64() * 1000.0)),
+        ])).collect())),
+        ("recent_errors", Json::Array(stats.recent_errors.iter().map(|(time, message)| object(vec![
+            ("time", string(log_format::time_iso8601(*time))),
+            ("message", string(message.clone())),
+        ])).collect())),
+    ];
+    if let Some(cache) = cache {
+        fields.push(("proxy_cache", object(vec![
+            ("entries", number(cache.entries as u64)),
+            ("memory_bytes", number(cache.memory_used)),
+            ("disk_bytes", number(cache.disk_used)),
+            ("hits", number(cache.hits)),
+            ("misses", number(cache.misses)),
+        ])));
+    }
+    object(fields)
+}
+
+fn to_html(stats: &metrics::Snapshot, cache: Option<&cache::CacheStats>) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Server status</title>\
+        <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:1.5em}\
+        td,th{padding:.2em 1em;text-align:left;border-bottom:1px solid #ddd}</style></head><body>\n<h1>Server status</h1>\n");
+    let rows = |html: &mut String, title: &str, rows: &[(&str, String)]| {
+        let _ = write!(html, "<h2>{}</h2>\n<table>\n", title);
+        for (name, value) in rows {
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+        }
+        html.push_str("</table>\n");
+    };
+    rows(&mut html, "Server", &[
+        ("Uptime", human_duration(stats.uptime)),
+        ("Open connections", stats.connections_open.to_string()),
+        ("Connections accepted", stats.connections.to_string()),
+        ("Connections rejected", stats.connections_rejected.to_string()),
+    ]);
+    rows(&mut html, "Requests", &[
+        ("Total", stats.requests.to_string()),
+        ("In flight", stats.in_flight.to_string()),
+        ("Per second (1m / 5m / 15m)", format!("{:.2} / {:.2} / {:.2}", stats.rates[0], stats.rates[1], stats.rates[2])),
+        ("2xx / 3xx / 4xx / 5xx", format!("{} / {} / {} / {}", stats.by_class[1], stats.by_class[2], stats.by_class[3], stats.by_class[4])),
+        ("Bytes sent", stats.bytes_sent.to_string()),
+    ]);
+    if let Some(cache) = cache {
+        let lookups = cache.hits + cache.misses;
+        let ratio = if lookups == 0 { 0.0 } else { cache.hits as f64 * 100.0 / lookups as f64 };
+        rows(&mut html, "Proxy cache", &[
+            ("Entries", cache.entries.to_string()),
+            ("Memory / disk bytes", format!("{} / {}", cache.memory_used, cache.disk_used)),
+            ("Hits / misses", format!("{} / {} ({:.1}% hits)", cache.hits, cache.misses, ratio)),
+        ]);
+    }
+    rows(&mut html, "Scripts", &[
+        ("Executions", stats.script_runs.to_string()),
+        ("Failures", stats.script_failures.to_string()),
+        ("Running", stats.running_scripts.len().to_string()),
+    ]);
+    if !stats.running_scripts.is_empty() {
+        html.push_str("<table>\n<tr><th>Script</th><th>Client</th><th>Running for</th></tr>\n");
+        for (path, client, running) in &stats.running_scripts {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&path.display().to_string()), client, human_duration(*running));
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("<h2>Routes, last 5 minutes</h2>\n");
+    if stats.routes.is_empty() {
+        html.push_str("<p>No requests</p>\n");
+    } else {
+        html.push_str("<table>\n<tr><th>Route</th><th>Requests</th><th>Errors</th><th>p50</th><th>p95</th></tr>\n");
+        for route in &stats.routes {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{} ({:.1}%)</td><td>{:.1} ms</td><td>{:.1} ms</td></tr>",
+                escape(&route.route), route.count, route.errors, route.errors as f64 * 100.0 / route.count as f64,
+                route.p50.as_secs_f64() * 1000.0, route.p95.as_secs_f64() * 1000.0);
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("<h2>Recent errors</h2>\n");
+    if stats.recent_errors.is_empty() {
+        html.push_str("<p>None</p>\n");
+    } else {
+        html.push_str("<table>\n");
+        for (time, message) in &stats.recent_errors {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", log_format::time_iso8601(*time), escape(message));
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+// `3d 4h 5m 6s`, leaving out leading zero units.
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m {}s", minutes, seconds),
+        (0, _, _) => format!("{}h {}m {}s", hours, minutes, seconds),
+        _ => format!("{}d {}h {}m {}s", days, hours, minutes, seconds),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,155 @@
+use std::io;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use crate::event::Level;
+use crate::log_format;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// `--syslog` or `--journald`: where access lines and server events go
+/// instead of stdout, stderr and the access log file.
+pub struct LogSink {
+    pub target: Target,
+    pub facility: u8,
+    pub tag: String,
+}
+
+pub enum Target {
+    /// A syslog server, `HOST[:PORT]` over UDP.
+    Udp(String),
+    /// The local syslog socket, usually /dev/log.
+    Unix(PathBuf),
+    Journald,
+}
+
+impl Target {
+    pub fn parse(option: &str, value: &str) -> Result<Target, String> {
+        if value.starts_with('/') {
+            return Ok(Target::Unix(PathBuf::from(value)));
+        }
+        let invalid = || format!("invalid value '{}' for '{}': expected /PATH or HOST[:PORT]", value, option);
+        let address = match value.rsplit_once(':') {
+            Some((host, port)) if !host.ends_with(':') && !host.is_empty() => {
+                port.parse::<u16>().map_err(|_| invalid())?;
+                value.to_string()
+            },
+            _ if !value.is_empty() => format!("{}:514", value),
+            _ => return Err(invalid()),
+        };
+        Ok(Target::Udp(address))
+    }
+}
+
+/// `daemon`, `local0` etc.
+pub fn parse_facility(option: &str, value: &str) -> Result<u8, String> {
+    const NAMES: [&str; 12] = ["kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp"];
+    if let Some(n) = value.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()).filter(|n| *n <= 7) {
+        return Ok(16 + n);
+    }
+    NAMES.iter().position(|name| *name == value)
+        .map(|n| n as u8)
+        .ok_or_else(|| format!("invalid value '{}' for '{}': expected a facility such as daemon or local0", value, option))
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+struct Sink {
+    socket: Socket,
+    journald: bool,
+    facility: u8,
+    tag: String,
+    hostname: Option<String>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+pub fn open(sink: &LogSink) -> Result<(), String> {
+    let failed = |e: io::Error| format!("Failed to open the log socket: {}", e);
+    let (socket, journald, hostname) = match &sink.target {
+        Target::Udp(address) => {
+            let socket = UdpSocket::bind(if address.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" }).map_err(failed)?;
+            socket.connect(address.as_str()).map_err(|e| format!("Failed to connect to syslog server {}: {}", address, e))?;
+            (Socket::Udp(socket), false, Some(hostname()))
+        },
+        Target::Unix(path) => {
+            let socket = UnixDatagram::unbound().map_err(failed)?;
+            socket.connect(path).map_err(|e| format!("Failed to connect to {}: {}", path.display(), e))?;
+            (Socket::Unix(socket), false, None)
+        },
+        Target::Journald => {
+            let socket = UnixDatagram::unbound().map_err(failed)?;
+            socket.connect(JOURNALD_SOCKET).map_err(|e| format!("Failed to connect to journald: {}", e))?;
+            (Socket::Unix(socket), true, None)
+        },
+    };
+    // A full socket buffer drops lines rather than stalling requests.
+    match &socket {
+        Socket::Udp(socket) => socket.set_nonblocking(true),
+        Socket::Unix(socket) => socket.set_nonblocking(true),
+    }.map_err(failed)?;
+    let _ = SINK.set(Sink { socket, journald, facility: sink.facility, tag: sink.tag.clone(), hostname });
+    Ok(())
+}
+
+/// Sends `message` to the open sink; false when there is none.
+pub fn send(level: Level, message: &str) -> bool {
+    let sink = match SINK.get() {
+        Some(sink) => sink,
+        None => return false,
+    };
+    let severity = match level {
+        Level::Info => 6,
+        Level::Warning => 4,
+        Level::Error => 3,
+    };
+    let datagram = if sink.journald {
+        journald_entry(severity, &sink.tag, message)
+    } else {
+        syslog_message(sink, severity, message).into_bytes()
+    };
+    let _ = match &sink.socket {
+        Socket::Udp(socket) => socket.send(&datagram),
+        Socket::Unix(socket) => socket.send(&datagram),
+    };
+    true
+}
+
+// RFC 3164: `<PRI>Oct 15 04:13:03 HOST TAG[PID]: MESSAGE`. The local socket
+// adds the host name itself.
+fn syslog_message(sink: &Sink, severity: u8, message: &str) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (_, month, day, hour, minute, second) = log_format::civil(SystemTime::now());
+    let mut line = format!(
+        "<{}>{} {:2} {:02}:{:02}:{:02} ",
+        sink.facility as u32 * 8 + severity as u32, MONTHS[month as usize - 1], day, hour, minute, second,
+    );
+    if let Some(hostname) = &sink.hostname {
+        line.push_str(hostname);
+        line.push(' ');
+    }
+    line.push_str(&format!("{}[{}]: {}", sink.tag, std::process::id(), message));
+    line
+}
+
+// journald's native protocol: one FIELD=value per line, with MESSAGE in the
+// length-prefixed form so it may contain newlines.
+fn journald_entry(severity: u8, tag: &str, message: &str) -> Vec<u8> {
+    let mut entry = format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\nSYSLOG_PID={}\nMESSAGE\n", severity, tag, std::process::id()).into_bytes();
+    entry.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    entry.extend_from_slice(message.as_bytes());
+    entry.push(b'\n');
+    entry
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::Body;
+use crate::auth;
+
+/// Sends a response body at `rate` bytes per second once the first `burst`
+/// bytes went out at full speed, like nginx's limit_rate and limit_rate_after.
+#[derive(Clone, Copy)]
+pub struct BandwidthLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+impl BandwidthLimit {
+    fn parse(value: &str) -> Option<BandwidthLimit> {
+        let (rate, burst) = match value.split_once(':') {
+            Some((rate, burst)) => (rate, burst.parse().ok()?),
+            None => (value, 0),
+        };
+        let rate = rate.parse().ok().filter(|rate| *rate > 0)?;
+        Some(BandwidthLimit { rate, burst })
+    }
+}
+
+/// A `--limit-rate-route PREFIX=BYTES/SEC[:BURST]` override.
+pub struct RouteBandwidth {
+    prefix: String,
+    limit: BandwidthLimit,
+}
+
+impl RouteBandwidth {
+    pub fn parse(option: &str, value: &str) -> Result<RouteBandwidth, String> {
+        let invalid = || format!("invalid value '{}' for '{}': expected PREFIX=BYTES/SEC[:BURST]", value, option);
+        let (prefix, limit) = value.split_once('=').ok_or_else(invalid)?;
+        if !prefix.starts_with('/') {
+            return Err(invalid());
+        }
+        Ok(RouteBandwidth {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            limit: BandwidthLimit::parse(limit).ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// The limit of the longest route covering `path`, else the global one.
+pub fn find_limit(global: Option<BandwidthLimit>, routes: &[RouteBandwidth], path: &str) -> Option<BandwidthLimit> {
+    let segments = auth::segments(path);
+    routes.iter()
+        .filter(|route| segments.starts_with(&auth::segments(&route.prefix)))
+        .max_by_key(|route| route.prefix.len())
+        .map(|route| route.limit)
+        .or(global)
+}
+
+pub fn throttle(body: Body, limit: BandwidthLimit) -> Body {
+    // Chunks of a tenth of a second keep the pace smooth.
+    let chunk_size = (limit.rate / 10).clamp(1024, 256 * 1024) as usize;
+    let start = Instant::now();
+    let state = (body, Bytes::new(), 0u64);
+    let chunks = stream::unfold(state, move |(mut body, mut pending, sent)| async move {
+        if pending.is_empty() {
+            pending = match body.next().await? {
+                Ok(bytes) => bytes,
+                Err(e) => return Some((Err(e), (body, Bytes::new(), sent))),
+            };
+        }
+        let chunk = pending.split_to(pending.len().min(chunk_size));
+        let due = (sent + chunk.len() as u64).saturating_sub(limit.burst) as f64 / limit.rate as f64;
+        tokio::time::sleep_until((start + Duration::from_secs_f64(due)).into()).await;
+        let sent = sent + chunk.len() as u64;
+        Some((Ok(chunk), (body, pending, sent)))
+    });
+    Body::wrap_stream(chunks)
+}
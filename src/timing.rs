@@ -0,0 +1,129 @@
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use futures_util::stream::Stream;
+use hyper::http::Extensions;
+use hyper::Request;
+
+/// Parts of a request's time reported by the `--slow-request` warning.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// From the request head being parsed until the handler first ran.
+    Queued,
+    /// Reading the request body.
+    Body,
+    /// Opening and reading the served file, including while it is sent.
+    File,
+    /// From starting a script until it exited.
+    Script,
+    /// Waiting for a proxied upstream's response head.
+    Upstream,
+}
+
+const PHASES: [(Phase, &str); 5] = [
+    (Phase::Queued, "queued"),
+    (Phase::Body, "request body"),
+    (Phase::File, "file read"),
+    (Phase::Script, "script"),
+    (Phase::Upstream, "upstream"),
+];
+
+/// Time spent per phase, kept in the request's extensions while
+/// `--slow-request` is set.
+pub struct Timings {
+    start: Instant,
+    micros: [AtomicU64; PHASES.len()],
+}
+
+impl Timings {
+    /// Starts timing `req`; `of` gets the totals back at the end.
+    pub fn start<B>(req: &mut Request<B>) {
+        req.extensions_mut().insert(Arc::new(Timings { start: Instant::now(), micros: Default::default() }));
+    }
+
+    pub fn of<B>(req: &Request<B>) -> Option<Arc<Timings>> {
+        req.extensions().get::<Arc<Timings>>().cloned()
+    }
+
+    pub fn add(&self, phase: Phase, elapsed: Duration) {
+        self.micros[phase as usize].fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// `handler 3.004s (queued 0.1ms, script 3.002s), send 1.2ms`: the time
+    /// until the response head was ready, what it went on, then the time
+    /// the body took to send.
+    pub fn describe(&self, responded: Instant, finished: Instant) -> String {
+        let mut out = format!("handler {}", human(responded.duration_since(self.start)));
+        let mut separator = " (";
+        for (phase, name) in PHASES {
+            let micros = self.micros[phase as usize].load(Ordering::Relaxed);
+            if micros > 0 || matches!(phase, Phase::Queued) {
+                let _ = write!(out, "{}{} {}", separator, name, human(Duration::from_micros(micros)));
+                separator = ", ";
+            }
+        }
+        let _ = write!(out, "), send {}", human(finished.duration_since(responded)));
+        out
+    }
+}
+
+/// Adds `elapsed` to `phase` when the request is being timed.
+pub fn record(extensions: &Extensions, phase: Phase, elapsed: Duration) {
+    if let Some(timings) = extensions.get::<Arc<Timings>>() {
+        timings.add(phase, elapsed);
+    }
+}
+
+/// Records the time since the request arrived as queueing; called as the
+/// handler starts.
+pub fn queued(extensions: &Extensions) {
+    if let Some(timings) = extensions.get::<Arc<Timings>>() {
+        timings.add(Phase::Queued, timings.start.elapsed());
+    }
+}
+
+/// Counts the time `stream` keeps its reader waiting towards `phase`.
+pub fn timed<S: Stream>(stream: S, extensions: &Extensions, phase: Phase) -> Timed<S> {
+    Timed { inner: Box::pin(stream), timings: extensions.get::<Arc<Timings>>().cloned(), phase, waiting: None }
+}
+
+pub struct Timed<S> {
+    inner: Pin<Box<S>>,
+    timings: Option<Arc<Timings>>,
+    phase: Phase,
+    waiting: Option<Instant>,
+}
+
+impl<S: Stream> Stream for Timed<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        let polled = Instant::now();
+        let result = this.inner.as_mut().poll_next(cx);
+        if let Some(timings) = &this.timings {
+            match result {
+                Poll::Pending => {
+                    this.waiting.get_or_insert(polled);
+                },
+                Poll::Ready(_) => timings.add(this.phase, this.waiting.take().unwrap_or(polled).elapsed()),
+            }
+        }
+        result
+    }
+}
+
+// `950us`, `12.3ms` or `3.004s`.
+fn human(duration: Duration) -> String {
+    let micros = duration.as_micros();
+    if micros < 1000 {
+        format!("{}us", micros)
+    } else if micros < 1_000_000 {
+        format!("{:.1}ms", micros as f64 / 1000.0)
+    } else {
+        format!("{:.3}s", duration.as_secs_f64())
+    }
+}
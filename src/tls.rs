@@ -0,0 +1,569 @@
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void, CStr, CString};
+use std::future::poll_fn;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::OnceLock;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+// The system's OpenSSL (libssl 3), through memory BIOs: OpenSSL never touches
+// the socket, so a TLS stream wraps any tokio stream, after a PROXY header too.
+// build.rs finds and links the libraries. OpenSSL's objects are opaque, so
+// they are only ever handled through pointers.
+type SslCtx = c_void;
+type Ssl = c_void;
+type Bio = c_void;
+type X509 = c_void;
+type ServernameCallback = unsafe extern "C" fn(*mut Ssl, *mut c_int, *mut c_void) -> c_int;
+
+// The values of the macros of the same names in OpenSSL 3's ssl.h and
+// tls1.h, which are part of its ABI. Most setters there are macros over
+// SSL_CTX_ctrl and SSL_ctrl, called here with their SSL_CTRL_* command.
+const SSL_FILETYPE_PEM: c_int = 1;
+const SSL_ERROR_WANT_READ: c_int = 2;
+const SSL_ERROR_ZERO_RETURN: c_int = 6;
+const SSL_CTRL_MODE: c_int = 33;
+const SSL_CTRL_SET_TLSEXT_SERVERNAME_CB: c_int = 53;
+const SSL_CTRL_SET_TLSEXT_SERVERNAME_ARG: c_int = 54;
+const SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+const SSL_CTRL_SET_MIN_PROTO_VERSION: c_int = 123;
+const SSL_MODE_ENABLE_PARTIAL_WRITE: c_long = 0x1;
+const SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER: c_long = 0x2;
+const SSL_OP_NO_COMPRESSION: u64 = 1 << 17;
+const SSL_OP_NO_RENEGOTIATION: u64 = 1 << 30;
+const TLS1_2_VERSION: c_long = 0x0303;
+const TLSEXT_NAMETYPE_HOST_NAME: c_int = 0;
+const SSL_TLSEXT_ERR_OK: c_int = 0;
+const SSL_VERIFY_PEER: c_int = 0x1;
+const SSL_VERIFY_FAIL_IF_NO_PEER_CERT: c_int = 0x2;
+// XN_FLAG_RFC2253, from x509.h: `CN=client,O=Example`, most specific first.
+const XN_FLAG_RFC2253: c_ulong = 0x317 | 1 << 16 | 1 << 20 | 1 << 24;
+// Sessions resumed with a verified client certificate must belong to this.
+const SESSION_ID_CONTEXT: &[u8] = b"rustywebserver";
+
+// libssl
+extern "C" {
+    fn TLS_server_method() -> *const c_void;
+    fn TLS_client_method() -> *const c_void;
+    fn SSL_CTX_new(method: *const c_void) -> *mut SslCtx;
+    fn SSL_CTX_free(ctx: *mut SslCtx);
+    fn SSL_CTX_ctrl(ctx: *mut SslCtx, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_CTX_callback_ctrl(ctx: *mut SslCtx, cmd: c_int, callback: Option<unsafe extern "C" fn()>) -> c_long;
+    fn SSL_CTX_set_options(ctx: *mut SslCtx, options: u64) -> u64;
+    fn SSL_CTX_use_certificate_chain_file(ctx: *mut SslCtx, file: *const c_char) -> c_int;
+    fn SSL_CTX_use_PrivateKey_file(ctx: *mut SslCtx, file: *const c_char, kind: c_int) -> c_int;
+    fn SSL_CTX_check_private_key(ctx: *const SslCtx) -> c_int;
+    fn SSL_CTX_get0_certificate(ctx: *const SslCtx) -> *mut X509;
+    fn SSL_CTX_load_verify_locations(ctx: *mut SslCtx, file: *const c_char, dir: *const c_char) -> c_int;
+    fn SSL_CTX_set_verify(ctx: *mut SslCtx, mode: c_int, callback: *const c_void);
+    fn SSL_CTX_set_client_CA_list(ctx: *mut SslCtx, names: *mut c_void);
+    fn SSL_CTX_set_default_verify_paths(ctx: *mut SslCtx) -> c_int;
+    fn SSL_CTX_set_session_id_context(ctx: *mut SslCtx, id: *const u8, len: c_uint) -> c_int;
+    fn SSL_load_client_CA_file(file: *const c_char) -> *mut c_void;
+    fn SSL_get1_peer_certificate(ssl: *const Ssl) -> *mut X509;
+    fn SSL_new(ctx: *mut SslCtx) -> *mut Ssl;
+    fn SSL_free(ssl: *mut Ssl);
+    fn SSL_set_bio(ssl: *mut Ssl, rbio: *mut Bio, wbio: *mut Bio);
+    fn SSL_set_accept_state(ssl: *mut Ssl);
+    fn SSL_set_connect_state(ssl: *mut Ssl);
+    fn SSL_ctrl(ssl: *mut Ssl, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_set1_host(ssl: *mut Ssl, host: *const c_char) -> c_int;
+    fn SSL_do_handshake(ssl: *mut Ssl) -> c_int;
+    fn SSL_read_ex(ssl: *mut Ssl, buf: *mut c_void, num: usize, read: *mut usize) -> c_int;
+    fn SSL_write_ex(ssl: *mut Ssl, buf: *const c_void, num: usize, written: *mut usize) -> c_int;
+    fn SSL_shutdown(ssl: *mut Ssl) -> c_int;
+    fn SSL_get_error(ssl: *const Ssl, ret: c_int) -> c_int;
+    fn SSL_get_version(ssl: *const Ssl) -> *const c_char;
+    fn SSL_get_servername(ssl: *const Ssl, kind: c_int) -> *const c_char;
+    fn SSL_set_SSL_CTX(ssl: *mut Ssl, ctx: *mut SslCtx) -> *mut SslCtx;
+}
+
+// libcrypto
+extern "C" {
+    fn BIO_s_mem() -> *const c_void;
+    fn BIO_new(method: *const c_void) -> *mut Bio;
+    fn BIO_read(bio: *mut Bio, data: *mut c_void, len: c_int) -> c_int;
+    fn BIO_write(bio: *mut Bio, data: *const c_void, len: c_int) -> c_int;
+    fn BIO_ctrl_pending(bio: *mut Bio) -> usize;
+    fn ERR_get_error() -> c_ulong;
+    fn ERR_error_string_n(e: c_ulong, buf: *mut c_char, len: usize);
+    fn ERR_clear_error();
+    fn BIO_free(bio: *mut Bio) -> c_int;
+    fn X509_free(cert: *mut X509);
+    fn X509_get_subject_name(cert: *const X509) -> *mut c_void;
+    fn X509_NAME_print_ex(out: *mut Bio, name: *const c_void, indent: c_int, flags: c_ulong) -> c_int;
+    fn X509_check_host(cert: *mut X509, name: *const c_char, len: usize, flags: c_uint, peername: *mut *mut c_char) -> c_int;
+}
+
+// Encrypted bytes read from the peer at a time.
+const CHUNK: usize = 16 * 1024;
+
+/// `--tls-cert` and `--tls-key`: the certificate chains and private keys the
+/// TCP listeners serve HTTPS with; TLS 1.2 and up. A client asking for a
+/// host name (SNI) gets the first certificate covering it, any other the
+/// first certificate. With `--tls-client-ca`, clients must present a
+/// certificate issued by one of its CAs or the handshake fails.
+pub struct Acceptor {
+    // Boxed, so the servername callback's pointer to it stays put.
+    sites: Box<Sites>,
+}
+
+struct Sites(Vec<Site>);
+
+struct Site {
+    // Owned, and freed on drop.
+    ctx: *mut SslCtx,
+    // Owned by `ctx`.
+    cert: *mut X509,
+}
+
+// SAFETY: an SSL_CTX may be shared by threads once it is set up; it is only
+// read from after `Acceptor::new`.
+unsafe impl Send for Acceptor {}
+unsafe impl Sync for Acceptor {}
+
+/// What a handshake settled, kept in the request extensions for scripts.
+#[derive(Clone)]
+pub struct Session {
+    pub protocol: String,
+    pub server_name: Option<String>,
+    /// The subject of the verified client certificate, in RFC 2253 form.
+    pub client_subject: Option<String>,
+}
+
+impl Acceptor {
+    /// Loads each pair of PEM files and the client CAs; the errors say which
+    /// file OpenSSL refused and why.
+    pub fn new(pairs: &[(PathBuf, PathBuf)], client_ca: Option<&Path>) -> Result<Acceptor, String> {
+        let mut sites = Vec::new();
+        for (cert, key) in pairs {
+            let site = Site::load(cert, key)?;
+            if let Some(client_ca) = client_ca {
+                site.require_client_cert(client_ca)?;
+            }
+            sites.push(site);
+        }
+        let sites = Box::new(Sites(sites));
+        let Some(default) = sites.0.first() else {
+            return Err("no TLS certificate".to_string());
+        };
+        if sites.0.len() > 1 {
+            // SAFETY: OpenSSL calls the callback with the argument set here,
+            // which points at `sites` for as long as the Acceptor lives. The
+            // transmute only erases the callback's type, as the C macro
+            // SSL_CTX_set_tlsext_servername_callback does.
+            unsafe {
+                SSL_CTX_callback_ctrl(default.ctx, SSL_CTRL_SET_TLSEXT_SERVERNAME_CB, Some(std::mem::transmute::<ServernameCallback, unsafe extern "C" fn()>(select_site)));
+                SSL_CTX_ctrl(default.ctx, SSL_CTRL_SET_TLSEXT_SERVERNAME_ARG, 0, &*sites as *const Sites as *mut c_void);
+            }
+        }
+        Ok(Acceptor { sites })
+    }
+
+    /// Runs the server side of a handshake on `io`.
+    pub async fn accept<I: AsyncRead + AsyncWrite + Unpin>(&self, io: I) -> io::Result<(TlsStream<I>, Session)> {
+        // SAFETY: the contexts are valid for as long as the Acceptor.
+        let ssl = unsafe { SSL_new(self.sites.0[0].ctx) };
+        let mut stream = TlsStream::new(io, ssl)?;
+        // SAFETY: `ssl` is owned by `stream`.
+        unsafe { SSL_set_accept_state(stream.ssl) };
+        poll_fn(|cx| stream.poll_handshake(cx)).await?;
+        let session = stream.session();
+        Ok((stream, session))
+    }
+}
+
+impl Site {
+    fn load(cert: &Path, key: &Path) -> Result<Site, String> {
+        // SAFETY: the context is owned by the Site from here on, and freed by
+        // its Drop should a file be refused.
+        let mut site = unsafe {
+            let ctx = SSL_CTX_new(TLS_server_method());
+            if ctx.is_null() {
+                return Err(format!("Failed to set up TLS: {}", openssl_error()));
+            }
+            SSL_CTX_ctrl(ctx, SSL_CTRL_SET_MIN_PROTO_VERSION, TLS1_2_VERSION, ptr::null_mut());
+            SSL_CTX_ctrl(ctx, SSL_CTRL_MODE, SSL_MODE_ENABLE_PARTIAL_WRITE | SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER, ptr::null_mut());
+            SSL_CTX_set_options(ctx, SSL_OP_NO_COMPRESSION | SSL_OP_NO_RENEGOTIATION);
+            Site { ctx, cert: ptr::null_mut() }
+        };
+        let cert_path = c_path(cert)?;
+        let key_path = c_path(key)?;
+        // SAFETY: `ctx` is valid and the paths are NUL-terminated.
+        unsafe {
+            ERR_clear_error();
+            if SSL_CTX_use_certificate_chain_file(site.ctx, cert_path.as_ptr()) != 1 {
+                return Err(format!("Failed to load the TLS certificate {}: {}", cert.display(), openssl_error()));
+            }
+            if SSL_CTX_use_PrivateKey_file(site.ctx, key_path.as_ptr(), SSL_FILETYPE_PEM) != 1 {
+                return Err(format!("Failed to load the TLS key {}: {}", key.display(), openssl_error()));
+            }
+            if SSL_CTX_check_private_key(site.ctx) != 1 {
+                return Err(format!("The TLS key {} does not match the certificate {}", key.display(), cert.display()));
+            }
+            site.cert = SSL_CTX_get0_certificate(site.ctx);
+            SSL_CTX_set_session_id_context(site.ctx, SESSION_ID_CONTEXT.as_ptr(), SESSION_ID_CONTEXT.len() as c_uint);
+        }
+        Ok(site)
+    }
+
+    // Every site checks clients the same way, as SNI may switch to any.
+    fn require_client_cert(&self, client_ca: &Path) -> Result<(), String> {
+        let ca_path = c_path(client_ca)?;
+        // SAFETY: `ctx` is valid, the path NUL-terminated, and the CA name
+        // list is handed over to `ctx`.
+        unsafe {
+            ERR_clear_error();
+            let names = SSL_load_client_CA_file(ca_path.as_ptr());
+            if names.is_null() || SSL_CTX_load_verify_locations(self.ctx, ca_path.as_ptr(), ptr::null()) != 1 {
+                return Err(format!("Failed to load the TLS client CAs {}: {}", client_ca.display(), openssl_error()));
+            }
+            SSL_CTX_set_client_CA_list(self.ctx, names);
+            SSL_CTX_set_verify(self.ctx, SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, ptr::null());
+        }
+        Ok(())
+    }
+
+    // Whether the certificate's subjectAltNames, or failing those its common
+    // name, cover `name`, wildcards included.
+    fn covers(&self, name: &CStr) -> bool {
+        // SAFETY: `cert` belongs to `ctx`, which is valid, and `name` is
+        // NUL-terminated.
+        unsafe { X509_check_host(self.cert, name.as_ptr(), name.to_bytes().len(), 0, ptr::null_mut()) == 1 }
+    }
+}
+
+impl Drop for Site {
+    fn drop(&mut self) {
+        // SAFETY: the context is owned by the Site; SSL objects made from it
+        // hold references of their own.
+        unsafe { SSL_CTX_free(self.ctx) };
+    }
+}
+
+// The servername callback: switches the connection to the first site whose
+// certificate covers the name the client asked for. Without a match the
+// handshake goes on with the first certificate, which the client may refuse.
+//
+// SAFETY: OpenSSL calls this during a handshake on `ssl`, with `sites`
+// pointing at the Acceptor's Sites, which outlive every connection.
+unsafe extern "C" fn select_site(ssl: *mut Ssl, _alert: *mut c_int, sites: *mut c_void) -> c_int {
+    let name = SSL_get_servername(ssl, TLSEXT_NAMETYPE_HOST_NAME);
+    if name.is_null() {
+        return SSL_TLSEXT_ERR_OK;
+    }
+    let name = CStr::from_ptr(name);
+    let Sites(sites) = &*sites.cast::<Sites>();
+    if let Some(site) = sites.iter().find(|site| site.covers(name)) {
+        SSL_set_SSL_CTX(ssl, site.ctx);
+    }
+    SSL_TLSEXT_ERR_OK
+}
+
+// The context of outgoing connections, which trust the system's CAs.
+struct Connector {
+    ctx: *mut SslCtx,
+}
+
+// SAFETY: as for the Acceptor, the context is only read from once set up.
+unsafe impl Send for Connector {}
+unsafe impl Sync for Connector {}
+
+impl Connector {
+    fn new() -> Connector {
+        // SAFETY: the context lives as long as the process; a null one makes
+        // every connect fail.
+        unsafe {
+            let ctx = SSL_CTX_new(TLS_client_method());
+            if !ctx.is_null() {
+                SSL_CTX_ctrl(ctx, SSL_CTRL_SET_MIN_PROTO_VERSION, TLS1_2_VERSION, ptr::null_mut());
+                SSL_CTX_ctrl(ctx, SSL_CTRL_MODE, SSL_MODE_ENABLE_PARTIAL_WRITE | SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER, ptr::null_mut());
+                SSL_CTX_set_default_verify_paths(ctx);
+                SSL_CTX_set_verify(ctx, SSL_VERIFY_PEER, ptr::null());
+            }
+            Connector { ctx }
+        }
+    }
+}
+
+/// Connects to `host` over TLS, checking its certificate against the system's
+/// CAs (or SSL_CERT_FILE and SSL_CERT_DIR) and the host name.
+pub async fn connect(host: &str, port: u16) -> io::Result<TlsStream<TcpStream>> {
+    static CONNECTOR: OnceLock<Connector> = OnceLock::new();
+    // `[::1]` in a URL is the address ::1.
+    let name = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let c_name = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "a host name with a NUL byte"))?;
+    let tcp = TcpStream::connect((name, port)).await?;
+    let ctx = CONNECTOR.get_or_init(Connector::new).ctx;
+    if ctx.is_null() {
+        return Err(io::Error::other("TLS is not available"));
+    }
+    // SAFETY: `ctx` is valid for the life of the process.
+    let mut stream = TlsStream::new(tcp, unsafe { SSL_new(ctx) })?;
+    // SAFETY: `ssl` is owned by `stream`; OpenSSL copies the name.
+    unsafe {
+        SSL_set1_host(stream.ssl, c_name.as_ptr());
+        // Addresses are not sent as SNI (RFC 6066 section 3).
+        if name.parse::<std::net::IpAddr>().is_err() {
+            SSL_ctrl(stream.ssl, SSL_CTRL_SET_TLSEXT_HOSTNAME, TLSEXT_NAMETYPE_HOST_NAME as c_long, c_name.as_ptr() as *mut c_void);
+        }
+        SSL_set_connect_state(stream.ssl);
+    }
+    poll_fn(|cx| stream.poll_handshake(cx)).await?;
+    Ok(stream)
+}
+
+/// A connection after its handshake, decrypting what is read from `inner`
+/// and encrypting what is written to it.
+pub struct TlsStream<I> {
+    inner: I,
+    // Owned, and freed on drop.
+    ssl: *mut Ssl,
+    // Both owned by `ssl`: what came from the peer, and what goes to it.
+    incoming: *mut Bio,
+    outgoing: *mut Bio,
+    // Encrypted bytes taken from `outgoing` and not yet written to `inner`.
+    pending: Vec<u8>,
+    sent: usize,
+    eof: bool,
+    shutdown: bool,
+}
+
+// SAFETY: the SSL object is only used through the stream, by one task at a time.
+unsafe impl<I: Send> Send for TlsStream<I> {}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> TlsStream<I> {
+    fn new(inner: I, ssl: *mut Ssl) -> io::Result<TlsStream<I>> {
+        if ssl.is_null() {
+            return Err(io::Error::other(openssl_error()));
+        }
+        // SAFETY: `ssl` is valid; SSL_set_bio hands both BIOs over to it, and
+        // the stream frees it on drop.
+        unsafe {
+            let incoming = BIO_new(BIO_s_mem());
+            let outgoing = BIO_new(BIO_s_mem());
+            if incoming.is_null() || outgoing.is_null() {
+                BIO_free(incoming);
+                BIO_free(outgoing);
+                SSL_free(ssl);
+                return Err(io::Error::other(openssl_error()));
+            }
+            SSL_set_bio(ssl, incoming, outgoing);
+            Ok(TlsStream { inner, ssl, incoming, outgoing, pending: Vec::new(), sent: 0, eof: false, shutdown: false })
+        }
+    }
+
+    fn session(&self) -> Session {
+        // SAFETY: `ssl` is valid; the version name is a static string and
+        // the server name lives as long as `ssl`.
+        unsafe {
+            let protocol = CStr::from_ptr(SSL_get_version(self.ssl)).to_string_lossy().into_owned();
+            let server_name = SSL_get_servername(self.ssl, TLSEXT_NAMETYPE_HOST_NAME);
+            let server_name = (!server_name.is_null()).then(|| CStr::from_ptr(server_name).to_string_lossy().into_owned());
+            Session { protocol, server_name, client_subject: self.client_subject() }
+        }
+    }
+
+    fn client_subject(&self) -> Option<String> {
+        // SAFETY: `ssl` is valid; the certificate reference taken here and
+        // the BIO are freed before returning.
+        unsafe {
+            let cert = SSL_get1_peer_certificate(self.ssl);
+            if cert.is_null() {
+                return None;
+            }
+            let bio = BIO_new(BIO_s_mem());
+            if bio.is_null() {
+                X509_free(cert);
+                return None;
+            }
+            X509_NAME_print_ex(bio, X509_get_subject_name(cert), 0, XN_FLAG_RFC2253);
+            let mut subject = vec![0u8; BIO_ctrl_pending(bio)];
+            let read = BIO_read(bio, subject.as_mut_ptr().cast(), subject.len() as c_int);
+            subject.truncate(read.max(0) as usize);
+            BIO_free(bio);
+            X509_free(cert);
+            Some(String::from_utf8_lossy(&subject).into_owned())
+        }
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            // SAFETY: `ssl` is valid.
+            let ret = unsafe {
+                ERR_clear_error();
+                SSL_do_handshake(self.ssl)
+            };
+            if ret == 1 {
+                return self.poll_send(cx);
+            }
+            // SAFETY: as above, right after the call it is asked about.
+            if unsafe { SSL_get_error(self.ssl, ret) } != SSL_ERROR_WANT_READ {
+                let e = io::Error::other(openssl_error());
+                // The alert telling the peer why, if it goes out at once.
+                let _ = self.poll_send(cx);
+                return Poll::Ready(Err(e));
+            }
+            ready!(self.poll_send(cx))?;
+            if self.eof {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the TLS handshake")));
+            }
+            ready!(self.poll_receive(cx))?;
+        }
+    }
+
+    // Writes out what OpenSSL produced, and whatever is left from before.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // SAFETY: `outgoing` belongs to `ssl`, which is valid.
+        unsafe {
+            let waiting = BIO_ctrl_pending(self.outgoing);
+            if waiting > 0 {
+                let start = self.pending.len();
+                self.pending.resize(start + waiting, 0);
+                let read = BIO_read(self.outgoing, self.pending[start..].as_mut_ptr().cast(), waiting as c_int);
+                self.pending.truncate(start + read.max(0) as usize);
+            }
+        }
+        while self.sent < self.pending.len() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.sent..]))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.sent += n,
+            }
+        }
+        self.pending.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    // Hands OpenSSL what the peer sent next; sets `eof` once it is gone.
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut chunk = [0u8; CHUNK];
+        let mut buf = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+        let received = buf.filled();
+        if received.is_empty() {
+            self.eof = true;
+        } else {
+            // SAFETY: `incoming` belongs to `ssl`; a memory BIO takes all of it.
+            unsafe { BIO_write(self.incoming, received.as_ptr().cast(), received.len() as c_int) };
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let unfilled = buf.initialize_unfilled();
+            let mut read = 0;
+            // SAFETY: `ssl` is valid and `unfilled` is writable for its length.
+            let ret = unsafe {
+                ERR_clear_error();
+                SSL_read_ex(this.ssl, unfilled.as_mut_ptr().cast(), unfilled.len(), &mut read)
+            };
+            if ret == 1 {
+                buf.advance(read);
+                return Poll::Ready(Ok(()));
+            }
+            // SAFETY: as above.
+            match unsafe { SSL_get_error(this.ssl, ret) } {
+                SSL_ERROR_WANT_READ => {
+                    // Session tickets and the like go out before waiting; a
+                    // full socket is no reason not to read.
+                    if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    // Many clients close without a close_notify; HTTP framing
+                    // tells a cut-off message apart.
+                    if this.eof {
+                        return Poll::Ready(Ok(()));
+                    }
+                    ready!(this.poll_receive(cx))?;
+                },
+                SSL_ERROR_ZERO_RETURN => return Poll::Ready(Ok(())),
+                _ => return Poll::Ready(Err(io::Error::other(openssl_error()))),
+            }
+        }
+    }
+}
+
+impl<I: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // Nothing more is encrypted until the last of it is sent.
+        ready!(this.poll_send(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut written = 0;
+        // SAFETY: `ssl` is valid and `buf` readable for its length.
+        let ret = unsafe {
+            ERR_clear_error();
+            SSL_write_ex(this.ssl, buf.as_ptr().cast(), buf.len(), &mut written)
+        };
+        if ret != 1 {
+            return Poll::Ready(Err(io::Error::other(openssl_error())));
+        }
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.shutdown {
+            // SAFETY: `ssl` is valid; this queues the close_notify alert.
+            unsafe {
+                ERR_clear_error();
+                SSL_shutdown(self.ssl);
+            }
+            self.shutdown = true;
+        }
+        ready!(self.poll_send(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<I> Drop for TlsStream<I> {
+    fn drop(&mut self) {
+        // SAFETY: the stream owns `ssl`, and `ssl` its BIOs.
+        unsafe { SSL_free(self.ssl) };
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString, String> {
+    CString::new(path.as_os_str().as_encoded_bytes()).map_err(|_| format!("{}: a path with a NUL byte", path.display()))
+}
+
+// The reasons OpenSSL queued for the last failure, oldest first.
+fn openssl_error() -> String {
+    let mut reasons = Vec::new();
+    loop {
+        // SAFETY: takes the oldest entry off this thread's error queue.
+        let code = unsafe { ERR_get_error() };
+        if code == 0 {
+            break;
+        }
+        let mut buf = [0 as c_char; 256];
+        // SAFETY: ERR_error_string_n writes at most `buf.len()` bytes,
+        // the last of them a NUL.
+        let reason = unsafe {
+            ERR_error_string_n(code, buf.as_mut_ptr(), buf.len());
+            CStr::from_ptr(buf.as_ptr())
+        };
+        reasons.push(reason.to_string_lossy().into_owned());
+    }
+    if reasons.is_empty() {
+        return "the connection was closed".to_string();
+    }
+    reasons.join("; ")
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::io;
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::Body;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use crate::event;
+use crate::gateway::{self, Connection, Output};
+
+// A plain WSGI request in the packet header's modifiers.
+const MODIFIER_WSGI: u8 = 0;
+// Room kept in the packet for the CONTENT_LENGTH of a chunked body.
+const LENGTH_ITEM: usize = 2 + "CONTENT_LENGTH".len() + 2 + 20;
+
+/// Runs one request over a fresh connection: a packet with the variables,
+/// then the body. The application answers with an HTTP response, whose
+/// status line is turned into a CGI Status header, and closes the connection.
+pub fn exchange(connection: Box<dyn Connection>, params: HashMap<String, String>, body: Body, limit: u64, upstream: String) -> Output {
+    let (reader, mut writer) = tokio::io::split(connection);
+    let mut packet = Vec::new();
+    for (name, value) in &params {
+        for item in [name, value] {
+            if item.len() > u16::MAX as usize {
+                return Box::pin(stream::once(future::ready(Err(io::Error::other("a variable too long for uwsgi")))));
+            }
+            packet.extend_from_slice(&(item.len() as u16).to_le_bytes());
+            packet.extend_from_slice(item.as_bytes());
+        }
+    }
+    if packet.len() + LENGTH_ITEM > u16::MAX as usize {
+        return Box::pin(stream::once(future::ready(Err(io::Error::other("request variables too large for uwsgi")))));
+    }
+
+    tokio::spawn(async move {
+        // The application may answer without reading all of its input, so
+        // a failed write only matters when the body was too large.
+        if let Err(e) = send_request(&mut writer, params, packet, body, limit).await {
+            if e.kind() == io::ErrorKind::InvalidData {
+                event::warning(&format!("uwsgi request to {} cut off: {}", upstream, e));
+                let _ = writer.shutdown().await;
+            }
+        }
+    });
+
+    Box::pin(stream::unfold((ReaderStream::new(reader), Some(Vec::new())), |(mut output, pending)| async move {
+        let Some(mut pending) = pending else {
+            return output.next().await.map(|chunk| (chunk, (output, None)));
+        };
+        loop {
+            match output.next().await {
+                Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), (output, None))),
+                None if pending.is_empty() => return None,
+                None => return Some((Ok(Bytes::from(pending)), (output, None))),
+            }
+            let status_line = pending.starts_with(b"HTTP/");
+            if status_line && pending.contains(&b'\n') {
+                return Some((Ok(Bytes::from(status_to_cgi(pending))), (output, None)));
+            }
+            if !status_line && (pending.len() >= 5 || !b"HTTP/".starts_with(&pending)) {
+                return Some((Ok(Bytes::from(pending)), (output, None)));
+            }
+        }
+    }))
+}
+
+async fn send_request(writer: &mut (impl AsyncWrite + Unpin), params: HashMap<String, String>, mut packet: Vec<u8>, mut body: Body, limit: u64) -> io::Result<()> {
+    // The application reads CONTENT_LENGTH bytes of body, so it has to be
+    // there even for a chunked body.
+    let (length, buffered) = gateway::sized_body(&params, &mut body, limit).await?;
+    if !params.contains_key("CONTENT_LENGTH") {
+        for item in ["CONTENT_LENGTH", &length.to_string()] {
+            packet.extend_from_slice(&(item.len() as u16).to_le_bytes());
+            packet.extend_from_slice(item.as_bytes());
+        }
+    }
+    let mut request = vec![MODIFIER_WSGI];
+    request.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+    request.push(0);
+    request.extend_from_slice(&packet);
+    writer.write_all(&request).await?;
+    gateway::send_body(writer, body, buffered, limit).await?;
+    writer.flush().await
+}
+
+// `HTTP/1.1 404 Not Found` becomes `Status: 404 Not Found`.
+fn status_to_cgi(response: Vec<u8>) -> Vec<u8> {
+    let status_start = response.iter().position(|b| *b == b' ').map_or(response.len(), |space| space + 1);
+    let mut converted = b"Status: ".to_vec();
+    converted.extend_from_slice(&response[status_start..]);
+    converted
+}
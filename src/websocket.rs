@@ -0,0 +1,221 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command};
+use tokio::sync::mpsc;
+use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
+use hyper::{Body, Request, Response, StatusCode};
+use crate::config::Config;
+use crate::script_limit::ScriptPermit;
+use crate::{base64, event, metrics, script_command, script_env, service_unavailable};
+use crate::sha1::sha1;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// How long a script gets to exit on its own after the client went away.
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+pub fn is_upgrade(req: &Request<Body>) -> bool {
+    let has_token = |name, token: &str| req.headers().get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token));
+    has_token(CONNECTION, "upgrade") && has_token(UPGRADE, "websocket")
+}
+
+/// Completes the handshake and attaches the script to the connection: every
+/// message received is written to its stdin followed by a newline, and every
+/// line it prints is sent back as a text message.
+pub async fn bridge(req: Request<Body>, script_path: PathBuf, client_addr: SocketAddr, config: &Config) -> Response<Body> {
+    let version_ok = req.headers().get(SEC_WEBSOCKET_VERSION).is_some_and(|v| v == "13");
+    let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if version_ok => key.as_bytes().to_vec(),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(SEC_WEBSOCKET_VERSION, "13")
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from("<html>400 Bad Request</html>"))
+                .unwrap();
+        }
+    };
+    let accept = base64::encode(&sha1(&[key.as_slice(), GUID.as_bytes()].concat()));
+    let permit = match config.script_limits.acquire(&script_path).await {
+        Some(permit) => permit,
+        None => return service_unavailable(),
+    };
+
+    let (parts, body) = req.into_parts();
+    let env_vars = script_env(&parts, &script_path, client_addr, config);
+    let command = script_command(&script_path, &env_vars, config);
+    let max_message_size = config.max_body_size;
+    let req = Request::from_parts(parts, body);
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => run(upgraded, script_path, client_addr, command, max_message_size, permit).await,
+            Err(e) => event::error(&format!("WebSocket upgrade failed: {}", e)),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept).unwrap())
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn run<S>(stream: S, script_path: PathBuf, client_addr: SocketAddr, command: std::process::Command, max_message_size: u64, _permit: ScriptPermit)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let _running = metrics::script_started(&script_path, client_addr.ip());
+    let mut child = match Command::from(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            event::error(&format!("Failed to execute {}: {}", script_path.display(), e));
+            metrics::script_failed();
+            return;
+        }
+    };
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+
+    let (reader, writer) = tokio::io::split(stream);
+    let (frames, outgoing) = mpsc::channel::<(u8, Vec<u8>)>(16);
+    let writer = tokio::spawn(write_frames(writer, outgoing));
+
+    let script_frames = frames.clone();
+    let mut script = tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if script_frames.send((OP_TEXT, line.into_bytes())).await.is_err() {
+                break;
+            }
+        }
+        let _ = child.wait().await;
+        let _ = script_frames.send((OP_CLOSE, 1000u16.to_be_bytes().to_vec())).await;
+    });
+
+    tokio::select! {
+        _ = read_frames(reader, stdin, frames.clone(), max_message_size) => {
+            // stdin is closed now; give the script a moment to notice.
+            if tokio::time::timeout(EXIT_GRACE, &mut script).await.is_err() {
+                script.abort();
+            }
+        },
+        _ = &mut script => {},
+    }
+    drop(frames);
+    let _ = writer.await;
+}
+
+// Returns when the client closes the connection or breaks the protocol.
+async fn read_frames<R>(mut reader: R, mut stdin: ChildStdin, frames: mpsc::Sender<(u8, Vec<u8>)>, max_message_size: u64)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut message = Vec::new();
+    loop {
+        let (fin, opcode, payload) = match read_frame(&mut reader, max_message_size).await {
+            Ok(frame) => frame,
+            Err(code) => {
+                let _ = frames.send((OP_CLOSE, code.to_be_bytes().to_vec())).await;
+                return;
+            }
+        };
+        match opcode {
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                message.extend_from_slice(&payload);
+                if message.len() as u64 > max_message_size {
+                    let _ = frames.send((OP_CLOSE, 1009u16.to_be_bytes().to_vec())).await;
+                    return;
+                }
+                if fin {
+                    message.push(b'\n');
+                    if stdin.write_all(&message).await.is_err() {
+                        return;
+                    }
+                    message.clear();
+                }
+            },
+            OP_PING => {
+                let _ = frames.send((OP_PONG, payload)).await;
+            },
+            OP_PONG => {},
+            OP_CLOSE => {
+                let _ = frames.send((OP_CLOSE, payload.get(..2).unwrap_or(&[]).to_vec())).await;
+                return;
+            },
+            _ => {
+                let _ = frames.send((OP_CLOSE, 1002u16.to_be_bytes().to_vec())).await;
+                return;
+            },
+        }
+    }
+}
+
+// Errors carry the close status code to answer with.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_message_size: u64) -> Result<(bool, u8, Vec<u8>), u16> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await.map_err(|_| 1006u16)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    // Client frames must be masked (RFC 6455 section 5.1).
+    if head[1] & 0x80 == 0 {
+        return Err(1002);
+    }
+    let len = match head[1] & 0x7F {
+        126 => reader.read_u16().await.map_err(|_| 1006u16)? as u64,
+        127 => reader.read_u64().await.map_err(|_| 1006u16)?,
+        len => len as u64,
+    };
+    if len > max_message_size {
+        return Err(1009);
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await.map_err(|_| 1006u16)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await.map_err(|_| 1006u16)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(mut writer: W, mut outgoing: mpsc::Receiver<(u8, Vec<u8>)>) {
+    while let Some((opcode, payload)) = outgoing.recv().await {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&payload);
+        if writer.write_all(&frame).await.is_err() || opcode == OP_CLOSE {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
@@ -0,0 +1,653 @@
+// A small streaming Zstandard (RFC 8878) encoder.
+// Every chunk passed to `compress` becomes one or more blocks with Huffman
+// coded literals and sequences coded with the predefined FSE tables; matches
+// may reach back into the previous 64 KiB of input. The Huffman weights are
+// always described directly, so blocks with a literal above 128 store their
+// literals raw.
+
+use std::sync::OnceLock;
+use crate::deflate::StreamEncoder;
+use crate::huffman::code_lengths;
+use crate::lz77::{Matcher, Sequence};
+
+const MAGIC: u32 = 0xFD2F_B528;
+const WINDOW_LOG: u32 = 16;
+const WINDOW_SIZE: usize = 1 << WINDOW_LOG;
+// Blocks hold no more than the window, nor more than 128 KiB.
+const BLOCK_MAX: usize = WINDOW_SIZE;
+
+const RAW_BLOCK: u32 = 0;
+const COMPRESSED_BLOCK: u32 = 2;
+
+const RAW_LITERALS: usize = 0;
+const RLE_LITERALS: usize = 1;
+const COMPRESSED_LITERALS: usize = 2;
+const MAX_LITERAL_CODE_LENGTH: u8 = 11;
+
+// Literal lengths from 16 and match lengths from 35 share a code between
+// values from a base, told apart by extra bits.
+const LITERAL_LENGTH_BASE: [u32; 20] = [
+    16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536,
+];
+const LITERAL_LENGTH_EXTRA: [u32; 20] = [1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+const MATCH_LENGTH_BASE: [u32; 21] = [
+    35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387, 32771, 65539,
+];
+const MATCH_LENGTH_EXTRA: [u32; 21] = [1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+// The predefined distributions, -1 standing for a "less than 1" probability.
+const LITERAL_LENGTH_DISTRIBUTION: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_DISTRIBUTION: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OFFSET_DISTRIBUTION: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+// One decoding state of an FSE table: the symbol it stands for, and how the
+// next state is read.
+struct State {
+    symbol: usize,
+    bits: u32,
+    baseline: u32,
+}
+
+struct Table {
+    log: u32,
+    states: Vec<State>,
+    // For each symbol and each next state, the state to be in before it.
+    previous: Vec<u32>,
+}
+
+impl Table {
+    fn new(distribution: &[i16], log: u32) -> Table {
+        let size = 1 << log;
+        let mut symbols = vec![0; size];
+        let mut high = size - 1;
+        for (symbol, &p) in distribution.iter().enumerate() {
+            if p == -1 {
+                symbols[high] = symbol;
+                high -= 1;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut position = 0;
+        for (symbol, &p) in distribution.iter().enumerate() {
+            for _ in 0..p.max(0) {
+                symbols[position] = symbol;
+                position = (position + step) & (size - 1);
+                while position > high {
+                    position = (position + step) & (size - 1);
+                }
+            }
+        }
+
+        let mut next: Vec<u32> = distribution.iter().map(|&p| p.unsigned_abs() as u32).collect();
+        let states: Vec<State> = symbols.iter().map(|&symbol| {
+            let n = next[symbol];
+            next[symbol] += 1;
+            let bits = log - n.ilog2();
+            State { symbol, bits, baseline: (n << bits) - size as u32 }
+        }).collect();
+
+        let mut previous = vec![0; distribution.len() * size];
+        for (i, state) in states.iter().enumerate() {
+            for next in state.baseline..state.baseline + (1 << state.bits) {
+                previous[state.symbol * size + next as usize] = i as u32;
+            }
+        }
+        Table { log, states, previous }
+    }
+
+    // A state that stands for `symbol`, to end the stream in.
+    fn last(&self, symbol: usize) -> u32 {
+        self.previous[symbol << self.log]
+    }
+
+    // The state standing for `symbol` that leads to `next`, after writing
+    // the bits the decoder reads to get there.
+    fn step(&self, symbol: usize, next: u32, bits: &mut BitWriter) -> u32 {
+        let state = self.previous[(symbol << self.log) + next as usize];
+        let s = &self.states[state as usize];
+        bits.put(next - s.baseline, s.bits);
+        state
+    }
+}
+
+// The literal length, match length and offset tables.
+fn tables() -> &'static [Table; 3] {
+    static TABLES: OnceLock<[Table; 3]> = OnceLock::new();
+    TABLES.get_or_init(|| [
+        Table::new(&LITERAL_LENGTH_DISTRIBUTION, 6),
+        Table::new(&MATCH_LENGTH_DISTRIBUTION, 6),
+        Table::new(&OFFSET_DISTRIBUTION, 5),
+    ])
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bits: u64,
+    nbits: u32,
+    out: Vec<u8>,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += len;
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    // The decoder reads backwards from the highest set bit of the last byte.
+    fn finish(mut self) -> Vec<u8> {
+        self.put(1, 1);
+        if self.nbits > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+pub struct ZstdEncoder {
+    matcher: Matcher,
+    header_written: bool,
+}
+
+impl ZstdEncoder {
+    /// `level` follows the zstd tool, 1-19; higher levels search longer for matches.
+    pub fn new(level: u32) -> Self {
+        ZstdEncoder {
+            matcher: Matcher::new(WINDOW_SIZE, 1 << level.clamp(1, 12)),
+            header_written: false,
+        }
+    }
+
+    fn header(&mut self) -> Vec<u8> {
+        if self.header_written {
+            return Vec::new();
+        }
+        self.header_written = true;
+        // No content size, dictionary or checksum; the Window_Descriptor
+        // exponent is the window log less 10.
+        let mut header = MAGIC.to_le_bytes().to_vec();
+        header.extend([0, ((WINDOW_LOG - 10) << 3) as u8]);
+        header
+    }
+}
+
+impl StreamEncoder for ZstdEncoder {
+    fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = self.header();
+        for block in data.chunks(BLOCK_MAX) {
+            let (sequences, _) = self.matcher.parse(block);
+            let compressed = compressed_block(block, &sequences);
+            if compressed.len() < block.len() {
+                block_header(&mut out, false, COMPRESSED_BLOCK, compressed.len());
+                out.extend(compressed);
+            } else {
+                block_header(&mut out, false, RAW_BLOCK, block.len());
+                out.extend_from_slice(block);
+            }
+        }
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let mut out = self.header();
+        // An empty raw block marked as the last one ends the frame.
+        block_header(&mut out, true, RAW_BLOCK, 0);
+        out
+    }
+}
+
+fn block_header(out: &mut Vec<u8>, last: bool, block_type: u32, size: usize) {
+    let header = (size as u32) << 3 | block_type << 1 | last as u32;
+    out.extend_from_slice(&header.to_le_bytes()[..3]);
+}
+
+fn compressed_block(data: &[u8], sequences: &[Sequence]) -> Vec<u8> {
+    let mut literals = Vec::new();
+    let mut pos = 0;
+    for sequence in sequences {
+        literals.extend_from_slice(&data[pos..pos + sequence.literals]);
+        pos += sequence.literals + sequence.length;
+    }
+    literals.extend_from_slice(&data[pos..]);
+    let mut out = literals_section(&literals);
+
+    let n = sequences.len();
+    match n {
+        0..128 => out.push(n as u8),
+        128..0x7F00 => out.extend([(n >> 8) as u8 + 0x80, n as u8]),
+        _ => out.extend([0xFF, (n - 0x7F00) as u8, ((n - 0x7F00) >> 8) as u8]),
+    }
+    if n > 0 {
+        // Predefined_Mode for all three tables.
+        out.push(0);
+        out.extend(sequences_bitstream(sequences));
+    }
+    out
+}
+
+fn literals_section(literals: &[u8]) -> Vec<u8> {
+    let mut counts = [0u32; 256];
+    for &byte in literals {
+        counts[byte as usize] += 1;
+    }
+    let raw_size = literals.len() + regenerated_size(RAW_LITERALS, literals.len()).len();
+    match counts.iter().filter(|&&c| c > 0).count() {
+        1 if literals.len() > 1 => {
+            let mut out = regenerated_size(RLE_LITERALS, literals.len());
+            out.push(literals[0]);
+            return out;
+        },
+        2.. => {
+            if let Some(out) = huffman_literals(literals, &counts).filter(|out| out.len() < raw_size) {
+                return out;
+            }
+        },
+        _ => {},
+    }
+    let mut out = regenerated_size(RAW_LITERALS, literals.len());
+    out.extend_from_slice(literals);
+    out
+}
+
+// The header of raw and RLE literals, with a 5, 12 or 20-bit Regenerated_Size.
+fn regenerated_size(block_type: usize, n: usize) -> Vec<u8> {
+    match n {
+        0..32 => vec![(n << 3 | block_type) as u8],
+        32..4096 => vec![(n << 4 | 0b0100 | block_type) as u8, (n >> 4) as u8],
+        _ => vec![(n << 4 | 0b1100 | block_type) as u8, (n >> 4) as u8, (n >> 12) as u8],
+    }
+}
+
+fn huffman_literals(literals: &[u8], counts: &[u32; 256]) -> Option<Vec<u8>> {
+    // The weight of the last symbol is left out, leaving room for 128 others.
+    let last = counts.iter().rposition(|&c| c > 0).unwrap();
+    if last > 128 {
+        return None;
+    }
+    let lengths = code_lengths(&counts[..=last], MAX_LITERAL_CODE_LENGTH);
+    let max = *lengths.iter().max().unwrap();
+    // Codes are handed out from the longest, in symbol order within a length.
+    let mut codes = [0u32; 129];
+    let mut code = 0;
+    for len in (1..=max).rev() {
+        for symbol in (0..=last).filter(|&s| lengths[s] == len) {
+            codes[symbol] = code;
+            code += 1;
+        }
+        code >>= 1;
+    }
+
+    // Tree description: a header byte, then 4-bit weights two to a byte.
+    let weight = |symbol: usize| match lengths.get(symbol) {
+        Some(&len) if len > 0 => max + 1 - len,
+        _ => 0,
+    };
+    let mut body = vec![127 + last as u8];
+    for symbol in (0..last).step_by(2) {
+        let second = if symbol + 1 < last { weight(symbol + 1) } else { 0 };
+        body.push(weight(symbol) << 4 | second);
+    }
+
+    // Each stream is read backwards from its end, so the last literal goes first.
+    let stream = |part: &[u8]| {
+        let mut bits = BitWriter::default();
+        for &byte in part.iter().rev() {
+            bits.put(codes[byte as usize], lengths[byte as usize] as u32);
+        }
+        bits.finish()
+    };
+    let n = literals.len();
+    let (size_format, size_bits): (usize, usize) = if n < 1024 {
+        body.extend(stream(literals));
+        (0, 10)
+    } else {
+        // Four streams, each of the first three with a quarter rounded up,
+        // after a jump table with their sizes.
+        let streams: Vec<Vec<u8>> = literals.chunks(n.div_ceil(4)).map(stream).collect();
+        for stream in &streams[..3] {
+            body.extend_from_slice(&(stream.len() as u16).to_le_bytes());
+        }
+        body.extend(streams.concat());
+        if n.max(body.len()) < 16384 { (2, 14) } else { (3, 18) }
+    };
+    if body.len() >= 1 << size_bits {
+        return None;
+    }
+    let header = COMPRESSED_LITERALS | size_format << 2 | n << 4 | body.len() << (4 + size_bits);
+    let mut out = header.to_le_bytes()[..(4 + 2 * size_bits).div_ceil(8)].to_vec();
+    out.extend(body);
+    Some(out)
+}
+
+// A code with its extra bits and their count.
+type Code = (usize, u32, u32);
+
+// Lengths below the first base each have a code of their own, counted from `min`.
+fn length_code(value: u32, min: u32, base: &[u32], extra: &[u32]) -> Code {
+    if value < base[0] {
+        return ((value - min) as usize, 0, 0);
+    }
+    let i = base.iter().rposition(|&b| b <= value).unwrap();
+    ((base[0] - min) as usize + i, value - base[i], extra[i])
+}
+
+// The sequences are written last to first, since the decoder reads the
+// bitstream backwards: per sequence, the offset, match length and literal
+// length extra bits, then the literal length, match length and offset state
+// updates, after the initial states.
+fn sequences_bitstream(sequences: &[Sequence]) -> Vec<u8> {
+    let [literal_lengths, match_lengths, offsets] = tables();
+    let codes: Vec<[Code; 3]> = sequences.iter().map(|s| {
+        let literal_length = length_code(s.literals as u32, 0, &LITERAL_LENGTH_BASE, &LITERAL_LENGTH_EXTRA);
+        let match_length = length_code(s.length as u32, 3, &MATCH_LENGTH_BASE, &MATCH_LENGTH_EXTRA);
+        // Offset values up to 3 stand for repeated offsets, which are not used.
+        let offset_value = s.distance as u32 + 3;
+        let offset_code = offset_value.ilog2();
+        let offset = (offset_code as usize, offset_value - (1 << offset_code), offset_code);
+        [literal_length, match_length, offset]
+    }).collect();
+
+    let mut bits = BitWriter::default();
+    let last = codes.len() - 1;
+    let mut literal_length_state = literal_lengths.last(codes[last][0].0);
+    let mut match_length_state = match_lengths.last(codes[last][1].0);
+    let mut offset_state = offsets.last(codes[last][2].0);
+    for (i, [literal_length, match_length, offset]) in codes.iter().enumerate().rev() {
+        if i < last {
+            offset_state = offsets.step(offset.0, offset_state, &mut bits);
+            match_length_state = match_lengths.step(match_length.0, match_length_state, &mut bits);
+            literal_length_state = literal_lengths.step(literal_length.0, literal_length_state, &mut bits);
+        }
+        bits.put(literal_length.1, literal_length.2);
+        bits.put(match_length.1, match_length.2);
+        bits.put(offset.1, offset.2);
+    }
+    bits.put(match_length_state, match_lengths.log);
+    bits.put(offset_state, offsets.log);
+    bits.put(literal_length_state, literal_lengths.log);
+    bits.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An RFC 8878 decoder of its own, for frames whose Huffman weights are
+    // described directly and whose sequences use the predefined tables and
+    // no repeated offsets, as made here and by the zstd tool for small input.
+    // Bitstreams are read backwards, from the highest set bit of their last
+    // byte.
+    struct BackBits<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BackBits<'_> {
+        fn new(data: &[u8]) -> BackBits<'_> {
+            let last = *data.last().unwrap();
+            assert_ne!(last, 0, "missing end marker");
+            BackBits { data, pos: data.len() * 8 - 1 - last.leading_zeros() as usize }
+        }
+
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, _| {
+                self.pos -= 1;
+                value << 1 | ((self.data[self.pos / 8] >> (self.pos % 8)) & 1) as u32
+            })
+        }
+    }
+
+    fn huffman_literals(data: &[u8], regenerated: usize, four_streams: bool) -> Vec<u8> {
+        let header = data[0] as usize;
+        assert!(header >= 128, "FSE compressed weights");
+        let count = header - 127;
+        let mut weights: Vec<u32> = (0..count).map(|i| (data[1 + i / 2] >> if i % 2 == 0 { 4 } else { 0 }) as u32 & 15).collect();
+        let total: u32 = weights.iter().filter(|&&w| w > 0).map(|&w| 1 << (w - 1)).sum();
+        let max_bits = total.ilog2() + 1;
+        let rest = (1 << max_bits) - total;
+        assert!(rest.is_power_of_two(), "weights do not complete the tree");
+        weights.push(rest.ilog2() + 1);
+
+        // (length, code) of each symbol, codes handed out from the longest.
+        let mut codes = vec![(0, 0); weights.len()];
+        let mut code = 0;
+        for length in (1..=max_bits).rev() {
+            for (symbol, &weight) in weights.iter().enumerate() {
+                if weight > 0 && max_bits + 1 - weight == length {
+                    codes[symbol] = (length, code);
+                    code += 1;
+                }
+            }
+            code >>= 1;
+        }
+        let decode = |stream: &[u8], n: usize| {
+            let mut bits = BackBits::new(stream);
+            let out: Vec<u8> = (0..n).map(|_| {
+                let (mut length, mut code) = (0, 0);
+                loop {
+                    code = code << 1 | bits.bits(1);
+                    length += 1;
+                    if let Some(symbol) = codes.iter().position(|&c| c == (length, code)) {
+                        return symbol as u8;
+                    }
+                    assert!(length < max_bits, "invalid prefix code");
+                }
+            }).collect();
+            assert_eq!(bits.pos, 0, "stream not used up");
+            out
+        };
+
+        let streams = &data[1 + count.div_ceil(2)..];
+        if !four_streams {
+            return decode(streams, regenerated);
+        }
+        // A jump table with the sizes of the first three streams.
+        let mut sizes: Vec<usize> = (0..3).map(|i| u16::from_le_bytes([streams[2 * i], streams[2 * i + 1]]) as usize).collect();
+        sizes.push(streams.len() - 6 - sizes.iter().sum::<usize>());
+        let segment = regenerated.div_ceil(4);
+        let mut pos = 6;
+        let mut out = Vec::new();
+        for (i, size) in sizes.into_iter().enumerate() {
+            let n = if i == 3 { regenerated - 3 * segment } else { segment };
+            out.extend(decode(&streams[pos..pos + size], n));
+            pos += size;
+        }
+        out
+    }
+
+    // The literals, and the length of the section.
+    fn literals_section(data: &[u8]) -> (Vec<u8>, usize) {
+        let block_type = data[0] & 3;
+        let size_format = (data[0] >> 2) & 3;
+        let header = u64::from_le_bytes(data[..8].try_into().unwrap());
+        if block_type < 2 {
+            let (regenerated, header_size) = match size_format {
+                0 | 2 => (data[0] as usize >> 3, 1),
+                1 => ((header >> 4) as usize & 0xfff, 2),
+                _ => ((header >> 4) as usize & 0xfffff, 3),
+            };
+            return match block_type {
+                0 => (data[header_size..header_size + regenerated].to_vec(), header_size + regenerated),
+                _ => (vec![data[header_size]; regenerated], header_size + 1),
+            };
+        }
+        assert_eq!(block_type, 2, "treeless literals");
+        let (size_bits, header_size) = match size_format {
+            0 | 1 => (10, 3),
+            2 => (14, 4),
+            _ => (18, 5),
+        };
+        let regenerated = (header >> 4) as usize & ((1 << size_bits) - 1);
+        let compressed = (header >> (4 + size_bits)) as usize & ((1 << size_bits) - 1);
+        let body = &data[header_size..header_size + compressed];
+        (huffman_literals(body, regenerated, size_format != 0), header_size + compressed)
+    }
+
+    fn sequences(data: &[u8], literals: &[u8], out: &mut Vec<u8>) {
+        let (count, mut pos) = match data[0] {
+            n @ 0..128 => (n as usize, 1),
+            n @ 128..=254 => (((n as usize - 128) << 8) + data[1] as usize, 2),
+            _ => (u16::from_le_bytes([data[1], data[2]]) as usize + 0x7F00, 3),
+        };
+        let mut literals = literals.iter();
+        if count > 0 {
+            assert_eq!(data[pos], 0, "predefined tables only");
+            pos += 1;
+            let [literal_lengths, match_lengths, offsets] = tables();
+            let mut bits = BackBits::new(&data[pos..]);
+            let mut literal_length_state = bits.bits(6) as usize;
+            let mut offset_state = bits.bits(5) as usize;
+            let mut match_length_state = bits.bits(6) as usize;
+            for i in 0..count {
+                let offset_code = offsets.states[offset_state].symbol as u32;
+                let offset_value = (1 << offset_code) + bits.bits(offset_code);
+                assert!(offset_value > 3, "repeated offset");
+                let code = match_lengths.states[match_length_state].symbol;
+                let match_length = match code {
+                    0..32 => code as u32 + 3,
+                    _ => MATCH_LENGTH_BASE[code - 32] + bits.bits(MATCH_LENGTH_EXTRA[code - 32]),
+                };
+                let code = literal_lengths.states[literal_length_state].symbol;
+                let literal_length = match code {
+                    0..16 => code as u32,
+                    _ => LITERAL_LENGTH_BASE[code - 16] + bits.bits(LITERAL_LENGTH_EXTRA[code - 16]),
+                };
+                if i + 1 < count {
+                    for (state, table) in [
+                        (&mut literal_length_state, literal_lengths),
+                        (&mut match_length_state, match_lengths),
+                        (&mut offset_state, offsets),
+                    ] {
+                        let s = &table.states[*state];
+                        *state = (s.baseline + bits.bits(s.bits)) as usize;
+                    }
+                }
+
+                out.extend(literals.by_ref().take(literal_length as usize));
+                let distance = offset_value as usize - 3;
+                assert!(distance <= out.len() && distance <= WINDOW_SIZE, "offset out of range");
+                for _ in 0..match_length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+            assert_eq!(bits.pos, 0, "bitstream not used up");
+        }
+        out.extend(literals);
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        assert_eq!(&data[..4], MAGIC.to_le_bytes());
+        let descriptor = data[4];
+        assert_eq!(descriptor & 0x07, 0, "dictionary or checksum");
+        let single_segment = descriptor & 0x20 != 0;
+        let content_size_bytes = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        let mut pos = 5 + !single_segment as usize + content_size_bytes;
+
+        let mut out = Vec::new();
+        loop {
+            let header = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+            let last = header & 1 == 1;
+            let size = (header >> 3) as usize;
+            pos += 3;
+            match (header >> 1) & 3 {
+                0 => {
+                    out.extend_from_slice(&data[pos..pos + size]);
+                    pos += size;
+                },
+                1 => {
+                    out.extend(std::iter::repeat_n(data[pos], size));
+                    pos += 1;
+                },
+                2 => {
+                    assert!(size <= BLOCK_MAX, "block too large");
+                    let block = [&data[pos..pos + size], &[0; 8]].concat();
+                    let (literals, used) = literals_section(&block);
+                    sequences(&block[used..size], &literals, &mut out);
+                    pos += size;
+                },
+                _ => panic!("reserved block type"),
+            }
+            if last {
+                assert_eq!(pos, data.len(), "trailing data");
+                return out;
+            }
+        }
+    }
+
+    fn encode(mut encoder: impl StreamEncoder, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            out.extend(encoder.compress(chunk));
+        }
+        out.extend(encoder.finish());
+        out
+    }
+
+    // Text that compresses well, noise from a xorshift generator that does
+    // not, and letters from it with no matches to speak of.
+    fn samples() -> Vec<Vec<u8>> {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(2000);
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        let noise = (0..70_000).map(|_| next() as u8).collect();
+        let letters = (0..70_000).map(|_| b'a' + (next() % 26) as u8).collect();
+        vec![Vec::new(), b"a".to_vec(), b"abcabcabcabcabcabcabcabc".to_vec(), text, noise, letters, vec![0; 100_000]]
+    }
+
+    #[test]
+    fn round_trip() {
+        for level in [1, 3, 19] {
+            for sample in samples() {
+                let whole = encode(ZstdEncoder::new(level), &[&sample]);
+                assert_eq!(decompress(&whole), sample, "level {}", level);
+                let chunks: Vec<&[u8]> = sample.chunks(8192).collect();
+                assert_eq!(decompress(&encode(ZstdEncoder::new(level), &chunks)), sample, "level {} in chunks", level);
+                let small: Vec<&[u8]> = sample.chunks(700).collect();
+                assert_eq!(decompress(&encode(ZstdEncoder::new(level), &small)), sample, "level {} in small chunks", level);
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_input() {
+        let samples = samples();
+        let text = encode(ZstdEncoder::new(3), &[&samples[3]]);
+        assert!(text.len() < samples[3].len() / 20, "{} bytes from {}", text.len(), samples[3].len());
+        // Letters alone take under 5 bits each once Huffman coded.
+        let letters = encode(ZstdEncoder::new(3), &[&samples[5]]);
+        assert!(letters.len() < samples[5].len() * 5 / 8, "{} bytes from {}", letters.len(), samples[5].len());
+    }
+
+    // The decoder itself, on a frame made by `zstd -19 --no-check`.
+    #[test]
+    fn decoder_reads_zstd_output() {
+        let data = [
+            0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x68, 0xad, 0x00, 0x00, 0x78, 0x61, 0x62, 0x72, 0x61, 0x63, 0x61,
+            0x64, 0x61, 0x62, 0x72, 0x61, 0x20, 0x61, 0x62, 0x72, 0x01, 0x00, 0xa7, 0x4b, 0x1d,
+        ];
+        assert_eq!(decompress(&data), b"abracadabra abracadabra abracadabra");
+    }
+}